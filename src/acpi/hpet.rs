@@ -85,6 +85,10 @@ impl GenericAddressStructure {
         volatile_load((crate::HPET_OFFSET + offset) as *const u64)
     }
 
+    pub unsafe fn read_u32(&self, offset: usize) -> u32 {
+        volatile_load((crate::HPET_OFFSET + offset) as *const u32)
+    }
+
     pub unsafe fn write_u64(&mut self, offset: usize, value: u64) {
         volatile_store((crate::HPET_OFFSET + offset) as *mut u64, value);
     }
@@ -106,6 +110,10 @@ impl GenericAddressStructure {
         volatile_load((self.address as usize + offset + crate::PHYS_OFFSET) as *const u64)
     }
 
+    pub unsafe fn read_u32(&self, offset: usize) -> u32 {
+        volatile_load((self.address as usize + offset + crate::PHYS_OFFSET) as *const u32)
+    }
+
     pub unsafe fn write_u64(&mut self, offset: usize, value: u64) {
         volatile_store((self.address as usize + offset + crate::PHYS_OFFSET) as *mut u64, value);
     }
@@ -14,6 +14,7 @@ use crate::paging::{KernelMapper, PageFlags, PhysicalAddress, RmmA, RmmArch};
 use self::madt::Madt;
 use self::rsdt::Rsdt;
 use self::sdt::Sdt;
+use self::srat::Srat;
 use self::xsdt::Xsdt;
 use self::hpet::Hpet;
 use self::rxsdt::Rxsdt;
@@ -23,6 +24,7 @@ pub mod hpet;
 pub mod madt;
 mod rsdt;
 pub mod sdt;
+mod srat;
 mod xsdt;
 mod rxsdt;
 mod rsdp;
@@ -142,6 +144,7 @@ pub unsafe fn init(already_supplied_rsdps: Option<(u64, u64)>) {
         // TODO: Let userspace setup HPET, and then provide an interface to specify which timer to
         // use?
         Hpet::init();
+        Srat::init();
     } else {
         println!("NO RSDP FOUND");
     }
@@ -0,0 +1,72 @@
+use crate::memory::numa;
+use crate::paging::PhysicalAddress;
+
+use super::sdt::Sdt;
+use super::find_sdt;
+
+/// Reserved bytes between the header and the affinity structures
+const RESERVED: usize = 12;
+
+const PROCESSOR_AFFINITY: u8 = 0;
+const MEMORY_AFFINITY: u8 = 1;
+const X2APIC_AFFINITY: u8 = 2;
+
+/// The entry is enabled, in the flags of every affinity structure
+const FLAG_ENABLED: u32 = 1;
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from(read_u32(data, offset)) | u64::from(read_u32(data, offset + 4)) << 32
+}
+
+/// The System Resource Affinity Table, which assigns CPUs and memory to proximity domains. The
+/// kernel uses the proximity domains as NUMA node numbers.
+pub struct Srat;
+
+impl Srat {
+    pub fn init() {
+        let srat_sdt = find_sdt("SRAT");
+        if srat_sdt.len() != 1 {
+            // Without an SRAT, everything is on node 0
+            return;
+        }
+        let data = srat_sdt[0].data();
+
+        let mut offset = RESERVED;
+        while offset + 2 <= data.len() {
+            let kind = data[offset];
+            let len = data[offset + 1] as usize;
+            if len < 2 || offset + len > data.len() {
+                println!("  SRAT: invalid entry length {} at {}", len, offset);
+                break;
+            }
+            let entry = &data[offset..offset + len];
+
+            match kind {
+                PROCESSOR_AFFINITY if len >= 16 => if read_u32(entry, 4) & FLAG_ENABLED == FLAG_ENABLED {
+                    let node = u32::from(entry[2]) | u32::from(entry[9]) << 8 | u32::from(entry[10]) << 16 | u32::from(entry[11]) << 24;
+                    // CPU IDs are the local APIC IDs
+                    numa::add_cpu(entry[3] as usize, node);
+                },
+                MEMORY_AFFINITY if len >= 40 => if read_u32(entry, 28) & FLAG_ENABLED == FLAG_ENABLED {
+                    let node = read_u32(entry, 2);
+                    let base = read_u64(entry, 8);
+                    let size = read_u64(entry, 16);
+                    println!("  SRAT: {:#x}-{:#x}: node {}", base, base + size, node);
+                    numa::add_memory(PhysicalAddress::new(base as usize), size as usize, node);
+                },
+                X2APIC_AFFINITY if len >= 24 => if read_u32(entry, 12) & FLAG_ENABLED == FLAG_ENABLED {
+                    numa::add_cpu(read_u32(entry, 8) as usize, read_u32(entry, 4));
+                },
+                _ => (),
+            }
+
+            offset += len;
+        }
+    }
+}
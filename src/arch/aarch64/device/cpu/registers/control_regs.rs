@@ -70,6 +70,12 @@ pub unsafe fn esr_el1() -> u32 {
     ret
 }
 
+pub unsafe fn far_el1() -> u64 {
+    let ret: u64;
+    asm!("mrs {}, far_el1", out(reg) ret);
+    ret
+}
+
 pub unsafe fn cntfreq_el0() -> u32 {
     let ret: u32;
     asm!("mrs {}, cntfrq_el0", out(reg) ret);
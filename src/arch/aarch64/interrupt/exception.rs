@@ -1,9 +1,11 @@
 use core::arch::asm;
 
 use crate::{
-    context,
+    context::{self, signal::{record_fault, FaultAccess, SEGV_ACCERR, SEGV_MAPERR, SEGV_STKOVF}},
     cpu_id,
+    device::cpu::registers::control_regs,
     interrupt::stack_trace,
+    paging::VirtualAddress,
     syscall,
     syscall::flag::*,
 
@@ -14,24 +16,58 @@ use crate::{
 exception_stack!(synchronous_exception_at_el1_with_sp0, |stack| {
     println!("Synchronous exception at EL1 with SP0");
     stack.dump();
-    stack_trace();
-    loop {}
+    panic!("Synchronous exception at EL1 with SP0");
 });
 
 exception_stack!(synchronous_exception_at_el1_with_spx, |stack| {
     println!("Synchronous exception at EL1 with SPx");
     stack.dump();
-    stack_trace();
-    loop {}
+    panic!("Synchronous exception at EL1 with SPx");
 });
 
 exception_stack!(synchronous_exception_at_el0, |stack| {
     with_exception_stack!(|stack| {
         let exception_code = (stack.iret.esr_el1 & (0x3f << 26)) >> 26;
-        if exception_code != 0b010101 {
+        // Translation faults, with a status code of 0b0001xx, may be on released anonymous memory
+        if (exception_code == 0b100000 || exception_code == 0b100100)
+            && stack.iret.esr_el1 & 0b111100 == 0b000100
+            && context::memory::fault_in_current(VirtualAddress::new(control_regs::far_el1() as usize)) {
+            context::count_page_fault(false);
+            stack.scratch.x0
+        // Permission faults on writes, with a status code of 0b0011xx, may be on private file
+        // mappings, which are copied on write
+        } else if exception_code == 0b100100
+            && stack.iret.esr_el1 & 0b111100 == 0b001100
+            && stack.iret.esr_el1 & (1 << 6) != 0
+            && context::memory::copy_on_write_current(VirtualAddress::new(control_regs::far_el1() as usize)) {
+            context::count_page_fault(false);
+            stack.scratch.x0
+        } else if exception_code != 0b010101 {
             println!("FATAL: Not an SVC induced synchronous exception");
             stack.dump();
             stack_trace();
+            // Instruction or data abort from a lower exception level
+            if exception_code == 0b100000 || exception_code == 0b100100 {
+                let far = control_regs::far_el1() as usize;
+                // Fault status code, permission faults are 0b0011xx
+                let fsc = stack.iret.esr_el1 & 0x3f;
+                let code = if fsc & 0b111100 == 0b001100 {
+                    SEGV_ACCERR
+                } else if context::memory::is_stack_overflow_current(VirtualAddress::new(far), stack.iret.sp_el0) {
+                    println!("Stack overflow");
+                    SEGV_STKOVF
+                } else {
+                    SEGV_MAPERR
+                };
+                let access = if exception_code == 0b100000 {
+                    FaultAccess::Execute
+                } else if stack.iret.esr_el1 & (1 << 6) != 0 {
+                    FaultAccess::Write
+                } else {
+                    FaultAccess::Read
+                };
+                record_fault(far, code, access);
+            }
             crate::ksignal(SIGSEGV);
             stack.scratch.x0
         } else {
@@ -44,6 +80,5 @@ exception_stack!(synchronous_exception_at_el0, |stack| {
 exception_stack!(unhandled_exception, |stack| {
     println!("Unhandled exception");
     stack.dump();
-    stack_trace();
-    loop {}
+    panic!("Unhandled exception");
 });
@@ -203,6 +203,11 @@ impl InterruptStack {
         self.scratch.x1 = all.x1;
         self.scratch.x0 = all.x0;
     }
+    /// Whether `all` can be loaded into these registers without making the context unrunnable.
+    /// Only general purpose registers are loaded, which are always fine.
+    pub fn can_load(&self, _all: &IntRegisters) -> bool {
+        true
+    }
 
     //TODO
     pub fn is_singlestep(&self) -> bool { false }
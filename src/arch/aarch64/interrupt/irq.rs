@@ -1,4 +1,4 @@
-use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT};
 
 use crate::context;
 use crate::context::timeout;
@@ -50,14 +50,15 @@ pub unsafe fn irq_handler_com1(irq: u32) {
 
 pub unsafe fn irq_handler_gentimer(irq: u32) {
     GENTIMER.clear_irq();
+    crate::rand::add_interrupt_entropy();
     {
         *time::OFFSET.lock() += GENTIMER.clk_freq as u128;
     }
 
     timeout::trigger();
 
-    // Switch after 3 ticks (about 6.75 ms)
-    if PIT_TICKS.fetch_add(1, Ordering::SeqCst) >= 2 {
+    // Switch once the current context has used up its quantum
+    if context::tick() {
         let _ = context::switch();
     }
     trigger(irq);
@@ -19,6 +19,9 @@ pub mod ipi;
 /// Paging
 pub mod paging;
 
+/// Hardware entropy sources
+pub mod rand;
+
 pub mod rmm;
 
 /// Initialization and start function
@@ -0,0 +1,35 @@
+//! # Huge pages
+//! User memory is only mapped with page entries on this architecture, so there are never huge
+//! pages to find, and `MAP_HUGETLB` grants fall back to page entries. See the x86_64 version.
+
+use rmm::PageFlush;
+
+use crate::memory::Enomem;
+
+use super::{PageFlags, PageMapper, PhysicalAddress, RmmA, VirtualAddress, ENTRY_COUNT, PAGE_SIZE};
+
+/// Number of pages mapped by a huge page entry
+pub const HUGE_PAGE_PAGES: usize = ENTRY_COUNT;
+
+/// Size of huge pages
+pub const HUGE_PAGE_SIZE: usize = HUGE_PAGE_PAGES * PAGE_SIZE;
+
+pub fn translate(_mapper: &PageMapper, _virt: VirtualAddress) -> Option<(PhysicalAddress, PageFlags<RmmA>)> {
+    None
+}
+
+pub unsafe fn map(_mapper: &mut PageMapper, _virt: VirtualAddress, _phys: PhysicalAddress, _flags: PageFlags<RmmA>) -> Option<PageFlush<RmmA>> {
+    None
+}
+
+pub unsafe fn remap(_mapper: &mut PageMapper, _virt: VirtualAddress, _flags: PageFlags<RmmA>) -> Option<PageFlush<RmmA>> {
+    None
+}
+
+pub unsafe fn unmap(_mapper: &mut PageMapper, _virt: VirtualAddress) -> Option<(PhysicalAddress, PageFlags<RmmA>, PageFlush<RmmA>)> {
+    None
+}
+
+pub unsafe fn split(_mapper: &mut PageMapper, _virt: VirtualAddress) -> Result<Option<PageFlush<RmmA>>, Enomem> {
+    Ok(None)
+}
@@ -22,6 +22,7 @@ pub type PageMapper = rmm::PageMapper<RmmA, crate::arch::rmm::LockedAllocator>;
 pub use crate::rmm::KernelMapper;
 
 pub mod entry;
+pub mod huge;
 pub mod mapper;
 
 /// Number of entries per page table
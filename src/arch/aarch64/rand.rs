@@ -0,0 +1,14 @@
+//! Hardware entropy sources
+
+/// There is no hardware random number generator support yet
+//TODO: FEAT_RNG RNDR
+pub fn hw_random() -> Option<u64> {
+    None
+}
+
+/// Virtual counter, whose low bits jitter between interrupts
+pub fn cycles() -> u64 {
+    let value: u64;
+    unsafe { core::arch::asm!("mrs {}, cntvct_el0", out(reg) value) };
+    value
+}
@@ -17,6 +17,8 @@ use crate::init::device_tree;
 use crate::interrupt;
 use crate::log::{self, info};
 use crate::paging::{self, KernelMapper};
+#[cfg(not(feature = "doc"))]
+use crate::symbols;
 
 /// Test of zero values in BSS.
 static BSS_TEST_ZERO: usize = 0;
@@ -103,6 +105,9 @@ pub unsafe extern fn kstart(args_ptr: *const KernelArgs) -> ! {
             );
         });
 
+        // Copy the command line before the memory of the bootloader can be reclaimed
+        crate::cmdline::init(env);
+
         info!("Redox OS starting...");
         info!("Kernel: {:X}:{:X}", args.kernel_base, args.kernel_base + args.kernel_size);
         info!("Stack: {:X}:{:X}", args.stack_base, args.stack_base + args.stack_size);
@@ -143,6 +148,13 @@ pub unsafe extern fn kstart(args_ptr: *const KernelArgs) -> ! {
         // Initialize paging
         let tcb_offset = paging::init(0);
 
+        // Locate the kernel symbol table, used by stack traces
+        #[cfg(not(feature = "doc"))]
+        symbols::init(slice::from_raw_parts(
+            (KERNEL_BASE.load(Ordering::SeqCst) + crate::KERNEL_OFFSET) as *const u8,
+            KERNEL_SIZE.load(Ordering::SeqCst)
+        ));
+
         // Test tdata and tbss
         {
             assert_eq!(TBSS_TEST_ZERO, 0);
@@ -2,3 +2,8 @@ pub fn counter() -> u128 {
     //TODO: aarch64 generic timer counter
     0
 }
+
+pub fn resolution() -> u128 {
+    // The time only advances on timer interrupts, until `counter` is implemented
+    unsafe { crate::device::generic_timer::GENTIMER.clk_freq as u128 }
+}
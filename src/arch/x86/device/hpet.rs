@@ -1,3 +1,5 @@
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+
 use crate::acpi::hpet::Hpet;
 use super::pit;
 
@@ -13,6 +15,7 @@ const GENERAL_CONFIG_OFFSET: usize = 0x10;
 const GENERAL_INTERRUPT_OFFSET: usize = 0x20;
 pub(crate) const MAIN_COUNTER_OFFSET: usize = 0xF0;
 // const NUM_TIMER_CAP_MASK: u64 = 0x0f00;
+const COUNT_SIZE_CAP: u64 = 0x2000;
 const LEG_RT_CAP: u64 = 0x8000;
 const T0_CONFIG_CAPABILITY_OFFSET: usize = 0x100;
 pub(crate) const T0_COMPARATOR_OFFSET: usize = 0x108;
@@ -67,6 +70,54 @@ pub unsafe fn init(hpet: &mut Hpet) -> bool {
     true
 }
 
+/// Last value returned by `read_counter` for HPETs with a 32-bit main counter, used to extend the
+/// counter to 64 bits across rollovers
+static LAST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Read the main counter as a monotonic 64-bit value.
+///
+/// The counter is read as two 32-bit halves, so that it cannot tear on CPUs without 64-bit MMIO
+/// reads. If the HPET only implements a 32-bit counter, rollovers are accounted for in software,
+/// which requires this to be called at least once per counter period.
+pub unsafe fn read_counter(hpet: &Hpet) -> u64 {
+    let capability = hpet.base_address.read_u64(CAPABILITY_OFFSET);
+
+    let counter = if capability & COUNT_SIZE_CAP == COUNT_SIZE_CAP {
+        // Re-read the high half until it is stable, in case the low half rolled over between the
+        // two reads.
+        loop {
+            let high = hpet.base_address.read_u32(MAIN_COUNTER_OFFSET + 4);
+            let low = hpet.base_address.read_u32(MAIN_COUNTER_OFFSET);
+            let high_again = hpet.base_address.read_u32(MAIN_COUNTER_OFFSET + 4);
+            if high == high_again {
+                break (u64::from(high) << 32) | u64::from(low);
+            }
+        }
+    } else {
+        let low = u64::from(hpet.base_address.read_u32(MAIN_COUNTER_OFFSET));
+        let mut last = LAST_COUNTER.load(Ordering::Acquire);
+        loop {
+            let mut counter = (last & !0xFFFF_FFFF) | low;
+            if counter < last {
+                // The 32-bit counter wrapped since it was last read
+                counter += 1 << 32;
+            }
+            match LAST_COUNTER.compare_exchange_weak(last, counter, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => break counter,
+                // Another CPU read a newer value; never go backwards
+                Err(newer) if newer >= counter => break newer,
+                Err(newer) => last = newer,
+            }
+        }
+    };
+
+    // Keep subsequent memory accesses, e.g. a TSC read being compared against this value, from
+    // being moved before the counter read.
+    fence(Ordering::SeqCst);
+
+    counter
+}
+
 pub unsafe fn debug(hpet: &mut Hpet) {
     println!("HPET @ {:#x}", { hpet.base_address.address });
 
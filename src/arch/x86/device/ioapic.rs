@@ -1,4 +1,5 @@
 use core::{fmt, ptr};
+use core::convert::TryFrom;
 
 use alloc::vec::Vec;
 use spin::Mutex;
@@ -109,6 +110,43 @@ impl IoApic {
         reg |= u64::from(mask) << 16;
         guard.write_ioredtbl(idx, reg);
     }
+    /// Change the physical local APIC ID that an entry is delivered to, leaving the vector,
+    /// trigger mode and polarity untouched.
+    pub fn set_destination(&self, gsi: u32, dest: u8) {
+        let idx = (gsi - self.gsi_start) as u8;
+        Self::write_destination(&mut self.regs.lock(), idx, dest);
+    }
+    /// Like `set_destination`, but give up and return false if the registers are in use, for
+    /// callers in interrupt handlers that may have interrupted their user on the same CPU
+    pub fn try_set_destination(&self, gsi: u32, dest: u8) -> bool {
+        let idx = (gsi - self.gsi_start) as u8;
+        match self.regs.try_lock() {
+            Some(mut guard) => {
+                Self::write_destination(&mut guard, idx, dest);
+                true
+            },
+            None => false,
+        }
+    }
+    fn write_destination(guard: &mut IoApicRegs, idx: u8, dest: u8) {
+        let reg = guard.read_ioredtbl(idx);
+        let (low_reg, high_reg) = (0x10 + idx * 2, 0x10 + idx * 2 + 1);
+        let low = reg as u32;
+        let high = (reg >> 32) as u32 & 0x00FF_FFFF | u32::from(dest) << 24;
+
+        if (low >> 15) & 1 == ApicTriggerMode::Level as u32 {
+            // A level triggered line stays asserted while masked, so masking it while the
+            // destination changes cannot lose an interrupt; it is redelivered once the original
+            // mask bit is restored.
+            guard.write_reg(low_reg, low | (1 << 16));
+            guard.write_reg(high_reg, high);
+            guard.write_reg(low_reg, low);
+        } else {
+            // Edges arriving while masked would be dropped, so instead only rewrite the upper
+            // half, which the I/O APIC applies atomically.
+            guard.write_reg(high_reg, high);
+        }
+    }
 }
 #[repr(u8)]
 #[derive(Clone, Copy, Debug)]
@@ -396,6 +434,43 @@ pub unsafe fn mask(irq: u8) {
     };
     apic.set_mask(gsi, true);
 }
+/// The physical local APIC ID of a CPU. The BSP is CPU 0, while APs are numbered by their local
+/// APIC ID when started from the MADT.
+pub fn cpu_apic_id(cpu_id: usize) -> Option<u8> {
+    if cpu_id == 0 {
+        super::local_apic::bsp_apic_id().and_then(|id| u8::try_from(id).ok())
+    } else {
+        u8::try_from(cpu_id).ok()
+    }
+}
+/// Deliver an IRQ to a specific CPU, e.g. the one processing the device's data. Returns false if
+/// the IRQ is not routed through an I/O APIC or the CPU cannot be addressed.
+pub unsafe fn set_irq_affinity(irq: u8, cpu_id: usize) -> bool {
+    let dest = match cpu_apic_id(cpu_id) {
+        Some(dest) => dest,
+        None => return false,
+    };
+    let gsi = resolve(irq);
+    let apic = match find_ioapic(gsi) {
+        Some(a) => a,
+        None => return false,
+    };
+    apic.set_destination(gsi, dest);
+    true
+}
+/// Like `set_irq_affinity`, but return false without waiting if the I/O APIC is in use, as it may
+/// be by the code that the caller interrupted
+pub unsafe fn try_set_irq_affinity(irq: u8, cpu_id: usize) -> bool {
+    let dest = match cpu_apic_id(cpu_id) {
+        Some(dest) => dest,
+        None => return false,
+    };
+    let gsi = resolve(irq);
+    match find_ioapic(gsi) {
+        Some(apic) => apic.try_set_destination(gsi, dest),
+        None => false,
+    }
+}
 pub unsafe fn unmask(irq: u8) {
     let gsi = resolve(irq);
     let apic = match find_ioapic(gsi) {
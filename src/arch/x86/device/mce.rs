@@ -0,0 +1,212 @@
+//! Machine check architecture, through which the processor reports hardware errors
+
+use core::fmt::Write;
+use x86::controlregs::{cr4, cr4_write, Cr4};
+use x86::msr::{rdmsr, wrmsr};
+
+use super::super::cpuid::cpuid;
+
+// TODO: Move to x86::msr
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17A;
+const IA32_MCG_CTL: u32 = 0x17B;
+const IA32_MC0_CTL: u32 = 0x400;
+
+/// Number of error reporting banks, in IA32_MCG_CAP
+const MCG_CAP_COUNT: u64 = 0xFF;
+/// IA32_MCG_CTL is present, in IA32_MCG_CAP
+const MCG_CAP_CTL_P: u64 = 1 << 8;
+
+bitflags! {
+    /// Bits of the IA32_MCG_STATUS MSR
+    pub struct McgStatus: u64 {
+        /// Execution can be restarted at the interrupted instruction
+        const RIPV = 1 << 0;
+        /// The interrupted instruction is the one that caused the error
+        const EIPV = 1 << 1;
+        /// A machine check is in progress, another one before this is cleared shuts the CPU down
+        const MCIP = 1 << 2;
+    }
+}
+
+bitflags! {
+    /// Bits of the IA32_MCi_STATUS MSRs, besides the error codes in bits 31:0
+    pub struct McStatus: u64 {
+        /// The bank holds a valid error
+        const VAL = 1 << 63;
+        /// An error was lost, because this one was logged before it was cleared
+        const OVER = 1 << 62;
+        /// The error was not corrected
+        const UC = 1 << 61;
+        /// Reporting the error was enabled in IA32_MCi_CTL
+        const EN = 1 << 60;
+        /// IA32_MCi_MISC holds more information
+        const MISCV = 1 << 59;
+        /// IA32_MCi_ADDR holds the address of the error
+        const ADDRV = 1 << 58;
+        /// The processor context is corrupt, so execution cannot continue
+        const PCC = 1 << 57;
+        /// The error was signaled with a machine check exception, rather than only logged
+        const S = 1 << 56;
+        /// Software has to act on the error before execution continues
+        const AR = 1 << 55;
+    }
+}
+
+fn mc_ctl(bank: u32) -> u32 {
+    IA32_MC0_CTL + 4 * bank
+}
+fn mc_status(bank: u32) -> u32 {
+    IA32_MC0_CTL + 4 * bank + 1
+}
+fn mc_addr(bank: u32) -> u32 {
+    IA32_MC0_CTL + 4 * bank + 2
+}
+fn mc_misc(bank: u32) -> u32 {
+    IA32_MC0_CTL + 4 * bank + 3
+}
+
+fn has_mca() -> (bool, bool) {
+    cpuid().map_or((false, false), |cpuid| {
+        cpuid.get_feature_info().map_or((false, false), |feature_info| {
+            (feature_info.has_mce(), feature_info.has_mca())
+        })
+    })
+}
+
+fn bank_count() -> u32 {
+    match has_mca() {
+        (_, true) => unsafe { (rdmsr(IA32_MCG_CAP) & MCG_CAP_COUNT) as u32 },
+        (_, false) => 0,
+    }
+}
+
+/// Enable machine check exceptions on this CPU. Without them, a machine check shuts the CPU down.
+/// Errors logged before, e.g. across a warm reset, are reported first.
+pub unsafe fn init() {
+    let (has_mce, has_mca) = has_mca();
+    if ! has_mce {
+        return;
+    }
+
+    if has_mca {
+        let cap = rdmsr(IA32_MCG_CAP);
+        if cap & MCG_CAP_CTL_P == MCG_CAP_CTL_P {
+            wrmsr(IA32_MCG_CTL, !0);
+        }
+
+        let mut writer = crate::debug::Writer::new();
+        for bank in 0..(cap & MCG_CAP_COUNT) as u32 {
+            report_bank(&mut writer, bank);
+            wrmsr(mc_ctl(bank), !0);
+            wrmsr(mc_status(bank), 0);
+        }
+    }
+
+    cr4_write(cr4() | Cr4::CR4_ENABLE_MACHINE_CHECK);
+}
+
+/// Outcome of a machine check, as reported by `report`
+pub struct MachineCheck {
+    /// The interrupted context can be resumed, possibly after being signalled
+    pub restartable: bool,
+    /// Some error was not corrected by hardware
+    pub uncorrected: bool,
+}
+
+/// Print the errors of every bank, then clear them and the machine check in progress flag.
+/// Nothing is allocated or locked, so that this works even if the error corrupted kernel memory.
+pub unsafe fn report(w: &mut impl Write) -> MachineCheck {
+    let mcg_status = McgStatus::from_bits_truncate(rdmsr(IA32_MCG_STATUS));
+    let _ = writeln!(w, "Machine check, MCG_STATUS {:?}", mcg_status);
+
+    let mut check = MachineCheck {
+        restartable: mcg_status.contains(McgStatus::RIPV),
+        uncorrected: false,
+    };
+    for bank in 0..bank_count() {
+        if let Some(status) = report_bank(w, bank) {
+            if status.contains(McStatus::PCC) {
+                check.restartable = false;
+            }
+            if status.contains(McStatus::UC) {
+                check.uncorrected = true;
+            }
+            wrmsr(mc_status(bank), 0);
+        }
+    }
+
+    wrmsr(IA32_MCG_STATUS, 0);
+    check
+}
+
+/// Print the error logged in `bank`, if any, and return its status bits
+unsafe fn report_bank(w: &mut impl Write, bank: u32) -> Option<McStatus> {
+    let raw = rdmsr(mc_status(bank));
+    let status = McStatus::from_bits_truncate(raw);
+    if ! status.contains(McStatus::VAL) {
+        return None;
+    }
+
+    let _ = write!(w, "  Bank {}: ", bank);
+    describe(w, raw as u16);
+    let _ = writeln!(w, ", model specific code {:#06x}", (raw >> 16) as u16);
+    let _ = writeln!(w, "    {:?}", status);
+    if status.contains(McStatus::ADDRV) {
+        let _ = writeln!(w, "    Address: {:#x}", rdmsr(mc_addr(bank)));
+    }
+    if status.contains(McStatus::MISCV) {
+        let _ = writeln!(w, "    Misc: {:#x}", rdmsr(mc_misc(bank)));
+    }
+
+    Some(status)
+}
+
+const TRANSACTIONS: [&str; 4] = ["instruction", "data", "generic", "unknown"];
+const LEVELS: [&str; 4] = ["L0", "L1", "L2", "generic level"];
+const REQUESTS: [&str; 16] = [
+    "generic", "read", "write", "data read", "data write", "instruction fetch", "prefetch",
+    "eviction", "snoop", "unknown", "unknown", "unknown", "unknown", "unknown", "unknown", "unknown",
+];
+const PARTICIPATIONS: [&str; 4] = ["source", "responder", "observer", "generic"];
+const MEMORY_IO: [&str; 4] = ["memory", "reserved", "I/O", "other"];
+const MEMORY_TRANSACTIONS: [&str; 8] = [
+    "generic", "read", "write", "address/command", "memory scrubbing", "unknown", "unknown", "unknown",
+];
+
+/// Decode the MCA error code in bits 15:0 of IA32_MCi_STATUS, in the simple or compound format
+fn describe(w: &mut impl Write, code: u16) {
+    let transaction = TRANSACTIONS[usize::from((code >> 2) & 0b11)];
+    let level = LEVELS[usize::from(code & 0b11)];
+    let request = REQUESTS[usize::from((code >> 4) & 0xF)];
+    // Bit 12 of compound codes only tells whether corrected errors are being filtered
+    let compound = code & !(1 << 12);
+
+    let _ = match code {
+        0x0000 => write!(w, "no error"),
+        0x0001 => write!(w, "unclassified error"),
+        0x0002 => write!(w, "microcode ROM parity error"),
+        0x0003 => write!(w, "external error"),
+        0x0004 => write!(w, "functional redundancy check error"),
+        0x0005 => write!(w, "internal parity error"),
+        0x0006 => write!(w, "SMM handler code access violation"),
+        0x0400 => write!(w, "internal timer error"),
+        _ if code & 0xFC00 == 0x0400 => write!(w, "internal unclassified error"),
+        _ if compound & 0xFFF0 == 0x0010 => write!(w, "{} TLB error, {}", transaction, level),
+        _ if compound & 0xFF80 == 0x0080 => {
+            let channel = code & 0xF;
+            let _ = write!(w, "memory controller {} error", MEMORY_TRANSACTIONS[usize::from((code >> 4) & 0b111)]);
+            if channel == 0xF {
+                write!(w, ", unknown channel")
+            } else {
+                write!(w, ", channel {}", channel)
+            }
+        },
+        _ if compound & 0xFF00 == 0x0100 => write!(w, "cache {} error, {} {}", request, transaction, level),
+        _ if compound & 0xF800 == 0x0800 => {
+            let timeout = if code & (1 << 8) != 0 { ", timed out" } else { "" };
+            write!(w, "bus {} {} error as {}, {}{}", request, MEMORY_IO[usize::from((code >> 2) & 0b11)], PARTICIPATIONS[usize::from((code >> 9) & 0b11)], level, timeout)
+        },
+        _ => write!(w, "unknown error {:#06x}", code),
+    };
+}
@@ -110,6 +110,15 @@ pub unsafe fn set_tss_stack(stack: usize) {
     KPCR.tss.0.esp0 = stack as u32;
 }
 
+/// Set the FS and GS bases used by userspace for TLS. The base of a segment is cached when its
+/// selector is loaded, so FS is reloaded here for the new base to apply. GS is reloaded anyway on
+/// the way back to userspace.
+pub unsafe fn set_user_tls(fsbase: u32, gsbase: u32) {
+    GDT[GDT_USER_FS].set_offset(fsbase);
+    GDT[GDT_USER_GS].set_offset(gsbase);
+    segmentation::load_fs(SegmentSelector::new(GDT_USER_FS as u16, Ring::Ring3));
+}
+
 // Initialize GDT
 pub unsafe fn init() {
     {
@@ -139,9 +148,26 @@ pub unsafe fn init() {
     segmentation::load_ss(SegmentSelector::new(GDT_KERNEL_DATA as u16, Ring::Ring0));
 }
 
+/// Read the CPU ID back from the GDT currently loaded, which is where paranoid interrupt handlers
+/// find it. Returns `None` if the loaded GDT is too small to contain it.
+pub unsafe fn loaded_cpu_id() -> Option<u32> {
+    let mut gdtr: DescriptorTablePointer<SegmentDescriptor> = DescriptorTablePointer {
+        limit: 0,
+        base: core::ptr::null(),
+    };
+    dtables::sgdt(&mut gdtr);
+
+    if usize::from(gdtr.limit) + 1 < (GDT_CPU_ID_CONTAINER + 1) * mem::size_of::<GdtEntry>() {
+        return None;
+    }
+    Some((gdtr.base as *const GdtEntry).add(GDT_CPU_ID_CONTAINER).cast::<u32>().read())
+}
+
 /// Initialize GDT with TLS
 pub unsafe fn init_paging(cpu_id: u32, tcb_offset: usize, stack_offset: usize) {
-    //TODO: will this work with multicore?
+    // INIT_GDT is shared by all CPUs, but they are started one after another, and the descriptor
+    // is cached in GS when it is loaded, so changing it for the next CPU is harmless. Every access
+    // to GDT below goes through this CPU's own TLS.
     {
         INIT_GDT[GDT_KERNEL_KPCR].set_offset(tcb_offset as u32);
         segmentation::load_gs(SegmentSelector::new(GDT_KERNEL_KPCR as u16, Ring::Ring0));
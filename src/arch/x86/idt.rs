@@ -9,6 +9,7 @@ use x86::segmentation::Descriptor as X86IdtEntry;
 use x86::dtables::{self, DescriptorTablePointer};
 
 use crate::interrupt::*;
+use crate::device::local_apic;
 use crate::ipi::IpiKind;
 
 use spin::RwLock;
@@ -142,6 +143,7 @@ pub unsafe fn init_paging_post_heap(is_bsp: bool, cpu_id: usize) {
         let idt = idts_btree.entry(cpu_id).or_insert_with(|| Box::leak(Box::new(Idt::new())));
         init_generic(is_bsp, idt);
     }
+    crate::device::irq_balance::set_online(cpu_id);
 }
 
 /// Initializes a fully functional IDT for use before it be moved into the map. This is ONLY called
@@ -150,43 +152,47 @@ pub unsafe fn init_paging_bsp() {
     init_generic(true, &mut INIT_BSP_IDT);
 }
 
-/// Initializes an IDT for any type of processor.
-pub unsafe fn init_generic(is_bsp: bool, idt: &mut Idt) {
-    let (current_idt, current_reservations) = (&mut idt.entries, &mut idt.reservations);
+/// Allocate a stack for entry `index` of the interrupt stack table of the current CPU, returning
+/// the index to pass to `IdtEntry::set_ist`
+unsafe fn allocate_ist(index: u8) -> u8 {
+    // Allocate 64 KiB of stack space for the backup stack.
+    const BACKUP_STACK_SIZE: usize = 65536;
+    assert_eq!(BACKUP_STACK_SIZE % crate::memory::PAGE_SIZE, 0);
+    let page_count = BACKUP_STACK_SIZE / crate::memory::PAGE_SIZE;
+    let frames = crate::memory::allocate_frames(page_count)
+        .expect("failed to allocate pages for backup interrupt stack");
 
-    IDTR.limit = (current_idt.len() * mem::size_of::<IdtEntry>() - 1) as u16;
-    IDTR.base = current_idt.as_ptr() as *const X86IdtEntry;
+    use crate::paging::{RmmA, RmmArch};
 
-    let backup_ist = {
-        // We give Non-Maskable Interrupts, Double Fault, and Machine Check exceptions separate
-        // stacks, since these (unless we are going to set up NMI watchdogs like Linux does) are
-        // considered the most fatal, especially Double Faults which are caused by errors __when
-        // accessing the system IDT__. If that goes wrong, then kernel memory may be partially
-        // corrupt, and we want a separate stack.
-        //
-        // Note that each CPU has its own "backup interrupt stack".
-        let index = 1_u8;
+    // Physical pages are mapped linearly. So is the linearly mapped virtual memory.
+    let base_address = RmmA::phys_to_virt(frames.start_address());
 
-        // Allocate 64 KiB of stack space for the backup stack.
-        const BACKUP_STACK_SIZE: usize = 65536;
-        assert_eq!(BACKUP_STACK_SIZE % crate::memory::PAGE_SIZE, 0);
-        let page_count = BACKUP_STACK_SIZE / crate::memory::PAGE_SIZE;
-        let frames = crate::memory::allocate_frames(page_count)
-            .expect("failed to allocate pages for backup interrupt stack");
+    // Stack always grows downwards.
+    let address = base_address.data() + BACKUP_STACK_SIZE;
 
-        use crate::paging::{RmmA, RmmArch};
+    // Put it in the IST, whose entries are numbered from 1.
+    //TODO: x86: crate::gdt::KPCR.tss.0.ist[usize::from(index - 1)] = address as u64;
 
-        // Physical pages are mapped linearly. So is the linearly mapped virtual memory.
-        let base_address = RmmA::phys_to_virt(frames.start_address());
+    index
+}
 
-        // Stack always grows downwards.
-        let address = base_address.data() + BACKUP_STACK_SIZE;
+/// Initializes an IDT for any type of processor.
+pub unsafe fn init_generic(is_bsp: bool, idt: &mut Idt) {
+    let (current_idt, current_reservations) = (&mut idt.entries, &mut idt.reservations);
 
-        // Put them in the 1st entry of the IST.
-        //TODO: x86: crate::gdt::KPCR.tss.0.ist[usize::from(index - 1)] = address as u64;
+    IDTR.limit = (current_idt.len() * mem::size_of::<IdtEntry>() - 1) as u16;
+    IDTR.base = current_idt.as_ptr() as *const X86IdtEntry;
 
-        index
-    };
+    // We give Non-Maskable Interrupts and Double Fault exceptions separate stacks, since these
+    // (unless we are going to set up NMI watchdogs like Linux does) are considered the most fatal,
+    // especially Double Faults which are caused by errors __when accessing the system IDT__. If
+    // that goes wrong, then kernel memory may be partially corrupt, and we want a separate stack.
+    //
+    // Note that each CPU has its own "backup interrupt stack".
+    let backup_ist = allocate_ist(1);
+    // Machine checks report hardware errors, which may have corrupted the kernel stack as well.
+    // They get a stack of their own, so that they work even if they arrive during an NMI.
+    let machine_check_ist = allocate_ist(2);
 
     // Set up exceptions
     current_idt[0].set_func(exception::divide_by_zero);
@@ -211,7 +217,7 @@ pub unsafe fn init_generic(is_bsp: bool, idt: &mut Idt) {
     current_idt[16].set_func(exception::fpu_fault);
     current_idt[17].set_func(exception::alignment_check);
     current_idt[18].set_func(exception::machine_check);
-    current_idt[18].set_ist(backup_ist);
+    current_idt[18].set_ist(machine_check_ist);
     current_idt[19].set_func(exception::simd);
     current_idt[20].set_func(exception::virtualization);
     // 21 through 29 reserved
@@ -249,21 +255,45 @@ pub unsafe fn init_generic(is_bsp: bool, idt: &mut Idt) {
         // TODO: use_default_irqs! but also the legacy IRQs that are only needed on one CPU
         current_idt[49].set_func(irq::lapic_error);
 
-        // reserve bit 49
-        *current_reservations[1].get_mut() |= (1 << 17);
+        // Legacy IRQs that are handled by drivers, which the IRQ balancer may move to this CPU
+        current_idt[37].set_func(irq::lpt2);
+        current_idt[38].set_func(irq::floppy);
+        current_idt[39].set_func(irq::lpt1);
+        current_idt[40].set_func(irq::rtc);
+        current_idt[41].set_func(irq::pci1);
+        current_idt[42].set_func(irq::pci2);
+        current_idt[43].set_func(irq::pci3);
+        current_idt[45].set_func(irq::fpu);
+        current_idt[46].set_func(irq::ata1);
+        current_idt[47].set_func(irq::ata2);
+
+        // reserve bit 49, and the bits of the balanced legacy IRQs
+        *current_reservations[1].get_mut() |= (1 << 17) | 0xEFE0;
     }
 
     use_default_irqs!(current_idt);
 
+    // Spurious interrupts of the local APIC, whose vector is programmed in the SIVR
+    current_idt[local_apic::SPURIOUS_VECTOR as usize].set_func(irq::lapic_spurious);
+
     // Set IPI handlers
     current_idt[IpiKind::Wakeup as usize].set_func(ipi::wakeup);
     current_idt[IpiKind::Switch as usize].set_func(ipi::switch);
     current_idt[IpiKind::Tlb as usize].set_func(ipi::tlb);
     current_idt[IpiKind::Pit as usize].set_func(ipi::pit);
+    current_idt[IpiKind::Microcode as usize].set_func(ipi::microcode);
+
+    // Thermal monitor interrupts of the local APIC, set up after the default IRQs which this vector
+    // would otherwise be one of
+    current_idt[local_apic::THERMAL_VECTOR as usize].set_func(irq::lapic_thermal);
+
     idt.set_reserved_mut(IpiKind::Wakeup as u8, true);
     idt.set_reserved_mut(IpiKind::Switch as u8, true);
     idt.set_reserved_mut(IpiKind::Tlb as u8, true);
     idt.set_reserved_mut(IpiKind::Pit as u8, true);
+    idt.set_reserved_mut(IpiKind::Microcode as u8, true);
+    idt.set_reserved_mut(local_apic::SPURIOUS_VECTOR, true);
+    idt.set_reserved_mut(local_apic::THERMAL_VECTOR, true);
     let current_idt = &mut idt.entries;
 
     // Set syscall function
@@ -0,0 +1,75 @@
+//! Emulation of instructions that raise #UD on some CPUs, so that userspace can use them anyway
+
+use core::cmp;
+
+use crate::interrupt::InterruptStack;
+use crate::memory::PAGE_SIZE;
+use crate::syscall::validate::validate_slice;
+
+/// Longest encoding of an x86 instruction
+pub const MAX_INSTRUCTION_LEN: usize = 15;
+
+/// Instructions that are emulated, by their exact encoding. The handler only updates the
+/// registers, and the instruction pointer is advanced past the encoding afterwards.
+const EMULATED: &[(&[u8], fn(&mut InterruptStack))] = &[
+    // RDTSCP, missing on older CPUs
+    (&[0x0F, 0x01, 0xF9], rdtscp),
+];
+
+/// Load the time stamp counter into EDX:EAX. Real CPUs load IA32_TSC_AUX into ECX, which the
+/// kernel does not set up, so the ID of the CPU is returned instead.
+fn rdtscp(stack: &mut InterruptStack) {
+    let tsc = unsafe { core::arch::x86::_rdtsc() };
+    stack.scratch.eax = tsc as u32 as usize;
+    stack.scratch.edx = (tsc >> 32) as u32 as usize;
+    stack.scratch.ecx = crate::cpu_id() as u32 as usize;
+}
+
+/// Copy the bytes of the instruction at `ip` into `buf`, stopping early at the end of what can be
+/// read: unmapped user pages, or the end of the page for the kernel. Returns the number of bytes
+/// copied.
+pub fn fetch(ip: usize, user: bool, buf: &mut [u8]) -> usize {
+    let mut count = 0;
+    while count < buf.len() {
+        let address = match ip.checked_add(count) {
+            Some(address) => address,
+            None => break,
+        };
+        let len = cmp::min(buf.len() - count, PAGE_SIZE - address % PAGE_SIZE);
+        let bytes = if user {
+            match validate_slice(address as *const u8, len) {
+                Ok(bytes) => bytes,
+                Err(_) => break,
+            }
+        } else if count == 0 {
+            // The faulting instruction is in mapped kernel text, at least up to the end of its page
+            unsafe { core::slice::from_raw_parts(address as *const u8, len) }
+        } else {
+            break;
+        };
+        buf[count..count + len].copy_from_slice(bytes);
+        count += len;
+    }
+    count
+}
+
+/// Emulate the user instruction that raised #UD, if it is one of those that are emulated.
+/// Returns whether it was, in which case execution resumes after it.
+pub fn invalid_opcode(stack: &mut InterruptStack) -> bool {
+    if stack.iret.cs & 0b11 != 0b11 {
+        return false;
+    }
+
+    let mut buf = [0; MAX_INSTRUCTION_LEN];
+    let len = fetch(stack.iret.eip, true, &mut buf);
+    let code = &buf[..len];
+
+    match EMULATED.iter().find(|(encoding, _)| code.starts_with(encoding)) {
+        Some(&(encoding, emulate)) => {
+            emulate(stack);
+            stack.iret.eip += encoding.len();
+            true
+        },
+        None => false,
+    }
+}
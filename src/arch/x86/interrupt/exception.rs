@@ -1,5 +1,13 @@
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use crate::{
-    interrupt::stack_trace,
+    context::{self, signal::{record_fault, FaultAccess, FPE_FLTDIV, FPE_FLTINV, FPE_FLTOVF, FPE_FLTRES, FPE_FLTUND, FPE_INTDIV, FPE_INTOVF, ILL_ILLOPC, SEGV_ACCERR, SEGV_MAPERR, SEGV_STKOVF}},
+    debug::Writer,
+    device::mce,
+    gdt,
+    interrupt::{self, stack_trace, trace::stack_trace_unlocked, InterruptStack},
+    paging::VirtualAddress,
     ptrace,
     syscall::flag::*,
 
@@ -11,11 +19,42 @@ extern {
     fn ksignal(signal: usize);
 }
 
-interrupt_stack!(divide_by_zero, |stack| {
-    println!("Divide by zero");
+/// Report an arithmetic fault of the instruction at `address` to the current context as SIGFPE
+/// with `code`. The kernel itself must never fault like this, so it panics instead.
+unsafe fn arithmetic_fault(stack: &InterruptStack, name: &str, code: usize, address: usize) {
     stack.dump();
     stack_trace();
+    if stack.iret.cs & 0b11 == 0b00 {
+        panic!("{} in kernel mode at {:#x}", name, address);
+    }
+    record_fault(address, code, FaultAccess::Execute);
     ksignal(SIGFPE);
+}
+
+/// The `si_code` for the unmasked exceptions among the x87 or SSE exception flags `flags`, in
+/// the bit order both use
+fn float_fault_code(flags: u32) -> usize {
+    if flags & 1 << 0 != 0 {
+        FPE_FLTINV
+    } else if flags & 1 << 2 != 0 {
+        FPE_FLTDIV
+    } else if flags & 1 << 3 != 0 {
+        FPE_FLTOVF
+    } else if flags & (1 << 1 | 1 << 4) != 0 {
+        // Denormal operands are reported as underflow
+        FPE_FLTUND
+    } else if flags & 1 << 5 != 0 {
+        FPE_FLTRES
+    } else {
+        0
+    }
+}
+
+interrupt_stack!(divide_by_zero, |stack| {
+    // Division by zero and quotients too large for the destination both raise #DE, which cannot be
+    // told apart without decoding the instruction, so neither is named more specifically
+    println!("Divide error");
+    arithmetic_fault(stack, "Divide error", FPE_INTDIV, stack.iret.eip);
 });
 
 interrupt_stack!(debug, @paranoid, |stack| {
@@ -41,11 +80,71 @@ interrupt_stack!(debug, @paranoid, |stack| {
     }
 });
 
+/// Number of attempts at taking the debug outputs before an NMI or a machine check gives up on
+/// printing
+const PARANOID_WRITER_ATTEMPTS: usize = 1_000_000;
+
+/// Take the debug outputs without waiting indefinitely, as the interrupted code may hold them
+fn paranoid_writer() -> Option<Writer<'static>> {
+    (0..PARANOID_WRITER_ATTEMPTS).find_map(|_| {
+        let writer = Writer::try_new();
+        if writer.is_none() {
+            core::hint::spin_loop();
+        }
+        writer
+    })
+}
+
+const NOT_IN_NMI: AtomicBool = AtomicBool::new(false);
+/// Whether each CPU is handling an NMI. Another NMI can arrive before the handler returns, once a
+/// fault within it has executed IRET, and must then be ignored.
+static IN_NMI: [AtomicBool; crate::MAX_CPU_COUNT] = [NOT_IN_NMI; crate::MAX_CPU_COUNT];
+
 interrupt_stack!(non_maskable, @paranoid, |stack| {
-    println!("Non-maskable interrupt");
-    stack.dump();
+    // The NMI may have arrived anywhere, even before the kernel TLS was set up, so the CPU is
+    // identified using the ID stored in its GDT
+    let cpu_id = gdt::loaded_cpu_id();
+    let in_nmi = cpu_id.and_then(|cpu_id| IN_NMI.get(cpu_id as usize));
+    if in_nmi.map_or(false, |in_nmi| in_nmi.swap(true, Ordering::Acquire)) {
+        return;
+    }
+
+    nmi_dump(cpu_id, stack);
+
+    if let Some(in_nmi) = in_nmi {
+        in_nmi.store(false, Ordering::Release);
+    }
 });
 
+/// Print the state of a CPU that received an NMI, e.g. one sent by `ipi::nmi` to find out where
+/// it is stuck. No lock is waited on, as the CPU may have been interrupted while holding it.
+unsafe fn nmi_dump(cpu_id: Option<u32>, stack: &InterruptStack) {
+    let mut writer = match paranoid_writer() {
+        Some(writer) => writer,
+        None => return,
+    };
+
+    match cpu_id {
+        Some(cpu_id) => {
+            let _ = writeln!(writer, "Non-maskable interrupt on CPU {}, PID {:?}", cpu_id, context::context_id());
+        }
+        None => {
+            let _ = writeln!(writer, "Non-maskable interrupt on unknown CPU");
+        }
+    }
+
+    let _ = writeln!(writer, "EFLAG: {:016x}", { stack.iret.eflags });
+    let _ = writeln!(writer, "CS:    {:016x}", { stack.iret.cs });
+    let _ = writeln!(writer, "EIP:   {:016x}", { stack.iret.eip });
+    let _ = writeln!(writer, "EBP:   {:016x}", { stack.preserved.ebp });
+
+    if stack.iret.cs & 0b11 != 0b00 {
+        let _ = writeln!(writer, "ESP:   {:016x}", { stack.iret.esp });
+    } else {
+        stack_trace_unlocked(&mut writer, stack.preserved.ebp);
+    }
+}
+
 interrupt_stack!(breakpoint, |stack| {
     // The processor lets EIP point to the instruction *after* int3, so
     // unhandled breakpoint interrupt don't go in an infinite loop. But we
@@ -69,9 +168,8 @@ interrupt_stack!(breakpoint, |stack| {
 
 interrupt_stack!(overflow, |stack| {
     println!("Overflow trap");
-    stack.dump();
-    stack_trace();
-    ksignal(SIGFPE);
+    // The trap leaves EIP after INTO, which is a single byte
+    arithmetic_fault(stack, "Overflow trap", FPE_INTOVF, stack.iret.eip - 1);
 });
 
 interrupt_stack!(bound_range, |stack| {
@@ -82,9 +180,21 @@ interrupt_stack!(bound_range, |stack| {
 });
 
 interrupt_stack!(invalid_opcode, |stack| {
+    if interrupt::emulate::invalid_opcode(stack) {
+        return;
+    }
+
     println!("Invalid opcode fault");
     stack.dump();
     stack_trace();
+
+    let ip = stack.iret.eip;
+    if stack.iret.cs & 0b11 == 0b00 {
+        let mut code = [0; interrupt::emulate::MAX_INSTRUCTION_LEN];
+        let len = interrupt::emulate::fetch(ip, false, &mut code);
+        panic!("Invalid opcode in kernel mode at {:#x}: {:02x?}", ip, &code[..len]);
+    }
+    record_fault(ip, ILL_ILLOPC, FaultAccess::Execute);
     ksignal(SIGILL);
 });
 
@@ -133,6 +243,17 @@ interrupt_error!(protection, |stack| {
 interrupt_error!(page, |stack| {
     let cr2: usize;
     core::arch::asm!("mov {}, cr2", out(reg) cr2);
+    // Only faults from userspace may take the address space lock, which the kernel could be
+    // holding already. The kernel faults released pages in when validating user buffers.
+    if stack.code & 1 << 0 == 0 && stack.code & 1 << 2 != 0 && context::memory::fault_in_current(VirtualAddress::new(cr2)) {
+        context::count_page_fault(false);
+        return;
+    }
+    // Writes to present pages may be to private file mappings, which are copied on write
+    if stack.code & 1 << 0 != 0 && stack.code & 1 << 1 != 0 && stack.code & 1 << 2 != 0 && context::memory::copy_on_write_current(VirtualAddress::new(cr2)) {
+        context::count_page_fault(false);
+        return;
+    }
     println!("Page fault: {:>016X}", cr2);
     println!("  Present: {}", stack.code & 1 << 0 != 0);
     println!("  Write: {}", stack.code & 1 << 1 != 0);
@@ -141,17 +262,38 @@ interrupt_error!(page, |stack| {
     println!("  Instruction fetch: {}", stack.code & 1 << 4 != 0);
     stack.dump();
     stack_trace();
+    let code = if stack.code & 1 << 0 != 0 {
+        SEGV_ACCERR
+    } else if stack.code & 1 << 2 != 0 && context::memory::is_stack_overflow_current(VirtualAddress::new(cr2), stack.iret.esp) {
+        println!("  Stack overflow");
+        SEGV_STKOVF
+    } else {
+        SEGV_MAPERR
+    };
+    let access = if stack.code & 1 << 4 != 0 {
+        FaultAccess::Execute
+    } else if stack.code & 1 << 1 != 0 {
+        FaultAccess::Write
+    } else {
+        FaultAccess::Read
+    };
+    record_fault(cr2, code, access);
     ksignal(SIGSEGV);
 });
 
 interrupt_stack!(fpu_fault, |stack| {
     println!("FPU floating point fault");
-    stack.dump();
-    stack_trace();
-    ksignal(SIGFPE);
+    let status: u16;
+    let mut control: u16 = 0;
+    core::arch::asm!("fnstsw ax", out("ax") status, options(nomem, nostack));
+    core::arch::asm!("fnstcw word ptr [{}]", in(reg) &mut control as *mut u16, options(nostack));
+    let code = float_fault_code(u32::from(status & !control & 0x3F));
+    // #MF is raised by the next x87 instruction, the one that faulted is in the x87 state
+    arithmetic_fault(stack, "FPU floating point fault", code, stack.iret.eip);
 });
 
 interrupt_error!(alignment_check, |stack| {
+    // Alignment checking only applies to user mode, with AC set in the flags of the process
     println!("Alignment check fault");
     stack.dump();
     stack_trace();
@@ -159,17 +301,49 @@ interrupt_error!(alignment_check, |stack| {
 });
 
 interrupt_stack!(machine_check, @paranoid, |stack| {
-    println!("Machine check fault");
-    stack.dump();
-    stack_trace();
-    ksignal(SIGBUS);
+    let check = match paranoid_writer() {
+        Some(mut writer) => {
+            let _ = writeln!(writer, "Machine check on CPU {:?}, PID {:?}, IP {:016x}", gdt::loaded_cpu_id(), context::context_id(), { stack.iret.eip });
+            mce::report(&mut writer)
+        },
+        None => mce::report(&mut NullWriter),
+    };
+
+    let user = stack.iret.cs & 0b11 != 0b00;
+    if ! check.restartable || (check.uncorrected && ! user) {
+        // Nothing can be trusted anymore, keep the CPU from doing further damage. The debug
+        // outputs may be held by the code that was interrupted, so they are only tried.
+        if let Some(mut writer) = paranoid_writer() {
+            let _ = writeln!(writer, "Unrecoverable machine check, halting CPU");
+        }
+        loop {
+            interrupt::disable();
+            interrupt::halt();
+        }
+    }
+
+    if check.uncorrected {
+        // The error only affected the interrupted process, which cannot continue
+        ksignal(SIGBUS);
+    }
 });
 
+/// Sink for machine check reports when the debug outputs cannot be taken
+struct NullWriter;
+
+impl Write for NullWriter {
+    fn write_str(&mut self, _s: &str) -> core::fmt::Result {
+        Ok(())
+    }
+}
+
 interrupt_stack!(simd, |stack| {
     println!("SIMD floating point fault");
-    stack.dump();
-    stack_trace();
-    ksignal(SIGFPE);
+    let mut mxcsr: u32 = 0;
+    core::arch::asm!("stmxcsr dword ptr [{}]", in(reg) &mut mxcsr as *mut u32, options(nostack));
+    // The exception masks are 7 bits above the flags they mask
+    let code = float_fault_code(mxcsr & !(mxcsr >> 7) & 0x3F);
+    arithmetic_fault(stack, "SIMD floating point fault", code, stack.iret.eip);
 });
 
 interrupt_stack!(virtualization, |stack| {
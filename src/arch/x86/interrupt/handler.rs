@@ -136,6 +136,14 @@ impl InterruptStack {
         // self.iret.cs = all.cs;
         // self.iret.eflags = all.eflags;
     }
+    /// Whether `all` can be loaded into these registers without making the context unrunnable,
+    /// as it must keep its user code and stack segments, and point into user memory
+    pub fn can_load(&self, all: &IntRegisters) -> bool {
+        all.cs == self.iret.cs
+            && all.ss == self.iret.ss
+            && all.eip < crate::USER_END_OFFSET
+            && all.esp < crate::USER_END_OFFSET
+    }
     /// Enables the "Trap Flag" in the FLAGS register, causing the CPU
     /// to send a Debug exception after the next instruction. This is
     /// used for singlestep in the proc: scheme.
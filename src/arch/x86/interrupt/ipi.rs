@@ -1,9 +1,7 @@
-use core::sync::atomic::Ordering;
 use x86::tlb;
 
 use crate::context;
 use crate::device::local_apic::LOCAL_APIC;
-use super::irq::PIT_TICKS;
 
 interrupt!(wakeup, || {
     LOCAL_APIC.eoi();
@@ -24,8 +22,14 @@ interrupt!(switch, || {
 interrupt!(pit, || {
     LOCAL_APIC.eoi();
 
-    // Switch after 3 ticks (about 6.75 ms)
-    if PIT_TICKS.fetch_add(1, Ordering::SeqCst) >= 2 {
+    // Switch once the current context has used up its quantum
+    if context::tick() {
         let _ = context::switch();
     }
 });
+
+interrupt!(microcode, || {
+    LOCAL_APIC.eoi();
+
+    crate::device::microcode::apply_pending();
+});
@@ -1,13 +1,14 @@
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 
 use alloc::vec::Vec;
 
 use crate::{interrupt, interrupt_stack};
 use crate::context::timeout;
-use crate::device::{local_apic, ioapic, pic, pit};
+use crate::device::{local_apic, ioapic, irq_balance, pic, pit};
 use crate::device::serial::{COM1, COM2};
 use crate::ipi::{ipi, IpiKind, IpiTarget};
 use crate::scheme::debug::{debug_input, debug_notify};
+use crate::scheme::keyboard::keyboard_input;
 use crate::scheme::serio::serio_input;
 use crate::{context, time};
 
@@ -34,15 +35,68 @@ pub fn spurious_count_irq15() -> usize {
 pub fn spurious_count() -> usize {
     spurious_count_irq7() + spurious_count_irq15()
 }
+
+static LAPIC_SPURIOUS_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn lapic_spurious_count() -> usize {
+    LAPIC_SPURIOUS_COUNT.load(Ordering::Relaxed)
+}
 pub fn spurious_irq_resource() -> syscall::Result<Vec<u8>> {
     match irq_method() {
-        IrqMethod::Apic => Ok(Vec::from(&b"(not implemented for APIC yet)"[..])),
+        IrqMethod::Apic => Ok(format!("{}\tLAPIC\n", lapic_spurious_count()).into_bytes()),
         IrqMethod::Pic => {
             Ok(format!("{}\tIRQ7\n{}\tIRQ15\n{}\ttotal\n", spurious_count_irq7(), spurious_count_irq15(), spurious_count()).into_bytes())
         }
     }
 }
 
+static LAPIC_ERROR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn lapic_error_count() -> usize {
+    LAPIC_ERROR_COUNT.load(Ordering::Relaxed)
+}
+pub fn lapic_error_resource() -> syscall::Result<Vec<u8>> {
+    Ok(format!("{}\n", lapic_error_count()).into_bytes())
+}
+
+/// Last thermal status of each CPU, as read by the thermal interrupt handler
+const NO_THERMAL_STATUS: AtomicU32 = AtomicU32::new(0);
+static THERMAL_STATUS: [AtomicU32; 64] = [NO_THERMAL_STATUS; 64];
+static THERMAL_EVENT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn thermal_event_count() -> usize {
+    THERMAL_EVENT_COUNT.load(Ordering::Relaxed)
+}
+pub fn thermal_resource() -> syscall::Result<Vec<u8>> {
+    let supported = unsafe { local_apic::LOCAL_APIC.has_thermal() };
+    if ! supported {
+        return Ok(b"unsupported\n".to_vec());
+    }
+
+    let mut string = format!("{} events\n", thermal_event_count());
+    for cpu_id in 0..crate::cpu_count() {
+        let status = match THERMAL_STATUS.get(cpu_id) {
+            Some(status) => status.load(Ordering::Relaxed),
+            None => break,
+        };
+        let flags = local_apic::ThermalStatus::from_bits_truncate(status);
+
+        let state = if flags.contains(local_apic::ThermalStatus::CRITICAL) {
+            "critical"
+        } else if flags.contains(local_apic::ThermalStatus::THROTTLING) {
+            "throttling"
+        } else {
+            "normal"
+        };
+        string.push_str(&format!("CPU {}: {}", cpu_id, state));
+        if flags.contains(local_apic::ThermalStatus::READING_VALID) {
+            string.push_str(&format!(", {} C below maximum", (status >> 16) & 0x7F));
+        }
+        string.push('\n');
+    }
+    Ok(string.into_bytes())
+}
+
 static IRQ_METHOD: AtomicUsize = AtomicUsize::new(IrqMethod::Pic as usize);
 
 pub fn set_irq_method(method: IrqMethod) {
@@ -67,6 +121,9 @@ extern {
 /// Notify the IRQ scheme that an IRQ has been registered. This should mask the IRQ until the
 /// scheme user unmasks it ("acknowledges" it).
 unsafe fn trigger(irq: u8) {
+    crate::rand::add_interrupt_entropy();
+    irq_balance::count(irq);
+
     match irq_method() {
         IrqMethod::Pic => if irq < 16 { pic_mask(irq) },
         IrqMethod::Apic => ioapic_mask(irq),
@@ -136,6 +193,7 @@ unsafe fn ioapic_unmask(irq: usize) {
 
 interrupt_stack!(pit_stack, |_stack| {
     // Saves CPU time by not sending IRQ event irq_trigger(0);
+    crate::rand::add_interrupt_entropy();
 
     {
         *time::OFFSET.lock() += pit::RATE;
@@ -149,19 +207,47 @@ interrupt_stack!(pit_stack, |_stack| {
     // Any better way of doing this?
     timeout::trigger();
 
-    // Switch after 3 ticks (about 6.75 ms)
-    if PIT_TICKS.fetch_add(1, Ordering::SeqCst) >= 2 {
+    irq_balance::tick();
+
+    // Switch once the current context has used up its quantum
+    if context::tick() {
         let _ = context::switch();
     }
 });
 
-interrupt!(keyboard, || {
-    let data: u8;
-    core::arch::asm!("in al, 0x60", out("al") data);
+/// Bytes read from the PS/2 controller per interrupt at most, in case a missing controller reads
+/// as having a full output buffer forever
+const PS2_DRAIN_MAX: usize = 16;
+
+/// Read every byte waiting in the PS/2 controller, as more may have arrived by the time the
+/// interrupt is handled, and pass it on to the `serio:` port of the device it came from. Bytes
+/// of the keyboard are also translated for the `keyboard:` scheme. Both IRQs are handled by the
+/// BSP, so one handler never takes the byte the other is about to read.
+unsafe fn ps2_drain() {
+    for _ in 0..PS2_DRAIN_MAX {
+        let status: u8;
+        core::arch::asm!("in al, 0x64", out("al") status);
+        // Output buffer full
+        if status & 1 == 0 {
+            break;
+        }
 
+        let data: u8;
+        core::arch::asm!("in al, 0x60", out("al") data);
+        // The byte is from the auxiliary device, the mouse
+        if status & (1 << 5) == 0 {
+            serio_input(0, data);
+            keyboard_input(data);
+        } else {
+            serio_input(1, data);
+        }
+    }
+}
+
+interrupt!(keyboard, || {
     eoi(1);
 
-    serio_input(0, data);
+    ps2_drain();
 });
 
 interrupt!(cascade, || {
@@ -226,12 +312,9 @@ interrupt!(pci3, || {
 });
 
 interrupt!(mouse, || {
-    let data: u8;
-    core::arch::asm!("in al, 0x60", out("al") data);
-
     eoi(12);
 
-    serio_input(1, data);
+    ps2_drain();
 });
 
 interrupt!(fpu, || {
@@ -260,10 +343,46 @@ interrupt!(lapic_timer, || {
 });
 
 interrupt!(lapic_error, || {
-    println!("Local apic internal error: ESR={:#0x}", local_apic::LOCAL_APIC.esr());
+    // Writing the ESR latches the errors that occurred since the last write, and clears them so
+    // that they are not reported again.
+    let esr = local_apic::LOCAL_APIC.esr();
+    let count = LAPIC_ERROR_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+
+    println!("Local apic internal error #{} on CPU {}: ESR={:#0x} {:?}", count, crate::cpu_id(), esr, local_apic::ApicError::from_bits_truncate(esr));
+
+    // The error LVT entry is edge triggered, so the EOI cannot cause it to fire again unless a
+    // new error occurs.
+    lapic_eoi();
+});
+
+interrupt!(lapic_thermal, || {
+    let status = x86::msr::rdmsr(local_apic::IA32_THERM_STATUS);
+    // Clear the log bits, so that the next interrupt only reports new events
+    x86::msr::wrmsr(local_apic::IA32_THERM_STATUS, status & !u64::from(local_apic::ThermalStatus::LOGS.bits()));
+
+    let status = status as u32;
+    let previous = THERMAL_STATUS.get(crate::cpu_id()).map_or(0, |last| last.swap(status, Ordering::Relaxed));
+    THERMAL_EVENT_COUNT.fetch_add(1, Ordering::Relaxed);
+
+    let throttling = local_apic::ThermalStatus::THROTTLING | local_apic::ThermalStatus::CRITICAL;
+    let flags = local_apic::ThermalStatus::from_bits_truncate(status);
+    if flags.intersects(throttling) && ! local_apic::ThermalStatus::from_bits_truncate(previous).intersects(throttling) {
+        println!("CPU {} is throttling: {:?}", crate::cpu_id(), flags);
+    }
+
+    crate::scheme::sys::notify("thermal");
+
+    // The thermal LVT entry is edge triggered like the error one, so the EOI only lets the next
+    // event through
     lapic_eoi();
 });
 
+interrupt!(lapic_spurious, || {
+    // Spurious interrupts are not in service, so sending an EOI here would acknowledge whichever
+    // interrupt is, if any.
+    LAPIC_SPURIOUS_COUNT.fetch_add(1, Ordering::Relaxed);
+});
+
 interrupt!(calib_pit, || {
     {
         *time::OFFSET.lock() += pit::RATE;
@@ -3,6 +3,7 @@
 #[macro_use]
 pub mod handler;
 
+pub mod emulate;
 pub mod exception;
 pub mod ipi;
 pub mod irq;
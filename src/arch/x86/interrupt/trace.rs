@@ -1,9 +1,7 @@
-use core::{mem, str};
+use core::{fmt, mem};
 
-use goblin::elf::sym;
-use rustc_demangle::demangle;
-
-use crate::{paging::{KernelMapper, VirtualAddress}};
+use crate::paging::{KernelMapper, PageMapper, TableKind, VirtualAddress};
+use crate::rmm::FRAME_ALLOCATOR;
 
 /// Get a stack trace
 //TODO: Check for stack being mapped before dereferencing
@@ -41,55 +39,44 @@ pub unsafe fn stack_trace() {
     }
 }
 
-/// Get a symbol
-//TODO: Do not create Elf object for every symbol lookup
-#[inline(never)]
-pub unsafe fn symbol_trace(addr: usize) {
-    use core::slice;
-    use core::sync::atomic::Ordering;
+/// Write a stack trace starting at the frame `ebp` to `w`, without taking any lock. This is for
+/// handlers that may have interrupted a holder of those locks, such as the NMI handler.
+pub unsafe fn stack_trace_unlocked(w: &mut impl fmt::Write, mut ebp: usize) {
+    let _ = writeln!(w, "TRACE: {:>016X}", ebp);
 
-    use crate::elf::Elf;
-    use crate::start::{KERNEL_BASE, KERNEL_SIZE};
+    // The kernel page table is only read, which is fine even if the interrupted code was changing it
+    let mapper = PageMapper::current(TableKind::Kernel, FRAME_ALLOCATOR);
 
-    let kernel_ptr = (KERNEL_BASE.load(Ordering::SeqCst) + crate::PHYS_OFFSET) as *const u8;
-    let kernel_slice = slice::from_raw_parts(kernel_ptr, KERNEL_SIZE.load(Ordering::SeqCst));
-    if let Ok(elf) = Elf::from(kernel_slice) {
-        let mut strtab_opt = None;
-        for section in elf.sections() {
-            if section.sh_type == ::goblin::elf::section_header::SHT_STRTAB {
-                strtab_opt = Some(section);
+    for _frame in 0..64 {
+        if let Some(eip_ebp) = ebp.checked_add(mem::size_of::<usize>()) {
+            let ebp_virt = VirtualAddress::new(ebp);
+            let eip_ebp_virt = VirtualAddress::new(eip_ebp);
+            if mapper.translate(ebp_virt).is_some() && mapper.translate(eip_ebp_virt).is_some() {
+                let eip = *(eip_ebp as *const usize);
+                if eip == 0 {
+                    let _ = writeln!(w, " {:>016X}: EMPTY RETURN", ebp);
+                    break;
+                }
+                let _ = writeln!(w, "  {:>016X}: {:>016X}", ebp, eip);
+                ebp = *(ebp as *const usize);
+                if let Some(symbol) = crate::symbols::lookup(eip) {
+                    let _ = writeln!(w, "    {}", symbol);
+                }
+            } else {
+                let _ = writeln!(w, "  {:>016X}: GUARD PAGE", ebp);
                 break;
             }
+        } else {
+            let _ = writeln!(w, "  {:>016X}: EBP OVERFLOW", ebp);
+            break;
         }
+    }
+}
 
-        if let Some(symbols) = elf.symbols() {
-            for sym in symbols {
-                if sym::st_type(sym.st_info) == sym::STT_FUNC
-                && addr >= sym.st_value as usize
-                && addr < (sym.st_value + sym.st_size) as usize
-                {
-                    println!("    {:>016X}+{:>04X}", sym.st_value, addr - sym.st_value as usize);
-
-                    if let Some(strtab) = strtab_opt {
-                        let start = strtab.sh_offset as usize + sym.st_name as usize;
-                        let mut end = start;
-                        while end < elf.data.len() {
-                            let b = elf.data[end];
-                            end += 1;
-                            if b == 0 {
-                                break;
-                            }
-                        }
-
-                        if end > start {
-                            let sym_slice = &elf.data[start .. end - 1];
-                            if let Ok(sym_name) = str::from_utf8(sym_slice) {
-                                println!("    {:#}", demangle(sym_name));
-                            }
-                        }
-                    }
-                }
-            }
-        }
+/// Print the function containing an address, if the kernel image has a symbol table
+#[inline(never)]
+pub fn symbol_trace(addr: usize) {
+    if let Some(symbol) = crate::symbols::lookup(addr) {
+        println!("    {}", symbol);
     }
 }
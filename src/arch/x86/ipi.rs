@@ -5,6 +5,7 @@ pub enum IpiKind {
     Tlb = 0x41,
     Switch = 0x42,
     Pit = 0x43,
+    Microcode = 0x44,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -27,3 +28,17 @@ pub fn ipi(kind: IpiKind, target: IpiTarget) {
     let icr = (target as u64) << 18 | 1 << 14 | (kind as u64);
     unsafe { LOCAL_APIC.set_icr(icr) };
 }
+
+/// Send an NMI to the CPU `cpu_id`, which then prints where it is, even if it is stuck with
+/// interrupts disabled. Returns false if the CPU cannot be addressed.
+pub fn nmi(cpu_id: usize) -> bool {
+    use crate::device::{ioapic, local_apic::LOCAL_APIC};
+
+    match ioapic::cpu_apic_id(cpu_id) {
+        Some(apic_id) => {
+            unsafe { LOCAL_APIC.ipi_nmi(u32::from(apic_id)) };
+            true
+        }
+        None => false,
+    }
+}
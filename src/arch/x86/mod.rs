@@ -32,6 +32,9 @@ pub mod paging;
 /// Page table isolation
 pub mod pti;
 
+/// Hardware entropy sources
+pub mod rand;
+
 pub mod rmm;
 
 /// Initialization and start function
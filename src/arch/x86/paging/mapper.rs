@@ -1,6 +1,6 @@
 use crate::ipi::{ipi, IpiKind, IpiTarget};
 
-use super::RmmA;
+use super::{RmmA, RmmArch};
 
 pub use rmm::{Flusher, PageFlush, PageFlushAll};
 
@@ -21,3 +21,46 @@ impl Drop for InactiveFlusher {
         ipi(IpiKind::Tlb, IpiTarget::Other);
     }
 }
+
+/// Number of pages a `BatchFlusher` invalidates one by one, before it falls back to flushing the
+/// whole TLB
+pub const BATCH_FLUSH_PAGES: usize = 32;
+
+/// Flusher shared by many grant operations, e.g. unmapping all grants of an exiting context,
+/// which sends other CPUs a single shootdown IPI once dropped rather than one per operation.
+///
+/// If the table is active, the first `BATCH_FLUSH_PAGES` pages are invalidated right away, and
+/// any further ones by a full TLB flush when dropped. Nothing is stored per page, so the batch is
+/// bounded however many pages it covers.
+pub struct BatchFlusher {
+    active: bool,
+    pages: usize,
+}
+impl BatchFlusher {
+    /// Create a flusher for a table, which is the one currently in use if `active` is set
+    pub fn new(active: bool) -> Self {
+        Self { active, pages: 0 }
+    }
+}
+
+impl Flusher<RmmA> for BatchFlusher {
+    fn consume(&mut self, flush: PageFlush<RmmA>) {
+        if self.active && self.pages < BATCH_FLUSH_PAGES {
+            flush.flush();
+        } else {
+            unsafe { flush.ignore(); }
+        }
+        self.pages += 1;
+    }
+}
+impl Drop for BatchFlusher {
+    fn drop(&mut self) {
+        if self.pages == 0 {
+            return;
+        }
+        if self.active && self.pages > BATCH_FLUSH_PAGES {
+            unsafe { RmmA::invalidate_all(); }
+        }
+        ipi(IpiKind::Tlb, IpiTarget::Other);
+    }
+}
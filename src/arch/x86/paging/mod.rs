@@ -21,6 +21,7 @@ pub type PageMapper = rmm::PageMapper<RmmA, crate::arch::rmm::LockedAllocator>;
 pub use crate::rmm::KernelMapper;
 
 pub mod entry;
+pub mod huge;
 pub mod mapper;
 
 /// Number of entries per page table
@@ -43,6 +44,7 @@ unsafe fn init_pat() {
     let pat2 = uncached;
     let pat3 = uncacheable;
 
+    // Selected by `EntryFlags::PAT` alone, for write combining device mappings
     let pat4 = write_combining;
     let pat5 = pat1;
     let pat6 = pat2;
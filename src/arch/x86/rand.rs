@@ -0,0 +1,57 @@
+//! Hardware entropy sources
+
+use super::cpuid::cpuid;
+
+/// Number of times to retry RDRAND and RDSEED, which fail when their entropy is exhausted
+const RETRIES: usize = 10;
+
+unsafe fn rdseed() -> Option<u32> {
+    for _ in 0..RETRIES {
+        let value: u32;
+        let ok: u8;
+        core::arch::asm!("rdseed {}; setc {}", out(reg) value, out(reg_byte) ok);
+        if ok != 0 {
+            return Some(value);
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
+unsafe fn rdrand() -> Option<u32> {
+    for _ in 0..RETRIES {
+        let value: u32;
+        let ok: u8;
+        core::arch::asm!("rdrand {}; setc {}", out(reg) value, out(reg_byte) ok);
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// 64 bits from the hardware random number generator, preferring RDSEED, which is not stretched
+/// by a DRBG, over RDRAND. Returns None if neither is supported or they keep failing.
+pub fn hw_random() -> Option<u64> {
+    let cpuid = cpuid()?;
+    let has_rdseed = cpuid.get_extended_feature_info().map_or(false, |info| info.has_rdseed());
+    let has_rdrand = cpuid.get_feature_info().map_or(false, |info| info.has_rdrand());
+
+    let half = || unsafe {
+        if has_rdseed {
+            if let Some(value) = rdseed() {
+                return Some(value);
+            }
+        }
+        if has_rdrand {
+            return rdrand();
+        }
+        None
+    };
+    Some((u64::from(half()?) << 32) | u64::from(half()?))
+}
+
+/// Cycle counter, whose low bits jitter between interrupts
+pub fn cycles() -> u64 {
+    unsafe { core::arch::x86::_rdtsc() }
+}
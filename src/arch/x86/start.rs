@@ -3,6 +3,7 @@
 /// It must create the IDT with the correct entries, those entries are
 /// defined in other files inside of the `arch` module
 
+use core::convert::TryInto;
 use core::slice;
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
@@ -19,6 +20,8 @@ use crate::idt;
 use crate::interrupt;
 use crate::log::{self, info};
 use crate::paging::{self, KernelMapper, TableKind};
+#[cfg(not(feature = "doc"))]
+use crate::symbols;
 
 /// Test of zero values in BSS.
 static BSS_TEST_ZERO: usize = 0;
@@ -105,6 +108,9 @@ pub unsafe extern fn kstart(args_ptr: *const KernelArgs) -> ! {
             );
         });
 
+        // Copy the command line before the memory of the bootloader can be reclaimed
+        crate::cmdline::init(env);
+
         info!("Redox OS starting...");
         info!("Kernel: {:X}:{:X}", { args.kernel_base }, { args.kernel_base } + { args.kernel_size });
         info!("Stack: {:X}:{:X}", { args.stack_base }, { args.stack_base } + { args.stack_size });
@@ -133,8 +139,19 @@ pub unsafe extern fn kstart(args_ptr: *const KernelArgs) -> ! {
         // Initialize paging
         let tcb_offset = paging::init(0);
 
+        // Locate the kernel symbol table, used by stack traces
+        #[cfg(not(feature = "doc"))]
+        symbols::init(slice::from_raw_parts(
+            (KERNEL_BASE.load(Ordering::SeqCst) + crate::PHYS_OFFSET) as *const u8,
+            KERNEL_SIZE.load(Ordering::SeqCst)
+        ));
+
         // Set up GDT after paging with TLS
         gdt::init_paging(0, tcb_offset, args.stack_base as usize + args.stack_size as usize);
+        assert_eq!(gdt::loaded_cpu_id(), Some(0), "BSP has the wrong CPU ID in its GDT");
+
+        // Choose how FPU and SIMD registers are saved, before any context is created
+        crate::context::init_kfx(true);
 
         // Set up IDT
         idt::init_paging_bsp();
@@ -239,7 +256,12 @@ pub unsafe extern fn kstart_ap(args_ptr: *const KernelArgsAp) -> ! {
         };
 
         // Set up GDT with TLS
-        gdt::init_paging(cpu_id as u32, tcb_offset, stack_end);
+        gdt::init_paging(cpu_id.try_into().expect("CPU ID does not fit in the GDT"), tcb_offset, stack_end);
+
+        // Paranoid interrupt handlers rely on this to find the TLS of the right CPU
+        assert_eq!(gdt::loaded_cpu_id(), Some(cpu_id as u32), "AP {} has the wrong CPU ID in its GDT", cpu_id);
+
+        crate::context::init_kfx(false);
 
         // Set up IDT for AP
         idt::init_paging_post_heap(false, cpu_id);
@@ -56,6 +56,23 @@ impl<'a> Writer<'a> {
         }
     }
 
+    /// Lock the outputs without waiting, for when a holder of the locks may never release them
+    pub fn try_new() -> Option<Writer<'a>> {
+        Some(Writer {
+            log: LOG.try_lock()?,
+            #[cfg(feature = "graphical_debug")]
+            display: DEBUG_DISPLAY.try_lock()?,
+            #[cfg(feature = "lpss_debug")]
+            lpss: LPSS.try_lock()?,
+            #[cfg(feature = "qemu_debug")]
+            qemu: QEMU.try_lock()?,
+            #[cfg(feature = "serial_debug")]
+            serial: COM1.try_lock()?,
+            #[cfg(feature = "system76_ec_debug")]
+            system76_ec: SYSTEM76_EC.try_lock()?,
+        })
+    }
+
     pub fn write(&mut self, buf: &[u8]) {
         {
             if let Some(ref mut log) = *self.log {
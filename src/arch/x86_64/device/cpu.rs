@@ -123,6 +123,7 @@ pub fn cpu_info<W: Write>(w: &mut W) -> Result {
         //if info.has_qm() { write!(w, " qm")? };
         if info.has_fpu_cs_ds_deprecated() { write!(w, " fpu_seg")? };
         if info.has_mpx() { write!(w, " mpx")? };
+        if info.has_la57() { write!(w, " la57")? };
     }
 
     writeln!(w)?;
@@ -0,0 +1,212 @@
+//! # IRQ balancing
+//! Spreads the legacy IRQs that are routed through the I/O APIC and handled by drivers over the
+//! CPUs, so that a single CPU is not saturated by interrupts. Every CPU counts the interrupts it
+//! handles, and once per interval the timer interrupt of the BSP moves at most one IRQ from the
+//! busiest CPU to the least busy one. IRQs that userspace pinned to a CPU through the
+//! `irq_balance` file of the sys: scheme are never moved, and balancing can be turned off there,
+//! or with `noirqbalance` on the kernel command line.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::device::ioapic;
+use crate::syscall::error::{Error, Result, EINVAL, ENODEV};
+use crate::time;
+
+/// Legacy IRQs whose handlers only pass them on to the irq: scheme, and which every CPU has
+/// handlers for. The others are handled by the kernel, on the BSP.
+pub const BALANCED_IRQS: [u8; 10] = [5, 6, 7, 8, 9, 10, 11, 13, 14, 15];
+
+/// CPUs that are kept track of, by ID
+const MAX_CPUS: usize = 64;
+/// Time between rebalancing, in nanoseconds
+const INTERVAL: u128 = time::NANOS_PER_SEC;
+/// Interrupts per interval that a CPU must handle more than another before an IRQ is moved
+const MIN_IMBALANCE: usize = 1000;
+/// Intervals that an IRQ stays on a CPU after being moved, so that it does not bounce between CPUs
+const MIN_STAY: u32 = 10;
+
+const NO_COUNT: AtomicUsize = AtomicUsize::new(0);
+const NO_COUNTS: [AtomicUsize; 16] = [NO_COUNT; 16];
+/// Interrupts of every legacy IRQ handled by every CPU since the last rebalancing
+static COUNTS: [[AtomicUsize; 16]; MAX_CPUS] = [NO_COUNTS; MAX_CPUS];
+
+const OFFLINE: AtomicBool = AtomicBool::new(false);
+/// CPUs whose IDT has handlers for the balanced IRQs
+static ONLINE: [AtomicBool; MAX_CPUS] = [OFFLINE; MAX_CPUS];
+
+/// Whether IRQs are moved between CPUs automatically
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+static BALANCER: Mutex<Balancer> = Mutex::new(Balancer {
+    next: 0,
+    irqs: [IrqState { cpu: 0, pinned: false, stay: 0, last: 0 }; 16],
+});
+
+#[derive(Clone, Copy)]
+struct IrqState {
+    /// CPU the IRQ is delivered to, initially the BSP
+    cpu: usize,
+    /// Whether userspace chose the CPU, in which case the IRQ is not moved
+    pinned: bool,
+    /// Intervals left before the IRQ may be moved again
+    stay: u32,
+    /// Interrupts in the last interval, on all CPUs
+    last: usize,
+}
+
+struct Balancer {
+    /// Monotonic time of the next rebalancing
+    next: u128,
+    irqs: [IrqState; 16],
+}
+
+impl Balancer {
+    fn rebalance(&mut self) {
+        // Take the counts of the interval that ended
+        let mut loads = [0; MAX_CPUS];
+        for irq in self.irqs.iter_mut() {
+            irq.last = 0;
+            irq.stay = irq.stay.saturating_sub(1);
+        }
+        for (cpu_id, counts) in COUNTS.iter().enumerate() {
+            for (irq, count) in counts.iter().enumerate() {
+                let count = count.swap(0, Ordering::Relaxed);
+                loads[cpu_id] += count;
+                self.irqs[irq].last += count;
+            }
+        }
+
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let online = || (0..MAX_CPUS).filter(|&cpu_id| ONLINE[cpu_id].load(Ordering::Relaxed));
+        let (busiest, idlest) = match (online().max_by_key(|&cpu_id| loads[cpu_id]), online().min_by_key(|&cpu_id| loads[cpu_id])) {
+            (Some(busiest), Some(idlest)) => (busiest, idlest),
+            _ => return,
+        };
+        let imbalance = loads[busiest] - loads[idlest];
+        if imbalance < MIN_IMBALANCE {
+            return;
+        }
+
+        // Move the busiest IRQ that leaves both CPUs less loaded than the busiest one was
+        let candidate = BALANCED_IRQS.iter()
+            .map(|&irq| (irq, self.irqs[usize::from(irq)]))
+            .filter(|&(_, state)| state.cpu == busiest && !state.pinned && state.stay == 0)
+            .filter(|&(_, state)| state.last > 0 && state.last < imbalance)
+            .max_by_key(|&(_, state)| state.last);
+
+        // Running in the timer interrupt, this must not wait for the I/O APIC, which the
+        // interrupted code may hold. The IRQ is then moved at a later interval instead.
+        if let Some((irq, _)) = candidate {
+            if unsafe { ioapic::try_set_irq_affinity(irq, idlest) } {
+                let state = &mut self.irqs[usize::from(irq)];
+                state.cpu = idlest;
+                state.stay = MIN_STAY;
+            }
+        }
+    }
+}
+
+/// Turn balancing off if requested on the kernel command line
+pub fn init() {
+    if crate::cmdline::cmdline().split(|b| b.is_ascii_whitespace()).any(|arg| arg == b"noirqbalance") {
+        ENABLED.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Mark a CPU as able to handle the balanced IRQs, once its IDT is set up
+pub fn set_online(cpu_id: usize) {
+    if let Some(online) = ONLINE.get(cpu_id) {
+        online.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Count an interrupt of a legacy IRQ on the current CPU
+pub fn count(irq: u8) {
+    if let Some(count) = COUNTS.get(crate::cpu_id()).and_then(|counts| counts.get(usize::from(irq))) {
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Rebalance the IRQs once the interval passed. Called from the timer interrupt of the BSP, so
+/// nothing is done if the interrupted code is using the balancer.
+pub fn tick() {
+    let now = time::monotonic();
+    let mut balancer = match BALANCER.try_lock() {
+        Some(balancer) => balancer,
+        None => return,
+    };
+    if now < balancer.next {
+        return;
+    }
+    balancer.next = now + INTERVAL;
+    balancer.rebalance();
+}
+
+/// Deliver an IRQ to `cpu_id` and stop balancing it, or resume balancing it if `cpu_id` is None
+fn pin(irq: u8, cpu_id: Option<usize>) -> Result<()> {
+    if !BALANCED_IRQS.contains(&irq) {
+        return Err(Error::new(EINVAL));
+    }
+
+    let mut balancer = BALANCER.lock();
+    let state = &mut balancer.irqs[usize::from(irq)];
+    match cpu_id {
+        Some(cpu_id) => {
+            if !ONLINE.get(cpu_id).map_or(false, |online| online.load(Ordering::Relaxed)) {
+                return Err(Error::new(EINVAL));
+            }
+            if !unsafe { ioapic::set_irq_affinity(irq, cpu_id) } {
+                return Err(Error::new(ENODEV));
+            }
+            state.cpu = cpu_id;
+            state.pinned = true;
+        },
+        None => {
+            state.pinned = false;
+            state.stay = 0;
+        },
+    }
+    Ok(())
+}
+
+/// List where the balanced IRQs are delivered, and how often they fired in the last interval
+pub fn resource() -> Result<Vec<u8>> {
+    let balancer = BALANCER.lock();
+
+    let mut string = String::new();
+    let _ = writeln!(string, "balance: {}", if ENABLED.load(Ordering::Relaxed) { "on" } else { "off" });
+    let _ = writeln!(string, "{:<6}{:<6}{:<6}{}", "IRQ", "CPU", "PIN", "COUNT");
+    for &irq in BALANCED_IRQS.iter() {
+        let state = &balancer.irqs[usize::from(irq)];
+        let _ = writeln!(string, "{:<6}{:<6}{:<6}{}", irq, state.cpu, if state.pinned { "yes" } else { "no" }, state.last);
+    }
+
+    Ok(string.into_bytes())
+}
+
+/// Turn balancing `on` or `off`, pin an IRQ to a CPU with `IRQ CPU`, or unpin it with `IRQ auto`
+pub fn write(buf: &[u8]) -> Result<usize> {
+    let line = core::str::from_utf8(buf).map_err(|_| Error::new(EINVAL))?;
+    let mut parts = line.split_whitespace();
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("on"), None, None) => ENABLED.store(true, Ordering::Relaxed),
+        (Some("off"), None, None) => ENABLED.store(false, Ordering::Relaxed),
+        (Some(irq), Some(cpu_id), None) => {
+            let irq = irq.parse::<u8>().map_err(|_| Error::new(EINVAL))?;
+            let cpu_id = match cpu_id {
+                "auto" => None,
+                cpu_id => Some(cpu_id.parse::<usize>().map_err(|_| Error::new(EINVAL))?),
+            };
+            pin(irq, cpu_id)?;
+        },
+        _ => return Err(Error::new(EINVAL)),
+    }
+    Ok(buf.len())
+}
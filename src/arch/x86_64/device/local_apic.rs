@@ -6,6 +6,16 @@ use crate::paging::{KernelMapper, PhysicalAddress, PageFlags, RmmA, RmmArch};
 
 use super::super::cpuid::cpuid;
 
+/// Vector of spurious interrupts, which must not be acknowledged with an EOI
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Vector of thermal monitor interrupts
+pub const THERMAL_VECTOR: u8 = 50;
+
+// TODO: Move to x86::msr
+const IA32_THERM_INTERRUPT: u32 = 0x19B;
+pub const IA32_THERM_STATUS: u32 = 0x19C;
+
 pub static mut LOCAL_APIC: LocalApic = LocalApic {
     address: 0,
     x2: false
@@ -73,13 +83,16 @@ impl LocalApic {
     }
 
     unsafe fn init_ap(&mut self) {
+        // Software enable the APIC, delivering spurious interrupts to their own vector
+        let sivr = 0x100 | u32::from(SPURIOUS_VECTOR);
         if self.x2 {
             wrmsr(IA32_APIC_BASE, rdmsr(IA32_APIC_BASE) | 1 << 10);
-            wrmsr(IA32_X2APIC_SIVR, 0x100);
+            wrmsr(IA32_X2APIC_SIVR, u64::from(sivr));
         } else {
-            self.write(0xF0, 0x100);
+            self.write(0xF0, sivr);
         }
         self.setup_error_int();
+        self.setup_thermal_int();
         //self.setup_timer();
     }
 
@@ -144,7 +157,7 @@ impl LocalApic {
         }
         self.set_icr(icr);
     }
-    // Not used just yet, but allows triggering an NMI to another processor.
+    /// Trigger an NMI on another processor, see `ipi::nmi`
     pub fn ipi_nmi(&mut self, apic_id: u32) {
         let shift = if self.x2 { 32 } else { 56 };
         self.set_icr((u64::from(apic_id) << shift) | (1 << 14) | (0b100 << 8));
@@ -233,9 +246,95 @@ impl LocalApic {
         }
     }
     unsafe fn setup_error_int(&mut self) {
+        // Clear any errors latched before the vector is set up, so that they are not reported
+        // again on the first error interrupt.
+        let _ = self.esr();
+        let _ = self.esr();
+
         let vector = 49u32;
         self.set_lvt_error(vector);
     }
+    pub unsafe fn lvt_thermal(&mut self) -> u32 {
+        if self.x2 {
+            rdmsr(IA32_X2APIC_LVT_THERMAL) as u32
+        } else {
+            self.read(0x330)
+        }
+    }
+    pub unsafe fn set_lvt_thermal(&mut self, lvt_thermal: u32) {
+        if self.x2 {
+            wrmsr(IA32_X2APIC_LVT_THERMAL, u64::from(lvt_thermal));
+        } else {
+            self.write(0x330, lvt_thermal);
+        }
+    }
+    /// Whether this CPU has the thermal monitor MSRs, and a thermal LVT entry to deliver their
+    /// interrupts
+    pub fn has_thermal(&self) -> bool {
+        let has_msrs = cpuid().map_or(false, |cpuid| {
+            cpuid.get_feature_info().map_or(false, |feature_info| {
+                feature_info.has_acpi()
+            })
+        });
+        // The thermal LVT entry is only implemented by APICs with at least six LVT entries, the
+        // version register holds the index of the last one
+        has_msrs && (self.version() >> 16) & 0xFF >= 5
+    }
+    unsafe fn setup_thermal_int(&mut self) {
+        if ! self.has_thermal() {
+            return;
+        }
+
+        // Clear the log bits, so that only events from now on are reported
+        let status = rdmsr(IA32_THERM_STATUS);
+        wrmsr(IA32_THERM_STATUS, status & !u64::from(ThermalStatus::LOGS.bits()));
+
+        // Interrupt on crossing the high or low temperature threshold, on PROCHOT# and on reaching
+        // the critical temperature
+        wrmsr(IA32_THERM_INTERRUPT, rdmsr(IA32_THERM_INTERRUPT) | 0b1_0111);
+
+        self.set_lvt_thermal(u32::from(THERMAL_VECTOR));
+    }
+}
+
+bitflags! {
+    /// Bits of the IA32_THERM_STATUS MSR. Bits 22:16 hold the digital readout, the temperature in
+    /// degrees Celsius below the maximum junction temperature.
+    pub struct ThermalStatus: u32 {
+        /// The processor is throttling because it is too hot
+        const THROTTLING = 1 << 0;
+        const THROTTLING_LOG = 1 << 1;
+        const PROCHOT = 1 << 2;
+        const PROCHOT_LOG = 1 << 3;
+        const CRITICAL = 1 << 4;
+        const CRITICAL_LOG = 1 << 5;
+        const THRESHOLD1 = 1 << 6;
+        const THRESHOLD1_LOG = 1 << 7;
+        const THRESHOLD2 = 1 << 8;
+        const THRESHOLD2_LOG = 1 << 9;
+        const POWER_LIMIT = 1 << 10;
+        const POWER_LIMIT_LOG = 1 << 11;
+        /// The digital readout is valid
+        const READING_VALID = 1 << 31;
+
+        /// Sticky bits, cleared by writing 0
+        const LOGS = Self::THROTTLING_LOG.bits | Self::PROCHOT_LOG.bits | Self::CRITICAL_LOG.bits
+            | Self::THRESHOLD1_LOG.bits | Self::THRESHOLD2_LOG.bits | Self::POWER_LIMIT_LOG.bits;
+    }
+}
+
+bitflags! {
+    /// Bits of the Error Status Register
+    pub struct ApicError: u32 {
+        const SEND_CHECKSUM = 1 << 0;
+        const RECEIVE_CHECKSUM = 1 << 1;
+        const SEND_ACCEPT = 1 << 2;
+        const RECEIVE_ACCEPT = 1 << 3;
+        const REDIRECTABLE_IPI = 1 << 4;
+        const SEND_ILLEGAL_VECTOR = 1 << 5;
+        const RECEIVE_ILLEGAL_VECTOR = 1 << 6;
+        const ILLEGAL_REGISTER_ADDRESS = 1 << 7;
+    }
 }
 
 #[repr(u8)]
@@ -0,0 +1,197 @@
+//! CPU microcode, which can be updated at runtime to fix errata
+
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86::msr::{rdmsr, wrmsr};
+
+use crate::ipi::{ipi, IpiKind, IpiTarget};
+use crate::syscall::error::{Error, Result, EINVAL, EIO, EOPNOTSUPP, ETIMEDOUT};
+use crate::time;
+
+use super::super::cpuid::cpuid;
+
+// TODO: Move to x86::msr
+const IA32_BIOS_UPDT_TRIG: u32 = 0x79;
+const IA32_BIOS_SIGN_ID: u32 = 0x8B;
+
+/// Size of the header of an Intel microcode update
+const HEADER_SIZE: usize = 48;
+/// Size of the update data, if the header gives it as 0
+const DEFAULT_DATA_SIZE: usize = 2000;
+
+/// How long the other CPUs have to apply an update, in nanoseconds
+const UPDATE_TIMEOUT: u128 = 1_000_000_000;
+
+const NO_REVISION: AtomicU32 = AtomicU32::new(0);
+/// Microcode revision of each CPU, as of boot or the last update
+static REVISIONS: [AtomicU32; 64] = [NO_REVISION; 64];
+
+/// Part of the copy of an update, which makes it 16 byte aligned as the processor requires
+#[derive(Clone, Copy)]
+#[repr(C, align(16))]
+struct UpdateChunk([u8; 16]);
+
+/// Update being applied by `update`, for the other CPUs to load when they receive the IPI
+static PENDING: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+/// Number of other CPUs that tried to load the pending update
+static DONE: AtomicUsize = AtomicUsize::new(0);
+/// Number of other CPUs that did not end up at the revision of the pending update
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+/// Only one update can be pending at a time
+static UPDATE_LOCK: Mutex<()> = Mutex::new(());
+
+fn vendor_is(vendor: &str) -> bool {
+    cpuid().map_or(false, |cpuid| {
+        cpuid.get_vendor_info().map_or(false, |info| info.as_str() == vendor)
+    })
+}
+
+/// Microcode revision of the current CPU, or 0 if it cannot be read
+pub fn revision() -> u32 {
+    if vendor_is("GenuineIntel") {
+        unsafe {
+            // The revision is only stored in the MSR by CPUID leaf 1, after clearing it
+            wrmsr(IA32_BIOS_SIGN_ID, 0);
+            let _ = cpuid().and_then(|cpuid| cpuid.get_feature_info());
+            (rdmsr(IA32_BIOS_SIGN_ID) >> 32) as u32
+        }
+    } else if vendor_is("AuthenticAMD") {
+        // AMD reports the patch level in the low half of the same MSR
+        unsafe { rdmsr(IA32_BIOS_SIGN_ID) as u32 }
+    } else {
+        0
+    }
+}
+
+/// Record the revision the current CPU booted with
+pub fn init() {
+    if let Some(slot) = REVISIONS.get(crate::cpu_id()) {
+        slot.store(revision(), Ordering::Relaxed);
+    }
+}
+
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = alloc::string::String::new();
+    for cpu_id in 0..crate::cpu_count() {
+        let revision = match REVISIONS.get(cpu_id) {
+            Some(revision) => revision.load(Ordering::Relaxed),
+            None => break,
+        };
+        string.push_str(&format!("CPU {}: {:#x}\n", cpu_id, revision));
+    }
+    Ok(string.into_bytes())
+}
+
+pub fn write(buf: &[u8]) -> Result<usize> {
+    update(buf).map(|()| buf.len())
+}
+
+fn dword(blob: &[u8], index: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&blob[index * 4..index * 4 + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+/// Check the header and checksum of an Intel microcode update. Whether it matches the processor
+/// is left to the processor, which rejects it otherwise.
+fn validate(blob: &[u8]) -> Result<()> {
+    if blob.len() < HEADER_SIZE || blob.len() % 4 != 0 {
+        return Err(Error::new(EINVAL));
+    }
+
+    // Header version and loader revision
+    if dword(blob, 0) != 1 || dword(blob, 5) != 1 {
+        return Err(Error::new(EINVAL));
+    }
+
+    let (data_size, total_size) = match dword(blob, 7) as usize {
+        0 => (DEFAULT_DATA_SIZE, HEADER_SIZE + DEFAULT_DATA_SIZE),
+        data_size => (data_size, dword(blob, 8) as usize),
+    };
+    if total_size != blob.len() || total_size < HEADER_SIZE + data_size {
+        return Err(Error::new(EINVAL));
+    }
+
+    // The dwords of the whole update, including the extended signature table, sum up to 0
+    let sum = (0..blob.len() / 4).fold(0u32, |sum, i| sum.wrapping_add(dword(blob, i)));
+    if sum != 0 {
+        return Err(Error::new(EINVAL));
+    }
+
+    Ok(())
+}
+
+/// Load the update at `update` into the current CPU, returning whether the CPU reports its
+/// revision afterwards
+unsafe fn apply(update: *const u8) -> bool {
+    let expected = ptr::read_unaligned(update.add(4) as *const u32);
+    wrmsr(IA32_BIOS_UPDT_TRIG, update.add(HEADER_SIZE) as u64);
+
+    let revision = revision();
+    if let Some(slot) = REVISIONS.get(crate::cpu_id()) {
+        slot.store(revision, Ordering::Relaxed);
+    }
+    revision == expected
+}
+
+/// Called on every other CPU by the microcode IPI
+pub unsafe fn apply_pending() {
+    let update = PENDING.load(Ordering::Acquire);
+    if update.is_null() {
+        return;
+    }
+    if ! apply(update) {
+        FAILED.fetch_add(1, Ordering::Relaxed);
+    }
+    DONE.fetch_add(1, Ordering::Release);
+}
+
+/// Apply an Intel microcode update on every CPU. Fails with EIO if any CPU rejects it.
+pub fn update(blob: &[u8]) -> Result<()> {
+    if ! vendor_is("GenuineIntel") {
+        return Err(Error::new(EOPNOTSUPP));
+    }
+    validate(blob)?;
+
+    // The update data must be 16 byte aligned, and the header is too
+    let mut aligned = vec![UpdateChunk([0; 16]); (blob.len() + 15) / 16];
+    unsafe {
+        ptr::copy_nonoverlapping(blob.as_ptr(), aligned.as_mut_ptr() as *mut u8, blob.len());
+    }
+    let update = aligned.as_ptr() as *const u8;
+
+    let _guard = UPDATE_LOCK.lock();
+
+    DONE.store(0, Ordering::Relaxed);
+    FAILED.store(0, Ordering::Relaxed);
+    PENDING.store(update as *mut u8, Ordering::Release);
+
+    let others = crate::cpu_count() - 1;
+    ipi(IpiKind::Microcode, IpiTarget::Other);
+
+    let mut failed = if unsafe { apply(update) } { 0 } else { 1 };
+
+    let deadline = time::monotonic() + UPDATE_TIMEOUT;
+    while DONE.load(Ordering::Acquire) < others {
+        if time::monotonic() >= deadline {
+            // A CPU may still read the update later, so it must not be freed
+            core::mem::forget(aligned);
+            PENDING.store(ptr::null_mut(), Ordering::Release);
+            println!("microcode: {} of {} CPUs did not respond", others - DONE.load(Ordering::Acquire), others);
+            return Err(Error::new(ETIMEDOUT));
+        }
+        core::hint::spin_loop();
+    }
+    PENDING.store(ptr::null_mut(), Ordering::Release);
+
+    failed += FAILED.load(Ordering::Relaxed);
+    if failed > 0 {
+        println!("microcode: update to revision {:#x} rejected by {} CPUs", dword(blob, 1), failed);
+        return Err(Error::new(EIO));
+    }
+
+    println!("microcode: updated all CPUs to revision {:#x}", dword(blob, 1));
+    Ok(())
+}
@@ -1,6 +1,9 @@
 pub mod cpu;
 pub mod ioapic;
+pub mod irq_balance;
 pub mod local_apic;
+pub mod mce;
+pub mod microcode;
 pub mod pic;
 pub mod pit;
 pub mod rtc;
@@ -15,6 +18,9 @@ use crate::paging::KernelMapper;
 pub unsafe fn init() {
     pic::init();
     local_apic::init(&mut KernelMapper::lock());
+    mce::init();
+    microcode::init();
+    irq_balance::init();
 }
 pub unsafe fn init_after_acpi()  {
     // this will disable the IOAPIC if needed.
@@ -50,4 +56,6 @@ pub unsafe fn init_noncore() {
 
 pub unsafe fn init_ap() {
     local_apic::init_ap();
+    mce::init();
+    microcode::init();
 }
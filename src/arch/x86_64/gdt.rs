@@ -145,6 +145,21 @@ pub unsafe fn init() {
     segmentation::load_ss(SegmentSelector::new(GDT_KERNEL_DATA as u16, Ring::Ring0));
 }
 
+/// Read the CPU ID back from the GDT currently loaded, which is where paranoid interrupt handlers
+/// find it. Returns `None` if the loaded GDT is too small to contain it.
+pub unsafe fn loaded_cpu_id() -> Option<u32> {
+    let mut gdtr: DescriptorTablePointer<SegmentDescriptor> = DescriptorTablePointer {
+        limit: 0,
+        base: core::ptr::null(),
+    };
+    dtables::sgdt(&mut gdtr);
+
+    if usize::from(gdtr.limit) + 1 < (GDT_CPU_ID_CONTAINER + 1) * mem::size_of::<GdtEntry>() {
+        return None;
+    }
+    Some((gdtr.base as *const GdtEntry).add(GDT_CPU_ID_CONTAINER).cast::<u32>().read())
+}
+
 /// Initialize GDT with TLS
 pub unsafe fn init_paging(cpu_id: u32, tcb_offset: usize, stack_offset: usize) {
     // Set temporary TLS segment to the self-pointer of the Thread Control Block.
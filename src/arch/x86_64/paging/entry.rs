@@ -12,7 +12,12 @@ pub struct Entry(u64);
 bitflags! {
     pub struct EntryFlags: usize {
         const NO_CACHE =        1 << 4;
+        /// Set by the CPU when the page is written to
+        const DIRTY =           1 << 6;
         const HUGE_PAGE =       1 << 7;
+        /// In entries mapping 4 KiB pages, selects the upper half of the PAT, whose first entry
+        /// is write combining
+        const PAT =             1 << 7;
         const GLOBAL =          1 << 8;
     }
 }
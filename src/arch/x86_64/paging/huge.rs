@@ -0,0 +1,132 @@
+//! # Huge pages
+//! Pages of `MAP_HUGETLB` grants are mapped by page directory entries of 2 MiB each where
+//! possible. The `PageMapper` only knows about page entries, and would walk into the frames of a
+//! huge page as if they were a page table, so any page of such a grant has to be looked up here
+//! first. The tables are accessed through the physical memory mapping.
+
+use rmm::PageFlush;
+
+use crate::memory::{allocate_frames, deallocate_frames, Enomem, Frame};
+
+use super::entry::EntryFlags;
+use super::{Page, PageFlags, PageMapper, PhysicalAddress, RmmA, RmmArch, VirtualAddress, ENTRY_COUNT, PAGE_SIZE};
+
+/// Number of pages mapped by a huge page entry
+pub const HUGE_PAGE_PAGES: usize = ENTRY_COUNT;
+
+/// Size of huge pages
+pub const HUGE_PAGE_SIZE: usize = HUGE_PAGE_PAGES * PAGE_SIZE;
+
+const HUGE: usize = EntryFlags::HUGE_PAGE.bits();
+
+unsafe fn table(phys: usize) -> *mut usize {
+    RmmA::phys_to_virt(PhysicalAddress::new(phys)).data() as *mut usize
+}
+
+/// The page directory entry for `virt`, and the entry of the page directory pointer table above
+/// it, if the tables down to the page directory are present. User memory is never mapped with
+/// 1 GiB entries.
+unsafe fn directory_entry(mapper: &PageMapper, virt: VirtualAddress) -> Option<(usize, *mut usize)> {
+    let page = Page::containing_address(virt);
+
+    let p4_entry = table(mapper.table().phys().data()).add(page.p4_index()).read();
+    if p4_entry & RmmA::ENTRY_FLAG_PRESENT == 0 {
+        return None;
+    }
+    let p3_entry = table(p4_entry & RmmA::PAGE_ADDRESS_MASK).add(page.p3_index()).read();
+    if p3_entry & RmmA::ENTRY_FLAG_PRESENT == 0 || p3_entry & HUGE == HUGE {
+        return None;
+    }
+    Some((p3_entry, table(p3_entry & RmmA::PAGE_ADDRESS_MASK).add(page.p2_index())))
+}
+
+/// The huge page entry mapping `virt`, if there is one
+unsafe fn huge_entry(mapper: &PageMapper, virt: VirtualAddress) -> Option<*mut usize> {
+    let (_, entry) = directory_entry(mapper, virt)?;
+    let data = entry.read();
+    (data & RmmA::ENTRY_FLAG_PRESENT != 0 && data & HUGE == HUGE).then_some(entry)
+}
+
+fn entry_address(data: usize) -> usize {
+    data & RmmA::PAGE_ADDRESS_MASK & !(HUGE_PAGE_SIZE - 1)
+}
+fn entry_flags(data: usize) -> usize {
+    data & RmmA::ENTRY_FLAGS_MASK & !HUGE
+}
+
+/// Translate `virt` to the frame of its page, if it is mapped by a huge page entry
+pub fn translate(mapper: &PageMapper, virt: VirtualAddress) -> Option<(PhysicalAddress, PageFlags<RmmA>)> {
+    let data = unsafe { huge_entry(mapper, virt)?.read() };
+    let offset = virt.data() & (HUGE_PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+    Some((PhysicalAddress::new(entry_address(data) + offset), unsafe { PageFlags::from_data(entry_flags(data)) }))
+}
+
+/// Map the huge page at `virt` to the contiguous frames at `phys`, both aligned to
+/// `HUGE_PAGE_SIZE`. Fails if any of its pages is mapped already, or if the tables above it
+/// cannot be allocated.
+pub unsafe fn map(mapper: &mut PageMapper, virt: VirtualAddress, phys: PhysicalAddress, flags: PageFlags<RmmA>) -> Option<PageFlush<RmmA>> {
+    if huge_entry(mapper, virt).is_some() {
+        return None;
+    }
+    if directory_entry(mapper, virt).map_or(true, |(_, entry)| entry.read() & RmmA::ENTRY_FLAG_PRESENT == 0) {
+        // Let the mapper allocate the tables down to the page directory. The page table below it
+        // is left empty, and replaced by the huge page entry.
+        let map_flush = mapper.map_phys(virt, phys, flags)?;
+        let (_, _, unmap_flush) = mapper.unmap_phys(virt, false).expect("page mapped by this call disappeared");
+        // The page was not present before, and nothing can have accessed it since
+        map_flush.ignore();
+        unmap_flush.ignore();
+    }
+
+    let (_, entry) = directory_entry(mapper, virt)?;
+    let page_table = entry.read() & RmmA::PAGE_ADDRESS_MASK;
+    if (0..ENTRY_COUNT).any(|index| table(page_table).add(index).read() & RmmA::ENTRY_FLAG_PRESENT != 0) {
+        return None;
+    }
+
+    entry.write(phys.data() | flags.data() | HUGE);
+    deallocate_frames(Frame::containing_address(PhysicalAddress::new(page_table)), 1);
+
+    Some(PageFlush::new(virt))
+}
+
+/// Change the flags of the huge page mapping `virt`, if there is one
+pub unsafe fn remap(mapper: &mut PageMapper, virt: VirtualAddress, flags: PageFlags<RmmA>) -> Option<PageFlush<RmmA>> {
+    let entry = huge_entry(mapper, virt)?;
+    entry.write(entry_address(entry.read()) | flags.data() | HUGE);
+
+    Some(PageFlush::new(virt))
+}
+
+/// Unmap the huge page mapping `virt`, if there is one, returning the first of its frames. The
+/// tables above it are kept.
+pub unsafe fn unmap(mapper: &mut PageMapper, virt: VirtualAddress) -> Option<(PhysicalAddress, PageFlags<RmmA>, PageFlush<RmmA>)> {
+    let entry = huge_entry(mapper, virt)?;
+    let data = entry.read();
+    entry.write(0);
+
+    Some((PhysicalAddress::new(entry_address(data)), PageFlags::from_data(entry_flags(data)), PageFlush::new(virt)))
+}
+
+/// Map the huge page mapping `virt`, if there is one, with page entries instead, so that its
+/// pages can be changed one by one. Each page keeps its frame and flags.
+pub unsafe fn split(mapper: &mut PageMapper, virt: VirtualAddress) -> Result<Option<PageFlush<RmmA>>, Enomem> {
+    let (parent, entry) = match directory_entry(mapper, virt) {
+        Some(entries) => entries,
+        None => return Ok(None),
+    };
+    let data = entry.read();
+    if data & RmmA::ENTRY_FLAG_PRESENT == 0 || data & HUGE != HUGE {
+        return Ok(None);
+    }
+
+    let page_table = allocate_frames(1).ok_or(Enomem)?.start_address().data();
+    for index in 0..ENTRY_COUNT {
+        table(page_table).add(index).write((entry_address(data) + index * PAGE_SIZE) | entry_flags(data));
+    }
+    // Link the page table the way the page directory itself is linked
+    entry.write(page_table | parent & !RmmA::PAGE_ADDRESS_MASK);
+
+    Ok(Some(PageFlush::new(virt)))
+}
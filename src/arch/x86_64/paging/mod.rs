@@ -21,6 +21,7 @@ pub type PageMapper = rmm::PageMapper<RmmA, crate::arch::rmm::LockedAllocator>;
 pub use crate::rmm::KernelMapper;
 
 pub mod entry;
+pub mod huge;
 pub mod mapper;
 
 /// Number of entries per page table
@@ -29,6 +30,30 @@ pub const ENTRY_COUNT: usize = RmmA::PAGE_ENTRIES;
 /// Size of pages
 pub const PAGE_SIZE: usize = RmmA::PAGE_SIZE;
 
+/// CR4 bit selecting 5-level paging, which can only be changed while paging is disabled
+const CR4_LA57: usize = 1 << 12;
+
+/// Whether the CPU is using 5-level paging
+pub fn la57_enabled() -> bool {
+    unsafe { x86::controlregs::cr4() }.bits() & CR4_LA57 == CR4_LA57
+}
+
+/// Report how many paging levels are used, and whether 5-level paging is supported. Only 4
+/// levels are supported: `RmmA` walks a fixed 4 levels, so LA57 is never enabled by the kernel,
+/// and `USER_END_OFFSET` stays within the lower half of the 4-level address space. Booting stops
+/// in `rmm::init` if the bootloader enabled it.
+pub fn check_levels() {
+    let la57_supported = super::cpuid::cpuid()
+        .and_then(|cpuid| cpuid.get_extended_feature_info())
+        .map_or(false, |info| info.has_la57());
+
+    log::info!(
+        "Paging: {} levels, 5-level paging {}",
+        RmmA::PAGE_LEVELS,
+        if la57_supported { "supported but unused" } else { "unsupported" }
+    );
+}
+
 /// Setup page attribute table
 unsafe fn init_pat() {
     let uncacheable = 0;
@@ -43,6 +68,7 @@ unsafe fn init_pat() {
     let pat2 = uncached;
     let pat3 = uncacheable;
 
+    // Selected by `EntryFlags::PAT` alone, for write combining device mappings
     let pat4 = write_combining;
     let pat5 = pat1;
     let pat6 = pat2;
@@ -194,7 +194,13 @@ unsafe fn inner<A: Arch>(
             }
         }
 
-        // Use the new table
+        // Use the new table. It only has 4 levels, which the CPU would walk as 5 if the
+        // bootloader enabled 5-level paging.
+        assert!(
+            !super::paging::la57_enabled(),
+            "5-level paging was enabled by the bootloader, but only {} levels are supported",
+            A::PAGE_LEVELS
+        );
         mapper.make_current();
     }
 
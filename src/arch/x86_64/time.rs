@@ -30,3 +30,17 @@ pub fn counter() -> u128 {
     // Calculate nanoseconds since last interrupt
     (elapsed as u128 * pit::PERIOD_FS) / 1_000_000
 }
+
+/// Granularity of `counter`, in nanoseconds. This is the period of the timer counting the time
+/// since the last interrupt, rounded up so as to never claim a finer resolution than there is.
+pub fn resolution() -> u128 {
+    #[cfg(feature = "acpi")]
+    if let Some(ref hpet) = *crate::acpi::ACPI_TABLE.hpet.read() {
+        // Get period in femtoseconds
+        let capability = unsafe { hpet.base_address.read_u64(hpet::CAPABILITY_OFFSET) };
+        let period_fs = (capability >> 32) as u128;
+        return (period_fs + 999_999) / 1_000_000;
+    }
+
+    (pit::PERIOD_FS + 999_999) / 1_000_000
+}
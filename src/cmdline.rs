@@ -0,0 +1,39 @@
+//! The kernel command line. The bootloader passes it as the `CMDLINE` variable of the
+//! environment, which lives in memory the bootloader handed over and which may be reclaimed, so it
+//! is copied into the kernel image as soon as the kernel starts.
+
+use core::cmp;
+use spin::Once;
+
+/// Longest command line that is kept, longer ones are truncated
+pub const CMDLINE_MAX: usize = 4096;
+
+static mut CMDLINE_BUF: [u8; CMDLINE_MAX] = [0; CMDLINE_MAX];
+static CMDLINE: Once<&'static [u8]> = Once::new();
+
+/// Copy the command line out of the environment `env` passed by the bootloader. Only the first
+/// call has any effect.
+pub fn init(env: &[u8]) {
+    CMDLINE.call_once(|| {
+        let value = env
+            .split(|&b| b == b'\n')
+            .find_map(|line| line.strip_prefix(b"CMDLINE="))
+            .unwrap_or(&[]);
+
+        let len = cmp::min(value.len(), CMDLINE_MAX);
+        if len < value.len() {
+            log::warn!("kernel command line truncated from {} to {} bytes", value.len(), len);
+        }
+
+        // Only written here, and `Once` makes sure that happens a single time
+        unsafe {
+            CMDLINE_BUF[..len].copy_from_slice(&value[..len]);
+            &CMDLINE_BUF[..len]
+        }
+    });
+}
+
+/// The kernel command line, or an empty slice if the bootloader passed none
+pub fn cmdline() -> &'static [u8] {
+    CMDLINE.get().copied().unwrap_or(&[])
+}
@@ -11,6 +11,7 @@ use crate::interrupt::handler::ScratchRegisters;
 use crate::device::cpu::registers::{control_regs, tlb};
 use crate::paging::{RmmA, RmmArch, TableKind};
 use crate::syscall::FloatRegisters;
+use crate::syscall::error::Result;
 
 /// This must be used by the kernel to ensure that context switches are done atomically
 /// Compare and exchange this to true when beginning a context switch on any CPU
@@ -22,6 +23,11 @@ pub static CONTEXT_SWITCH_LOCK: AtomicBool = AtomicBool::new(false);
 pub const KFX_SIZE: usize = 1024;
 pub const KFX_ALIGN: usize = 16;
 
+/// Size of the FX area of each context
+pub fn kfx_size() -> usize {
+    KFX_SIZE
+}
+
 #[derive(Clone, Debug)]
 pub struct Context {
     elr_el1: usize,
@@ -146,7 +152,7 @@ impl super::Context {
         }
     }
 
-    pub fn set_fx_regs(&mut self, mut new: FloatRegisters) {
+    pub fn set_fx_regs(&mut self, mut new: FloatRegisters) -> Result<()> {
         if !self.arch.fx_loadable {
             panic!("TODO: make set_fx_regs always work");
         }
@@ -154,6 +160,7 @@ impl super::Context {
         unsafe {
             ptr::write(self.kfx.as_mut_ptr() as *mut FloatRegisters, new);
         }
+        Ok(())
     }
 }
 
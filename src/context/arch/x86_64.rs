@@ -1,5 +1,5 @@
 use core::mem;
-use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
 
 use alloc::sync::Arc;
 
@@ -7,6 +7,7 @@ use crate::{push_scratch, pop_scratch};
 use crate::interrupt::handler::ScratchRegisters;
 use crate::paging::{RmmA, RmmArch, TableKind};
 use crate::syscall::FloatRegisters;
+use crate::syscall::error::{Error, Result, EINVAL, EOPNOTSUPP};
 
 use memoffset::offset_of;
 use spin::Once;
@@ -18,9 +19,177 @@ use spin::Once;
 pub static CONTEXT_SWITCH_LOCK: AtomicBool = AtomicBool::new(false);
 
 const ST_RESERVED: u128 = 0xFFFF_FFFF_FFFF_0000_0000_0000_0000_0000;
+/// Valid MXCSR bits on CPUs that store zero as the MXCSR_MASK
+const MXCSR_MASK_DEFAULT: u32 = 0xFFBF;
+/// Initial value of the x87 control word, which FNINIT and XRSTOR of the initial state load
+const FCW_DEFAULT: u16 = 0x037F;
+
+/// Alignment of the FX area, as required by XSAVE
+pub const KFX_ALIGN: usize = 64;
+
+/// Size of the FXSAVE area, which is also the legacy region at the start of the XSAVE area
+const FXSAVE_SIZE: usize = 512;
+/// Offset of the XSTATE_BV field of the XSAVE header, telling which components are not in their
+/// initial state
+const XSTATE_BV_OFFSET: usize = 512;
+/// XSAVE components for the x87 FPU, SSE and AVX state
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+const KFX_FXSAVE: u8 = 0;
+const KFX_XSAVE: u8 = 1;
+const KFX_XSAVEOPT: u8 = 2;
+
+/// Mechanism used to save and restore the FX area on context switch
+static KFX_MECHANISM: AtomicU8 = AtomicU8::new(KFX_FXSAVE);
+static KFX_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_SIZE);
+
+/// Size of the FX area of each context, for the mechanism selected by `init_kfx`
+pub fn kfx_size() -> usize {
+    KFX_SIZE.load(Ordering::Relaxed)
+}
+
+/// Select the FX save mechanism, which the BSP does from CPUID before any context exists, and
+/// enable it on the current CPU. XSAVE is preferred, as it also saves the AVX state, and
+/// XSAVEOPT additionally skips components that have not changed. Every CPU must use the same
+/// mechanism, so APs only enable the one the BSP selected.
+pub unsafe fn init_kfx(bsp: bool) {
+    let has_xsave = cpuid(1, 0).2 & (1 << 26) != 0;
+
+    if bsp && has_xsave {
+        let has_xsaveopt = cpuid(0xD, 1).0 & 1 != 0;
+        KFX_MECHANISM.store(if has_xsaveopt { KFX_XSAVEOPT } else { KFX_XSAVE }, Ordering::Relaxed);
+    }
+    if KFX_MECHANISM.load(Ordering::Relaxed) == KFX_FXSAVE {
+        return;
+    }
+    assert!(has_xsave, "XSAVE was selected by the BSP, but is unsupported on this CPU");
+
+    x86::controlregs::cr4_write(x86::controlregs::cr4() | x86::controlregs::Cr4::CR4_ENABLE_OS_XSAVE);
+
+    let supported = {
+        let (eax, _, _, edx) = cpuid(0xD, 0);
+        u64::from(eax) | (u64::from(edx) << 32)
+    };
+    let xcr0 = supported & (XCR0_X87 | XCR0_SSE | XCR0_AVX);
+    core::arch::asm!("xsetbv", in("ecx") 0, in("eax") xcr0 as u32, in("edx") (xcr0 >> 32) as u32);
+
+    if bsp {
+        // EBX is the size needed for the components enabled in XCR0
+        let size = cpuid(0xD, 0).1 as usize;
+        KFX_SIZE.store(size.max(FXSAVE_SIZE + 64), Ordering::Relaxed);
+    }
+}
+
+fn cpuid(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32) {
+    let result = unsafe { core::arch::x86_64::__cpuid_count(leaf, subleaf) };
+    (result.eax, result.ebx, result.ecx, result.edx)
+}
+
+/// Event select encodings of the architectural performance monitoring events, in the order
+/// CPUID leaf 0xA reports their availability: core cycles, instructions retired, reference
+/// cycles, last level cache references, last level cache misses, branch instructions retired and
+/// branch mispredicts retired
+const PMC_EVENTS: [u64; 7] = [0x003C, 0x00C0, 0x013C, 0x4F2E, 0x412E, 0x00C4, 0x00C5];
+
+/// Event select flags to count in userspace, and to enable the counter
+const PMC_EVTSEL_USR: u64 = 1 << 16;
+const PMC_EVTSEL_EN: u64 = 1 << 22;
+
+/// Version of the architectural performance monitoring, and the mask of the counter bits
+static PMC_INFO: Once<(u32, u64)> = Once::new();
+
+fn pmc_info() -> (u32, u64) {
+    *PMC_INFO.call_once(|| {
+        if cpuid(0, 0).0 < 0xA {
+            return (0, 0);
+        }
+        let eax = cpuid(0xA, 0).0;
+        let width = (eax >> 16) & 0xFF;
+        let mask = if width == 0 || width >= 64 { u64::MAX } else { (1 << width) - 1 };
+        (eax & 0xFF, mask)
+    })
+}
+
+/// Look up the event select value for the architectural event `event`, failing with EINVAL for
+/// events that do not exist, and EOPNOTSUPP if the CPU cannot count it
+pub fn pmc_event_select(event: usize) -> Result<u64> {
+    let code = *PMC_EVENTS.get(event).ok_or(Error::new(EINVAL))?;
+
+    let (version, _) = pmc_info();
+    if version == 0 {
+        return Err(Error::new(EOPNOTSUPP));
+    }
+    let (eax, unavailable, _, _) = cpuid(0xA, 0);
+    let counters = (eax >> 8) & 0xFF;
+    let events = (eax >> 24) & 0xFF;
+    if counters == 0 || event as u32 >= events || unavailable & (1 << event) != 0 {
+        return Err(Error::new(EOPNOTSUPP));
+    }
+
+    Ok(code | PMC_EVTSEL_USR | PMC_EVTSEL_EN)
+}
+
+/// Start counting `evtsel` from zero in the first general purpose counter
+unsafe fn pmc_start(evtsel: u64) {
+    use x86::msr;
+
+    msr::wrmsr(msr::IA32_PERFEVTSEL0, 0);
+    msr::wrmsr(msr::IA32_PMC0, 0);
+    if pmc_info().0 >= 2 {
+        msr::wrmsr(msr::IA32_PERF_GLOBAL_OVF_CTRL, 1);
+        msr::wrmsr(msr::IA32_PERF_GLOBAL_CTRL, msr::rdmsr(msr::IA32_PERF_GLOBAL_CTRL) | 1);
+    }
+    msr::wrmsr(msr::IA32_PERFEVTSEL0, evtsel);
+}
 
-pub const KFX_SIZE: usize = 512;
-pub const KFX_ALIGN: usize = 16;
+/// Read the count of the first general purpose counter since `pmc_start`. Should the counter have
+/// wrapped around, which is reported from version 2 on, its full range is added.
+unsafe fn pmc_read() -> u64 {
+    let (low, high): (u32, u32);
+    core::arch::asm!("rdpmc", in("ecx") 0, out("eax") low, out("edx") high);
+
+    let (version, mask) = pmc_info();
+    let mut count = (u64::from(high) << 32 | u64::from(low)) & mask;
+    if version >= 2 && x86::msr::rdmsr(x86::msr::IA32_PERF_GLOBAL_STATUS) & 1 != 0 {
+        count = count.saturating_add(mask).saturating_add(1);
+    }
+    count
+}
+
+/// Stop the first general purpose counter, returning its count
+unsafe fn pmc_stop() -> u64 {
+    let count = pmc_read();
+    x86::msr::wrmsr(x86::msr::IA32_PERFEVTSEL0, 0);
+    count
+}
+
+/// Count the event selected by `evtsel`, from `pmc_event_select`, while `context` runs, or stop
+/// counting if zero. The count starts over from zero. The context must either not be running, or
+/// be the current one.
+pub unsafe fn set_pmc(context: &mut super::Context, evtsel: u64, current: bool) {
+    if current {
+        if context.pmc_evtsel != 0 {
+            pmc_stop();
+        }
+        if evtsel != 0 {
+            pmc_start(evtsel);
+        }
+    }
+    context.pmc_evtsel = evtsel;
+    context.pmc_count = 0;
+}
+
+/// Number of events counted while `context` ran. If it is the current one, the events counted
+/// since it was switched to are included.
+pub fn pmc_count(context: &super::Context, current: bool) -> u64 {
+    if current && context.pmc_evtsel != 0 {
+        context.pmc_count.saturating_add(unsafe { pmc_read() })
+    } else {
+        context.pmc_count
+    }
+}
 
 #[derive(Clone, Debug)]
 #[repr(C)]
@@ -93,6 +262,24 @@ impl Context {
 impl super::Context {
     pub fn get_fx_regs(&self) -> FloatRegisters {
         let mut regs = unsafe { self.kfx.as_ptr().cast::<FloatRegisters>().read() };
+        // XSAVEOPT does not write components that are in their initial state, so the legacy area
+        // can hold whatever an earlier save left there. Such components are read back with their
+        // initial values instead.
+        if KFX_MECHANISM.load(Ordering::Relaxed) != KFX_FXSAVE {
+            let xstate_bv = u64::from(self.kfx[XSTATE_BV_OFFSET]);
+            if xstate_bv & XCR0_X87 == 0 {
+                regs.fcw = FCW_DEFAULT;
+                regs.fsw = 0;
+                regs.ftw = 0;
+                regs.fop = 0;
+                regs.fip = 0;
+                regs.fdp = 0;
+                regs.st_space = [0; 8];
+            }
+            if xstate_bv & XCR0_SSE == 0 {
+                regs.xmm_space = Default::default();
+            }
+        }
         regs._reserved = 0;
         let mut new_st = regs.st_space;
         for st in &mut new_st {
@@ -103,9 +290,17 @@ impl super::Context {
         regs
     }
 
-    pub fn set_fx_regs(&mut self, mut new: FloatRegisters) {
+    /// Set the floating point registers, failing with EINVAL if MXCSR has reserved bits set
+    pub fn set_fx_regs(&mut self, mut new: FloatRegisters) -> Result<()> {
         {
             let old = unsafe { &*(self.kfx.as_ptr().cast::<FloatRegisters>()) };
+            // FXRSTOR and XRSTOR fault on reserved MXCSR bits, which would happen in the kernel
+            // on the next switch to this context
+            let mxcsr_mask = if old.mxcsr_mask == 0 { MXCSR_MASK_DEFAULT } else { old.mxcsr_mask };
+            if new.mxcsr & !mxcsr_mask != 0 {
+                return Err(Error::new(EINVAL));
+            }
+            new.mxcsr_mask = old.mxcsr_mask;
             new._reserved = old._reserved;
             let old_st = new.st_space;
             let mut new_st = new.st_space;
@@ -121,6 +316,12 @@ impl super::Context {
         unsafe {
             self.kfx.as_mut_ptr().cast::<FloatRegisters>().write(new);
         }
+
+        // XRSTOR would otherwise reset the x87 and SSE state instead of loading it
+        if KFX_MECHANISM.load(Ordering::Relaxed) != KFX_FXSAVE {
+            self.kfx[XSTATE_BV_OFFSET] |= (XCR0_X87 | XCR0_SSE) as u8;
+        }
+        Ok(())
     }
 }
 
@@ -134,12 +335,30 @@ pub unsafe fn empty_cr3() -> rmm::PhysicalAddress {
 
 /// Switch to the next context by restoring its stack and registers
 pub unsafe fn switch_to(prev: &mut super::Context, next: &mut super::Context) {
-    core::arch::asm!("
-        fxsave64 [{prev_fx}]
-        fxrstor64 [{next_fx}]
-        ", prev_fx = in(reg) prev.kfx.as_mut_ptr(),
-        next_fx = in(reg) next.kfx.as_ptr(),
-    );
+    match KFX_MECHANISM.load(Ordering::Relaxed) {
+        KFX_XSAVEOPT => core::arch::asm!("
+            xsaveopt64 [{prev_fx}]
+            xrstor64 [{next_fx}]
+            ", prev_fx = in(reg) prev.kfx.as_mut_ptr(),
+            next_fx = in(reg) next.kfx.as_ptr(),
+            in("eax") u32::MAX,
+            in("edx") u32::MAX,
+        ),
+        KFX_XSAVE => core::arch::asm!("
+            xsave64 [{prev_fx}]
+            xrstor64 [{next_fx}]
+            ", prev_fx = in(reg) prev.kfx.as_mut_ptr(),
+            next_fx = in(reg) next.kfx.as_ptr(),
+            in("eax") u32::MAX,
+            in("edx") u32::MAX,
+        ),
+        _ => core::arch::asm!("
+            fxsave64 [{prev_fx}]
+            fxrstor64 [{next_fx}]
+            ", prev_fx = in(reg) prev.kfx.as_mut_ptr(),
+            next_fx = in(reg) next.kfx.as_ptr(),
+        ),
+    }
 
     {
         use x86::{bits64::segmentation::*, msr};
@@ -161,6 +380,13 @@ pub unsafe fn switch_to(prev: &mut super::Context, next: &mut super::Context) {
         }
     }
 
+    if prev.pmc_evtsel != 0 {
+        prev.pmc_count = prev.pmc_count.saturating_add(pmc_stop());
+    }
+    if next.pmc_evtsel != 0 {
+        pmc_start(next.pmc_evtsel);
+    }
+
     match next.addr_space {
         // Since Arc is essentially just wraps a pointer, in this case a regular pointer (as
         // opposed to dyn or slice fat pointers), and NonNull optimization exists, map_or will
@@ -1,6 +1,5 @@
 use alloc::{
     boxed::Box,
-    collections::VecDeque,
     string::{String},
     sync::Arc,
     vec::Vec,
@@ -15,16 +14,23 @@ use spin::RwLock;
 use crate::arch::{interrupt::InterruptStack, paging::PAGE_SIZE};
 use crate::common::unique::Unique;
 use crate::context::arch;
+use crate::context::deadline;
 use crate::context::file::{FileDescriptor, FileDescription};
 use crate::context::memory::AddrSpace;
+use crate::context::rlimit::{Rlimits, RLIMIT_AS, RLIMIT_NOFILE};
+use crate::context::signal::{FaultInfo, PendingSignals};
 use crate::ipi::{ipi, IpiKind, IpiTarget};
 use crate::memory::Enomem;
-use crate::scheme::{SchemeNamespace, FileHandle};
+use crate::scheme::{SchemeId, SchemeNamespace, FileHandle};
 use crate::sync::WaitMap;
 
 use crate::syscall::data::SigAction;
-use crate::syscall::error::{Result, Error, ESRCH};
-use crate::syscall::flag::{SIG_DFL, SigActionFlags};
+use crate::syscall::limit::SyscallLimit;
+use crate::syscall::error::{Result, Error, EBADF, ESRCH};
+use crate::syscall::flag::{SIG_DFL, SIGCONT, SIGKILL, SigActionFlags};
+
+/// Size of the kernel stack of spawned contexts
+pub const KSTACK_SIZE: usize = 65_536;
 
 /// Unique identifier for a context (i.e. `pid`).
 use ::core::sync::atomic::AtomicUsize;
@@ -99,6 +105,24 @@ impl PartialEq for WaitpidKey {
 
 impl Eq for WaitpidKey {}
 
+/// Resource usage of a context, as reported by getrusage
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct Rusage {
+    /// CPU time used, in nanoseconds
+    pub cpu_time: u64,
+    /// Page faults resolved without I/O
+    pub minor_faults: u64,
+    /// Page faults that required I/O. There is no swap, so this is currently always zero.
+    pub major_faults: u64,
+    /// Switches away from the context because it blocked or yielded
+    pub voluntary_switches: u64,
+    /// Switches away from the context because its quantum ran out
+    pub involuntary_switches: u64,
+    /// Peak number of frames owned by the address space
+    pub max_rss: u64,
+}
+
 pub struct ContextSnapshot {
     // Copy fields
     pub id: ContextId,
@@ -137,6 +161,7 @@ impl ContextSnapshot {
                     scheme: description.scheme,
                     number: description.number,
                     flags: description.flags,
+                    lock: description.lock,
                 })
             } else {
                 None
@@ -189,43 +214,91 @@ pub struct Context {
     pub egid: u32,
     /// The effective namespace id
     pub ens: SchemeNamespace,
+    /// Context was placed in its namespace by its parent, and may only leave it for the null
+    /// namespace. Inherited by its children.
+    pub sandboxed: bool,
     /// Signal mask
     pub sigmask: [u64; 2],
+    /// Controlling terminal, as the scheme and file number of the handle it was set from
+    pub ctty: Option<(SchemeId, usize)>,
     /// Process umask
     pub umask: usize,
     /// Status of context
     pub status: Status,
     pub status_reason: &'static str,
+    /// Token of the condition the context is blocked on, if blocked with `block_on`
+    pub wait_token: Option<usize>,
     /// Context running or not
     pub running: bool,
     /// CPU ID, if locked
     pub cpu_id: Option<usize>,
+    /// CPUs the context may be moved to, bit `n` for CPU `n`. CPUs from 64 up are only allowed if
+    /// every bit is set. Inherited by children.
+    pub affinity: u64,
+    /// Monotonic time the context was last stolen by an idle CPU, see `switch::STEAL_COOLDOWN`
+    pub stolen_at: Option<u128>,
     /// Time this context was switched to
     pub switch_time: u128,
     /// Amount of CPU time used
     pub cpu_time: u128,
+    /// Nice value, from `PRIO_MIN` (most favorable) to `PRIO_MAX`, scaling the quantum the context
+    /// receives when switched to
+    pub priority: i32,
+    /// Low latency contexts are preferred by the scheduler, and preempt the running context as
+    /// soon as they become runnable. The scheduler still gives other contexts a turn after
+    /// `LOW_LATENCY_BURST` consecutive low latency switches, so they cannot starve them.
+    pub low_latency: bool,
+    /// Page fault and context switch counts
+    pub rusage: Rusage,
     /// Current system call
     pub syscall: Option<(usize, usize, usize, usize, usize, usize)>,
+    /// Number of system calls issued
+    pub syscall_count: u64,
+    /// Rate limit of system calls, inherited by children
+    pub syscall_limit: Option<SyscallLimit>,
+    /// Soft and hard resource limits, inherited by children
+    pub rlimits: Rlimits,
     /// Head buffer to use when system call buffers are not page aligned
     pub syscall_head: AlignedBox<[u8; PAGE_SIZE], PAGE_SIZE>,
     /// Tail buffer to use when system call buffers are not page aligned
     pub syscall_tail: AlignedBox<[u8; PAGE_SIZE], PAGE_SIZE>,
     /// Context is halting parent
     pub vfork: bool,
-    /// Context is being waited on
-    pub waitpid: Arc<WaitMap<WaitpidKey, (ContextId, usize)>>,
+    /// Context is halted until its vfork child execs or exits. Signals are only queued meanwhile,
+    /// as handling them would run on the address space the child is using.
+    pub vfork_wait: bool,
+    /// Context is being forcibly killed. Every wait in the kernel gives up instead of blocking, so
+    /// that the context gets back to the end of its syscall, where it exits.
+    pub force_kill: bool,
+    /// Context is being waited on. Each event carries the status, and the resource usage of the
+    /// child at the time of the event.
+    pub waitpid: Arc<WaitMap<WaitpidKey, (ContextId, usize, Rusage)>>,
     /// Context should handle pending signals
-    pub pending: VecDeque<u8>,
-    /// Context should wake up at specified time
-    pub wake: Option<u128>,
+    pub pending: PendingSignals,
+    /// Context should wake up at specified time, only changed through `set_wake`
+    wake: Option<u128>,
+    /// The last memory or arithmetic fault, until it is reported to a SIGSEGV, SIGBUS or SIGFPE handler
+    pub fault: Option<FaultInfo>,
+    /// The value queued with the real-time signal being delivered, if it was sent by `sigqueue`
+    pub sigval: Option<usize>,
     /// The architecture specific context
     pub arch: arch::Context,
-    /// Kernel FX - used to store SIMD and FPU registers on context switch
-    pub kfx: AlignedBox<[u8; arch::KFX_SIZE], {arch::KFX_ALIGN}>,
+    /// Event select value of the performance counter counting while this context runs, or zero.
+    /// This is kept out of `arch`, which is restored after signal handlers.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub pmc_evtsel: u64,
+    /// Events counted by the performance counter, up to the last switch from this context
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub pmc_count: u64,
+    /// Kernel FX - used to store SIMD and FPU registers on context switch, sized for the save
+    /// mechanism selected at boot
+    pub kfx: AlignedBytes<{arch::KFX_ALIGN}>,
     /// Kernel stack
     pub kstack: Option<Box<[u8]>>,
+    /// Entry function of a spawned context whose kernel stack has not been allocated yet
+    pub kstack_entry: Option<extern fn()>,
     /// Kernel signal backup: Registers, Kernel FX, Kernel Stack, Signal number
-    pub ksig: Option<(arch::Context, AlignedBox<[u8; arch::KFX_SIZE], {arch::KFX_ALIGN}>, Option<Box<[u8]>>, u8)>,
+    pub ksig: Option<(arch::Context, AlignedBytes<{arch::KFX_ALIGN}>, Option<Box<[u8]>>, u8)>,
     /// Restore ksig context on next switch
     pub ksig_restore: bool,
     /// Address space containing a page table lock, and grants. Normally this will have a value,
@@ -244,9 +317,10 @@ pub struct Context {
     /// kstack address at the time of creation is the first element in
     /// this tuple.
     pub regs: Option<(usize, Unique<InterruptStack>)>,
-    /// A somewhat hacky way to initially stop a context when creating
-    /// a new instance of the proc: scheme, entirely separate from
-    /// signals or any other way to restart a process.
+    /// Keeps the context from being scheduled until `start` is called, entirely separate from
+    /// signals or any other way to stop a process. This is set for contexts created with
+    /// `ContextList::spawn_stopped`, and by the proc: scheme so that a tracer can set up a new
+    /// context before it runs. Signals sent in the meantime stay queued until it is started.
     pub ptrace_stop: bool,
     /// A pointer to the signal stack. If this is unset, none of the sigactions can be anything
     /// else than SIG_DFL, otherwise signals will not be delivered. Userspace is responsible for
@@ -329,6 +403,63 @@ impl<T: Clone + ValidForZero, const ALIGN: usize> Clone for AlignedBox<T, ALIGN>
     }
 }
 
+/// Like `AlignedBox<[u8; N], ALIGN>`, but for buffers whose size is only known at runtime
+pub struct AlignedBytes<const ALIGN: usize> {
+    inner: Unique<u8>,
+    len: usize,
+}
+
+impl<const ALIGN: usize> AlignedBytes<ALIGN> {
+    fn layout(len: usize) -> core::alloc::Layout {
+        core::alloc::Layout::from_size_align(len, ALIGN).expect("invalid layout for aligned bytes")
+    }
+    pub fn try_zeroed(len: usize) -> Result<Self, Enomem> {
+        assert_ne!(len, 0);
+        Ok(unsafe {
+            let ptr = crate::ALLOCATOR.alloc_zeroed(Self::layout(len));
+            if ptr.is_null() {
+                return Err(Enomem);
+            }
+            Self {
+                inner: Unique::new_unchecked(ptr),
+                len,
+            }
+        })
+    }
+}
+
+impl<const ALIGN: usize> core::fmt::Debug for AlignedBytes<ALIGN> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "[aligned bytes at {:p}, size {} alignment {}]", self.inner.as_ptr(), self.len, ALIGN)
+    }
+}
+impl<const ALIGN: usize> Drop for AlignedBytes<ALIGN> {
+    fn drop(&mut self) {
+        unsafe {
+            crate::ALLOCATOR.dealloc(self.inner.as_ptr(), Self::layout(self.len));
+        }
+    }
+}
+impl<const ALIGN: usize> core::ops::Deref for AlignedBytes<ALIGN> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { core::slice::from_raw_parts(self.inner.as_ptr(), self.len) }
+    }
+}
+impl<const ALIGN: usize> core::ops::DerefMut for AlignedBytes<ALIGN> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { core::slice::from_raw_parts_mut(self.inner.as_ptr(), self.len) }
+    }
+}
+impl<const ALIGN: usize> Clone for AlignedBytes<ALIGN> {
+    fn clone(&self) -> Self {
+        let mut new = Self::try_zeroed(self.len).unwrap_or_else(|_| alloc::alloc::handle_alloc_error(Self::layout(self.len)));
+        new.copy_from_slice(self);
+        new
+    }
+}
+
 impl Context {
     pub fn new(id: ContextId) -> Result<Context> {
         let syscall_head = AlignedBox::try_zeroed()?;
@@ -344,24 +475,44 @@ impl Context {
             euid: 0,
             egid: 0,
             ens: SchemeNamespace::from(0),
+            sandboxed: false,
             sigmask: [0; 2],
+            ctty: None,
             umask: 0o022,
             status: Status::Blocked,
             status_reason: "",
+            wait_token: None,
             running: false,
             cpu_id: None,
+            affinity: u64::MAX,
+            stolen_at: None,
             switch_time: 0,
             cpu_time: 0,
+            priority: 0,
+            low_latency: false,
+            rusage: Rusage::default(),
             syscall: None,
+            syscall_count: 0,
+            syscall_limit: None,
+            rlimits: Rlimits::default(),
             syscall_head,
             syscall_tail,
             vfork: false,
+            vfork_wait: false,
+            force_kill: false,
             waitpid: Arc::new(WaitMap::new()),
-            pending: VecDeque::new(),
+            pending: PendingSignals::new(),
             wake: None,
+            fault: None,
+            sigval: None,
             arch: arch::Context::new(),
-            kfx: AlignedBox::<[u8; arch::KFX_SIZE], {arch::KFX_ALIGN}>::try_zeroed()?,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            pmc_evtsel: 0,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            pmc_count: 0,
+            kfx: AlignedBytes::<{arch::KFX_ALIGN}>::try_zeroed(arch::kfx_size())?,
             kstack: None,
+            kstack_entry: None,
             ksig: None,
             ksig_restore: false,
             addr_space: None,
@@ -376,27 +527,154 @@ impl Context {
         Ok(this)
     }
 
+    /// Allocate the kernel stack of a spawned context and point it at its entry function. This is
+    /// deferred until the context is first ready to run, so that contexts which never run, for
+    /// example because they are killed right away, never use the memory.
+    pub fn alloc_kstack(&mut self) -> Result<(), Enomem> {
+        let func = match self.kstack_entry {
+            Some(func) => func,
+            None => return Ok(()),
+        };
+
+        let mut stack = Vec::new();
+        stack.try_reserve_exact(KSTACK_SIZE).map_err(|_| Enomem)?;
+        stack.resize(KSTACK_SIZE, 0_u8);
+        let mut stack = stack.into_boxed_slice();
+        let offset = stack.len() - mem::size_of::<usize>();
+
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        unsafe {
+            let func_ptr = stack.as_mut_ptr().add(offset);
+            *(func_ptr as *mut usize) = func as usize;
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        {
+            self.arch.set_lr(func as usize);
+            self.arch.set_context_handle();
+        }
+
+        self.arch.set_stack(stack.as_ptr() as usize + offset);
+        self.kstack = Some(stack);
+        self.kstack_entry = None;
+        Ok(())
+    }
+
     /// Block the context, and return true if it was runnable before being blocked
     pub fn block(&mut self, reason: &'static str) -> bool {
         if self.status == Status::Runnable {
             self.status = Status::Blocked;
             self.status_reason = reason;
+            self.wait_token = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Resource usage of the context so far
+    pub fn rusage(&self) -> Rusage {
+        Rusage {
+            cpu_time: self.cpu_time as u64,
+            max_rss: self.addr_space.as_ref().map_or(0, |addr_space| addr_space.read().peak_resident_frames as u64),
+            ..self.rusage
+        }
+    }
+
+    /// Whether the affinity of the context allows it to run on the CPU `cpu_id`
+    pub fn allows_cpu(&self, cpu_id: usize) -> bool {
+        if cpu_id < 64 {
+            self.affinity & 1 << cpu_id != 0
+        } else {
+            self.affinity == u64::MAX
+        }
+    }
+
+    /// Monotonic time at which the scheduler unblocks the context, if it is blocked by then
+    pub fn wake(&self) -> Option<u128> {
+        self.wake
+    }
+
+    /// Set or clear the time at which the scheduler unblocks the context, registering it with the
+    /// scheduler so that it does not have to check every context
+    pub fn set_wake(&mut self, wake: Option<u128>) {
+        if let Some(old) = self.wake {
+            deadline::remove(old, self.id);
+        }
+        if let Some(new) = wake {
+            deadline::insert(new, self.id);
+        }
+        self.wake = wake;
+    }
+
+    /// Clear the time at which the scheduler unblocks the context once it has passed. This is
+    /// called by the scheduler, which removes the deadline itself, without blocking on the lock
+    /// that `set_wake` takes.
+    pub fn expire_wake(&mut self) {
+        self.wake = None;
+    }
+
+    /// Block the context on the condition identified by `token`, so that `unblock_if` with the
+    /// same token wakes it. Returns true if it was runnable before being blocked.
+    pub fn block_on(&mut self, reason: &'static str, token: usize) -> bool {
+        if self.block(reason) {
+            self.wait_token = Some(token);
             true
         } else {
             false
         }
     }
 
+    /// Unblock the context only if it is still blocked on the condition identified by `token`.
+    /// Once it has been woken by anything else, such as a signal or a timeout, the token no longer
+    /// matches, so late wakeups meant for a previous wait are ignored. Returns true if the context
+    /// was unblocked.
+    pub fn unblock_if(&mut self, token: usize) -> bool {
+        if self.wait_token == Some(token) {
+            self.unblock()
+        } else {
+            false
+        }
+    }
+
+    /// Queue the signal `sig`, with `value` if it is a real-time signal, resuming the context if it
+    /// is stopped and `sig` is SIGCONT. A context that never ran has no kernel stack to handle
+    /// SIGKILL on, so it is kept from running instead, and true is returned for the caller to exit
+    /// it with `exit_context` once it no longer holds any context lock.
+    pub fn send_signal(&mut self, sig: u8, value: Option<usize>) -> Result<bool> {
+        self.pending.queue(sig, value)?;
+        // Convert stopped processes to blocked if sending SIGCONT
+        if usize::from(sig) == SIGCONT {
+            if let Status::Stopped(_sig) = self.status {
+                self.status = Status::Blocked;
+            }
+        }
+        if usize::from(sig) == SIGKILL && self.kstack_entry.take().is_some() {
+            self.status = Status::Blocked;
+            self.status_reason = "kill";
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
     /// Unblock context, and return true if it was blocked before being marked runnable
     pub fn unblock(&mut self) -> bool {
         if self.status == Status::Blocked {
             self.status = Status::Runnable;
             self.status_reason = "";
+            self.wait_token = None;
 
             if let Some(cpu_id) = self.cpu_id {
                if cpu_id != crate::cpu_id() {
-                    // Send IPI if not on current CPU
-                    ipi(IpiKind::Wakeup, IpiTarget::Other);
+                    // Send IPI if not on current CPU, making it reschedule right away if this
+                    // context must not wait for the running one to use up its quantum
+                    if self.low_latency {
+                        ipi(IpiKind::Switch, IpiTarget::Other);
+                    } else {
+                        ipi(IpiKind::Wakeup, IpiTarget::Other);
+                    }
+               } else if self.low_latency {
+                    super::switch::request_preempt();
                }
             }
 
@@ -406,24 +684,50 @@ impl Context {
         }
     }
 
+    /// Let a context that was kept stopped by `ptrace_stop` be scheduled again, returning whether
+    /// it was stopped. Any signals queued while it was stopped are delivered once it runs.
+    pub fn start(&mut self) -> bool {
+        if !self.ptrace_stop {
+            return false;
+        }
+        self.ptrace_stop = false;
+
+        if self.status == Status::Runnable {
+            if let Some(cpu_id) = self.cpu_id {
+                if cpu_id != crate::cpu_id() {
+                    // Send IPI if not on current CPU
+                    ipi(IpiKind::Wakeup, IpiTarget::Other);
+                }
+            }
+        }
+
+        true
+    }
+
     /// Add a file to the lowest available slot.
     /// Return the file descriptor number or None if no slot was found
     pub fn add_file(&self, file: FileDescriptor) -> Option<FileHandle> {
         self.add_file_min(file, 0)
     }
 
+    /// Number of file descriptors the context may use, from `RLIMIT_NOFILE`
+    pub fn max_files(&self) -> usize {
+        core::cmp::min(self.rlimits.soft(RLIMIT_NOFILE), super::CONTEXT_MAX_FILES)
+    }
+
     /// Add a file to the lowest available slot greater than or equal to min.
     /// Return the file descriptor number or None if no slot was found
     pub fn add_file_min(&self, file: FileDescriptor, min: usize) -> Option<FileHandle> {
+        let max = self.max_files();
         let mut files = self.files.write();
-        for (i, file_option) in files.iter_mut().enumerate() {
+        for (i, file_option) in files.iter_mut().enumerate().take(max) {
             if file_option.is_none() && i >= min {
                 *file_option = Some(file);
                 return Some(FileHandle::from(i));
             }
         }
         let len = files.len();
-        if len < super::CONTEXT_MAX_FILES {
+        if len < max {
             if len >= min {
                 files.push(Some(file));
                 Some(FileHandle::from(len))
@@ -446,11 +750,11 @@ impl Context {
         }
     }
 
-    /// Insert a file with a specific handle number. This is used by dup2
+    /// Insert a file with a specific handle number
     /// Return the file descriptor number or None if the slot was not empty, or i was invalid
     pub fn insert_file(&self, i: FileHandle, file: FileDescriptor) -> Option<FileHandle> {
         let mut files = self.files.write();
-        if i.into() < super::CONTEXT_MAX_FILES {
+        if i.into() < self.max_files() {
             while i.into() >= files.len() {
                 files.push(None);
             }
@@ -465,6 +769,22 @@ impl Context {
         }
     }
 
+    /// Install a file with a specific handle number, whether or not the slot is empty. This is
+    /// used by dup2, which must not let the number be reused between closing and replacing it.
+    /// Return the file previously in the slot, which the caller has to close, or EBADF if i was
+    /// invalid
+    pub fn replace_file(&self, i: FileHandle, file: FileDescriptor) -> Result<Option<FileDescriptor>> {
+        let mut files = self.files.write();
+        if i.into() < self.max_files() {
+            while i.into() >= files.len() {
+                files.push(None);
+            }
+            Ok(files[i.into()].replace(file))
+        } else {
+            Err(Error::new(EBADF))
+        }
+    }
+
     /// Remove a file
     // TODO: adjust files vector to smaller size if possible
     pub fn remove_file(&self, i: FileHandle) -> Option<FileDescriptor> {
@@ -476,6 +796,19 @@ impl Context {
         }
     }
 
+    /// Exchange the files at two handle numbers under a single lock, so that nobody sees one
+    /// handle changed but not the other. Both must be open, otherwise nothing is changed and
+    /// EBADF is returned.
+    pub fn swap_files(&self, a: FileHandle, b: FileHandle) -> Result<()> {
+        let mut files = self.files.write();
+        let is_open = |i: FileHandle| matches!(files.get(i.into()), Some(Some(_)));
+        if ! is_open(a) || ! is_open(b) {
+            return Err(Error::new(EBADF));
+        }
+        files.swap(a.into(), b.into());
+        Ok(())
+    }
+
     pub fn addr_space(&self) -> Result<&Arc<RwLock<AddrSpace>>> {
         self.addr_space.as_ref().ok_or(Error::new(ESRCH))
     }
@@ -484,6 +817,11 @@ impl Context {
         if self.id == super::context_id() {
             unsafe { addr_space.read().table.utable.make_current(); }
         }
+        {
+            // Keep a lower limit that was set on the address space through proc: before switching
+            let mut addr_space = addr_space.write();
+            addr_space.rlimit_as = core::cmp::min(addr_space.rlimit_as, self.rlimits.soft(RLIMIT_AS));
+        }
 
         self.addr_space.replace(addr_space)
     }
@@ -0,0 +1,60 @@
+//! Deadlines at which blocked contexts are woken, kept in order so that the scheduler can find
+//! the expired ones without checking every context. Entries are added and removed by
+//! `Context::set_wake`, and removed by the scheduler once it has handled them.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::context::ContextId;
+
+/// Most expired deadlines handled by one switch. Any others are handled by the next.
+pub const EXPIRED_MAX: usize = 32;
+
+/// Pending deadlines, as monotonic time and the context to wake, latest first so that the expired
+/// ones are at the end. The scheduler may run from the timer interrupt, where it must neither
+/// allocate nor free, so it only ever removes entries, which keeps the capacity.
+static DEADLINES: Mutex<Vec<(u128, ContextId)>> = Mutex::new(Vec::new());
+
+fn search(deadlines: &[(u128, ContextId)], entry: (u128, ContextId)) -> Result<usize, usize> {
+    deadlines.binary_search_by(|probe| entry.cmp(probe))
+}
+
+pub fn insert(deadline: u128, id: ContextId) {
+    let mut deadlines = DEADLINES.lock();
+    if let Err(index) = search(&deadlines, (deadline, id)) {
+        deadlines.insert(index, (deadline, id));
+    }
+}
+
+pub fn remove(deadline: u128, id: ContextId) {
+    let mut deadlines = DEADLINES.lock();
+    if let Ok(index) = search(&deadlines, (deadline, id)) {
+        deadlines.remove(index);
+    }
+}
+
+/// The earliest deadlines up to monotonic time `now`, at most `EXPIRED_MAX` of them. They stay
+/// pending until the scheduler has handled them and removes them with `remove_handled`. The
+/// scheduler may run from the timer interrupt while the interrupted code holds the lock, in which
+/// case nothing is returned, and the deadlines are returned by a later call instead.
+pub fn expired(now: u128) -> [Option<(u128, ContextId)>; EXPIRED_MAX] {
+    let mut expired = [None; EXPIRED_MAX];
+    if let Some(deadlines) = DEADLINES.try_lock() {
+        let entries = deadlines.iter().rev().take_while(|&&(deadline, _)| deadline <= now);
+        for (slot, &entry) in expired.iter_mut().zip(entries) {
+            *slot = Some(entry);
+        }
+    }
+    expired
+}
+
+/// Remove a deadline returned by `expired` once the scheduler has handled it. This does not wait
+/// for the lock either: if it is held, the deadline is returned again by a later call to
+/// `expired`, and handled again, which finds it stale.
+pub fn remove_handled(deadline: u128, id: ContextId) {
+    if let Some(mut deadlines) = DEADLINES.try_lock() {
+        if let Ok(index) = search(&deadlines, (deadline, id)) {
+            deadlines.remove(index);
+        }
+    }
+}
@@ -1,6 +1,7 @@
 //! File structs
 
 use alloc::sync::Arc;
+use crate::context::flock::{self, HeldLock};
 use crate::event;
 use spin::RwLock;
 use crate::scheme::{self, SchemeNamespace, SchemeId};
@@ -17,6 +18,8 @@ pub struct FileDescription {
     pub number: usize,
     /// The flags passed to open or fcntl(SETFL)
     pub flags: usize,
+    /// The advisory lock held through this description
+    pub lock: Option<HeldLock>,
 }
 
 /// A file descriptor
@@ -31,9 +34,14 @@ pub struct FileDescriptor {
 
 impl FileDescriptor {
     pub fn close(self) -> Result<usize> {
+        let owner = Arc::as_ptr(&self.description) as usize;
         if let Ok(file) = Arc::try_unwrap(self.description) {
             let file = file.into_inner();
 
+            if let Some(held) = file.lock {
+                flock::unlock(owner, held);
+            }
+
             event::unregister_file(file.scheme, file.number);
 
             let scheme = {
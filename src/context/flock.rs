@@ -0,0 +1,170 @@
+//! Advisory file locks
+//!
+//! Locks are owned by file descriptions, so they are shared by every descriptor duplicated from
+//! the same open, and released once the last of those is closed. Schemes do not take part, which
+//! is why a file is identified by its scheme and the path the scheme reports for it: descriptions
+//! opened separately on the same file then exclude each other.
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::context::{self, ContextId};
+use crate::scheme::SchemeId;
+use crate::sync::WaitCondition;
+use crate::syscall::error::{Error, Result, EAGAIN, EDEADLK, EINTR};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LockKind {
+    Shared,
+    Exclusive,
+}
+
+/// A lock held by a file description
+#[derive(Clone, Copy, Debug)]
+pub struct HeldLock {
+    pub kind: LockKind,
+    /// Identifier of the locked file in `LOCKS`
+    file: usize,
+}
+
+impl HeldLock {
+    /// Whether both locks are on the same file
+    pub fn same_file(&self, other: &HeldLock) -> bool {
+        self.file == other.file
+    }
+}
+
+/// The scheme and the path of a file
+pub type LockKey = (SchemeId, Box<[u8]>);
+
+struct LockedFile {
+    key: LockKey,
+    /// Lock held by each owning description, with the context that acquired it
+    holders: BTreeMap<usize, (LockKind, ContextId)>,
+}
+
+impl LockedFile {
+    /// Contexts holding a lock on this file that conflicts with `kind` requested by `owner`
+    fn conflicts(&self, owner: usize, kind: LockKind) -> impl Iterator<Item = ContextId> + '_ {
+        self.holders.iter()
+            .filter(move |&(&holder, &(held_kind, _))| {
+                holder != owner && (kind == LockKind::Exclusive || held_kind == LockKind::Exclusive)
+            })
+            .map(|(_, &(_, context_id))| context_id)
+    }
+}
+
+struct Locks {
+    next_file: usize,
+    files: BTreeMap<usize, LockedFile>,
+    keys: BTreeMap<LockKey, usize>,
+    /// The file, owner and kind that each context is blocked on in `lock`
+    waiting: BTreeMap<ContextId, (usize, usize, LockKind)>,
+}
+
+impl Locks {
+    fn file_id(&mut self, key: LockKey) -> usize {
+        if let Some(&file) = self.keys.get(&key) {
+            return file;
+        }
+
+        let file = self.next_file;
+        self.next_file += 1;
+        self.keys.insert(key.clone(), file);
+        self.files.insert(file, LockedFile { key, holders: BTreeMap::new() });
+        file
+    }
+
+    fn remove_if_unused(&mut self, file: usize) {
+        if self.files.get(&file).map_or(false, |locked| locked.holders.is_empty()) {
+            if let Some(locked) = self.files.remove(&file) {
+                self.keys.remove(&locked.key);
+            }
+        }
+    }
+
+    /// Whether blocking `context_id` on `file` would close a cycle of contexts waiting on each
+    /// other's locks
+    fn would_deadlock(&self, context_id: ContextId, file: usize, owner: usize, kind: LockKind) -> bool {
+        let mut pending: Vec<ContextId> = match self.files.get(&file) {
+            Some(locked) => locked.conflicts(owner, kind).collect(),
+            None => return false,
+        };
+        let mut visited = BTreeSet::new();
+
+        while let Some(holder) = pending.pop() {
+            if holder == context_id {
+                return true;
+            }
+            if ! visited.insert(holder) {
+                continue;
+            }
+            if let Some(&(file, owner, kind)) = self.waiting.get(&holder) {
+                if let Some(locked) = self.files.get(&file) {
+                    pending.extend(locked.conflicts(owner, kind));
+                }
+            }
+        }
+
+        false
+    }
+}
+
+static LOCKS: Mutex<Locks> = Mutex::new(Locks {
+    next_file: 0,
+    files: BTreeMap::new(),
+    keys: BTreeMap::new(),
+    waiting: BTreeMap::new(),
+});
+
+static LOCK_CONDITION: WaitCondition = WaitCondition::new();
+
+/// Lock the file `key` for the description `owner`, replacing the lock it already holds on that
+/// file. If the lock is not available, EAGAIN is returned unless `wait` is set, in which case
+/// this blocks until it is, or fails with EDEADLK if the lock holders are waiting on the caller.
+pub fn lock(owner: usize, key: LockKey, kind: LockKind, wait: bool) -> Result<HeldLock> {
+    let context_id = context::context_id();
+
+    loop {
+        let mut locks = LOCKS.lock();
+        let file = locks.file_id(key.clone());
+
+        let available = locks.files.get(&file).map_or(true, |locked| locked.conflicts(owner, kind).next().is_none());
+        if available {
+            locks.waiting.remove(&context_id);
+            if let Some(locked) = locks.files.get_mut(&file) {
+                locked.holders.insert(owner, (kind, context_id));
+            }
+            return Ok(HeldLock { kind, file });
+        }
+
+        if ! wait {
+            return Err(Error::new(EAGAIN));
+        }
+        if locks.would_deadlock(context_id, file, owner, kind) {
+            locks.waiting.remove(&context_id);
+            return Err(Error::new(EDEADLK));
+        }
+
+        locks.waiting.insert(context_id, (file, owner, kind));
+        if ! LOCK_CONDITION.wait(locks, "flock") {
+            LOCKS.lock().waiting.remove(&context_id);
+            return Err(Error::new(EINTR));
+        }
+    }
+}
+
+/// Release a lock held by the description `owner`
+pub fn unlock(owner: usize, held: HeldLock) {
+    {
+        let mut locks = LOCKS.lock();
+        if let Some(locked) = locks.files.get_mut(&held.file) {
+            locked.holders.remove(&owner);
+        }
+        locks.remove_if_unused(held.file);
+    }
+
+    LOCK_CONDITION.notify();
+}
@@ -1,6 +1,6 @@
 use alloc::sync::Arc;
 use alloc::collections::BTreeMap;
-use core::{iter, mem};
+use core::iter;
 use core::sync::atomic::Ordering;
 
 use spin::RwLock;
@@ -79,29 +79,20 @@ impl ContextList {
             let mut context = context_lock.write();
             let _ = context.set_addr_space(super::memory::new_addrspace()?);
 
-            let mut stack = vec![0; 65_536].into_boxed_slice();
-            let offset = stack.len() - mem::size_of::<usize>();
-
-            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-            unsafe {
-                let offset = stack.len() - mem::size_of::<usize>();
-                let func_ptr = stack.as_mut_ptr().add(offset);
-                *(func_ptr as *mut usize) = func as usize;
-            }
-
-            #[cfg(target_arch = "aarch64")]
-            {
-                let context_id = context.id.into();
-                context.arch.set_lr(func as usize);
-                context.arch.set_context_handle();
-            }
-
-            context.arch.set_stack(stack.as_ptr() as usize + offset);
-            context.kstack = Some(stack);
+            // The kernel stack is only allocated once the context is first ready to run
+            context.kstack_entry = Some(func);
         }
         Ok(context_lock)
     }
 
+    /// Spawn a context like `spawn`, but which is not scheduled until `Context::start` is called,
+    /// so that its memory, files and registers can be set up before it first runs
+    pub fn spawn_stopped(&mut self, func: extern fn()) -> Result<&Arc<RwLock<Context>>> {
+        let context_lock = self.spawn(func)?;
+        context_lock.write().ptrace_stop = true;
+        Ok(context_lock)
+    }
+
     pub fn remove(&mut self, id: ContextId) -> Option<Arc<RwLock<Context>>> {
         self.map.remove(&id)
     }
@@ -0,0 +1,190 @@
+//! Detection of long waits on the contexts lock, enabled by the `lock_debug` feature. The CPU
+//! holding the write lock, and the CPUs holding read locks, are tracked along with when and where
+//! they took the lock. A CPU that waits on the lock for longer than `WAIT_THRESHOLD` reports this,
+//! which usually points right at the deadlock.
+
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use spin::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use super::ContextList;
+
+/// How long a CPU may wait for the lock before the wait is reported, in nanoseconds
+const WAIT_THRESHOLD: u64 = 100_000_000;
+
+/// Number of CPUs whose read locks are tracked
+// TODO: Track all CPUs
+const TRACKED_CPUS: usize = 64;
+
+const NO_CPU: usize = usize::MAX;
+
+/// A CPU holding the lock
+struct Holder {
+    /// Number of guards held, or the CPU holding the write lock
+    value: AtomicUsize,
+    /// Time the lock was taken, in nanoseconds
+    since: AtomicU64,
+    /// Where the lock was taken
+    site: AtomicPtr<Location<'static>>,
+}
+
+impl Holder {
+    const fn new(value: usize) -> Self {
+        Holder {
+            value: AtomicUsize::new(value),
+            since: AtomicU64::new(0),
+            site: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    fn taken(&self, site: &'static Location<'static>) {
+        self.since.store(now(), Ordering::Relaxed);
+        self.site.store(site as *const _ as *mut _, Ordering::Relaxed);
+    }
+
+    fn held_for(&self) -> u64 {
+        now().saturating_sub(self.since.load(Ordering::Relaxed))
+    }
+
+    fn site(&self) -> &'static Location<'static> {
+        let site = self.site.load(Ordering::Relaxed);
+        unsafe { site.as_ref() }.unwrap_or_else(|| Location::caller())
+    }
+}
+
+static WRITER: Holder = Holder::new(NO_CPU);
+
+const READER: Holder = Holder::new(0);
+static READERS: [Holder; TRACKED_CPUS] = [READER; TRACKED_CPUS];
+
+fn now() -> u64 {
+    crate::time::monotonic() as u64
+}
+
+pub struct ContextsReadGuard {
+    guard: RwLockReadGuard<'static, ContextList>,
+    cpu: usize,
+}
+
+impl Deref for ContextsReadGuard {
+    type Target = ContextList;
+
+    fn deref(&self) -> &ContextList {
+        &self.guard
+    }
+}
+
+impl Drop for ContextsReadGuard {
+    fn drop(&mut self) {
+        if let Some(reader) = READERS.get(self.cpu) {
+            reader.value.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+pub struct ContextsWriteGuard {
+    guard: RwLockWriteGuard<'static, ContextList>,
+}
+
+impl Deref for ContextsWriteGuard {
+    type Target = ContextList;
+
+    fn deref(&self) -> &ContextList {
+        &self.guard
+    }
+}
+
+impl DerefMut for ContextsWriteGuard {
+    fn deref_mut(&mut self) -> &mut ContextList {
+        &mut self.guard
+    }
+}
+
+impl Drop for ContextsWriteGuard {
+    fn drop(&mut self) {
+        WRITER.value.store(NO_CPU, Ordering::Relaxed);
+    }
+}
+
+#[track_caller]
+pub fn read(lock: &'static RwLock<ContextList>) -> ContextsReadGuard {
+    let site = Location::caller();
+    let guard = wait(site, "read", || lock.try_read());
+
+    let cpu = crate::cpu_id();
+    if let Some(reader) = READERS.get(cpu) {
+        if reader.value.fetch_add(1, Ordering::Relaxed) == 0 {
+            reader.taken(site);
+        }
+    }
+
+    ContextsReadGuard { guard, cpu }
+}
+
+#[track_caller]
+pub fn write(lock: &'static RwLock<ContextList>) -> ContextsWriteGuard {
+    let site = Location::caller();
+    let guard = wait(site, "write", || lock.try_write());
+
+    WRITER.taken(site);
+    WRITER.value.store(crate::cpu_id(), Ordering::Relaxed);
+
+    ContextsWriteGuard { guard }
+}
+
+/// Spin until `try_lock` succeeds, reporting the wait and the holders of the lock if it takes
+/// longer than `WAIT_THRESHOLD`
+fn wait<G>(site: &'static Location<'static>, kind: &str, mut try_lock: impl FnMut() -> Option<G>) -> G {
+    if let Some(guard) = try_lock() {
+        return guard;
+    }
+
+    let start = now();
+    let mut reported = false;
+    loop {
+        if let Some(guard) = try_lock() {
+            if reported {
+                println!(
+                    "CPU {} took contexts {} lock at {} after {} ms",
+                    crate::cpu_id(), kind, site, (now() - start) / 1_000_000
+                );
+            }
+            return guard;
+        }
+
+        let waited = now().saturating_sub(start);
+        if !reported && waited >= WAIT_THRESHOLD {
+            reported = true;
+            report(site, kind, waited);
+        }
+
+        core::hint::spin_loop();
+    }
+}
+
+fn report(site: &'static Location<'static>, kind: &str, waited: u64) {
+    println!(
+        "CPU {} waiting {} ms for contexts {} lock at {}",
+        crate::cpu_id(), waited / 1_000_000, kind, site
+    );
+
+    let writer = WRITER.value.load(Ordering::Relaxed);
+    if writer != NO_CPU {
+        println!(
+            "  write lock held by CPU {} for {} ms, taken at {}",
+            writer, WRITER.held_for() / 1_000_000, WRITER.site()
+        );
+    }
+
+    for (cpu, reader) in READERS.iter().enumerate() {
+        let count = reader.value.load(Ordering::Relaxed);
+        if count > 0 {
+            println!(
+                "  {} read lock(s) held by CPU {} for {} ms, first taken at {}",
+                count, cpu, reader.held_for() / 1_000_000, reader.site()
+            );
+        }
+    }
+}
@@ -3,7 +3,9 @@ use alloc::{sync::Arc, vec::Vec};
 use core::borrow::Borrow;
 use core::cmp::{self, Eq, Ordering, PartialEq, PartialOrd};
 use core::fmt::{self, Debug};
+use core::mem;
 use core::ops::Deref;
+use core::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
 use spin::{RwLock, RwLockWriteGuard};
 use syscall::{
     flag::MapFlags,
@@ -13,12 +15,46 @@ use rmm::Arch as _;
 
 use crate::arch::paging::PAGE_SIZE;
 use crate::context::file::FileDescriptor;
-use crate::memory::{Enomem, Frame};
-use crate::paging::mapper::{Flusher, InactiveFlusher, PageFlushAll};
-use crate::paging::{KernelMapper, Page, PageFlags, PageIter, PageMapper, RmmA, round_up_pages, TableKind, VirtualAddress};
+use crate::memory::{numa, Enomem, Frame, FrameHint};
+use crate::paging::mapper::{BatchFlusher, Flusher, InactiveFlusher, PageFlushAll};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::paging::entry::EntryFlags;
+use crate::paging::huge::{self, HUGE_PAGE_PAGES, HUGE_PAGE_SIZE};
+use crate::paging::{KernelMapper, Page, PageFlags, PageIter, PageMapper, PhysicalAddress, RmmA, round_up_pages, TableKind, VirtualAddress};
 
 pub const MMAP_MIN_DEFAULT: usize = PAGE_SIZE;
 
+/// Set by `AddrSpace::probe` if the page is currently backed by a frame
+// TODO: Move to syscall::flag
+pub const MPROBE_RESIDENT: MapFlags = unsafe { MapFlags::from_bits_unchecked(0x0080_0000) };
+
+/// Lock an anonymous mapping, which is backed by frames as soon as it is mapped, so that its
+/// pages stay resident until it is unmapped
+// TODO: Move to syscall::flag
+pub const MAP_LOCKED: MapFlags = unsafe { MapFlags::from_bits_unchecked(0x0020_0000) };
+
+/// Map anonymous memory with huge page entries where the size and alignment permit, backed by
+/// contiguous frames. Pages outside whole huge pages, and huge pages for which no contiguous
+/// frames are free, are mapped with page entries as usual.
+// TODO: Move to syscall::flag
+pub const MAP_HUGETLB: MapFlags = unsafe { MapFlags::from_bits_unchecked(0x0040_0000) };
+
+/// Flags of `msync`: write dirty pages back, and check that the range can be reloaded from the
+/// file. Writeback is always done before returning, so `MS_ASYNC` and `MS_SYNC` behave alike.
+// TODO: Move to syscall::flag
+pub const MS_ASYNC: usize = 1;
+// TODO: Move to syscall::flag
+pub const MS_INVALIDATE: usize = 2;
+// TODO: Move to syscall::flag
+pub const MS_SYNC: usize = 4;
+
+/// How far below the lowest page of a stack a fault can be to count as overflowing it. Functions
+/// with large frames can move the stack pointer past the guard page in one step.
+pub const STACK_GUARD_SIZE: usize = 16 * PAGE_SIZE;
+/// How far below the stack pointer an access can be to still be on the stack, as leaf functions
+/// may use the area below it without moving it
+const STACK_RED_ZONE: usize = 128;
+
 pub fn page_flags(flags: MapFlags) -> PageFlags<RmmA> {
     PageFlags::new()
         .user(true)
@@ -35,6 +71,56 @@ pub fn map_flags(page_flags: PageFlags<RmmA>) -> MapFlags {
     flags
 }
 
+/// Whether the CPU wrote to a page since its dirty bit was last cleared. Without a dirty bit in
+/// the page tables, every writable page counts as dirty.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn is_dirty(flags: PageFlags<RmmA>) -> bool {
+    flags.data() & EntryFlags::DIRTY.bits() != 0
+}
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn is_dirty(flags: PageFlags<RmmA>) -> bool {
+    flags.has_write()
+}
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn with_dirty(flags: PageFlags<RmmA>, dirty: bool) -> PageFlags<RmmA> {
+    flags.custom_flag(EntryFlags::DIRTY.bits(), dirty)
+}
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn with_dirty(flags: PageFlags<RmmA>, _dirty: bool) -> PageFlags<RmmA> {
+    flags
+}
+
+/// Translate a user `address`, including pages of `MAP_HUGETLB` grants mapped by huge page
+/// entries, which `PageMapper::translate` would walk into as if they were a page table
+pub fn translate(mapper: &PageMapper, address: VirtualAddress) -> Option<(PhysicalAddress, PageFlags<RmmA>)> {
+    huge::translate(mapper, address).or_else(|| mapper.translate(address))
+}
+
+/// Map the huge pages that `region` starts or ends within with page entries instead, so that the
+/// grants can be split at its edges without cutting through a huge page
+pub fn split_huge_edges(mapper: &mut PageMapper, region: Region, mut flusher: impl Flusher<RmmA>) -> Result<(), Enomem> {
+    for address in [region.start_address(), region.end_address()] {
+        if address.data() % HUGE_PAGE_SIZE == 0 {
+            continue;
+        }
+        if let Some(flush) = unsafe { huge::split(mapper, address)? } {
+            flusher.consume(flush);
+        }
+    }
+    Ok(())
+}
+
+/// Map every huge page overlapping `region` with page entries instead
+pub fn split_huge_pages(mapper: &mut PageMapper, region: Region, mut flusher: impl Flusher<RmmA>) -> Result<(), Enomem> {
+    let start = region.start_address().data() & !(HUGE_PAGE_SIZE - 1);
+    for address in (start..region.end_address().data()).step_by(HUGE_PAGE_SIZE) {
+        if let Some(flush) = unsafe { huge::split(mapper, VirtualAddress::new(address))? } {
+            flusher.consume(flush);
+        }
+    }
+    Ok(())
+}
+
 pub struct UnmapResult {
     pub file_desc: Option<GrantFileRef>,
 }
@@ -59,11 +145,68 @@ pub struct AddrSpace {
     /// the exception that we have a memory safe kernel which doesn't have to protect itself
     /// against null pointers, so fixed mmaps to address zero are still allowed.
     pub mmap_min: usize,
+    /// Number of frames currently owned by grants, and the highest it has been
+    pub resident_frames: usize,
+    pub peak_resident_frames: usize,
+    /// Limit on the total size of the grants, checked when mapping memory, like `RLIMIT_AS`.
+    /// Anonymous memory counts in full whether or not its pages are resident, so memory that has
+    /// been mapped can always be backed by frames as far as the limit is concerned, unless
+    /// `overcommit` is set.
+    ///
+    /// This is lowered to the soft `RLIMIT_AS` of the contexts using the address space, and can be
+    /// lowered further through the `rlimit-as` file of `proc:`, which the context limit then never
+    /// overrides.
+    pub rlimit_as: usize,
+    /// Whether `rlimit_as` only counts pages of owned memory that are backed by frames, so that
+    /// released pages can be mapped without being charged until they are faulted in again. Off by
+    /// default, which charges every mapped page, like Linux with overcommit disabled.
+    pub overcommit: bool,
 }
 impl AddrSpace {
     pub fn current() -> Result<Arc<RwLock<Self>>> {
         Ok(Arc::clone(super::current()?.read().addr_space()?))
     }
+    /// Follow a change of the soft `RLIMIT_AS` of a context using this address space from `old`
+    /// to `new`. The limit is only replaced if it is still the one of the context, and not a lower
+    /// one set through `proc:`.
+    pub fn update_rlimit_as(&mut self, old: usize, new: usize) {
+        self.rlimit_as = if self.rlimit_as < old {
+            cmp::min(self.rlimit_as, new)
+        } else {
+            new
+        };
+    }
+
+    /// Size charged against `rlimit_as`, see `overcommit`
+    pub fn charged_size(&self) -> usize {
+        if self.overcommit {
+            self.resident_frames * PAGE_SIZE
+        } else {
+            self.grants.size()
+        }
+    }
+    /// Size charged against `rlimit_as` for the part of the grants within `region`
+    fn charged_in(&self, region: Region) -> usize {
+        self.grants.conflicts(region).map(|grant| {
+            let intersection = grant.intersect(region);
+            if !self.overcommit {
+                return intersection.size();
+            }
+            if !grant.owned {
+                return 0;
+            }
+            let base = Page::containing_address(intersection.start_address());
+            Page::range_exclusive(base, base.next_by(intersection.size() / PAGE_SIZE))
+                .filter(|page| self.table.translate(page.start_address()).is_some())
+                .count() * PAGE_SIZE
+        }).sum()
+    }
+
+    /// Whether one more frame of owned memory stays within `rlimit_as`. Without overcommit, the
+    /// page was already charged when it was mapped.
+    fn may_charge_frame(&self) -> bool {
+        !self.overcommit || (self.resident_frames + 1) * PAGE_SIZE <= self.rlimit_as
+    }
 
     /// Attempt to clone an existing address space so that all mappings are copied (CoW).
     pub fn try_clone(&mut self) -> Result<Arc<RwLock<Self>>> {
@@ -76,6 +219,8 @@ impl AddrSpace {
         let this_mapper = &mut self.table.utable;
         let new_mapper = &mut new_guard.table.utable;
 
+        let mut resident_frames = 0;
+
         for grant in self.grants.iter() {
             if grant.desc_opt.is_some() { continue; }
 
@@ -83,11 +228,22 @@ impl AddrSpace {
 
             // TODO: Replace this with CoW
             if grant.owned {
-                new_grant = Grant::zeroed(Page::containing_address(grant.start_address()), grant.size() / PAGE_SIZE, grant.flags(), new_mapper, ())?;
+                let base = Page::containing_address(grant.start_address());
+                new_grant = if grant.huge {
+                    Grant::zeroed_huge(base, grant.size() / PAGE_SIZE, grant.flags(), new_mapper, (), FrameHint::local())?
+                } else {
+                    Grant::zeroed(base, grant.size() / PAGE_SIZE, grant.flags(), new_mapper, (), FrameHint::local())?
+                };
+                resident_frames += new_grant.size() / PAGE_SIZE;
 
                 for page in new_grant.pages().map(Page::start_address) {
-                    let current_frame = unsafe { RmmA::phys_to_virt(this_mapper.translate(page).expect("grant containing unmapped pages").0) }.data() as *const u8;
-                    let new_frame = unsafe { RmmA::phys_to_virt(new_mapper.translate(page).expect("grant containing unmapped pages").0) }.data() as *mut u8;
+                    // Released pages read as zero, which the new frame already is
+                    let current_frame = match translate(this_mapper, page) {
+                        Some((frame, _)) => unsafe { RmmA::phys_to_virt(frame) }.data() as *const u8,
+                        None if grant.allocator_owned => continue,
+                        None => panic!("grant containing unmapped pages"),
+                    };
+                    let new_frame = unsafe { RmmA::phys_to_virt(translate(new_mapper, page).expect("grant containing unmapped pages").0) }.data() as *mut u8;
 
                     unsafe {
                         new_frame.copy_from_nonoverlapping(current_frame, PAGE_SIZE);
@@ -102,6 +258,10 @@ impl AddrSpace {
 
             new_guard.grants.insert(new_grant);
         }
+        new_guard.resident_frames = resident_frames;
+        new_guard.peak_resident_frames = resident_frames;
+        new_guard.rlimit_as = self.rlimit_as;
+        new_guard.overcommit = self.overcommit;
         Ok(new)
     }
     pub fn new() -> Result<Self> {
@@ -109,6 +269,10 @@ impl AddrSpace {
             grants: UserGrants::new(),
             table: setup_new_utable()?,
             mmap_min: MMAP_MIN_DEFAULT,
+            resident_frames: 0,
+            peak_resident_frames: 0,
+            rlimit_as: usize::MAX,
+            overcommit: false,
         })
     }
     pub fn is_current(&self) -> bool {
@@ -127,6 +291,8 @@ impl AddrSpace {
 
         let region = Region::new(base.start_address(), page_count * PAGE_SIZE);
 
+        split_huge_edges(mapper, region, &mut flusher)?;
+
         // TODO: Remove allocation
         let regions = self.grants.conflicts(region).map(|g| *g.region()).collect::<Vec<_>>();
 
@@ -158,11 +324,15 @@ impl AddrSpace {
         }
         Ok(())
     }
-    pub fn munmap(mut self: RwLockWriteGuard<'_, Self>, page: Page, page_count: usize) {
+    /// Unmap the given range. Huge pages it only covers in part are split first, which fails with
+    /// ENOMEM if no page table can be allocated, leaving the range mapped.
+    pub fn munmap(mut self: RwLockWriteGuard<'_, Self>, page: Page, page_count: usize) -> Result<()> {
         let mut notify_files = Vec::new();
 
         let requested = Region::new(page.start_address(), page_count * PAGE_SIZE);
-        let mut flusher = PageFlushAll::new();
+        let mut flusher = BatchFlusher::new(self.is_current());
+
+        split_huge_edges(&mut self.table.utable, requested, &mut flusher)?;
 
         let conflicting: Vec<Region> = self.grants.conflicts(requested).map(Region::from).collect();
 
@@ -184,9 +354,16 @@ impl AddrSpace {
                 self.grants.insert(after);
             }
 
+            if grant.is_owned() {
+                let table = &self.table;
+                let mapped = grant.pages().filter(|page| table.translate(page.start_address()).is_some()).count();
+                self.resident_frames = self.resident_frames.saturating_sub(mapped);
+            }
+
             // Remove irrelevant region
             grant.unmap(&mut self.table.utable, &mut flusher);
         }
+        drop(flusher);
         drop(self);
 
         for (file_ref, intersection) in notify_files {
@@ -205,15 +382,30 @@ impl AddrSpace {
 
             let _ = file_ref.desc.close();
         }
+
+        Ok(())
     }
     pub fn mmap(&mut self, page: Option<Page>, page_count: usize, flags: MapFlags, map: impl FnOnce(Page, PageFlags<RmmA>, &mut PageMapper, &mut dyn Flusher<RmmA>) -> Result<Grant>) -> Result<Page> {
         // Finally, the end of all "T0DO: Abstract with other grant creation"!
         if page_count == 0 {
             return Err(Error::new(EINVAL));
         }
+        // Whatever a fixed mapping replaces is unmapped first, and stops being charged
+        let replaced = match page {
+            Some(page) if flags.contains(MapFlags::MAP_FIXED) && !flags.contains(MapFlags::MAP_FIXED_NOREPLACE) => self.charged_in(Region::new(page.start_address(), page_count * PAGE_SIZE)),
+            _ => 0,
+        };
+        if self.charged_size().saturating_sub(replaced).saturating_add(page_count * PAGE_SIZE) > self.rlimit_as {
+            return Err(Error::new(ENOMEM));
+        }
 
         let region = match page {
             Some(page) => self.grants.find_free_at(self.mmap_min, page.start_address(), page_count * PAGE_SIZE, flags)?,
+            // Huge page mappings are best placed at a huge page boundary, but can do without
+            None if flags.contains(MAP_HUGETLB) && page_count >= HUGE_PAGE_PAGES => self.grants
+                .find_free_aligned(self.mmap_min, page_count * PAGE_SIZE, HUGE_PAGE_SIZE)
+                .or_else(|| self.grants.find_free(self.mmap_min, page_count * PAGE_SIZE))
+                .ok_or(Error::new(ENOMEM))?,
             None => self.grants.find_free(self.mmap_min, page_count * PAGE_SIZE).ok_or(Error::new(ENOMEM))?,
         };
         let page = Page::containing_address(region.start_address());
@@ -227,20 +419,490 @@ impl AddrSpace {
             &mut inactive as &mut dyn Flusher<RmmA>
         };
 
-        self.grants.insert(map(page, page_flags(flags), &mut self.table.utable, flusher)?);
+        let grant = map(page, page_flags(flags), &mut self.table.utable, flusher)?;
+        if grant.is_owned() {
+            self.add_resident_frames(page_count);
+        }
+        self.grants.insert(grant);
         Ok(page)
     }
+    fn add_resident_frames(&mut self, count: usize) {
+        self.resident_frames += count;
+        self.peak_resident_frames = cmp::max(self.peak_resident_frames, self.resident_frames);
+    }
+    /// Report which pages starting at `base` are resident, one byte per page with bit 0 set if
+    /// the page is currently backed by a frame. The range is clamped to the end of userspace, and
+    /// the number of pages reported is returned.
+    pub fn mincore(&self, base: Page, vec: &mut [u8]) -> usize {
+        let start = base.start_address().data();
+        let end = cmp::min(start.saturating_add(vec.len().saturating_mul(PAGE_SIZE)), crate::USER_END_OFFSET);
+        let page_count = end.saturating_sub(start) / PAGE_SIZE;
+
+        // Walk the page tables rather than the grants, so that ranges spanning several grants (or
+        // holes between them) are handled uniformly, and so that pages of a grant which have not
+        // yet been faulted in are reported as not resident.
+        for (i, byte) in vec[..page_count].iter_mut().enumerate() {
+            let address = VirtualAddress::new(start + i * PAGE_SIZE);
+            *byte = self.table.translate(address).is_some() as u8;
+        }
+
+        page_count
+    }
+    /// Report the NUMA node of the frame backing each page starting at `base`, like `mincore`.
+    /// Pages of a grant which have not been faulted in yet have no frame and are reported as
+    /// `-ENOENT`, and pages outside of any grant as `-EFAULT`.
+    pub fn numa_nodes(&self, base: Page, nodes: &mut [i32]) -> usize {
+        let start = base.start_address().data();
+        let end = cmp::min(start.saturating_add(nodes.len().saturating_mul(PAGE_SIZE)), crate::USER_END_OFFSET);
+        let page_count = end.saturating_sub(start) / PAGE_SIZE;
+
+        for (i, node) in nodes[..page_count].iter_mut().enumerate() {
+            let address = VirtualAddress::new(start + i * PAGE_SIZE);
+            *node = match self.table.translate(address) {
+                Some((frame, _)) => numa::frame_node(frame) as i32,
+                None if self.grants.contains(address).is_some() => -ENOENT,
+                None => -EFAULT,
+            };
+        }
+
+        page_count
+    }
+    /// Count the bytes mapped by the grants, by what backs them. Taken while the address space is
+    /// locked, so that the counts are consistent with each other.
+    pub fn memory_stats(&self) -> MemoryStats {
+        let mut stats = MemoryStats::default();
+
+        for grant in self.grants.iter() {
+            stats.total += grant.size();
+            if grant.is_locked() {
+                stats.locked += grant.size();
+            }
+            match grant.kind() {
+                GrantKind::Anonymous => for page in grant.pages() {
+                    // Pages that are not faulted in yet, or were released, are only reserved
+                    if self.table.translate(page.start_address()).is_some() {
+                        stats.anonymous_resident += PAGE_SIZE;
+                    } else {
+                        stats.anonymous_reserved += PAGE_SIZE;
+                    }
+                },
+                GrantKind::Borrowed => stats.borrowed += grant.size(),
+                GrantKind::File => stats.file += grant.size(),
+            }
+        }
+
+        stats
+    }
+    /// Whether a fault at the unmapped `address`, with the user stack pointer at `sp`, is caused
+    /// by the stack overflowing into the unmapped guard region below it. The stack is the grant
+    /// just above `address`, and `sp` must have reached the guard region too, so that a wild
+    /// pointer that happens to land below some grant is not mistaken for an overflow.
+    pub fn is_stack_overflow(&self, address: VirtualAddress, sp: usize) -> bool {
+        if self.grants.contains(address).is_some() {
+            return false;
+        }
+        let stack = match self.grants.conflicts(Region::new(address, STACK_GUARD_SIZE)).next() {
+            Some(grant) => *grant.region(),
+            None => return false,
+        };
+
+        address.data().saturating_add(STACK_RED_ZONE) >= sp
+            && sp.saturating_add(STACK_GUARD_SIZE) >= stack.start_address().data()
+    }
+    /// Report whether the page containing `address` is mapped, as the `PROT_*` flags it can be
+    /// accessed with, or empty if it isn't mapped at all. Pages of a grant which have not been
+    /// faulted in yet are still mapped, with the permissions of the grant, but lack
+    /// `MPROBE_RESIDENT`.
+    pub fn probe(&self, address: VirtualAddress) -> MapFlags {
+        if address.data() >= crate::USER_END_OFFSET {
+            return MapFlags::empty();
+        }
+        if let Some((_, flags)) = self.table.translate(address) {
+            return map_flags(flags) | MPROBE_RESIDENT;
+        }
+        self.grants.contains(address).map_or(MapFlags::empty(), |grant| map_flags(grant.flags()))
+    }
+    /// Clear the dirty bits of the pages of shared file mappings in the given range, returning
+    /// every page that was dirty with the file and offset it has to be written back to. The
+    /// range must be entirely mapped, or ENOMEM is returned. If `invalidate` is set, locked
+    /// pages in the range fail this with EBUSY, as they cannot be reloaded from a file.
+    pub fn take_dirty(&mut self, base: Page, page_count: usize, invalidate: bool) -> Result<Vec<(Page, GrantFileRef)>> {
+        let region = Region::new(base.start_address(), page_count * PAGE_SIZE);
+
+        let mut covered = 0;
+        for grant in self.grants.conflicts(region) {
+            if invalidate && grant.locked {
+                return Err(Error::new(EBUSY));
+            }
+            covered += grant.intersect(region).size();
+        }
+        if covered != region.size() {
+            return Err(Error::new(ENOMEM));
+        }
+
+        let mut flusher = BatchFlusher::new(self.is_current());
+        let mapper = &mut self.table.utable;
+
+        let mut dirty = Vec::new();
+        for grant in self.grants.conflicts(region) {
+            let file_ref = match grant.desc_opt {
+                Some(ref file_ref) if !file_ref.flags.contains(MapFlags::MAP_PRIVATE) => file_ref,
+                // Private and anonymous memory is never written to a file
+                _ => continue,
+            };
+            let intersection = grant.intersect(region);
+            for page in intersection.pages() {
+                let flags = match mapper.translate(page.start_address()) {
+                    Some((_, flags)) if is_dirty(flags) => flags,
+                    _ => continue,
+                };
+                dirty.push((page, GrantFileRef {
+                    desc: file_ref.desc.clone(),
+                    offset: file_ref.offset + (page.start_address().data() - grant.start_address().data()),
+                    flags: file_ref.flags,
+                }));
+                // Writes from now on dirty the page again, so that they are not lost
+                if let Some(result) = unsafe { mapper.remap(page.start_address(), with_dirty(flags, false)) } {
+                    flusher.consume(result);
+                }
+            }
+        }
+
+        Ok(dirty)
+    }
+    /// Mark pages dirty again after writing them back failed, so that a later `msync` retries
+    pub fn set_dirty(&mut self, pages: impl Iterator<Item = Page>) {
+        let mut flusher = BatchFlusher::new(self.is_current());
+        let mapper = &mut self.table.utable;
+
+        for page in pages {
+            if let Some((_, flags)) = mapper.translate(page.start_address()) {
+                if let Some(result) = unsafe { mapper.remap(page.start_address(), with_dirty(flags, true)) } {
+                    flusher.consume(result);
+                }
+            }
+        }
+    }
+    /// Back every page in the given range with a frame up front, so that the memory is known to
+    /// be available before it is used. The range must be entirely covered by anonymous grants.
+    ///
+    /// Returns the number of pages that had to be newly allocated. If the frames cannot all be
+    /// allocated, the pages allocated by this call are freed again and ENOMEM is returned, leaving
+    /// the range as it was.
+    pub fn reserve(&mut self, base: Page, page_count: usize) -> Result<usize> {
+        let requested = Region::new(base.start_address(), page_count * PAGE_SIZE);
+
+        let mut covered = 0;
+        for grant in self.grants.conflicts(requested) {
+            if !grant.owned || !grant.allocator_owned || grant.desc_opt.is_some() {
+                return Err(Error::new(EINVAL));
+            }
+            covered += grant.intersect(requested).size();
+        }
+        if covered != requested.size() {
+            return Err(Error::new(EFAULT));
+        }
+        if self.overcommit && self.charged_size() + requested.size() - self.charged_in(requested) > self.rlimit_as {
+            return Err(Error::new(ENOMEM));
+        }
+
+        let (mut active, mut inactive);
+        let flusher = if self.is_current() {
+            active = PageFlushAll::new();
+            &mut active as &mut dyn Flusher<RmmA>
+        } else {
+            inactive = InactiveFlusher::new();
+            &mut inactive as &mut dyn Flusher<RmmA>
+        };
+        let mapper = &mut self.table.utable;
+
+        // TODO: Remove allocation
+        let mut reserved = Vec::new();
+
+        for page in requested.pages() {
+            if translate(mapper, page.start_address()).is_some() {
+                continue;
+            }
+            let flags = self.grants.contains(page.start_address()).expect("range was checked to be covered by grants").flags();
+
+            match unsafe { mapper.map(page.start_address(), flags) } {
+                Some(flush) => {
+                    flusher.consume(flush);
+                    reserved.push(page);
+                }
+                None => {
+                    for page in reserved {
+                        let (entry, _, flush) = unsafe { mapper.unmap_phys(page.start_address(), true) }
+                            .expect("page reserved by this call disappeared");
+                        crate::memory::deallocate_frames(Frame::containing_address(entry), 1);
+                        flusher.consume(flush);
+                    }
+                    return Err(Error::new(ENOMEM));
+                }
+            }
+        }
+
+        self.add_resident_frames(reserved.len());
+        Ok(reserved.len())
+    }
+    /// Free the frames backing the pages in the given range, which must be entirely covered by
+    /// anonymous grants. The grants themselves are kept, and touching a released page again maps
+    /// a new zeroed frame, as with `MADV_DONTNEED`. Locked grants cannot be released.
+    ///
+    /// Returns the number of frames that were freed.
+    pub fn release(&mut self, base: Page, page_count: usize) -> Result<usize> {
+        let requested = Region::new(base.start_address(), page_count * PAGE_SIZE);
+
+        let mut covered = 0;
+        for grant in self.grants.conflicts(requested) {
+            if !grant.owned || !grant.allocator_owned || grant.desc_opt.is_some() || grant.locked {
+                return Err(Error::new(EINVAL));
+            }
+            covered += grant.intersect(requested).size();
+        }
+        if covered != requested.size() {
+            return Err(Error::new(EFAULT));
+        }
+
+        let mut released = 0;
+        {
+            // Other CPUs may be running threads of this address space, so their TLBs need to be
+            // shot down as well
+            let mut flusher = BatchFlusher::new(self.is_current());
+            let mapper = &mut self.table.utable;
+
+            // Huge pages within the range are released whole, those it only covers in part page
+            // by page
+            split_huge_edges(mapper, requested, &mut flusher)?;
+
+            for page in requested.pages() {
+                if let Some((entry, _, flush)) = unsafe { huge::unmap(mapper, page.start_address()) } {
+                    crate::memory::deallocate_frames(Frame::containing_address(entry), HUGE_PAGE_PAGES);
+                    flusher.consume(flush);
+                    released += HUGE_PAGE_PAGES;
+                    continue;
+                }
+                if let Some((entry, _, flush)) = unsafe { mapper.unmap_phys(page.start_address(), true) } {
+                    crate::memory::deallocate_frames(Frame::containing_address(entry), 1);
+                    flusher.consume(flush);
+                    released += 1;
+                }
+            }
+        }
+
+        self.resident_frames = self.resident_frames.saturating_sub(released);
+        Ok(released)
+    }
+    /// Give a private file mapping its own copy of the page it borrows from the scheme at
+    /// `address`, when it is first written to. Returns whether `address` was in such a mapping.
+    /// The page is split off into a grant owned like anonymous memory, so that further writes
+    /// never reach the page of the scheme, while the rest stays borrowed. Copies of neighbouring
+    /// pages are joined into one grant, so that writing across a large mapping does not leave a
+    /// grant for every page.
+    pub fn copy_on_write(&mut self, address: VirtualAddress) -> bool {
+        let region = match self.grants.contains(address) {
+            Some(grant) if grant.is_private_borrow() && grant.flags().has_write() => Region::from(grant),
+            // Another thread may have copied the page first, while this CPU still had the
+            // read-only entry cached
+            Some(grant) if grant.flags().has_write() => {
+                return self.table.translate(address).map_or(false, |(_, flags)| flags.has_write());
+            }
+            _ => return false,
+        };
+
+        if !self.may_charge_frame() {
+            return false;
+        }
+        let frame = match crate::memory::allocate_frames_hinted(1, FrameHint::local()) {
+            Some(frame) => frame,
+            None => return false,
+        };
+
+        let page = Page::containing_address(address);
+        let grant = self.grants.take(&region).expect("grant cannot magically disappear while we hold the lock!");
+        let (before, mut grant, after) = grant.extract(Region::new(page.start_address(), PAGE_SIZE)).expect("grant contains the written page");
+        if let Some(before) = before {
+            self.grants.insert(before);
+        }
+        if let Some(after) = after {
+            self.grants.insert(after);
+        }
+
+        {
+            // Other threads of this address space may have the read-only entry cached as well
+            let mut flusher = BatchFlusher::new(self.is_current());
+            let (src, _, flush) = unsafe { self.table.utable.unmap_phys(page.start_address(), false) }
+                .expect("borrowed grant containing unmapped pages");
+            flusher.consume(flush);
+
+            unsafe {
+                let src = RmmA::phys_to_virt(src).data() as *const u8;
+                let dst = RmmA::phys_to_virt(frame.start_address()).data() as *mut u8;
+                dst.copy_from_nonoverlapping(src, PAGE_SIZE);
+            }
+
+            // The page table is still there, so mapping cannot fail
+            let flush = unsafe { self.table.utable.map_phys(page.start_address(), frame.start_address(), grant.flags()) }
+                .expect("failed to map copied page");
+            flusher.consume(flush);
+        }
+
+        grant.owned = true;
+        grant.allocator_owned = true;
+
+        let previous = page.start_address().data().checked_sub(PAGE_SIZE)
+            .and_then(|address| self.grants.contains(VirtualAddress::new(address)))
+            .filter(|previous| previous.continues_copy(&grant))
+            .map(Region::from);
+        if let Some(previous) = previous {
+            let mut previous = self.grants.take(&previous).expect("grant cannot magically disappear while we hold the lock!");
+            previous.absorb(grant);
+            grant = previous;
+        }
+        let next = self.grants.contains(grant.end_address())
+            .filter(|next| grant.continues_copy(next))
+            .map(Region::from);
+        if let Some(next) = next {
+            let next = self.grants.take(&next).expect("grant cannot magically disappear while we hold the lock!");
+            grant.absorb(next);
+        }
+
+        self.grants.insert(grant);
+        self.add_resident_frames(1);
+        true
+    }
+    /// Map a new zeroed frame for a page that was released from an anonymous grant, returning
+    /// whether `address` was in such a page
+    pub fn fault_in(&mut self, address: VirtualAddress) -> bool {
+        let flags = match self.grants.contains(address) {
+            Some(grant) if grant.owned && grant.allocator_owned && grant.desc_opt.is_none() => grant.flags(),
+            _ => return false,
+        };
+        let page = Page::containing_address(address);
+        if self.table.translate(page.start_address()).is_some() {
+            return false;
+        }
+        if !self.may_charge_frame() {
+            return false;
+        }
+
+        match unsafe { self.table.utable.map(page.start_address(), flags) } {
+            // The page was not present, so no CPU can have it cached
+            Some(flush) => flush.flush(),
+            None => return false,
+        }
+        self.add_resident_frames(1);
+        true
+    }
+    /// Fault in every released page of anonymous memory in the given range, so that it can be
+    /// borrowed. Pages outside anonymous grants are left as they are.
+    pub fn fault_in_range(&mut self, base: Page, page_count: usize) {
+        for page in Page::range_exclusive(base, base.next_by(page_count)) {
+            if self.table.translate(page.start_address()).is_none() {
+                self.fault_in(page.start_address());
+            }
+        }
+    }
+}
+
+/// Try to resolve a page fault at a user `address` of the current context, by mapping memory
+/// that was released with `AddrSpace::release`
+pub fn fault_in_current(address: VirtualAddress) -> bool {
+    if address.data() >= crate::USER_END_OFFSET {
+        return false;
+    }
+    match AddrSpace::current() {
+        Ok(addr_space) => addr_space.write().fault_in(address),
+        Err(_) => false,
+    }
+}
+
+/// Try to resolve a write fault at a user `address` of the current context, by copying a
+/// private file mapping
+pub fn copy_on_write_current(address: VirtualAddress) -> bool {
+    if address.data() >= crate::USER_END_OFFSET {
+        return false;
+    }
+    match AddrSpace::current() {
+        Ok(addr_space) => addr_space.write().copy_on_write(address),
+        Err(_) => false,
+    }
+}
+
+/// Whether a fault of the current context at the unmapped user `address` is a stack overflow,
+/// see `AddrSpace::is_stack_overflow`
+pub fn is_stack_overflow_current(address: VirtualAddress, sp: usize) -> bool {
+    if address.data() >= crate::USER_END_OFFSET {
+        return false;
+    }
+    match AddrSpace::current() {
+        Ok(addr_space) => addr_space.read().is_stack_overflow(address, sp),
+        Err(_) => false,
+    }
+}
+
+/// Bytes mapped by an address space, by what backs them. Every byte is in exactly one of the
+/// categories, which add up to `total`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemoryStats {
+    pub total: usize,
+    /// Anonymous memory backed by frames
+    pub anonymous_resident: usize,
+    /// Anonymous memory not backed by frames yet, which is zero when first accessed
+    pub anonymous_reserved: usize,
+    /// Memory of fmapped files, including private pages copied on write
+    pub file: usize,
+    /// Memory borrowed from elsewhere, such as physical memory or the buffers of a scheme call
+    pub borrowed: usize,
+    /// Part of the anonymous resident memory that is locked, see `MAP_LOCKED`
+    pub locked: usize,
+}
+
+/// What a grant maps, for keeping count of the grants
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GrantKind {
+    /// Memory owned by the grant
+    Anonymous,
+    /// Memory borrowed from elsewhere, such as physical memory or the buffers of a scheme call
+    Borrowed,
+    /// Memory of an fmapped file
+    File,
+}
+
+impl GrantKind {
+    pub const ALL: [GrantKind; 3] = [GrantKind::Anonymous, GrantKind::Borrowed, GrantKind::File];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            GrantKind::Anonymous => "anonymous",
+            GrantKind::Borrowed => "borrowed",
+            GrantKind::File => "file",
+        }
+    }
+}
+
+/// Number of grants in all address spaces, indexed by `GrantKind`
+static GRANT_COUNTS: [AtomicUsize; 3] = [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)];
+
+/// Number of grants of `kind` in all address spaces
+pub fn grant_count(kind: GrantKind) -> usize {
+    GRANT_COUNTS[kind as usize].load(AtomicOrdering::Relaxed)
 }
 
 #[derive(Debug)]
 pub struct UserGrants {
     inner: BTreeSet<Grant>,
     holes: BTreeMap<VirtualAddress, usize>,
+    /// Total size of the grants
+    size: usize,
+    /// Number of grants, indexed by `GrantKind`
+    counts: [usize; 3],
     // TODO: Would an additional map ordered by (size,start) to allow for O(log n) allocations be
     // beneficial?
 
     //TODO: technically VirtualAddress is from a scheme's context!
-    pub funmap: BTreeMap<Region, VirtualAddress>,
+    /// Address of every fmapped region in the scheme, and the flags it was mapped with
+    pub funmap: BTreeMap<Region, (VirtualAddress, MapFlags)>,
 }
 
 impl Default for UserGrants {
@@ -254,6 +916,8 @@ impl UserGrants {
         Self {
             inner: BTreeSet::new(),
             holes: core::iter::once((VirtualAddress::new(0), crate::USER_END_OFFSET)).collect::<BTreeMap<_, _>>(),
+            size: 0,
+            counts: [0; 3],
             funmap: BTreeMap::new(),
         }
     }
@@ -276,26 +940,28 @@ impl UserGrants {
             .take_while(move |region| !region.intersect(requested).is_empty())
     }
     /// Return a free region with the specified size
-    // TODO: Alignment (x86_64: 4 KiB, 2 MiB, or 1 GiB).
     pub fn find_free(&self, min: usize, size: usize) -> Option<Region> {
+        self.find_free_aligned(min, size, PAGE_SIZE)
+    }
+    /// Return a free region with the specified size, starting at a multiple of `align`, which
+    /// must be a power of two no smaller than `PAGE_SIZE`
+    // TODO: 1 GiB alignment on x86_64.
+    pub fn find_free_aligned(&self, min: usize, size: usize, align: usize) -> Option<Region> {
         // Get first available hole, but do reserve the page starting from zero as most compiled
         // languages cannot handle null pointers safely even if they point to valid memory. If an
         // application absolutely needs to map the 0th page, they will have to do so explicitly via
         // MAP_FIXED/MAP_FIXED_NOREPLACE.
         // TODO: Allow explicitly allocating guard pages?
 
-        let (hole_start, _hole_size) = self.holes.iter()
+        let start = self.holes.iter()
             .skip_while(|(hole_offset, hole_size)| hole_offset.data() + **hole_size <= min)
-            .find(|(hole_offset, hole_size)| {
-                let avail_size = if hole_offset.data() <= min && min <= hole_offset.data() + **hole_size {
-                    **hole_size - (min - hole_offset.data())
-                } else {
-                    **hole_size
-                };
-                size <= avail_size
+            .find_map(|(hole_offset, hole_size)| {
+                let hole_end = hole_offset.data() + *hole_size;
+                let start = cmp::max(hole_offset.data(), min).checked_add(align - 1)? & !(align - 1);
+                (start.checked_add(size)? <= hole_end).then_some(start)
             })?;
         // Create new region
-        Some(Region::new(VirtualAddress::new(cmp::max(hole_start.data(), min)), size))
+        Some(Region::new(VirtualAddress::new(start), size))
     }
     /// Return a free region, respecting the user's hinted address and flags. Address may be null.
     pub fn find_free_at(&mut self, min: usize, address: VirtualAddress, size: usize, flags: MapFlags) -> Result<Region> {
@@ -408,22 +1074,50 @@ impl UserGrants {
         }
         */
 
+        self.size += grant.size();
+        self.count(grant.kind(), 1, 0);
         self.inner.insert(grant);
     }
+    fn count(&mut self, kind: GrantKind, added: usize, removed: usize) {
+        self.counts[kind as usize] = self.counts[kind as usize] + added - removed;
+        GRANT_COUNTS[kind as usize].fetch_add(added, AtomicOrdering::Relaxed);
+        GRANT_COUNTS[kind as usize].fetch_sub(removed, AtomicOrdering::Relaxed);
+    }
     pub fn remove(&mut self, region: &Region) -> bool {
         self.take(region).is_some()
     }
     pub fn take(&mut self, region: &Region) -> Option<Grant> {
         let grant = self.inner.take(region)?;
         Self::unreserve(&mut self.holes, grant.region());
+        self.size -= grant.size();
+        self.count(grant.kind(), 0, 1);
         Some(grant)
     }
+    /// Number of grants of `kind`
+    pub fn count_of(&self, kind: GrantKind) -> usize {
+        self.counts[kind as usize]
+    }
+    /// Total size of the grants, whether or not their pages are resident
+    pub fn size(&self) -> usize {
+        self.size
+    }
     pub fn iter(&self) -> impl Iterator<Item = &Grant> + '_ {
         self.inner.iter()
     }
     pub fn is_empty(&self) -> bool { self.inner.is_empty() }
-    pub fn into_iter(self) -> impl Iterator<Item = Grant> {
-        self.inner.into_iter()
+    pub fn into_iter(mut self) -> impl Iterator<Item = Grant> {
+        for kind in GrantKind::ALL {
+            self.count(kind, 0, self.counts[kind as usize]);
+        }
+        mem::take(&mut self.inner).into_iter()
+    }
+}
+
+impl Drop for UserGrants {
+    fn drop(&mut self) {
+        for kind in GrantKind::ALL {
+            self.count(kind, 0, self.counts[kind as usize]);
+        }
     }
 }
 
@@ -601,6 +1295,11 @@ pub struct Grant {
     pub(crate) allocator_owned: bool,
     //TODO: This is probably a very heavy way to keep track of fmap'd files, perhaps move to the context?
     pub desc_opt: Option<GrantFileRef>,
+    /// Whether the pages must stay resident, see `MAP_LOCKED`
+    locked: bool,
+    /// Whether the grant was mapped with huge page entries, see `MAP_HUGETLB`. Every huge page
+    /// lies within a single grant, but some may since have been split or released.
+    huge: bool,
 }
 #[derive(Clone, Debug)]
 pub struct GrantFileRef {
@@ -615,11 +1314,59 @@ impl Grant {
     pub fn is_owned(&self) -> bool {
         self.owned
     }
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+    /// Lock the pages of an anonymous grant, which are all backed by frames when it is created
+    pub fn lock(&mut self) {
+        assert!(self.owned && self.allocator_owned && self.desc_opt.is_none(), "only anonymous grants can be locked");
+        self.locked = true;
+    }
+    pub fn kind(&self) -> GrantKind {
+        if self.desc_opt.is_some() {
+            GrantKind::File
+        } else if self.owned {
+            GrantKind::Anonymous
+        } else {
+            GrantKind::Borrowed
+        }
+    }
+    /// Whether the grant is a private file mapping that still borrows the pages of the scheme
+    pub fn is_private_borrow(&self) -> bool {
+        !self.owned && self.desc_opt.as_ref().map_or(false, |file_ref| file_ref.flags.contains(MapFlags::MAP_PRIVATE))
+    }
 
     pub fn region(&self) -> &Region {
         &self.region
     }
 
+    /// Whether `next` continues this grant with pages copied from the same private file mapping,
+    /// starting where this grant ends, at the file offset following the one of this grant
+    fn continues_copy(&self, next: &Grant) -> bool {
+        let (file_ref, next_file_ref) = match (&self.desc_opt, &next.desc_opt) {
+            (Some(file_ref), Some(next_file_ref)) => (file_ref, next_file_ref),
+            _ => return false,
+        };
+        self.owned && self.allocator_owned && next.owned && next.allocator_owned
+            && self.mapped && next.mapped
+            && self.locked == next.locked
+            && self.flags.data() == next.flags.data()
+            && self.end_address() == next.start_address()
+            && Arc::ptr_eq(&file_ref.desc.description, &next_file_ref.desc.description)
+            && file_ref.flags == next_file_ref.flags
+            && file_ref.offset + self.size() == next_file_ref.offset
+    }
+
+    /// Extend this grant by `next`, which must continue it as checked by `continues_copy`
+    fn absorb(&mut self, mut next: Grant) {
+        self.region.size += next.size();
+        next.mapped = false;
+        if let Some(file_ref) = next.desc_opt.take() {
+            // This grant refers to the same file description, so it is never closed here
+            let _ = file_ref.desc.close();
+        }
+    }
+
     /// Get a mutable reference to the region. This is unsafe, because a bad
     /// region could lead to the wrong addresses being unmapped.
     unsafe fn region_mut(&mut self) -> &mut Region {
@@ -646,22 +1393,98 @@ impl Grant {
             owned: false,
             allocator_owned: false,
             desc_opt: None,
+            locked: false,
+            huge: false,
         })
     }
-    pub fn zeroed(dst: Page, page_count: usize, flags: PageFlags<RmmA>, mapper: &mut PageMapper, mut flusher: impl Flusher<RmmA>) -> Result<Grant, Enomem> {
-        // TODO: Unmap partially in case of ENOMEM
+    /// Map newly allocated zeroed frames. Each page gets the cache color matching its virtual
+    /// address, on top of whatever `hint` asks for.
+    pub fn zeroed(dst: Page, page_count: usize, flags: PageFlags<RmmA>, mapper: &mut PageMapper, mut flusher: impl Flusher<RmmA>, hint: FrameHint) -> Result<Grant, Enomem> {
         for page in Page::range_exclusive(dst, dst.next_by(page_count)) {
-            let flush = unsafe { mapper.map(page.start_address(), flags) }.ok_or(Enomem)?;
-            flusher.consume(flush);
+            let color = page.start_address().data() / PAGE_SIZE;
+            let flush = crate::memory::allocate_frames_hinted(1, hint.with_color(color)).and_then(|frame| {
+                let flush = unsafe { mapper.map_phys(page.start_address(), frame.start_address(), flags) };
+                if flush.is_none() {
+                    crate::memory::deallocate_frames(frame, 1);
+                }
+                flush
+            });
+            match flush {
+                Some(flush) => flusher.consume(flush),
+                None => {
+                    // Unmap the pages mapped so far, so that nothing is left behind
+                    for page in Page::range_exclusive(dst, page) {
+                        let (entry, _, flush) = unsafe { mapper.unmap_phys(page.start_address(), true) }
+                            .expect("page mapped by this call disappeared");
+                        crate::memory::deallocate_frames(Frame::containing_address(entry), 1);
+                        flusher.consume(flush);
+                    }
+                    return Err(Enomem);
+                }
+            }
         }
-        Ok(Grant { region: Region { start: dst.start_address(), size: page_count * PAGE_SIZE }, flags, mapped: true, owned: true, allocator_owned: true, desc_opt: None })
+        Ok(Grant { region: Region { start: dst.start_address(), size: page_count * PAGE_SIZE }, flags, mapped: true, owned: true, allocator_owned: true, desc_opt: None, locked: false, huge: false })
+    }
+    /// Like `zeroed`, but map every huge page within the range with a huge page entry, backed by
+    /// contiguous frames. The pages before the first and after the last huge page, and huge pages
+    /// for which no contiguous frames are free, are mapped like in `zeroed`.
+    pub fn zeroed_huge(dst: Page, page_count: usize, flags: PageFlags<RmmA>, mapper: &mut PageMapper, mut flusher: impl Flusher<RmmA>, hint: FrameHint) -> Result<Grant, Enomem> {
+        let end = dst.start_address().data() + page_count * PAGE_SIZE;
+        // Grows with every page mapped, so that it can unmap them again on failure
+        let mut grant = Grant { region: Region { start: dst.start_address(), size: 0 }, flags, mapped: true, owned: true, allocator_owned: true, desc_opt: None, locked: false, huge: true };
+
+        while grant.end_address().data() < end {
+            let address = grant.end_address();
+
+            if address.data() % HUGE_PAGE_SIZE == 0 && end - address.data() >= HUGE_PAGE_SIZE {
+                if let Some(frame) = crate::memory::allocate_frames_aligned(HUGE_PAGE_PAGES, HUGE_PAGE_PAGES) {
+                    match unsafe { huge::map(mapper, address, frame.start_address(), flags) } {
+                        Some(flush) => {
+                            flusher.consume(flush);
+                            grant.region.size += HUGE_PAGE_SIZE;
+                            continue;
+                        }
+                        None => crate::memory::deallocate_frames(frame, HUGE_PAGE_PAGES),
+                    }
+                }
+            }
+
+            let color = address.data() / PAGE_SIZE;
+            let flush = crate::memory::allocate_frames_hinted(1, hint.with_color(color)).and_then(|frame| {
+                let flush = unsafe { mapper.map_phys(address, frame.start_address(), flags) };
+                if flush.is_none() {
+                    crate::memory::deallocate_frames(frame, 1);
+                }
+                flush
+            });
+            match flush {
+                Some(flush) => {
+                    flusher.consume(flush);
+                    grant.region.size += PAGE_SIZE;
+                }
+                None => {
+                    grant.unmap(mapper, flusher);
+                    return Err(Enomem);
+                }
+            }
+        }
+        Ok(grant)
     }
     pub fn borrow(src_base: Page, dst_base: Page, page_count: usize, flags: PageFlags<RmmA>, desc_opt: Option<GrantFileRef>, src_mapper: &mut PageMapper, dst_mapper: &mut PageMapper, dst_flusher: impl Flusher<RmmA>) -> Result<Grant, Enomem> {
         Self::copy_inner(src_base, dst_base, page_count, flags, desc_opt, src_mapper, dst_mapper, (), dst_flusher, false, false, false)
     }
+    /// Borrow the pages of a private file mapping. Whatever `flags` says, the pages are mapped
+    /// read-only, so that the first write faults into `AddrSpace::copy_on_write`.
+    pub fn borrow_private(src_base: Page, dst_base: Page, page_count: usize, flags: PageFlags<RmmA>, desc_opt: Option<GrantFileRef>, src_mapper: &mut PageMapper, dst_mapper: &mut PageMapper, dst_flusher: impl Flusher<RmmA>) -> Result<Grant, Enomem> {
+        let mut grant = Self::borrow(src_base, dst_base, page_count, flags.write(false), desc_opt, src_mapper, dst_mapper, dst_flusher)?;
+        grant.flags = flags;
+        Ok(grant)
+    }
     pub fn reborrow(src_grant: &Grant, dst_base: Page, src_mapper: &mut PageMapper, dst_mapper: &mut PageMapper, dst_flusher: impl Flusher<RmmA>) -> Result<Grant> {
         Self::borrow(Page::containing_address(src_grant.start_address()), dst_base, src_grant.size() / PAGE_SIZE, src_grant.flags(), src_grant.desc_opt.clone(), src_mapper, dst_mapper, dst_flusher).map_err(Into::into)
     }
+    /// Move the pages of `src_grant` to `dst_base`. Its huge pages must have been split, see
+    /// `split_huge_pages`, and are moved as page entries.
     pub fn transfer(mut src_grant: Grant, dst_base: Page, src_mapper: &mut PageMapper, dst_mapper: &mut PageMapper, src_flusher: impl Flusher<RmmA>, dst_flusher: impl Flusher<RmmA>) -> Result<Grant> {
         assert!(core::mem::replace(&mut src_grant.mapped, false));
         let desc_opt = src_grant.desc_opt.take();
@@ -687,13 +1510,24 @@ impl Grant {
 
         for index in 0..page_count {
             let src_page = src_base.next_by(index);
-            let (address, _entry_flags) = if unmap {
-                let (entry, entry_flags, flush) = unsafe { src_mapper.unmap_phys(src_page.start_address(), true).expect("grant references unmapped memory") };
-                src_flusher.consume(flush);
-
-                (entry, entry_flags)
+            let translated = if unmap {
+                unsafe { src_mapper.unmap_phys(src_page.start_address(), true) }.map(|(entry, entry_flags, flush)| {
+                    src_flusher.consume(flush);
+                    (entry, entry_flags)
+                })
             } else {
-                src_mapper.translate(src_page.start_address()).expect("grant references unmapped memory")
+                translate(src_mapper, src_page.start_address())
+            };
+            let (address, _entry_flags) = match translated {
+                Some(translated) => translated,
+                // Released pages of anonymous memory move along as holes
+                None if owned && allocator_owned => {
+                    successful_count = index + 1;
+                    continue;
+                }
+                // Released memory must have been faulted in by the caller, see
+                // `AddrSpace::fault_in_range`
+                None => break,
             };
 
             let flush = match unsafe { dst_mapper.map_phys(dst_base.next_by(index).start_address(), address, flags) } {
@@ -712,6 +1546,7 @@ impl Grant {
             for index in 0..successful_count {
                 let (frame, _, flush) = match unsafe { dst_mapper.unmap_phys(dst_base.next_by(index).start_address(), true) } {
                     Some(f) => f,
+                    None if owned && allocator_owned => continue,
                     None => unreachable!("grant unmapped by someone else in the meantime despite having a &mut PageMapper"),
                 };
                 dst_flusher.consume(flush);
@@ -733,6 +1568,8 @@ impl Grant {
             owned,
             allocator_owned,
             desc_opt,
+            locked: false,
+            huge: false,
         })
     }
 
@@ -740,12 +1577,44 @@ impl Grant {
         self.flags
     }
 
+    /// Start of every huge page within the grant, if it is a `MAP_HUGETLB` grant. Whether each is
+    /// still mapped by a huge page entry is up to the page table.
+    fn huge_pages(&self) -> impl Iterator<Item = VirtualAddress> {
+        let (start, end) = if self.huge {
+            (
+                (self.start_address().data() + HUGE_PAGE_SIZE - 1) & !(HUGE_PAGE_SIZE - 1),
+                self.end_address().data() & !(HUGE_PAGE_SIZE - 1),
+            )
+        } else {
+            (0, 0)
+        };
+        (start..end).step_by(HUGE_PAGE_SIZE).map(VirtualAddress::new)
+    }
+
     pub fn remap(&mut self, mapper: &mut PageMapper, mut flusher: impl Flusher<RmmA>, flags: PageFlags<RmmA>) {
         assert!(self.mapped);
 
+        // Borrowed pages of private mappings stay read-only until they have been copied
+        let entry_flags = if self.is_private_borrow() { flags.write(false) } else { flags };
+
+        for address in self.huge_pages() {
+            if let Some(flush) = unsafe { huge::remap(mapper, address, entry_flags) } {
+                flusher.consume(flush);
+            }
+        }
+
         for page in self.pages() {
+            // Pages of huge pages were remapped as a whole above
+            if self.huge && huge::translate(mapper, page.start_address()).is_some() {
+                continue;
+            }
             unsafe {
-                let result = mapper.remap(page.start_address(), flags).expect("grant contained unmap address");
+                let result = match mapper.remap(page.start_address(), entry_flags) {
+                    Some(result) => result,
+                    // Released pages pick up the new flags when faulted in again
+                    None if self.owned && self.allocator_owned => continue,
+                    None => panic!("grant contained unmap address"),
+                };
                 flusher.consume(result);
             }
         }
@@ -759,9 +1628,21 @@ impl Grant {
     pub fn unmap(mut self, mapper: &mut PageMapper, mut flusher: impl Flusher<RmmA>) -> UnmapResult {
         assert!(self.mapped);
 
+        // Huge pages go first, leaving their pages unmapped like released ones below
+        for address in self.huge_pages() {
+            if let Some((entry, _, flush)) = unsafe { huge::unmap(mapper, address) } {
+                crate::memory::deallocate_frames(Frame::containing_address(entry), HUGE_PAGE_PAGES);
+                flusher.consume(flush);
+            }
+        }
+
         for page in self.pages() {
-            let (entry, _, flush) = unsafe { mapper.unmap_phys(page.start_address(), true) }
-                .unwrap_or_else(|| panic!("missing page at {:#0x} for grant {:?}", page.start_address().data(), self));
+            let (entry, _, flush) = match unsafe { mapper.unmap_phys(page.start_address(), true) } {
+                Some(result) => result,
+                // Pages of anonymous memory that have been released are not backed by frames
+                None if self.owned && self.allocator_owned => continue,
+                None => panic!("missing page at {:#0x} for grant {:?}", page.start_address().data(), self),
+            };
 
             if self.owned && self.allocator_owned {
                 // TODO: make sure this frame can be safely freed, physical use counter.
@@ -806,13 +1687,22 @@ impl Grant {
         assert_eq!(region.start_address().data() % PAGE_SIZE, 0, "split_out must be called on page-size aligned start address");
         assert_eq!(region.size() % PAGE_SIZE, 0, "split_out must be called on page-size aligned end address");
 
+        // Each part maps the file from the offset its first page had in the whole grant
+        let start = self.start_address();
+        let desc_at = |part: &Region| self.desc_opt.clone().map(|mut file_ref| {
+            file_ref.offset += part.start_address().data() - start.data();
+            file_ref
+        });
+
         let before_grant = self.before(region).map(|region| Grant {
             region,
             flags: self.flags,
             mapped: self.mapped,
             owned: self.owned,
             allocator_owned: self.allocator_owned,
-            desc_opt: self.desc_opt.clone(),
+            desc_opt: desc_at(&region),
+            locked: self.locked,
+            huge: self.huge,
         });
         let after_grant = self.after(region).map(|region| Grant {
             region,
@@ -820,9 +1710,14 @@ impl Grant {
             mapped: self.mapped,
             owned: self.owned,
             allocator_owned: self.allocator_owned,
-            desc_opt: self.desc_opt.clone(),
+            desc_opt: desc_at(&region),
+            locked: self.locked,
+            huge: self.huge,
         });
 
+        if let Some(file_ref) = self.desc_opt.as_mut() {
+            file_ref.offset += region.start_address().data() - start.data();
+        }
         unsafe {
             *self.region_mut() = region;
         }
@@ -886,6 +1781,13 @@ pub struct Table {
     pub utable: PageMapper,
 }
 
+impl Table {
+    /// Translate a user `address`, see `translate`
+    pub fn translate(&self, address: VirtualAddress) -> Option<(PhysicalAddress, PageFlags<RmmA>)> {
+        translate(&self.utable, address)
+    }
+}
+
 impl Drop for Table {
     fn drop(&mut self) {
         if self.utable.is_current() {
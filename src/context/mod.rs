@@ -1,18 +1,24 @@
 //! # Context management
 //!
 //! For resources on contexts, please consult [wikipedia](https://en.wikipedia.org/wiki/Context_switch) and  [osdev](https://wiki.osdev.org/Context_Switching)
+use core::mem;
 use core::sync::atomic::Ordering;
 
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 
-use spin::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use spin::{RwLock, RwLockWriteGuard};
+#[cfg(not(feature = "lock_debug"))]
+use spin::RwLockReadGuard;
 
+use crate::ipi::{ipi, IpiKind, IpiTarget};
 use crate::paging::{RmmA, RmmArch, TableKind};
-use crate::syscall::error::{Error, ESRCH, Result};
+use crate::scheme::FileHandle;
+use crate::syscall::error::{Error, EAGAIN, EBADF, EDEADLK, EINVAL, EMFILE, EPERM, ESRCH, Result};
 
-pub use self::context::{Context, ContextId, ContextSnapshot, Status, WaitpidKey};
+pub use self::context::{Context, ContextId, ContextSnapshot, Rusage, Status, WaitpidKey};
 pub use self::list::ContextList;
-pub use self::switch::switch;
+pub use self::switch::{alloc_kstacks, cpu_stats, init_sched_quantum, sched_quantum_ticks, set_sched_quantum_ticks, switch, tick, CpuStats, PRIO_MAX, PRIO_MIN};
 
 #[cfg(target_arch = "aarch64")]
 #[path = "arch/aarch64.rs"]
@@ -35,12 +41,25 @@ mod list;
 /// Context switch function
 mod switch;
 
+/// Wakeup deadlines of sleeping contexts
+mod deadline;
+
+/// Detection of long waits on the contexts lock
+#[cfg(feature = "lock_debug")]
+mod lock_debug;
+
 /// File struct - defines a scheme and a file number
 pub mod file;
 
+/// Advisory file locks
+pub mod flock;
+
 /// Memory struct - contains a set of pages for a context
 pub mod memory;
 
+/// Resource limits
+pub mod rlimit;
+
 /// Signal handling
 pub mod signal;
 
@@ -55,6 +74,11 @@ pub const CONTEXT_MAX_CONTEXTS: usize = (isize::max_value() as usize) - 1;
 /// Maximum context files
 pub const CONTEXT_MAX_FILES: usize = 65_536;
 
+/// Highest context ID exempt from broadcast signals: ID 1 is the kernel context of the BSP, which
+/// runs kmain, and ID 2 is the context spawned for userspace_init, which becomes init. Signalling
+/// init would take down the whole system, so it is skipped along with the kernel context.
+pub const CONTEXT_BROADCAST_EXEMPT: usize = 2;
+
 /// Contexts list
 static CONTEXTS: RwLock<ContextList> = RwLock::new(ContextList::new());
 
@@ -62,6 +86,10 @@ static CONTEXTS: RwLock<ContextList> = RwLock::new(ContextList::new());
 static CONTEXT_ID: context::AtomicContextId = context::AtomicContextId::default();
 
 pub use self::arch::empty_cr3;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use self::arch::init_kfx;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use self::arch::{pmc_count, pmc_event_select, set_pmc};
 
 pub fn init() {
     let mut contexts = contexts_mut();
@@ -74,18 +102,36 @@ pub fn init() {
     context.running = true;
     context.cpu_id = Some(crate::cpu_id());
     CONTEXT_ID.store(context.id, Ordering::SeqCst);
+
+    switch::init_cpu_stats(context.id);
 }
 
 /// Get the global schemes list, const
+#[cfg(not(feature = "lock_debug"))]
 pub fn contexts() -> RwLockReadGuard<'static, ContextList> {
     CONTEXTS.read()
 }
 
+/// Get the global schemes list, const
+#[cfg(feature = "lock_debug")]
+#[track_caller]
+pub fn contexts() -> lock_debug::ContextsReadGuard {
+    lock_debug::read(&CONTEXTS)
+}
+
 /// Get the global schemes list, mutable
+#[cfg(not(feature = "lock_debug"))]
 pub fn contexts_mut() -> RwLockWriteGuard<'static, ContextList> {
     CONTEXTS.write()
 }
 
+/// Get the global schemes list, mutable
+#[cfg(feature = "lock_debug")]
+#[track_caller]
+pub fn contexts_mut() -> lock_debug::ContextsWriteGuard {
+    lock_debug::write(&CONTEXTS)
+}
+
 pub fn context_id() -> ContextId {
     // Thread local variables can and should only be modified using Relaxed. This is to prevent a
     // hardware thread from racing with itself, for example if there is an interrupt. Orderings
@@ -99,3 +145,220 @@ pub fn context_id() -> ContextId {
 pub fn current() -> Result<Arc<RwLock<Context>>> {
     contexts().current().ok_or(Error::new(ESRCH)).map(Arc::clone)
 }
+
+/// Whether the current context is being forcibly killed, see `Context::force_kill`
+pub fn force_killed() -> bool {
+    current().map_or(false, |context_lock| context_lock.read().force_kill)
+}
+
+/// Queue `sig`, with `value` if it is a real-time signal, for every user context other than the
+/// caller and init, as `Context::send_signal` does, waking those blocked contexts that can take it.
+/// The contexts lock is only held while collecting the contexts, which are then locked one at a
+/// time, keeping this cheap enough for the shutdown path. Returns the number of contexts the signal
+/// was queued for, or EAGAIN if it was queued for none because their real-time queues were full.
+pub fn broadcast_signal(sig: u8, value: Option<usize>) -> Result<usize> {
+    let current = context_id();
+    let targets: Vec<Arc<RwLock<Context>>> = contexts()
+        .iter()
+        .filter(|(&id, _)| id != current && id.into() > CONTEXT_BROADCAST_EXEMPT)
+        .map(|(_, context_lock)| Arc::clone(context_lock))
+        .collect();
+
+    let mut sent = 0;
+    let mut queue_full = 0;
+    for context_lock in targets {
+        let unstarted = {
+            let mut context = context_lock.write();
+            // Kernel contexts have no address space to handle signals in
+            if context.addr_space.is_none() || matches!(context.status, Status::Exited(_)) {
+                continue;
+            }
+            let unstarted = match context.send_signal(sig, value) {
+                Ok(unstarted) => unstarted,
+                Err(_) => {
+                    queue_full += 1;
+                    continue;
+                }
+            };
+            if !unstarted && context.status == Status::Blocked && (! context.vfork_wait || context.pending.has_kill()) && context.pending.has_deliverable(&context.sigmask) {
+                context.unblock();
+            }
+            unstarted
+        };
+        if unstarted {
+            crate::syscall::process::exit_context(&context_lock, usize::from(sig), true);
+        }
+        sent += 1;
+    }
+
+    if sent == 0 && queue_full > 0 {
+        Err(Error::new(EAGAIN))
+    } else {
+        Ok(sent)
+    }
+}
+
+/// Move the context `id` to the run queue of the CPU `cpu_id`, which must be online and allowed by
+/// its affinity. Kernel
+/// contexts, such as the idle context of each CPU, must stay where they are. A running context
+/// continues on its new CPU once it is switched away from.
+pub fn migrate(id: ContextId, cpu_id: usize) -> Result<()> {
+    if !cpu_stats().contains_key(&cpu_id) {
+        return Err(Error::new(EINVAL));
+    }
+
+    let context_lock = Arc::clone(contexts().get(id).ok_or(Error::new(ESRCH))?);
+    let mut context = context_lock.write();
+    if context.addr_space.is_none() {
+        return Err(Error::new(EPERM));
+    }
+    if let Status::Exited(_) = context.status {
+        return Err(Error::new(ESRCH));
+    }
+    if !context.allows_cpu(cpu_id) {
+        return Err(Error::new(EINVAL));
+    }
+
+    context.cpu_id = Some(cpu_id);
+    if cpu_id != crate::cpu_id() {
+        ipi(IpiKind::Wakeup, IpiTarget::Other);
+    }
+    Ok(())
+}
+
+/// Restrict the context `id` to the CPUs in `affinity`, bit `n` for CPU `n`, of which at least one
+/// must be online. A context whose CPU is no longer allowed is moved to the first allowed one.
+pub fn set_affinity(id: ContextId, affinity: u64) -> Result<()> {
+    let online: Vec<usize> = cpu_stats().keys().copied().collect();
+
+    let context_lock = Arc::clone(contexts().get(id).ok_or(Error::new(ESRCH))?);
+    let mut context = context_lock.write();
+    if context.addr_space.is_none() {
+        return Err(Error::new(EPERM));
+    }
+
+    let old_affinity = mem::replace(&mut context.affinity, affinity);
+    let allowed = match online.iter().copied().find(|&cpu_id| context.allows_cpu(cpu_id)) {
+        Some(cpu_id) => cpu_id,
+        None => {
+            context.affinity = old_affinity;
+            return Err(Error::new(EINVAL));
+        }
+    };
+
+    if context.cpu_id.map_or(false, |cpu_id| !context.allows_cpu(cpu_id)) {
+        context.cpu_id = Some(allowed);
+        if allowed != crate::cpu_id() {
+            ipi(IpiKind::Wakeup, IpiTarget::Other);
+        }
+    }
+    Ok(())
+}
+
+/// Count a page fault of the current context that was resolved. Faults ending in a signal are
+/// not counted, as with `getrusage` on Linux.
+pub fn count_page_fault(major: bool) {
+    if let Ok(context_lock) = current() {
+        let mut context = context_lock.write();
+        if major {
+            context.rusage.major_faults += 1;
+        } else {
+            context.rusage.minor_faults += 1;
+        }
+    }
+}
+
+/// Give the context `to_pid` a descriptor for the file description behind the current context's
+/// descriptor `fd`, which is how descriptors are passed between processes. The description is
+/// shared rather than reopened, so both processes see the same offset and flags, while the new
+/// descriptor never has close-on-exec set. As with `kill`, the receiving context has to belong to
+/// the sender's user, unless the sender is root.
+pub fn send_fd(to_pid: ContextId, fd: FileHandle) -> Result<FileHandle> {
+    let (file, ruid, euid) = {
+        let current_lock = current()?;
+        let current = current_lock.read();
+        (current.get_file(fd).ok_or(Error::new(EBADF))?, current.ruid, current.euid)
+    };
+
+    let target_lock = contexts().get(to_pid).map(Arc::clone).ok_or(Error::new(ESRCH))?;
+    let target = target_lock.read();
+
+    if let Status::Exited(_) = target.status {
+        return Err(Error::new(ESRCH));
+    }
+    if euid != 0 && euid != target.ruid && ruid != target.ruid {
+        return Err(Error::new(EPERM));
+    }
+
+    target.add_file(file::FileDescriptor {
+        cloexec: false,
+        ..file
+    }).ok_or(Error::new(EMFILE))
+}
+
+/// Write-lock two distinct contexts without risking an ABBA deadlock.
+///
+/// Whenever more than one context must be locked at once, the locks have to be acquired in
+/// ascending `ContextId` order, which this function does regardless of the order of its arguments.
+/// The guards are returned in the order they were requested. Locking the same context twice
+/// would deadlock, so EDEADLK is returned in that case instead.
+pub fn lock_two_contexts<'a>(a_id: ContextId, a: &'a RwLock<Context>, b_id: ContextId, b: &'a RwLock<Context>)
+    -> Result<(RwLockWriteGuard<'a, Context>, RwLockWriteGuard<'a, Context>)> {
+    lock_two_ordered(a_id, a, b_id, b).ok_or(Error::new(EDEADLK))
+}
+
+fn lock_two_ordered<'a, K: Ord, T>(a_key: K, a: &'a RwLock<T>, b_key: K, b: &'a RwLock<T>)
+    -> Option<(RwLockWriteGuard<'a, T>, RwLockWriteGuard<'a, T>)> {
+    match a_key.cmp(&b_key) {
+        core::cmp::Ordering::Less => {
+            let a_guard = a.write();
+            let b_guard = b.write();
+            Some((a_guard, b_guard))
+        }
+        core::cmp::Ordering::Greater => {
+            let b_guard = b.write();
+            let a_guard = a.write();
+            Some((a_guard, b_guard))
+        }
+        core::cmp::Ordering::Equal => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use alloc::sync::Arc;
+    use spin::RwLock;
+
+    use super::lock_two_ordered;
+
+    /// Two threads repeatedly locking the same pair in opposite argument order must not deadlock
+    #[test]
+    fn lock_two_reciprocal() {
+        let a = Arc::new(RwLock::new(0usize));
+        let b = Arc::new(RwLock::new(0usize));
+
+        let threads = [false, true].map(|reversed| {
+            let (a, b) = (Arc::clone(&a), Arc::clone(&b));
+            std::thread::spawn(move || {
+                for _ in 0..10_000 {
+                    let (mut x, mut y) = if reversed {
+                        lock_two_ordered(2, &*b, 1, &*a).unwrap()
+                    } else {
+                        lock_two_ordered(1, &*a, 2, &*b).unwrap()
+                    };
+                    *x += 1;
+                    *y += 1;
+                }
+            })
+        });
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(*a.read(), 20_000);
+        assert_eq!(*b.read(), 20_000);
+        assert!(lock_two_ordered(1, &*a, 1, &*a).is_none());
+    }
+}
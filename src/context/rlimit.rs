@@ -0,0 +1,68 @@
+//! # Resource limits
+//! A soft and a hard limit per resource, as with `getrlimit` and `setrlimit`. The soft limit is
+//! the one that is enforced, and any context may move it up to the hard limit, while only root
+//! may raise the hard limit. Limits are inherited by children. Resources are identified by their
+//! index in the table, so a new limit only needs a new `RLIMIT_*` number and a default.
+
+use crate::syscall::error::{Error, Result, EINVAL, EPERM};
+
+/// Most file descriptors, limiting the lowest number that cannot be allocated
+// TODO: Move to syscall::flag
+pub const RLIMIT_NOFILE: usize = 0;
+/// Most bytes of address space, checked when mapping memory
+// TODO: Move to syscall::flag
+pub const RLIMIT_AS: usize = 1;
+/// Number of resources that have limits
+pub const RLIMIT_COUNT: usize = 2;
+
+/// Value of a limit that is not enforced
+// TODO: Move to syscall::flag
+pub const RLIM_INFINITY: usize = usize::MAX;
+
+/// Soft and hard limit of a resource, as passed to and from userspace
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub struct Rlimit {
+    pub soft: usize,
+    pub hard: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct Rlimits {
+    limits: [Rlimit; RLIMIT_COUNT],
+}
+
+impl Default for Rlimits {
+    fn default() -> Self {
+        let unlimited = Rlimit { soft: RLIM_INFINITY, hard: RLIM_INFINITY };
+        let mut limits = [unlimited; RLIMIT_COUNT];
+        limits[RLIMIT_NOFILE] = Rlimit { soft: super::CONTEXT_MAX_FILES, hard: super::CONTEXT_MAX_FILES };
+        Self { limits }
+    }
+}
+
+impl Rlimits {
+    /// Get the limits of `resource`, or EINVAL if it has none
+    pub fn get(&self, resource: usize) -> Result<Rlimit> {
+        self.limits.get(resource).copied().ok_or(Error::new(EINVAL))
+    }
+
+    /// Get the enforced limit of `resource`, which must be one of the `RLIMIT_*` numbers
+    pub fn soft(&self, resource: usize) -> usize {
+        self.limits[resource].soft
+    }
+
+    /// Set the limits of `resource`. Raising the hard limit needs `privileged`, and the soft limit
+    /// can never be above the hard one.
+    pub fn set(&mut self, resource: usize, limit: Rlimit, privileged: bool) -> Result<()> {
+        let current = self.limits.get_mut(resource).ok_or(Error::new(EINVAL))?;
+        if limit.soft > limit.hard {
+            return Err(Error::new(EINVAL));
+        }
+        if limit.hard > current.hard && !privileged {
+            return Err(Error::new(EPERM));
+        }
+        *current = limit;
+        Ok(())
+    }
+}
@@ -1,11 +1,210 @@
+use alloc::collections::VecDeque;
 use alloc::sync::Arc;
 use core::mem;
-use syscall::flag::{PTRACE_FLAG_IGNORE, PTRACE_STOP_SIGNAL, SIG_DFL, SIG_IGN, SIGCHLD, SIGCONT, SIGKILL, SIGSTOP, SIGTSTP, SIGTTIN, SIGTTOU};
+use syscall::flag::{PTRACE_FLAG_IGNORE, PTRACE_STOP_SIGNAL, SIG_DFL, SIG_IGN, SIGBUS, SIGCHLD, SIGCONT, SIGFPE, SIGILL, SIGKILL, SIGSEGV, SIGSTOP, SIGTSTP, SIGTTIN, SIGTTOU};
 use syscall::ptrace_event;
 
 use crate::context::{contexts, switch, Status, WaitpidKey};
 use crate::start::usermode;
 use crate::ptrace;
+use crate::syscall::error::{Error, Result, EAGAIN};
+
+/// First real-time signal. Signals below this are standard signals, which are coalesced while
+/// pending, whereas real-time signals are queued.
+pub const SIGRTMIN: usize = 32;
+
+/// Maximum number of real-time signals that can be queued for a single context
+pub const SIGRT_QUEUE_MAX: usize = 32;
+
+/// `si_code` for a fault on an address that is not mapped
+pub const SEGV_MAPERR: usize = 1;
+/// `si_code` for a fault on a mapped address without the required permissions
+pub const SEGV_ACCERR: usize = 2;
+/// `si_code` for a fault in the unmapped guard region below a stack, when the stack overflowed
+// TODO: Move to syscall::flag
+pub const SEGV_STKOVF: usize = 0x100;
+/// `si_code` for an integer division by zero
+// TODO: Move to syscall::flag
+pub const FPE_INTDIV: usize = 1;
+/// `si_code` for an integer overflow
+// TODO: Move to syscall::flag
+pub const FPE_INTOVF: usize = 2;
+/// `si_code` for a floating point division by zero
+// TODO: Move to syscall::flag
+pub const FPE_FLTDIV: usize = 3;
+/// `si_code` for a floating point overflow
+// TODO: Move to syscall::flag
+pub const FPE_FLTOVF: usize = 4;
+/// `si_code` for a floating point underflow
+// TODO: Move to syscall::flag
+pub const FPE_FLTUND: usize = 5;
+/// `si_code` for an inexact floating point result
+// TODO: Move to syscall::flag
+pub const FPE_FLTRES: usize = 6;
+/// `si_code` for an invalid floating point operation
+// TODO: Move to syscall::flag
+pub const FPE_FLTINV: usize = 7;
+/// `si_code` for an illegal opcode
+// TODO: Move to syscall::flag
+pub const ILL_ILLOPC: usize = 1;
+/// `si_code` for a signal sent by `sigqueue`, with its value in `si_value`
+// TODO: Move to syscall::flag
+pub const SI_QUEUE: usize = -1isize as usize;
+
+/// The kind of access that caused a fault
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FaultAccess {
+    Read = 0,
+    Write = 1,
+    Execute = 2,
+}
+
+/// The most recent memory or arithmetic fault of a context, recorded by the architecture fault
+/// handlers. The address of an arithmetic fault is the address of the faulting instruction.
+#[derive(Clone, Copy, Debug)]
+pub struct FaultInfo {
+    pub address: usize,
+    pub code: usize,
+    pub access: FaultAccess,
+}
+
+/// Signal information pushed onto the signal stack, directly above the restorer address, when
+/// a user handler is invoked
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct SigInfo {
+    pub si_signo: usize,
+    pub si_code: usize,
+    pub si_addr: usize,
+    /// One of the `FaultAccess` values, only meaningful if `si_code` is nonzero
+    pub si_access: usize,
+    /// The value passed to `sigqueue`, only meaningful if `si_code` is `SI_QUEUE`
+    pub si_value: usize,
+}
+
+/// Record a fault of the current context, replacing any previous one, so that it can be reported
+/// to the signal handler
+pub fn record_fault(address: usize, code: usize, access: FaultAccess) {
+    let contexts = contexts();
+    if let Some(context_lock) = contexts.current() {
+        context_lock.write().fault = Some(FaultInfo { address, code, access });
+    }
+}
+
+/// Returns true if the signal is blocked by the mask. SIGKILL and SIGSTOP can never be blocked.
+pub fn is_masked(mask: &[u64; 2], sig: u8) -> bool {
+    let sig = usize::from(sig);
+    if sig == 0 || sig == SIGKILL || sig == SIGSTOP {
+        return false;
+    }
+    let bit = sig - 1;
+    mask[bit / 64] & (1 << (bit % 64)) != 0
+}
+
+/// Signals that have been sent to a context, but not yet delivered
+#[derive(Debug, Default)]
+pub struct PendingSignals {
+    /// Standard signals, where bit `sig - 1` is set if `sig` is pending
+    standard: u64,
+    /// Real-time signals, in the order they were sent, with the value they were queued with
+    realtime: VecDeque<(u8, Option<usize>)>,
+}
+
+impl PendingSignals {
+    pub fn new() -> Self {
+        Self {
+            standard: 0,
+            realtime: VecDeque::new(),
+        }
+    }
+
+    /// Mark a signal as pending. Standard signals that are already pending are coalesced, while
+    /// real-time signals are queued, failing with EAGAIN if the queue is full.
+    pub fn push(&mut self, sig: u8) -> Result<()> {
+        self.queue(sig, None)
+    }
+
+    /// Mark a signal as pending like `push`, queueing `value` with it if it is a real-time signal.
+    /// Standard signals carry no value.
+    pub fn queue(&mut self, sig: u8, value: Option<usize>) -> Result<()> {
+        let sig_usize = usize::from(sig);
+        if sig_usize == 0 {
+            return Ok(());
+        }
+        if sig_usize < SIGRTMIN {
+            self.standard |= 1 << (sig_usize - 1);
+        } else {
+            if self.realtime.len() >= SIGRT_QUEUE_MAX {
+                return Err(Error::new(EAGAIN));
+            }
+            self.realtime.push_back((sig, value));
+        }
+        Ok(())
+    }
+
+    /// Remove and return the next signal not blocked by the mask, with its queued value. Standard
+    /// signals are delivered first, followed by real-time signals. Both are delivered lowest number
+    /// first, and real-time signals of the same number in the order they were sent.
+    pub fn pop(&mut self, mask: &[u64; 2]) -> Option<(u8, Option<usize>)> {
+        // SIGKILL takes precedence over anything else
+        if self.standard & (1 << (SIGKILL - 1)) != 0 {
+            self.standard &= !(1 << (SIGKILL - 1));
+            return Some((SIGKILL as u8, None));
+        }
+
+        let mut standard = self.standard;
+        while standard != 0 {
+            let bit = standard.trailing_zeros();
+            let sig = (bit + 1) as u8;
+            if !is_masked(mask, sig) {
+                self.standard &= !(1 << bit);
+                return Some((sig, None));
+            }
+            standard &= !(1 << bit);
+        }
+
+        // `min_by_key` returns the first of equal elements, which keeps signals of one number FIFO
+        let (index, _) = self.realtime.iter()
+            .enumerate()
+            .filter(|&(_, &(sig, _))| !is_masked(mask, sig))
+            .min_by_key(|&(_, &(sig, _))| sig)?;
+        self.realtime.remove(index)
+    }
+
+    /// Returns true if a signal not blocked by the mask is pending
+    pub fn has_deliverable(&self, mask: &[u64; 2]) -> bool {
+        let mut standard = self.standard;
+        while standard != 0 {
+            let bit = standard.trailing_zeros();
+            if !is_masked(mask, (bit + 1) as u8) {
+                return true;
+            }
+            standard &= !(1 << bit);
+        }
+
+        self.realtime.iter().any(|&(sig, _)| !is_masked(mask, sig))
+    }
+
+    /// Returns true if SIGKILL is pending, which no mask can block
+    pub fn has_kill(&self) -> bool {
+        self.standard & (1 << (SIGKILL - 1)) != 0
+    }
+
+    /// The pending signals, blocked or not, as a set with the layout of a signal mask
+    pub fn set(&self) -> [u64; 2] {
+        let mut set = [self.standard, 0];
+        for &(sig, _) in self.realtime.iter() {
+            let bit = usize::from(sig) - 1;
+            set[bit / 64] |= 1 << (bit % 64);
+        }
+        set
+    }
+
+    /// Returns true if no signals are pending, blocked or not
+    pub fn is_empty(&self) -> bool {
+        self.standard == 0 && self.realtime.is_empty()
+    }
+}
 
 pub fn is_user_handled(handler: Option<extern "C" fn(usize)>) -> bool {
     let handler = handler.map(|ptr| ptr as usize).unwrap_or(0);
@@ -13,12 +212,19 @@ pub fn is_user_handled(handler: Option<extern "C" fn(usize)>) -> bool {
 }
 
 pub extern "C" fn signal_handler(sig: usize) {
-    let ((action, restorer), sigstack) = {
+    let ((action, restorer), sigstack, fault, value) = {
         let contexts = contexts();
         let context_lock = contexts.current().expect("context::signal_handler not inside of context");
-        let context = context_lock.read();
+        let mut context = context_lock.write();
+        // Consume the fault, so that a later signal does not report stale information
+        let fault = if sig == SIGSEGV || sig == SIGBUS || sig == SIGFPE || sig == SIGILL {
+            context.fault.take()
+        } else {
+            None
+        };
+        let value = context.sigval.take();
         let actions = context.actions.read();
-        (actions[sig], context.sigstack)
+        (actions[sig], context.sigstack, fault, value)
     };
 
     let handler = action.sa_handler.map(|ptr| ptr as usize).unwrap_or(0);
@@ -32,6 +238,15 @@ pub extern "C" fn signal_handler(sig: usize) {
         unreachable!();
     }
 
+    // Handlers only ever run on the signal stack, never on the stack of the interrupted code, which
+    // may have just overflowed. Without a signal stack, the default action is taken instead.
+    let handler = if is_user_handled(action.sa_handler) && sigstack.is_none() {
+        println!("{}: no signal stack to handle signal {} on", crate::context::context_id().into(), sig);
+        SIG_DFL
+    } else {
+        handler
+    };
+
     if handler == SIG_DFL {
         match sig {
             SIGCHLD => {
@@ -43,11 +258,11 @@ pub extern "C" fn signal_handler(sig: usize) {
                 {
                     let contexts = contexts();
 
-                    let (pid, pgid, ppid) = {
+                    let (pid, pgid, ppid, rusage) = {
                         let context_lock = contexts.current().expect("context::signal_handler not inside of context");
                         let mut context = context_lock.write();
                         context.status = Status::Runnable;
-                        (context.id, context.pgid, context.ppid)
+                        (context.id, context.pgid, context.ppid, context.rusage())
                     };
 
                     if let Some(parent_lock) = contexts.get(ppid) {
@@ -59,7 +274,7 @@ pub extern "C" fn signal_handler(sig: usize) {
                         waitpid.send(WaitpidKey {
                             pid: Some(pid),
                             pgid: Some(pgid)
-                        }, (pid, 0xFFFF));
+                        }, (pid, 0xFFFF, rusage));
                     } else {
                         println!("{}: {} not found for continue", pid.into(), ppid.into());
                     }
@@ -71,11 +286,11 @@ pub extern "C" fn signal_handler(sig: usize) {
                 {
                     let contexts = contexts();
 
-                    let (pid, pgid, ppid) = {
+                    let (pid, pgid, ppid, rusage) = {
                         let context_lock = contexts.current().expect("context::signal_handler not inside of context");
                         let mut context = context_lock.write();
                         context.status = Status::Stopped(sig);
-                        (context.id, context.pgid, context.ppid)
+                        (context.id, context.pgid, context.ppid, context.rusage())
                     };
 
                     if let Some(parent_lock) = contexts.get(ppid) {
@@ -87,7 +302,7 @@ pub extern "C" fn signal_handler(sig: usize) {
                         waitpid.send(WaitpidKey {
                             pid: Some(pid),
                             pgid: Some(pgid)
-                        }, (pid, (sig << 8) | 0x7F));
+                        }, (pid, (sig << 8) | 0x7F, rusage));
                     } else {
                         println!("{}: {} not found for stop", pid.into(), ppid.into());
                     }
@@ -115,10 +330,19 @@ pub extern "C" fn signal_handler(sig: usize) {
         };
 
         unsafe {
-            let mut sp = sigstack.expect("sigaction was set while sigstack was not") - 256;
+            let mut sp = sigstack.expect("handler was run without a sigstack") - 256;
 
             sp = (sp / 16) * 16;
 
+            sp -= mem::size_of::<SigInfo>();
+            *(sp as *mut SigInfo) = SigInfo {
+                si_signo: sig,
+                si_code: if value.is_some() { SI_QUEUE } else { fault.map_or(0, |fault| fault.code) },
+                si_addr: fault.map_or(0, |fault| fault.address),
+                si_access: fault.map_or(0, |fault| fault.access as usize),
+                si_value: value.unwrap_or(0),
+            };
+
             sp -= mem::size_of::<usize>();
             *(sp as *mut usize) = restorer;
 
@@ -1,23 +1,117 @@
 use core::cell::Cell;
+use core::cmp;
 use core::ops::Bound;
-use core::sync::atomic::Ordering;
+use core::str;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 
-use spin::RwLock;
+use spin::{RwLock, RwLockReadGuard};
 
 use crate::context::signal::signal_handler;
-use crate::context::{arch, contexts, Context, Status, CONTEXT_ID};
+use crate::context::{arch, contexts, deadline, Context, ContextId, Status, CONTEXT_ID};
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use crate::gdt;
 use crate::interrupt::irq::PIT_TICKS;
 use crate::interrupt;
+use crate::log::info;
 use crate::ptrace;
-use crate::time;
+
+/// Number of timer ticks a context may run for before it is preempted, unless it blocks or yields
+/// earlier
+pub const SCHED_QUANTUM_TICKS_DEFAULT: usize = 3;
+/// Upper bound on the quantum, so that CPU-bound contexts cannot delay their peers indefinitely
+pub const SCHED_QUANTUM_TICKS_MAX: usize = 25;
+
+static SCHED_QUANTUM_TICKS: AtomicUsize = AtomicUsize::new(SCHED_QUANTUM_TICKS_DEFAULT);
+
+pub fn sched_quantum_ticks() -> usize {
+    SCHED_QUANTUM_TICKS.load(Ordering::Relaxed)
+}
+
+/// Set the scheduler quantum, clamped to `1..=SCHED_QUANTUM_TICKS_MAX`. Returns the value applied.
+pub fn set_sched_quantum_ticks(ticks: usize) -> usize {
+    let ticks = ticks.max(1).min(SCHED_QUANTUM_TICKS_MAX);
+    SCHED_QUANTUM_TICKS.store(ticks, Ordering::Relaxed);
+    ticks
+}
+
+/// Read `SCHED_QUANTUM_TICKS` from the boot environment, if present
+pub fn init_sched_quantum(env: &[u8]) {
+    for line in str::from_utf8(env).unwrap_or("").lines() {
+        let mut parts = line.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let value = parts.next().unwrap_or("");
+
+        if name == "SCHED_QUANTUM_TICKS" {
+            if let Ok(ticks) = value.parse::<usize>() {
+                let applied = set_sched_quantum_ticks(ticks);
+                info!("Scheduler quantum: {} ticks", applied);
+            }
+        }
+    }
+}
+
+/// Most favorable nice value
+pub const PRIO_MIN: i32 = -20;
+/// Least favorable nice value
+pub const PRIO_MAX: i32 = 19;
+
+/// Quantum of the context running on this CPU, chosen according to its priority when switched to
+#[thread_local]
+static CURRENT_QUANTUM_TICKS: AtomicUsize = AtomicUsize::new(SCHED_QUANTUM_TICKS_DEFAULT);
+
+/// The quantum for a context with the nice value `priority`. The default quantum is scaled
+/// linearly, from twice as long at `PRIO_MIN` to a single tick at `PRIO_MAX`, so that favored
+/// contexts run for longer without starving the others.
+fn quantum_ticks(priority: i32) -> usize {
+    let base = sched_quantum_ticks();
+    let weight = (PRIO_MAX + 1 - priority.max(PRIO_MIN).min(PRIO_MAX)) as usize;
+    (base * weight / (PRIO_MAX + 1) as usize).max(1).min(SCHED_QUANTUM_TICKS_MAX)
+}
+
+/// Account a timer tick to the running context, returning true if its quantum has been used up
+/// and it should be preempted.
+///
+/// The tick counter is reset on every switch, so each context receives a full quantum regardless
+/// of how much of it the previous one used, and equal-priority contexts are served round-robin.
+pub fn tick() -> bool {
+    let ticks = PIT_TICKS.fetch_add(1, Ordering::SeqCst) + 1;
+    PREEMPT_PENDING.swap(false, Ordering::Relaxed) || ticks >= CURRENT_QUANTUM_TICKS.load(Ordering::Relaxed)
+}
+
+/// Set when a low latency context becomes runnable on this CPU, to switch on the next tick even if
+/// the running context has quantum left
+#[thread_local]
+static PREEMPT_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Preempt the running context on the next tick, so that a low latency context that became
+/// runnable on this CPU is switched to. Switching right away is not possible, as the caller holds
+/// the context lock.
+pub fn request_preempt() {
+    PREEMPT_PENDING.store(true, Ordering::Relaxed);
+}
+
+/// Number of consecutive switches to low latency contexts, after which the next context is chosen
+/// round-robin again, so that normal contexts are not starved
+pub const LOW_LATENCY_BURST: usize = 4;
+
+/// Consecutive switches to low latency contexts on this CPU
+#[thread_local]
+static LOW_LATENCY_STREAK: AtomicUsize = AtomicUsize::new(0);
+
+/// Most contexts that an idle CPU steals from the busiest other CPU in one switch
+pub const STEAL_MAX: usize = 2;
+/// Nanoseconds after being stolen during which a context is not stolen again, so that it does not
+/// bounce between CPUs
+pub const STEAL_COOLDOWN: u128 = 50_000_000;
+/// CPUs that can be stolen from, by ID
+const STEAL_CPUS: usize = 256;
 
 unsafe fn update(context: &mut Context, cpu_id: usize) {
-    // Take ownership if not already owned
-    if context.cpu_id == None {
+    // Take ownership if not already owned, and if the affinity allows it
+    if context.cpu_id == None && context.allows_cpu(cpu_id) {
         context.cpu_id = Some(cpu_id);
         // println!("{}: take {} {}", cpu_id, context.id, *context.name.read());
     }
@@ -47,20 +141,27 @@ unsafe fn update(context: &mut Context, cpu_id: usize) {
         context.unblock();
     }
 
-    // Unblock when there are pending signals
-    if context.status == Status::Blocked && !context.pending.is_empty() {
+    // Unblock when there are pending signals, unless a vfork child is using the address space.
+    // SIGKILL always unblocks, and the vfork wait gives up when it sees it.
+    if context.status == Status::Blocked && (! context.vfork_wait || context.pending.has_kill()) && context.pending.has_deliverable(&context.sigmask) {
         context.unblock();
     }
+}
 
-    // Wake from sleep
-    if context.status == Status::Blocked && context.wake.is_some() {
-        let wake = context.wake.expect("context::switch: wake not set");
-
-        let current = time::monotonic();
-        if current >= wake {
-            context.wake = None;
-            context.unblock();
-        }
+/// Wake a context whose sleep deadline `deadline` has passed, returning whether the deadline has
+/// been handled. A context that is not blocked at the moment keeps the deadline pending, so that
+/// it still fires if the context blocks again before clearing it.
+fn wake(context: &mut Context, deadline: u128) -> bool {
+    // The context may have changed its deadline after it was queued
+    if context.wake() != Some(deadline) {
+        return true;
+    }
+    if context.status == Status::Blocked {
+        context.expire_wake();
+        context.unblock();
+        true
+    } else {
+        false
     }
 }
 
@@ -83,9 +184,94 @@ pub unsafe extern "C" fn switch_finish_hook() {
 #[thread_local]
 static SWITCH_RESULT: Cell<Option<SwitchResult>> = Cell::new(None);
 
+/// Scheduler statistics of a single CPU. Only the CPU itself updates them, from `switch`.
+pub struct CpuStats {
+    /// Number of switches to another context
+    pub switches: AtomicUsize,
+    /// Nanoseconds spent in the idle context of the CPU
+    pub idle_time: AtomicU64,
+    /// Nanoseconds spent in any other context
+    pub busy_time: AtomicU64,
+    /// Number of contexts waiting to run on the CPU, as of the last call to `switch`
+    pub run_queue: AtomicUsize,
+    /// Sum of `run_queue` over all calls to `switch`, for averaging
+    pub run_queue_sum: AtomicU64,
+    /// Number of calls to `switch`
+    pub samples: AtomicU64,
+    /// Number of contexts stolen from other CPUs while idle
+    pub stolen: AtomicUsize,
+}
+
+impl CpuStats {
+    const fn new() -> Self {
+        CpuStats {
+            switches: AtomicUsize::new(0),
+            idle_time: AtomicU64::new(0),
+            busy_time: AtomicU64::new(0),
+            run_queue: AtomicUsize::new(0),
+            run_queue_sum: AtomicU64::new(0),
+            samples: AtomicU64::new(0),
+            stolen: AtomicUsize::new(0),
+        }
+    }
+}
+
+#[thread_local]
+static CPU_STATS: CpuStats = CpuStats::new();
+
+/// The context each CPU runs when there is nothing else to do, i.e. the one it booted in
+#[thread_local]
+static IDLE_CONTEXT: Cell<Option<ContextId>> = Cell::new(None);
+
+/// The statistics of every CPU, by CPU ID, so that they can be read from other CPUs
+static CPU_STATS_LIST: RwLock<BTreeMap<usize, &'static CpuStats>> = RwLock::new(BTreeMap::new());
+
+/// Register the statistics of the current CPU, which idles in the context `idle`
+pub fn init_cpu_stats(idle: ContextId) {
+    IDLE_CONTEXT.set(Some(idle));
+    // The CPU never stops, so its thread-local statistics outlive any reader
+    let stats = unsafe { &*(&CPU_STATS as *const CpuStats) };
+    CPU_STATS_LIST.write().insert(crate::cpu_id(), stats);
+}
+
+pub fn cpu_stats() -> RwLockReadGuard<'static, BTreeMap<usize, &'static CpuStats>> {
+    CPU_STATS_LIST.read()
+}
+
 unsafe fn runnable(context: &Context, cpu_id: usize) -> bool {
-    // Switch to context if it needs to run, is not currently running, and is owned by the current CPU
-    !context.running && !context.ptrace_stop && context.status == Status::Runnable && context.cpu_id == Some(cpu_id)
+    // Switch to context if it needs to run, is not currently running, has been started, has a
+    // kernel stack, and is owned by the current CPU
+    !context.running && !context.ptrace_stop && context.status == Status::Runnable
+        && context.kstack_entry.is_none() && context.cpu_id == Some(cpu_id)
+}
+
+/// Allocate the kernel stacks of spawned contexts that are ready to run for the first time. The
+/// scheduler may run from the timer interrupt, where the interrupted code can hold the heap lock,
+/// so it passes over such contexts, and the idle loop of each CPU calls this before switching.
+/// A stack that cannot be allocated is retried by the next call.
+pub fn alloc_kstacks() {
+    for (_pid, context_lock) in contexts().iter() {
+        if context_lock.read().kstack_entry.is_none() {
+            continue;
+        }
+        let mut context = context_lock.write();
+        if context.status != Status::Runnable || context.ptrace_stop {
+            continue;
+        }
+        if context.alloc_kstack().is_err() {
+            log::warn!("context::alloc_kstacks: failed to allocate kernel stack for {}", context.id.into());
+        }
+    }
+}
+
+/// Whether the CPU `cpu_id` may steal the context, which is waiting to run on another CPU
+fn stealable(context: &Context, cpu_id: usize, now: u128) -> bool {
+    // Kernel contexts, such as the idle context of each CPU, stay where they are
+    context.addr_space.is_some()
+        && !context.running && !context.ptrace_stop && context.status == Status::Runnable
+        && context.cpu_id.map_or(false, |owner| owner != cpu_id)
+        && context.allows_cpu(cpu_id)
+        && context.stolen_at.map_or(true, |stolen_at| now.saturating_sub(stolen_at) >= STEAL_COOLDOWN)
 }
 
 /// Switch to the next context
@@ -96,7 +282,7 @@ unsafe fn runnable(context: &Context, cpu_id: usize) -> bool {
 pub unsafe fn switch() -> bool {
     // TODO: Better memory orderings?
     //set PIT Interrupt counter to 0, giving each process same amount of PIT ticks
-    let _ticks = PIT_TICKS.swap(0, Ordering::SeqCst);
+    let ticks = PIT_TICKS.swap(0, Ordering::SeqCst);
 
     // Set the global lock to avoid the unsafe operations below from causing issues
     while arch::CONTEXT_SWITCH_LOCK.compare_exchange_weak(false, true, Ordering::SeqCst, Ordering::Relaxed).is_err() {
@@ -110,6 +296,13 @@ pub unsafe fn switch() -> bool {
     let mut from_context_guard;
     let mut to_context_lock: Option<(Arc<spin::RwLock<Context>>, *mut Context)> = None;
     let mut to_sig = None;
+    let mut run_queue = 0;
+    // Contexts that could be stolen, by the CPU they are waiting on
+    let mut stealable_on = [0u16; STEAL_CPUS];
+    // The low latency context to prefer, the first after the current one in round-robin order
+    let prefer_low_latency = LOW_LATENCY_STREAK.load(Ordering::Relaxed) < LOW_LATENCY_BURST;
+    let mut low_latency_after = None;
+    let mut low_latency_before = None;
     {
         let contexts = contexts();
         {
@@ -118,6 +311,20 @@ pub unsafe fn switch() -> bool {
                 .expect("context::switch: not inside of context"));
             from_context_guard = from_context_lock.write();
         }
+        let from_id = from_context_guard.id;
+
+        for &(deadline, id) in deadline::expired(switch_time).iter().flatten() {
+            let handled = if id == from_id {
+                wake(&mut from_context_guard, deadline)
+            } else if let Some(context_lock) = contexts.get(id) {
+                wake(&mut context_lock.write(), deadline)
+            } else {
+                true
+            };
+            if handled {
+                deadline::remove_handled(deadline, id);
+            }
+        }
 
         for (pid, context_lock) in contexts.iter() {
             let mut context;
@@ -128,32 +335,89 @@ pub unsafe fn switch() -> bool {
                 &mut *context
             };
             update(context_ref, cpu_id);
+            if runnable(context_ref, cpu_id) {
+                run_queue += 1;
+
+                if context_ref.low_latency {
+                    if *pid > from_id {
+                        low_latency_after.get_or_insert(*pid);
+                    } else {
+                        low_latency_before.get_or_insert(*pid);
+                    }
+                }
+            } else if stealable(context_ref, cpu_id, switch_time) {
+                if let Some(count) = context_ref.cpu_id.and_then(|owner| stealable_on.get_mut(owner)) {
+                    *count = count.saturating_add(1);
+                }
+            }
         }
 
-        for (_pid, context_lock) in contexts
-            // Include all contexts with IDs greater than the current...
-            .range(
-                (Bound::Excluded(from_context_guard.id), Bound::Unbounded)
-            )
-            .chain(contexts
-                // ... and all contexts with IDs less than the current...
-                .range((Bound::Unbounded, Bound::Excluded(from_context_guard.id)))
-            )
-            // ... but not the current context, which is already locked
-        {
-            let context_lock = Arc::clone(context_lock);
-            let mut to_context_guard = context_lock.write();
+        // With nothing else to run, take over some of the contexts waiting on the busiest CPU. At
+        // most half of them are taken, rounded up, so that the load is split rather than moved.
+        let busiest = stealable_on.iter().enumerate().max_by_key(|&(_, &count)| count).filter(|&(_, &count)| count > 0);
+        if let (0, Some((victim, &count))) = (run_queue, busiest) {
+            let limit = cmp::min(STEAL_MAX, (usize::from(count) + 1) / 2);
+            let mut stolen = 0;
+            for (pid, context_lock) in contexts.iter() {
+                if stolen == limit {
+                    break;
+                }
+                if *pid == from_id {
+                    continue;
+                }
+                let mut context = context_lock.write();
+                if context.cpu_id == Some(victim) && stealable(&context, cpu_id, switch_time) {
+                    context.cpu_id = Some(cpu_id);
+                    context.stolen_at = Some(switch_time);
+                    stolen += 1;
+                }
+            }
+            run_queue += stolen;
+            CPU_STATS.stolen.fetch_add(stolen, Ordering::Relaxed);
+        }
 
-            if runnable(&*to_context_guard, cpu_id) {
-                if to_context_guard.ksig.is_none() {
-                    to_sig = to_context_guard.pending.pop_front();
+        CPU_STATS.run_queue.store(run_queue, Ordering::Relaxed);
+        CPU_STATS.run_queue_sum.fetch_add(run_queue as u64, Ordering::Relaxed);
+        CPU_STATS.samples.fetch_add(1, Ordering::Relaxed);
+
+        // Try the preferred low latency context first, and fall back to round-robin if there is
+        // none or it is no longer runnable
+        let preferred = low_latency_after.or(low_latency_before).filter(|_| prefer_low_latency);
+
+        'select: for only in preferred.into_iter().map(Some).chain(Some(None)) {
+            for (pid, context_lock) in contexts
+                // Include all contexts with IDs greater than the current...
+                .range(
+                    (Bound::Excluded(from_context_guard.id), Bound::Unbounded)
+                )
+                .chain(contexts
+                    // ... and all contexts with IDs less than the current...
+                    .range((Bound::Unbounded, Bound::Excluded(from_context_guard.id)))
+                )
+                // ... but not the current context, which is already locked
+            {
+                if only.map_or(false, |only| only != *pid) {
+                    continue;
+                }
+
+                let context_lock = Arc::clone(context_lock);
+                let mut to_context_guard = context_lock.write();
+
+                if runnable(&*to_context_guard, cpu_id) {
+                    if to_context_guard.ksig.is_none() {
+                        let sigmask = to_context_guard.sigmask;
+                        if let Some((sig, value)) = to_context_guard.pending.pop(&sigmask) {
+                            to_context_guard.sigval = value;
+                            to_sig = Some(sig);
+                        }
+                    }
+                    let ptr: *mut Context = &mut *to_context_guard;
+                    core::mem::forget(to_context_guard);
+                    to_context_lock = Some((context_lock, ptr));
+                    break 'select;
+                } else {
+                    continue;
                 }
-                let ptr: *mut Context = &mut *to_context_guard;
-                core::mem::forget(to_context_guard);
-                to_context_lock = Some((context_lock, ptr));
-                break;
-            } else {
-                continue;
             }
         }
     };
@@ -164,11 +428,33 @@ pub unsafe fn switch() -> bool {
 
         // Set old context as not running and update CPU time
         from_context_guard.running = false;
-        from_context_guard.cpu_time += switch_time.saturating_sub(from_context_guard.switch_time);
+        let elapsed = switch_time.saturating_sub(from_context_guard.switch_time);
+        from_context_guard.cpu_time += elapsed;
+
+        if IDLE_CONTEXT.get() == Some(from_context_guard.id) {
+            CPU_STATS.idle_time.fetch_add(elapsed as u64, Ordering::Relaxed);
+        } else {
+            CPU_STATS.busy_time.fetch_add(elapsed as u64, Ordering::Relaxed);
+        }
+        CPU_STATS.switches.fetch_add(1, Ordering::Relaxed);
+
+        // A context that is still runnable and has used up its quantum was preempted by the timer.
+        // Anything else, i.e. blocking, stopping, exiting or yielding, is a voluntary switch.
+        if from_context_guard.status == Status::Runnable && ticks >= CURRENT_QUANTUM_TICKS.load(Ordering::Relaxed) {
+            from_context_guard.rusage.involuntary_switches += 1;
+        } else {
+            from_context_guard.rusage.voluntary_switches += 1;
+        }
 
         // Set new context as running and set switch time
         to_context.running = true;
         to_context.switch_time = switch_time;
+        CURRENT_QUANTUM_TICKS.store(quantum_ticks(to_context.priority), Ordering::Relaxed);
+        if to_context.low_latency {
+            LOW_LATENCY_STREAK.fetch_add(1, Ordering::Relaxed);
+        } else {
+            LOW_LATENCY_STREAK.store(0, Ordering::Relaxed);
+        }
 
         #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
         {
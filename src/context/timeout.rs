@@ -39,6 +39,11 @@ pub fn register(scheme_id: SchemeId, event_id: usize, clock: usize, time: TimeSp
     });
 }
 
+/// Remove the timeouts of `event_id` on `scheme_id` that have not fired yet
+pub fn cancel(scheme_id: SchemeId, event_id: usize) {
+    registry().retain(|timeout| timeout.scheme_id != scheme_id || timeout.event_id != event_id);
+}
+
 pub fn trigger() {
     let mut registry = registry();
 
@@ -51,7 +51,7 @@ pub unsafe fn debugger(target_id: Option<crate::context::ContextId>) {
                 println!("stack: {:>016x}", sp);
                 //Maximum 64 usizes
                 for _ in 0..64 {
-                    if context.addr_space.as_ref().map_or(false, |space| space.read().table.utable.translate(crate::paging::VirtualAddress::new(sp)).is_some()) {
+                    if context.addr_space.as_ref().map_or(false, |space| space.read().table.translate(crate::paging::VirtualAddress::new(sp)).is_some()) {
                         let value = *(sp as *const usize);
                         println!("    {:>016x}: {:>016x}", sp, value);
                         if let Some(next_sp) = sp.checked_add(core::mem::size_of::<usize>()) {
@@ -125,7 +125,7 @@ pub unsafe fn debugger(target_id: Option<crate::context::ContextId>) {
             println!("stack: {:>08x}", sp);
             //Maximum 64 dwords
             for _ in 0..64 {
-                if context.addr_space.as_ref().map_or(false, |space| space.read().table.utable.translate(crate::paging::VirtualAddress::new(sp)).is_some()) {
+                if context.addr_space.as_ref().map_or(false, |space| space.read().table.translate(crate::paging::VirtualAddress::new(sp)).is_some()) {
                     let value = *(sp as *const usize);
                     println!("    {:>08x}: {:>08x}", sp, value);
                     if let Some(next_sp) = sp.checked_add(core::mem::size_of::<usize>()) {
@@ -198,7 +198,7 @@ pub unsafe fn debugger(target_id: Option<crate::context::ContextId>) {
             println!("stack: {:>016x}", rsp);
             //Maximum 64 qwords
             for _ in 0..64 {
-                if context.addr_space.as_ref().map_or(false, |space| space.read().table.utable.translate(crate::paging::VirtualAddress::new(rsp)).is_some()) {
+                if context.addr_space.as_ref().map_or(false, |space| space.read().table.translate(crate::paging::VirtualAddress::new(rsp)).is_some()) {
                     let value = *(rsp as *const usize);
                     println!("    {:>016x}: {:>016x}", rsp, value);
                     if let Some(next_rsp) = rsp.checked_add(core::mem::size_of::<usize>()) {
@@ -242,6 +242,10 @@ pub unsafe fn check_consistency(addr_space: &mut crate::context::memory::AddrSpa
             };
 
             for p2i in 0..512 {
+                // Huge pages have no page table below them
+                if huge::translate(&addr_space.table.utable, VirtualAddress::new((p2i << 21) | (p3i << 30) | (p4i << 39))).is_some() {
+                    continue;
+                }
                 let p1 = match p2.next(p2i) {
                     Some(p1) => p1,
                     None => continue,
@@ -276,7 +280,7 @@ pub unsafe fn check_consistency(addr_space: &mut crate::context::memory::AddrSpa
 
     for grant in addr_space.grants.iter() {
         for page in grant.pages() {
-            let _entry = match addr_space.table.utable.translate(page.start_address()) {
+            let _entry = match addr_space.table.translate(page.start_address()) {
                 Some(e) => e,
                 None => {
                     log::error!("GRANT AT {:?} LACKING MAPPING AT PAGE {:p}", grant.region(), page.start_address().data() as *const u8);
@@ -12,6 +12,10 @@ use crate::syscall::flag::EventFlags;
 
 int_like!(EventQueueId, AtomicEventQueueId, usize, AtomicUsize);
 
+/// Hangup, reported on descriptors whose scheme has been unmounted
+// TODO: Move to syscall::flag
+pub const EVENT_HUP: EventFlags = unsafe { EventFlags::from_bits_unchecked(4) };
+
 pub struct EventQueue {
     id: EventQueueId,
     queue: WaitQueue<Event>,
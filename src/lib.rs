@@ -88,6 +88,9 @@ pub use crate::consts::*;
 /// Shared data structures
 pub mod common;
 
+/// Kernel command line
+pub mod cmdline;
+
 /// Architecture-dependent stuff
 #[macro_use]
 pub mod arch;
@@ -134,9 +137,16 @@ pub mod panic;
 /// Process tracing
 pub mod ptrace;
 
+/// Kernel random number generator
+pub mod rand;
+
 /// Schemes, filesystem handlers
 pub mod scheme;
 
+/// Kernel symbols, for stack traces
+#[cfg(not(feature="doc"))]
+pub mod symbols;
+
 /// Synchronization primitives
 pub mod sync;
 
@@ -163,6 +173,10 @@ pub fn cpu_id() -> usize {
     CPU_ID.load(Ordering::Relaxed)
 }
 
+/// Upper bound on the number of CPUs, and on their IDs. On x86, CPU IDs are the 8-bit local APIC
+/// IDs listed in the MADT.
+pub const MAX_CPU_COUNT: usize = 256;
+
 /// The count of all CPUs that can have work scheduled
 static CPU_COUNT : AtomicUsize = AtomicUsize::new(0);
 
@@ -201,6 +215,8 @@ pub fn kmain(cpus: usize, bootstrap: Bootstrap) -> ! {
     info!("BSP: {:?} {}", pid, cpus);
     info!("Env: {:?}", ::core::str::from_utf8(bootstrap.env));
 
+    context::init_sched_quantum(bootstrap.env);
+
     BOOTSTRAP.call_once(|| bootstrap);
 
     match context::contexts_mut().spawn(userspace_init) {
@@ -219,6 +235,7 @@ pub fn kmain(cpus: usize, bootstrap: Bootstrap) -> ! {
     loop {
         unsafe {
             interrupt::disable();
+            context::alloc_kstacks();
             if context::switch() {
                 interrupt::enable_and_nop();
             } else {
@@ -243,6 +260,7 @@ pub fn kmain_ap(id: usize) -> ! {
         loop {
             unsafe {
                 interrupt::disable();
+                context::alloc_kstacks();
                 if context::switch() {
                     interrupt::enable_and_nop();
                 } else {
@@ -2,6 +2,7 @@
 //! Some code was borrowed from [Phil Opp's Blog](http://os.phil-opp.com/allocating-frames.html)
 
 use core::cmp;
+use spin::Mutex;
 
 use crate::arch::rmm::LockedAllocator;
 pub use crate::paging::{PAGE_SIZE, PhysicalAddress};
@@ -13,6 +14,9 @@ use rmm::{
 use crate::syscall::flag::{PartialAllocStrategy, PhysallocFlags};
 use crate::syscall::error::{ENOMEM, Error};
 
+/// Memory locality
+pub mod numa;
+
 /// A memory map area
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(packed)]
@@ -26,25 +30,182 @@ pub struct MemoryArea {
 /// Get the number of frames available
 pub fn free_frames() -> usize {
     unsafe {
-        LockedAllocator.usage().free().data()
+        LockedAllocator.usage().free().data() + FRAME_POOL.lock().count
     }
 }
 
 /// Get the number of frames used
 pub fn used_frames() -> usize {
     unsafe {
-        LockedAllocator.usage().used().data()
+        LockedAllocator.usage().used().data().saturating_sub(FRAME_POOL.lock().count)
     }
 }
 
-/// Allocate a range of frames
-pub fn allocate_frames(count: usize) -> Option<Frame> {
+fn allocate_unpooled(count: usize) -> Option<Frame> {
     unsafe {
         LockedAllocator.allocate(FrameCount::new(count)).map(|phys| {
             Frame::containing_address(PhysicalAddress::new(phys.data()))
         })
     }
 }
+
+/// Allocate a range of frames
+pub fn allocate_frames(count: usize) -> Option<Frame> {
+    // Frames set aside for hinted allocations are still free. A single one can be used as is, but
+    // a range may only be found once they are handed back.
+    allocate_unpooled(count).or_else(|| if count == 1 {
+        FRAME_POOL.lock().take(None, None)
+    } else {
+        FRAME_POOL.lock().drain();
+        allocate_unpooled(count)
+    })
+}
+
+/// Number of cache colors. Frames of different colors map to disjoint sets of a physically indexed
+/// cache, so giving consecutive pages consecutive colors avoids conflict misses between them.
+pub const FRAME_COLORS: usize = 16;
+
+/// Number of memory nodes whose frames are set aside for hinted allocations. Frames of other
+/// nodes go back to the frame allocator right away.
+const FRAME_POOL_NODES: usize = 8;
+
+/// Most frames set aside for hinted allocations at once
+const FRAME_POOL_MAX: usize = 4096;
+
+/// Single frames that were freed, kept in a free list for each memory node and cache color, so
+/// that hinted allocations can pick a frame that fits. Each free frame holds the number of the
+/// next one in its list, or zero for the last. The frames count as free in the usage, and are
+/// handed back to the frame allocator when a range cannot be allocated without them.
+struct FramePool {
+    lists: [[usize; FRAME_COLORS]; FRAME_POOL_NODES],
+    count: usize,
+}
+
+static FRAME_POOL: Mutex<FramePool> = Mutex::new(FramePool {
+    lists: [[0; FRAME_COLORS]; FRAME_POOL_NODES],
+    count: 0,
+});
+
+impl FramePool {
+    fn link(number: usize) -> *mut usize {
+        (number * PAGE_SIZE + crate::PHYS_OFFSET) as *mut usize
+    }
+
+    /// Set the single frame `frame` aside, unless the pool is full or the frame is on a node
+    /// that is not pooled, in which case it is handed back
+    fn put(&mut self, frame: Frame) -> Option<Frame> {
+        let node = numa::frame_node(frame.start_address()) as usize;
+        if self.count >= FRAME_POOL_MAX || node >= FRAME_POOL_NODES || frame.number == 0 {
+            return Some(frame);
+        }
+        let head = &mut self.lists[node][frame.number % FRAME_COLORS];
+        unsafe { Self::link(frame.number).write(*head); }
+        *head = frame.number;
+        self.count += 1;
+        None
+    }
+
+    /// Take a frame of `node` and `color`, or of any node or color where it is `None`. The frame
+    /// is zeroed, as frames from the frame allocator are.
+    fn take(&mut self, node: Option<usize>, color: Option<usize>) -> Option<Frame> {
+        let nodes = node.map_or(0..FRAME_POOL_NODES, |node| node..node + 1);
+        let colors = color.map_or(0..FRAME_COLORS, |color| color..color + 1);
+        let number = nodes.filter(|&node| node < FRAME_POOL_NODES).find_map(|node| {
+            colors.clone().map(|color| (node, color)).find(|&(node, color)| self.lists[node][color] != 0)
+        }).map(|(node, color)| {
+            let head = &mut self.lists[node][color];
+            let number = *head;
+            *head = unsafe { Self::link(number).read() };
+            number
+        })?;
+        self.count -= 1;
+
+        unsafe { core::ptr::write_bytes(Self::link(number) as *mut u8, 0, PAGE_SIZE); }
+        Some(Frame { number })
+    }
+
+    /// Hand every frame set aside back to the frame allocator
+    fn drain(&mut self) {
+        for head in self.lists.iter_mut().flatten() {
+            while *head != 0 {
+                let number = *head;
+                *head = unsafe { Self::link(number).read() };
+                self.count -= 1;
+                unsafe {
+                    LockedAllocator.free(rmm::PhysicalAddress::new(number * PAGE_SIZE), FrameCount::new(1));
+                }
+            }
+        }
+    }
+}
+
+/// Where an allocation should preferably get its frames from. Hints are only advisory: when no
+/// frame satisfying them is free, any free frame is used instead.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameHint {
+    /// CPU the frames should be local to, as given by the memory nodes of the SRAT
+    pub cpu: Option<usize>,
+    /// Cache color of the first frame
+    pub color: Option<usize>,
+}
+
+impl FrameHint {
+    /// Frames local to the current CPU, which is the default for allocations made on behalf of a
+    /// context
+    pub fn local() -> Self {
+        FrameHint {
+            cpu: Some(crate::cpu_id()),
+            color: None,
+        }
+    }
+
+    pub fn with_color(self, color: usize) -> Self {
+        FrameHint {
+            color: Some(color % FRAME_COLORS),
+            ..self
+        }
+    }
+}
+
+/// Allocate a range of frames, following `hint` where possible. Single frames are picked from
+/// the frames set aside by node and color, first on the node of the hinted CPU, and then on any
+/// node. Ranges, and single frames none of the set aside ones fit, come from the frame allocator.
+pub fn allocate_frames_hinted(count: usize, hint: FrameHint) -> Option<Frame> {
+    if count == 1 && (hint.cpu.is_some() || hint.color.is_some()) {
+        // CPU IDs are local APIC IDs, which is what the SRAT assigns nodes by
+        let node = hint.cpu.map(|cpu| numa::cpu_node(cpu) as usize);
+        let frame = {
+            let mut pool = FRAME_POOL.lock();
+            pool.take(node, hint.color).or_else(|| if node.is_some() { pool.take(None, hint.color) } else { None })
+        };
+        if let Some(frame) = frame {
+            return Some(frame);
+        }
+    }
+    allocate_frames(count)
+}
+
+/// Allocate a range of frames, the first of which is at a multiple of `align` frames
+pub fn allocate_frames_aligned(count: usize, align: usize) -> Option<Frame> {
+    if align <= 1 {
+        return allocate_frames(count);
+    }
+
+    // Allocate enough to be sure an aligned range lies within, and give back the rest
+    let total = count.checked_add(align - 1)?;
+    let frame = allocate_frames(total)?;
+    let head = (align - frame.number % align) % align;
+    let tail = total - head - count;
+    let aligned = frame.next_by(head);
+    if tail > 0 {
+        deallocate_frames(frame.next_by(head + count), tail);
+    }
+    if head > 0 {
+        deallocate_frames(frame, head);
+    }
+    Some(aligned)
+}
+
 pub fn allocate_frames_complex(count: usize, flags: PhysallocFlags, strategy: Option<PartialAllocStrategy>, min: usize) -> Option<(Frame, usize)> {
     //TODO: support partial allocation
     if flags == PhysallocFlags::SPACE_64 && strategy.is_none() {
@@ -64,6 +225,14 @@ pub fn allocate_frames_complex(count: usize, flags: PhysallocFlags, strategy: Op
 
 /// Deallocate a range of frames frame
 pub fn deallocate_frames(frame: Frame, count: usize) {
+    let frame = if count == 1 {
+        match FRAME_POOL.lock().put(frame) {
+            Some(frame) => frame,
+            None => return,
+        }
+    } else {
+        frame
+    };
     unsafe {
         LockedAllocator.free(
             rmm::PhysicalAddress::new(frame.start_address().data()),
@@ -0,0 +1,57 @@
+//! Memory locality, as the node each range of physical memory and each CPU belongs to.
+//!
+//! The map is empty on systems that do not describe their topology, such as single-node systems,
+//! in which case everything is on node 0.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use spin::RwLock;
+
+use super::PhysicalAddress;
+
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryAffinity {
+    pub base: PhysicalAddress,
+    pub size: usize,
+    pub node: u32,
+}
+
+static MEMORY: RwLock<Vec<MemoryAffinity>> = RwLock::new(Vec::new());
+/// Node of each CPU, by local APIC ID, which need not be contiguous
+static CPUS: RwLock<BTreeMap<usize, u32>> = RwLock::new(BTreeMap::new());
+
+/// Record that the physical memory starting at `base` belongs to `node`
+pub fn add_memory(base: PhysicalAddress, size: usize, node: u32) {
+    MEMORY.write().push(MemoryAffinity { base, size, node });
+}
+
+/// Record that the CPU with the local APIC ID `apic_id` belongs to `node`
+pub fn add_cpu(apic_id: usize, node: u32) {
+    CPUS.write().insert(apic_id, node);
+}
+
+/// Node of the frame at `address`
+pub fn frame_node(address: PhysicalAddress) -> u32 {
+    MEMORY.read().iter()
+        .find(|area| address.data().wrapping_sub(area.base.data()) < area.size)
+        .map_or(0, |area| area.node)
+}
+
+/// Node of the CPU with the local APIC ID `apic_id`
+pub fn cpu_node(apic_id: usize) -> u32 {
+    CPUS.read().get(&apic_id).copied().unwrap_or(0)
+}
+
+/// List the node of every CPU, by local APIC ID, and of every memory range, one per line
+pub fn resource() -> Vec<u8> {
+    use core::fmt::Write;
+
+    let mut string = alloc::string::String::new();
+    for (apic_id, node) in CPUS.read().iter() {
+        let _ = writeln!(string, "APIC {}: node {}", apic_id, node);
+    }
+    for area in MEMORY.read().iter() {
+        let _ = writeln!(string, "{:#x}-{:#x}: node {}", area.base.data(), area.base.data() + area.size, area.node);
+    }
+    string.into_bytes()
+}
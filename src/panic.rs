@@ -1,7 +1,9 @@
 //! Intrinsics for panic handling
 
 use core::alloc::Layout;
+use core::fmt::Write;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::{cpu_id, context, interrupt, syscall};
 
@@ -9,10 +11,22 @@ use crate::{cpu_id, context, interrupt, syscall};
 #[no_mangle]
 pub extern "C" fn rust_eh_personality() {}
 
+/// How deeply the panic handler is nested on this CPU. Other CPUs can still report their own
+/// panics while one is halting.
+#[thread_local]
+static PANIC_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
 /// Required to handle panics
 #[panic_handler]
 #[no_mangle]
 pub extern "C" fn rust_begin_unwind(info: &PanicInfo) -> ! {
+    match PANIC_DEPTH.fetch_add(1, Ordering::Relaxed) {
+        0 => (),
+        1 => nested_panic(info),
+        // Even the message of the nested panic could not be printed
+        _ => halt(),
+    }
+
     println!("KERNEL PANIC: {}", info);
 
     unsafe { interrupt::stack_trace(); }
@@ -33,6 +47,21 @@ pub extern "C" fn rust_begin_unwind(info: &PanicInfo) -> ! {
     }
 
     println!("HALT");
+    halt()
+}
+
+/// Report a panic raised while handling another one, and halt. Nothing is done that could have
+/// caused the first panic to fail again, and as it may have been interrupted while holding the
+/// locks of the debug outputs, the message is only printed if these can be taken right away.
+fn nested_panic(info: &PanicInfo) -> ! {
+    if let Some(mut writer) = crate::arch::debug::Writer::try_new() {
+        let _ = writeln!(writer, "KERNEL PANIC WHILE PANICKING: {}", info);
+        let _ = writeln!(writer, "HALT");
+    }
+    halt()
+}
+
+fn halt() -> ! {
     loop {
         unsafe { interrupt::halt(); }
     }
@@ -35,6 +35,17 @@ use alloc::{
 use core::cmp;
 use spin::{Mutex, Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
+/// Written to a trace handle together with the breakpoint flags, to report the
+/// entry (`PTRACE_STOP_PRE_SYSCALL` with the number and arguments) and exit
+/// (`PTRACE_STOP_POST_SYSCALL` with the number and return value) of every
+/// syscall as events, without stopping the tracee
+// TODO: Move to syscall::flag
+pub const PTRACE_FLAG_TRACE_SYSCALLS: u64 = 1 << 63;
+
+/// Most syscall events queued for a tracer. A tracee that has more to report
+/// is stopped until the tracer reads some.
+const SYSCALL_EVENTS_MAX: usize = 256;
+
 //  ____                _
 // / ___|  ___  ___ ___(_) ___  _ __  ___
 // \___ \ / _ \/ __/ __| |/ _ \| '_ \/ __|
@@ -46,6 +57,8 @@ pub struct SessionData {
     breakpoint: Option<Breakpoint>,
     events: VecDeque<PtraceEvent>,
     file_id: usize,
+    /// Report every syscall entry and exit, regardless of the breakpoint
+    trace_syscalls: bool,
 }
 impl SessionData {
     fn add_event(&mut self, event: PtraceEvent) {
@@ -67,6 +80,13 @@ impl SessionData {
         });
     }
 
+    /// Enable or disable reporting every syscall of the tracee. This stays in
+    /// effect until changed, unlike breakpoints which have to be set again
+    /// after each stop.
+    pub fn set_trace_syscalls(&mut self, trace_syscalls: bool) {
+        self.trace_syscalls = trace_syscalls;
+    }
+
     /// Returns true if the breakpoint is reached, or if there isn't a
     /// breakpoint
     pub fn is_reached(&self) -> bool {
@@ -96,9 +116,14 @@ impl SessionData {
 
 #[derive(Debug)]
 pub struct Session {
+    /// The context which opened the session
+    pub tracer_id: ContextId,
     pub data: Mutex<SessionData>,
     pub tracee: WaitCondition,
     pub tracer: WaitCondition,
+    /// Notified when the tracer reads events, for a tracee waiting for room
+    /// in the queue. Separate from `tracee`, which resumes from breakpoints.
+    pub room: WaitCondition,
 }
 impl Session {
     pub fn with_session<F, T>(pid: ContextId, callback: F) -> Result<T>
@@ -139,13 +164,16 @@ pub fn try_new_session(pid: ContextId, file_id: usize) -> bool {
         Entry::Occupied(_) => false,
         Entry::Vacant(vacant) => {
             vacant.insert(Arc::new(Session {
+                tracer_id: context::context_id(),
                 data: Mutex::new(SessionData {
                     breakpoint: None,
                     events: VecDeque::new(),
                     file_id,
+                    trace_syscalls: false,
                 }),
                 tracee: WaitCondition::new(),
                 tracer: WaitCondition::new(),
+                room: WaitCondition::new(),
             }));
             true
         }
@@ -158,6 +186,7 @@ pub fn close_session(pid: ContextId) {
     if let Some(session) = sessions_mut().remove(&pid) {
         session.tracer.notify();
         session.tracee.notify();
+        session.room.notify();
     }
 }
 
@@ -181,6 +210,11 @@ pub fn is_traced(pid: ContextId) -> bool {
     sessions().contains_key(&pid)
 }
 
+/// Returns the context that opened the session tracing `pid`, if any
+pub fn tracer_of(pid: ContextId) -> Option<ContextId> {
+    sessions().get(&pid).map(|session| session.tracer_id)
+}
+
 /// Trigger a notification to the event: scheme
 fn proc_trigger_event(file_id: usize, flags: EventFlags) {
     if let Some(scheme_id) = proc::PROC_SCHEME_ID.get() {
@@ -218,6 +252,39 @@ pub fn send_event(event: PtraceEvent) -> Option<()> {
     Some(())
 }
 
+/// Queue a syscall entry or exit event for the tracer, if it asked for all
+/// syscalls to be traced. The tracee isn't stopped, so this is cheaper than a
+/// breakpoint and can't be missed by a tracer that isn't waiting, unless the
+/// queue is full, in which case the tracee waits for the tracer to read it.
+/// The event is dropped if the tracee is signalled meanwhile.
+///
+/// Note: Don't call while holding any locks or allocated data, this may
+/// switch contexts.
+pub fn syscall_event(event: PtraceEvent) -> Option<()> {
+    let id = context::context_id();
+
+    loop {
+        let session = Arc::clone(sessions().get(&id)?);
+        let mut data = session.data.lock();
+
+        if !data.trace_syscalls {
+            return None;
+        }
+
+        if data.events.len() < SYSCALL_EVENTS_MAX {
+            data.add_event(event);
+            session.tracer.notify();
+
+            return Some(());
+        }
+
+        session.tracer.notify();
+        if !session.room.wait(data, "ptrace::syscall_event") {
+            return None;
+        }
+    }
+}
+
 //  ____                 _                _       _
 // | __ ) _ __ ___  __ _| | ___ __   ___ (_)_ __ | |_ ___
 // |  _ \| '__/ _ \/ _` | |/ / '_ \ / _ \| | '_ \| __/ __|
@@ -261,6 +328,9 @@ pub fn wait(pid: ContextId) -> Result<()> {
         if session.tracer.wait(data, "ptrace::wait") {
             // We successfully waited, wake up!
             break;
+        } else {
+            // Don't leave the tracer stuck if it's being signalled
+            return Err(Error::new(EINTR));
         }
     }
 
@@ -308,6 +378,9 @@ pub fn breakpoint_callback(match_flags: PtraceFlags, event: Option<PtraceEvent>)
         if session.tracee.wait(data, "ptrace::breakpoint_callback") {
             // We successfully waited, wake up!
             break Some(breakpoint.flags);
+        } else if context::force_killed() {
+            // Waiting again would return right away, forever
+            break None;
         }
     }
 }
@@ -438,6 +511,46 @@ pub unsafe fn regs_for_mut(context: &mut Context) -> Option<&mut InterruptStack>
         .map(|ptr| &mut *ptr)
 }
 
+/// Safe version of `regs_for`, for a context that is not running. As long as the context is
+/// borrowed, its lock is held, so it cannot start running and change its registers in the
+/// meantime. Fails with EBUSY if the context is running, and with ENOTRECOVERABLE if it has no
+/// saved registers.
+pub fn stopped_regs(context: &Context) -> Result<&InterruptStack> {
+    if context.running {
+        return Err(Error::new(EBUSY));
+    }
+    unsafe { regs_for(context) }.ok_or(Error::new(ENOTRECOVERABLE))
+}
+
+/// Mutable version of `stopped_regs`
+pub fn stopped_regs_mut(context: &mut Context) -> Result<&mut InterruptStack> {
+    if context.running {
+        return Err(Error::new(EBUSY));
+    }
+    unsafe { regs_for_mut(context) }.ok_or(Error::new(ENOTRECOVERABLE))
+}
+
+/// Lock the context `pid` and run `callback` on its registers, see `stopped_regs`. Fails with
+/// ESRCH if the context doesn't exist.
+pub fn with_regs<F, T>(pid: ContextId, callback: F) -> Result<T>
+where
+    F: FnOnce(&InterruptStack) -> Result<T>,
+{
+    let context_lock = Arc::clone(context::contexts().get(pid).ok_or(Error::new(ESRCH))?);
+    let context = context_lock.read();
+    callback(stopped_regs(&context)?)
+}
+
+/// Mutable version of `with_regs`
+pub fn with_regs_mut<F, T>(pid: ContextId, callback: F) -> Result<T>
+where
+    F: FnOnce(&mut InterruptStack) -> Result<T>,
+{
+    let context_lock = Arc::clone(context::contexts().get(pid).ok_or(Error::new(ESRCH))?);
+    let mut context = context_lock.write();
+    callback(stopped_regs_mut(&mut context)?)
+}
+
 //  __  __
 // |  \/  | ___ _ __ ___   ___  _ __ _   _
 // | |\/| |/ _ \ '_ ` _ \ / _ \| '__| | | |
@@ -477,7 +590,7 @@ pub fn context_memory(addrspace: &mut AddrSpace, offset: VirtualAddress, len: us
         // [addr,addr+len) is a continuous page starting and/or ending at page boundaries, with the
         // possible exception of an unaligned head/tail.
 
-        let (address, flags) = addrspace.table.utable.translate(VirtualAddress::new(addr))?;
+        let (address, flags) = addrspace.table.translate(VirtualAddress::new(addr))?;
 
         let start = RmmA::phys_to_virt(address).data() + addr % crate::memory::PAGE_SIZE;
         Some((core::ptr::slice_from_raw_parts_mut(start as *mut u8, len), flags.has_write()))
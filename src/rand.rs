@@ -0,0 +1,209 @@
+//! Kernel random number generator
+//!
+//! Entropy is gathered from the hardware random number generator, if there is one, and from the
+//! jitter of the cycle counter across interrupts. It seeds a ChaCha20 generator, which is
+//! reseeded periodically and erases its key after every request, so that earlier output cannot
+//! be recovered from its state.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::arch::rand::{cycles, hw_random};
+use crate::time;
+
+/// Interrupt samples with a new cycle delta needed before the generator is seeded from them alone
+const SEED_CREDITS: usize = 256;
+/// Nanoseconds after which the generator is reseeded
+const RESEED_INTERVAL: u128 = 60 * time::NANOS_PER_SEC;
+/// Bytes after which the generator is reseeded
+const RESEED_BYTES: usize = 1024 * 1024;
+
+const ZERO: AtomicU64 = AtomicU64::new(0);
+/// Entropy pool, mixed into without locking so that interrupt handlers can add to it
+static POOL: [AtomicU64; 4] = [ZERO; 4];
+static POOL_INDEX: AtomicUsize = AtomicUsize::new(0);
+static LAST_CYCLES: AtomicU64 = AtomicU64::new(0);
+static LAST_DELTA: AtomicU64 = AtomicU64::new(0);
+/// Estimate of the entropy in the pool, in samples
+static CREDITS: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the generator has been seeded from enough entropy
+static SEEDED: AtomicBool = AtomicBool::new(false);
+/// Whether the readers waiting for enough entropy to seed the generator still have to be told
+static SEED_READY_PENDING: AtomicBool = AtomicBool::new(true);
+/// Whether random numbers were generated before seeding, which is only warned about once
+static UNSEEDED_WARNED: AtomicBool = AtomicBool::new(false);
+static RNG: Mutex<Option<ChaCha>> = Mutex::new(None);
+
+fn mix(word: &AtomicU64, value: u64) {
+    let mut old = word.load(Ordering::Relaxed);
+    loop {
+        let new = (old ^ value).rotate_left(17).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        match word.compare_exchange_weak(old, new, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => break,
+            Err(current) => old = current,
+        }
+    }
+}
+
+/// Add the time of an interrupt to the entropy pool. Only samples whose distance to the previous
+/// one changed are credited, so that a perfectly periodic timer does not count as entropy.
+pub fn add_interrupt_entropy() {
+    let now = cycles();
+    let delta = now.wrapping_sub(LAST_CYCLES.swap(now, Ordering::Relaxed));
+    if LAST_DELTA.swap(delta, Ordering::Relaxed) != delta {
+        CREDITS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let index = POOL_INDEX.fetch_add(1, Ordering::Relaxed) % POOL.len();
+    mix(&POOL[index], now);
+
+    if SEED_READY_PENDING.load(Ordering::Relaxed)
+        && CREDITS.load(Ordering::Relaxed) >= SEED_CREDITS
+        && crate::scheme::rand::seed_ready()
+    {
+        SEED_READY_PENDING.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Mix data into the entropy pool without crediting it, since its origin is unknown
+pub fn add_entropy(buf: &[u8]) {
+    for chunk in buf.chunks(8) {
+        let mut bytes = [0; 8];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        let index = POOL_INDEX.fetch_add(1, Ordering::Relaxed) % POOL.len();
+        mix(&POOL[index], u64::from_le_bytes(bytes));
+    }
+}
+
+/// Whether the generator has been seeded, seeding it now if enough entropy became available
+pub fn is_seeded() -> bool {
+    if SEEDED.load(Ordering::Acquire) {
+        return true;
+    }
+    if hw_random().is_some() || CREDITS.load(Ordering::Relaxed) >= SEED_CREDITS {
+        let mut rng = RNG.lock();
+        rng.get_or_insert_with(ChaCha::new).reseed();
+        SEEDED.store(true, Ordering::Release);
+        return true;
+    }
+    false
+}
+
+/// Fill `buf` with random bytes. This never blocks, so if the generator has not been seeded yet,
+/// it is seeded with whatever entropy has been gathered so far.
+pub fn fill(buf: &mut [u8]) {
+    if ! is_seeded() && ! UNSEEDED_WARNED.swap(true, Ordering::Relaxed) {
+        println!("rand: generating random numbers before being seeded");
+    }
+
+    let mut rng = RNG.lock();
+    let rng = rng.get_or_insert_with(|| {
+        let mut rng = ChaCha::new();
+        rng.reseed();
+        rng
+    });
+
+    if rng.generated >= RESEED_BYTES || time::monotonic() >= rng.reseeded_at + RESEED_INTERVAL {
+        rng.reseed();
+    }
+    rng.fill(buf);
+}
+
+/// ChaCha20 with fast key erasure
+struct ChaCha {
+    key: [u32; 8],
+    /// Block counter under the current key
+    counter: u64,
+    /// Bytes generated since the last reseed
+    generated: usize,
+    /// Monotonic time of the last reseed
+    reseeded_at: u128,
+}
+
+impl ChaCha {
+    fn new() -> Self {
+        ChaCha {
+            key: [0; 8],
+            counter: 0,
+            generated: 0,
+            reseeded_at: 0,
+        }
+    }
+
+    /// Mix the entropy pool and hardware randomness into the key
+    fn reseed(&mut self) {
+        for (i, word) in POOL.iter().enumerate() {
+            let value = word.load(Ordering::Relaxed);
+            self.key[2 * i] ^= value as u32;
+            self.key[2 * i + 1] ^= (value >> 32) as u32;
+        }
+        for i in 0..4 {
+            if let Some(value) = hw_random() {
+                self.key[2 * i] ^= value as u32;
+                self.key[2 * i + 1] ^= (value >> 32) as u32;
+            }
+        }
+        let now = cycles();
+        self.key[0] ^= now as u32;
+        self.key[1] ^= (now >> 32) as u32;
+
+        // Reseeds use a different nonce than output, so the new key is independent of earlier output
+        let block = block(&self.key, 0, 1);
+        self.key.copy_from_slice(&block[..8]);
+        self.counter = 0;
+        self.generated = 0;
+        self.reseeded_at = time::monotonic();
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(64) {
+            let block = block(&self.key, self.counter, 0);
+            self.counter += 1;
+            for (bytes, word) in chunk.chunks_mut(4).zip(block.iter()) {
+                bytes.copy_from_slice(&word.to_le_bytes()[..bytes.len()]);
+            }
+        }
+        self.generated = self.generated.saturating_add(buf.len());
+
+        // Replace the key, so that the output just produced cannot be reconstructed
+        let block = block(&self.key, self.counter, 0);
+        self.key.copy_from_slice(&block[..8]);
+        self.counter = 0;
+    }
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]); state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]); state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]); state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]); state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// One ChaCha20 block, with a 64 bit counter and nonce
+fn block(key: &[u32; 8], counter: u64, nonce: u64) -> [u32; 16] {
+    let mut input = [0u32; 16];
+    // "expand 32-byte k"
+    input[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+    input[14] = nonce as u32;
+    input[15] = (nonce >> 32) as u32;
+
+    let mut state = input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (word, input) in state.iter_mut().zip(input.iter()) {
+        *word = word.wrapping_add(*input);
+    }
+    state
+}
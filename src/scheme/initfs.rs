@@ -0,0 +1,339 @@
+//! A read-only filesystem image kept in memory, so that userspace can be loaded before any disk
+//! drivers exist. The bootloader passes the physical address and size of the image in the
+//! environment, as the hexadecimal `INITFS_ADDR` and `INITFS_SIZE`.
+//!
+//! The image starts with a header, followed by a table of entries. All integers are little
+//! endian, and offsets are relative to the start of the image.
+//!
+//! ```text
+//! header: magic: [u8; 8] = "KINITFS\0", entry_count: u32, reserved: u32
+//! entry:  path_offset: u32, path_len: u16, mode: u16, data_offset: u32, data_len: u32
+//! ```
+//!
+//! Paths are relative to the root and use `/` as separator. Every directory other than the root
+//! needs an entry of its own with `MODE_DIR` set, and its contents are the entries directly below
+//! it. Reading a directory yields the names of its contents, one per line. Files can only be
+//! mapped if their data starts at a page boundary within the image.
+
+use alloc::borrow::Cow;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{cmp, slice, str};
+use spin::RwLock;
+use rmm::Flusher;
+
+use crate::context;
+use crate::context::memory::{AddrSpace, Grant};
+use crate::memory::Frame;
+use crate::paging::{KernelMapper, Page, PageFlags, PhysicalAddress, VirtualAddress, PAGE_SIZE};
+use crate::paging::mapper::PageFlushAll;
+use crate::syscall::data::{Map, Stat};
+use crate::syscall::error::*;
+use crate::syscall::flag::{MapFlags, MODE_DIR, MODE_FILE, MODE_TYPE, O_ACCMODE, O_DIRECTORY, O_RDONLY, O_STAT};
+use crate::syscall::scheme::{calc_seek_offset_usize, Scheme};
+use crate::syscall::validate_region;
+
+const MAGIC: &[u8; 8] = b"KINITFS\0";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+
+struct Entry {
+    path: &'static str,
+    mode: u16,
+    data: &'static [u8],
+    /// Physical address of the data
+    phys: usize,
+}
+
+struct Handle {
+    entry: usize,
+    /// Contents of the file, or the generated listing of a directory
+    data: Cow<'static, [u8]>,
+    seek: usize,
+}
+
+pub struct InitFsScheme {
+    next_id: AtomicUsize,
+    entries: Vec<Entry>,
+    /// Physical address of the end of the image
+    end: usize,
+    handles: RwLock<BTreeMap<usize, Handle>>,
+}
+
+impl InitFsScheme {
+    pub fn new() -> Option<InitFsScheme> {
+        let mut phys = 0;
+        let mut size = 0;
+
+        for line in str::from_utf8(crate::init_env()).unwrap_or("").lines() {
+            let mut parts = line.splitn(2, '=');
+            let name = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("");
+
+            if name == "INITFS_ADDR" {
+                phys = usize::from_str_radix(value, 16).unwrap_or(0);
+            }
+
+            if name == "INITFS_SIZE" {
+                size = usize::from_str_radix(value, 16).unwrap_or(0);
+            }
+        }
+
+        if phys == 0 || size == 0 {
+            return None;
+        }
+
+        // Ensure the image is mapped, read-only
+        let virt = phys + crate::PHYS_OFFSET;
+        unsafe {
+            let mut mapper = KernelMapper::lock();
+
+            let mut flush_all = PageFlushAll::new();
+            let start_page = Page::containing_address(VirtualAddress::new(virt));
+            let end_page = Page::containing_address(VirtualAddress::new(virt + size - 1));
+            for page in Page::range_inclusive(start_page, end_page) {
+                if mapper.translate(page.start_address()).is_none() {
+                    let frame = Frame::containing_address(PhysicalAddress::new(page.start_address().data() - crate::PHYS_OFFSET));
+                    let result = mapper.get_mut().expect("expected KernelMapper not to be in use while initializing initfs scheme").map_phys(page.start_address(), frame.start_address(), PageFlags::new()).expect("failed to map initfs page");
+                    flush_all.consume(result);
+                }
+            }
+            flush_all.flush();
+        }
+
+        let image = unsafe { slice::from_raw_parts(virt as *const u8, size) };
+        match parse(image, phys) {
+            Some(entries) => Some(InitFsScheme {
+                next_id: AtomicUsize::new(0),
+                entries,
+                end: phys + size,
+                handles: RwLock::new(BTreeMap::new()),
+            }),
+            None => {
+                log::warn!("initfs image at {:X}:{:X} is invalid", phys, phys + size);
+                None
+            }
+        }
+    }
+
+    /// Names of the entries directly below the directory `path`, one per line
+    fn listing(&self, path: &str) -> Vec<u8> {
+        let mut listing = Vec::new();
+        for entry in self.entries.iter() {
+            let name = if path.is_empty() {
+                entry.path
+            } else {
+                match entry.path.strip_prefix(path).and_then(|rest| rest.strip_prefix('/')) {
+                    Some(name) => name,
+                    None => continue,
+                }
+            };
+            if name.contains('/') {
+                continue;
+            }
+            listing.extend_from_slice(name.as_bytes());
+            listing.push(b'\n');
+        }
+        listing
+    }
+
+    fn fmap_inner(&self, id: usize, addr_space: &Arc<RwLock<AddrSpace>>, map: &Map) -> Result<usize> {
+        let (phys, len) = {
+            let handles = self.handles.read();
+            let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+            // The root directory has no entry
+            let entry = self.entries.get(handle.entry).ok_or(Error::new(EISDIR))?;
+            if entry.mode & MODE_TYPE != MODE_FILE {
+                return Err(Error::new(EISDIR));
+            }
+            (entry.phys, entry.data.len())
+        };
+
+        // The image is shared by everyone mapping it, so it must never be written to
+        if map.flags.contains(MapFlags::PROT_WRITE) {
+            return Err(Error::new(EACCES));
+        }
+        if phys % PAGE_SIZE != 0 || map.offset % PAGE_SIZE != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        // The last page of a file may be partial, but must not reach past the page holding the end
+        // of the image, which is the last one mapped for it
+        let image_len = (self.end - phys + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        let mapped_len = cmp::min((len + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE, image_len);
+        if map.offset.checked_add(map.size).map_or(true, |end| end > mapped_len) {
+            return Err(Error::new(EINVAL));
+        }
+
+        let (requested_page, page_count) = validate_region(map.address, map.size)?;
+
+        let page = addr_space
+            .write()
+            .mmap((map.address != 0).then_some(requested_page), page_count, map.flags, |dst_page, flags, dst_mapper, dst_flusher| {
+                Grant::physmap(
+                    Frame::containing_address(PhysicalAddress::new(phys + map.offset)),
+                    dst_page,
+                    page_count,
+                    flags,
+                    dst_mapper,
+                    dst_flusher,
+                )
+            })?;
+
+        Ok(page.start_address().data())
+    }
+}
+
+fn read_u16(image: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(image.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn read_u32(image: &[u8], offset: usize) -> Option<usize> {
+    Some(u32::from_le_bytes(image.get(offset..offset + 4)?.try_into().ok()?) as usize)
+}
+
+/// Parse the entry table of `image`, which is located at the physical address `phys`
+fn parse(image: &'static [u8], phys: usize) -> Option<Vec<Entry>> {
+    if image.get(..MAGIC.len())? != MAGIC {
+        return None;
+    }
+    let count = read_u32(image, 8)?;
+
+    let mut entries = Vec::new();
+    for i in 0..count {
+        let offset = HEADER_SIZE.checked_add(i.checked_mul(ENTRY_SIZE)?)?;
+
+        let path_offset = read_u32(image, offset)?;
+        let path_len = usize::from(read_u16(image, offset + 4)?);
+        let mode = read_u16(image, offset + 6)?;
+        let data_offset = read_u32(image, offset + 8)?;
+        let data_len = read_u32(image, offset + 12)?;
+
+        let path = str::from_utf8(image.get(path_offset..path_offset.checked_add(path_len)?)?).ok()?;
+        let path = path.trim_matches('/');
+        if path.is_empty() {
+            return None;
+        }
+        let data = if mode & MODE_TYPE == MODE_DIR {
+            &[]
+        } else {
+            image.get(data_offset..data_offset.checked_add(data_len)?)?
+        };
+
+        entries.push(Entry {
+            path,
+            mode,
+            data,
+            phys: phys + data_offset,
+        });
+    }
+
+    Some(entries)
+}
+
+impl Scheme for InitFsScheme {
+    fn open(&self, path: &str, flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
+        if flags & O_ACCMODE != O_RDONLY && flags & O_STAT != O_STAT {
+            return Err(Error::new(EROFS));
+        }
+
+        let path = path.trim_matches('/');
+        let (entry, mode) = if path.is_empty() {
+            (usize::MAX, MODE_DIR | 0o555)
+        } else {
+            let index = self.entries.iter().position(|entry| entry.path == path).ok_or(Error::new(ENOENT))?;
+            (index, self.entries[index].mode)
+        };
+
+        let data = if mode & MODE_TYPE == MODE_DIR {
+            if flags & O_DIRECTORY != O_DIRECTORY && flags & O_STAT != O_STAT {
+                return Err(Error::new(EISDIR));
+            }
+            Cow::Owned(self.listing(path))
+        } else {
+            if flags & O_DIRECTORY == O_DIRECTORY {
+                return Err(Error::new(ENOTDIR));
+            }
+            Cow::Borrowed(self.entries[entry].data)
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.handles.write().insert(id, Handle {
+            entry,
+            data,
+            seek: 0,
+        });
+        Ok(id)
+    }
+
+    fn read(&self, id: usize, buffer: &mut [u8]) -> Result<usize> {
+        let mut handles = self.handles.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        let src = handle.data.get(handle.seek..).unwrap_or(&[]);
+        let count = core::cmp::min(buffer.len(), src.len());
+        buffer[..count].copy_from_slice(&src[..count]);
+        handle.seek += count;
+
+        Ok(count)
+    }
+
+    fn seek(&self, id: usize, pos: isize, whence: usize) -> Result<isize> {
+        let mut handles = self.handles.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        let new_offset = calc_seek_offset_usize(handle.seek, pos, whence, handle.data.len())?;
+        handle.seek = new_offset as usize;
+        Ok(new_offset)
+    }
+
+    fn fmap(&self, id: usize, map: &Map) -> Result<usize> {
+        self.fmap_inner(id, &Arc::clone(context::current()?.read().addr_space()?), map)
+    }
+
+    fn fcntl(&self, id: usize, _cmd: usize, _arg: usize) -> Result<usize> {
+        let handles = self.handles.read();
+        let _handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+
+        Ok(0)
+    }
+
+    fn fpath(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let handles = self.handles.read();
+        let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+        let path = self.entries.get(handle.entry).map_or("", |entry| entry.path);
+
+        let mut i = 0;
+        for &b in b"initfs:/".iter().chain(path.as_bytes()) {
+            if i >= buf.len() {
+                break;
+            }
+            buf[i] = b;
+            i += 1;
+        }
+
+        Ok(i)
+    }
+
+    fn fstat(&self, id: usize, stat: &mut Stat) -> Result<usize> {
+        let handles = self.handles.read();
+        let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+
+        stat.st_mode = self.entries.get(handle.entry).map_or(MODE_DIR | 0o555, |entry| entry.mode);
+        stat.st_uid = 0;
+        stat.st_gid = 0;
+        stat.st_size = handle.data.len() as u64;
+
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        self.handles.write().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
+impl crate::scheme::KernelScheme for InitFsScheme {
+    fn kfmap(&self, number: usize, addr_space: &Arc<RwLock<AddrSpace>>, map: &Map, _consume: bool) -> Result<usize> {
+        self.fmap_inner(number, addr_space, map)
+    }
+}
@@ -0,0 +1,281 @@
+//! `keyboard:` - characters typed on the PS/2 keyboard, so that there is console input before
+//! any driver runs. Scancodes of set 1, which the controller translates to by default, are
+//! turned into bytes as a terminal would send them, tracking the state of the modifier keys.
+//! The raw scancodes are still passed to `serio:0` for the userspace driver.
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::event;
+use crate::scheme::*;
+use crate::sync::WaitQueue;
+use crate::syscall::flag::{EventFlags, EVENT_READ, F_GETFL, F_SETFL, O_ACCMODE, O_NONBLOCK};
+use crate::syscall::scheme::Scheme;
+
+static SCHEME_ID: AtomicSchemeId = AtomicSchemeId::default();
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Input queue
+static INPUT: Once<WaitQueue<u8>> = Once::new();
+
+/// Initialize input queue, called if needed
+fn init_input() -> WaitQueue<u8> {
+    WaitQueue::new()
+}
+
+#[derive(Clone, Copy)]
+struct Handle {
+    flags: usize,
+}
+
+static HANDLES: Once<RwLock<BTreeMap<usize, Handle>>> = Once::new();
+
+fn init_handles() -> RwLock<BTreeMap<usize, Handle>> {
+    RwLock::new(BTreeMap::new())
+}
+
+fn handles() -> RwLockReadGuard<'static, BTreeMap<usize, Handle>> {
+    HANDLES.call_once(init_handles).read()
+}
+
+fn handles_mut() -> RwLockWriteGuard<'static, BTreeMap<usize, Handle>> {
+    HANDLES.call_once(init_handles).write()
+}
+
+/// Scancode prefix of the keys added after the original keyboard
+const EXTENDED: u8 = 0xE0;
+/// Set in the scancode when a key is released
+const RELEASED: u8 = 0x80;
+
+/// Bytes of the keys of scancode set 1 without and with shift, or 0 for modifiers and keys that
+/// type nothing
+const SET1: [(u8, u8); 0x3A] = [
+    (0, 0), (0x1B, 0x1B), (b'1', b'!'), (b'2', b'@'), (b'3', b'#'), (b'4', b'$'), (b'5', b'%'), (b'6', b'^'),
+    (b'7', b'&'), (b'8', b'*'), (b'9', b'('), (b'0', b')'), (b'-', b'_'), (b'=', b'+'), (0x7F, 0x7F), (b'\t', b'\t'),
+    (b'q', b'Q'), (b'w', b'W'), (b'e', b'E'), (b'r', b'R'), (b't', b'T'), (b'y', b'Y'), (b'u', b'U'), (b'i', b'I'),
+    (b'o', b'O'), (b'p', b'P'), (b'[', b'{'), (b']', b'}'), (b'\n', b'\n'), (0, 0), (b'a', b'A'), (b's', b'S'),
+    (b'd', b'D'), (b'f', b'F'), (b'g', b'G'), (b'h', b'H'), (b'j', b'J'), (b'k', b'K'), (b'l', b'L'), (b';', b':'),
+    (b'\'', b'"'), (b'`', b'~'), (0, 0), (b'\\', b'|'), (b'z', b'Z'), (b'x', b'X'), (b'c', b'C'), (b'v', b'V'),
+    (b'b', b'B'), (b'n', b'N'), (b'm', b'M'), (b',', b'<'), (b'.', b'>'), (b'/', b'?'), (0, 0), (b'*', b'*'),
+    (0, 0), (b' ', b' '),
+];
+
+const LEFT_CTRL: u8 = 0x1D;
+const LEFT_SHIFT: u8 = 0x2A;
+const RIGHT_SHIFT: u8 = 0x36;
+const LEFT_ALT: u8 = 0x38;
+const CAPS_LOCK: u8 = 0x3A;
+
+/// What a key typed
+enum Typed {
+    Nothing,
+    Byte(u8),
+    Sequence(&'static [u8]),
+}
+
+/// State of the modifier keys, and of the scancode being received
+struct Keyboard {
+    extended: bool,
+    left_shift: bool,
+    right_shift: bool,
+    ctrl: bool,
+    alt: bool,
+    caps_lock: bool,
+}
+
+static KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard {
+    extended: false,
+    left_shift: false,
+    right_shift: false,
+    ctrl: false,
+    alt: false,
+    caps_lock: false,
+});
+
+impl Keyboard {
+    /// Update the state with a byte of a scancode, and return what was typed
+    fn scancode(&mut self, data: u8) -> Typed {
+        if data == EXTENDED {
+            self.extended = true;
+            return Typed::Nothing;
+        }
+        let extended = core::mem::replace(&mut self.extended, false);
+        let pressed = data & RELEASED == 0;
+        let key = data & !RELEASED;
+
+        match (extended, key) {
+            // Right control and alt are the extended codes of the left ones
+            (_, LEFT_CTRL) => self.ctrl = pressed,
+            (_, LEFT_ALT) => self.alt = pressed,
+            // Extended shifts are sent around other keys, such as print screen, and are not real
+            (false, LEFT_SHIFT) => self.left_shift = pressed,
+            (false, RIGHT_SHIFT) => self.right_shift = pressed,
+            (false, CAPS_LOCK) => if pressed { self.caps_lock = !self.caps_lock },
+            _ if !pressed => (),
+            (true, key) => return match key {
+                0x1C => Typed::Byte(b'\n'),
+                0x35 => Typed::Byte(b'/'),
+                0x47 => Typed::Sequence(b"\x1B[H"),
+                0x48 => Typed::Sequence(b"\x1B[A"),
+                0x4B => Typed::Sequence(b"\x1B[D"),
+                0x4D => Typed::Sequence(b"\x1B[C"),
+                0x4F => Typed::Sequence(b"\x1B[F"),
+                0x50 => Typed::Sequence(b"\x1B[B"),
+                0x53 => Typed::Sequence(b"\x1B[3~"),
+                _ => Typed::Nothing,
+            },
+            (false, key) => return self.translate(key).map_or(Typed::Nothing, Typed::Byte),
+        }
+        Typed::Nothing
+    }
+
+    /// Bytes typed by a key of the original keyboard, with the modifiers applied
+    fn translate(&self, key: u8) -> Option<u8> {
+        let &(normal, shifted) = SET1.get(usize::from(key))?;
+        let shift = self.left_shift || self.right_shift;
+        // Caps lock only affects letters, and shift undoes it
+        let upper = if normal.is_ascii_lowercase() { self.caps_lock != shift } else { shift };
+        let byte = if upper { shifted } else { normal };
+        match byte {
+            0 => None,
+            b'a'..=b'z' | b'A'..=b'Z' | b'@' | b'[' | b'\\' | b']' | b'^' | b'_' | b' ' if self.ctrl => Some(byte & 0x1F),
+            byte => Some(byte),
+        }
+    }
+}
+
+/// Add a byte read from the keyboard port of the PS/2 controller. Modifiers are tracked even
+/// while the scheme is not open, but nothing is queued then, as the userspace driver reads the
+/// keyboard through `serio:` instead.
+pub fn keyboard_input(data: u8) {
+    let (typed, alt) = {
+        let mut keyboard = KEYBOARD.lock();
+        (keyboard.scancode(data), keyboard.alt)
+    };
+
+    if handles().is_empty() {
+        return;
+    }
+
+    let input = INPUT.call_once(init_input);
+    match typed {
+        Typed::Nothing => return,
+        Typed::Byte(byte) => {
+            // Alt sends the key prefixed by escape, as terminals do
+            if alt {
+                input.send(0x1B);
+            }
+            input.send(byte);
+        },
+        Typed::Sequence(sequence) => {
+            input.send_from(sequence);
+        },
+    }
+
+    for (id, _handle) in handles().iter() {
+        event::trigger(SCHEME_ID.load(Ordering::SeqCst), *id, EVENT_READ);
+    }
+}
+
+pub struct KeyboardScheme;
+
+impl KeyboardScheme {
+    pub fn new(scheme_id: SchemeId) -> Self {
+        SCHEME_ID.store(scheme_id, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Scheme for KeyboardScheme {
+    fn open(&self, path: &str, flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        if uid != 0 {
+            return Err(Error::new(EPERM));
+        }
+
+        if ! path.is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        handles_mut().insert(id, Handle {
+            flags: flags & ! O_ACCMODE
+        });
+
+        Ok(id)
+    }
+
+    /// Read the file `number` into the `buffer`
+    ///
+    /// Returns the number of bytes read
+    fn read(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let handle = {
+            let handles = handles();
+            *handles.get(&id).ok_or(Error::new(EBADF))?
+        };
+
+        INPUT.call_once(init_input)
+            .receive_into(buf, handle.flags & O_NONBLOCK != O_NONBLOCK, "KeyboardScheme::read")
+            .ok_or(Error::new(EINTR))
+    }
+
+    fn fcntl(&self, id: usize, cmd: usize, arg: usize) -> Result<usize> {
+        let mut handles = handles_mut();
+        if let Some(handle) = handles.get_mut(&id) {
+            match cmd {
+                F_GETFL => Ok(handle.flags),
+                F_SETFL => {
+                    handle.flags = arg & ! O_ACCMODE;
+                    Ok(0)
+                },
+                _ => Err(Error::new(EINVAL))
+            }
+        } else {
+            Err(Error::new(EBADF))
+        }
+    }
+
+    fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
+        let _handle = {
+            let handles = handles();
+            *handles.get(&id).ok_or(Error::new(EBADF))?
+        };
+
+        Ok(EventFlags::empty())
+    }
+
+    fn fpath(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let _handle = {
+            let handles = handles();
+            *handles.get(&id).ok_or(Error::new(EBADF))?
+        };
+
+        let mut i = 0;
+        let scheme_path = b"keyboard:";
+        while i < buf.len() && i < scheme_path.len() {
+            buf[i] = scheme_path[i];
+            i += 1;
+        }
+
+        Ok(i)
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        let _handle = {
+            let handles = handles();
+            *handles.get(&id).ok_or(Error::new(EBADF))?
+        };
+
+        Ok(0)
+    }
+
+    /// Close the file `number`
+    fn close(&self, id: usize) -> Result<usize> {
+        let _handle = {
+            let mut handles = handles_mut();
+            handles.remove(&id).ok_or(Error::new(EBADF))?
+        };
+
+        Ok(0)
+    }
+}
+impl crate::scheme::KernelScheme for KeyboardScheme {}
@@ -2,8 +2,8 @@ use alloc::sync::Arc;
 use spin::RwLock;
 
 use crate::context;
-use crate::context::memory::{AddrSpace, Grant};
-use crate::memory::{free_frames, used_frames, PAGE_SIZE};
+use crate::context::memory::{AddrSpace, Grant, MAP_HUGETLB, MAP_LOCKED};
+use crate::memory::{free_frames, used_frames, FrameHint, PAGE_SIZE};
 
 use crate::syscall::data::{Map, StatVfs};
 use crate::syscall::error::*;
@@ -17,12 +17,27 @@ impl MemoryScheme {
     }
 
     pub fn fmap_anonymous(addr_space: &Arc<RwLock<AddrSpace>>, map: &Map) -> Result<usize> {
+        Self::fmap_anonymous_hinted(addr_space, map, FrameHint::local())
+    }
+
+    /// Map zeroed memory. Every page is backed by a frame before this returns, and with
+    /// `MAP_LOCKED` the pages stay that way. With `MAP_HUGETLB`, huge pages are used where the
+    /// frames allow. If not all frames can be allocated, nothing is mapped and ENOMEM is returned.
+    pub fn fmap_anonymous_hinted(addr_space: &Arc<RwLock<AddrSpace>>, map: &Map, hint: FrameHint) -> Result<usize> {
         let (requested_page, page_count) = crate::syscall::validate::validate_region(map.address, map.size)?;
 
         let page = addr_space
             .write()
             .mmap((map.address != 0).then_some(requested_page), page_count, map.flags, |page, flags, mapper, flusher| {
-                Ok(Grant::zeroed(page, page_count, flags, mapper, flusher)?)
+                let mut grant = if map.flags.contains(MAP_HUGETLB) {
+                    Grant::zeroed_huge(page, page_count, flags, mapper, flusher, hint)?
+                } else {
+                    Grant::zeroed(page, page_count, flags, mapper, flusher, hint)?
+                };
+                if map.flags.contains(MAP_LOCKED) {
+                    grant.lock();
+                }
+                Ok(grant)
             })?;
 
         Ok(page.start_address().data())
@@ -16,7 +16,7 @@ use alloc::{
 use core::sync::atomic::AtomicUsize;
 use spin::{Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-use crate::context::{memory::AddrSpace, file::FileDescriptor};
+use crate::context::{memory::AddrSpace, file::FileDescriptor, ContextId};
 use crate::syscall::error::*;
 use crate::syscall::scheme::Scheme;
 
@@ -27,13 +27,18 @@ use self::debug::DebugScheme;
 use self::event::EventScheme;
 use self::irq::IrqScheme;
 use self::itimer::ITimerScheme;
+use self::keyboard::KeyboardScheme;
 use self::memory::MemoryScheme;
+use self::null::NullScheme;
 use self::pipe::PipeScheme;
 use self::proc::ProcScheme;
+use self::rand::RandScheme;
 use self::root::RootScheme;
 use self::serio::SerioScheme;
 use self::sys::SysScheme;
 use self::time::TimeScheme;
+use self::timer::TimerScheme;
+use self::zero::ZeroScheme;
 
 /// When compiled with the "acpi" feature - `acpi:` - allows drivers to read a limited set of ACPI tables.
 #[cfg(all(feature = "acpi", any(target_arch = "x86", target_arch = "x86_64")))]
@@ -45,24 +50,36 @@ pub mod debug;
 /// `event:` - allows reading of `Event`s which are registered using `fevent`
 pub mod event;
 
+/// `initfs:` - read-only filesystem image handed to the kernel at boot
+pub mod initfs;
+
 /// `irq:` - allows userspace handling of IRQs
 pub mod irq;
 
 /// `itimer:` - support for getitimer and setitimer
 pub mod itimer;
 
+/// `keyboard:` - characters typed on the PS/2 keyboard, for console input
+pub mod keyboard;
+
 /// When `disk/live:` - embedded filesystem for live disk
 pub mod live;
 
 /// `memory:` - a scheme for accessing physical memory
 pub mod memory;
 
+/// `null:` - discards writes and reads as empty
+pub mod null;
+
 /// `pipe:` - used internally by the kernel to implement `pipe`
 pub mod pipe;
 
 /// `proc:` - allows tracing processes and reading/writing their memory
 pub mod proc;
 
+/// `rand:` and `urandom:` - random numbers from the kernel random number generator
+pub mod rand;
+
 /// `:` - allows the creation of userspace schemes, tightly dependent on `user`
 pub mod root;
 
@@ -75,9 +92,15 @@ pub mod sys;
 /// `time:` - allows reading time, setting timeouts and getting events when they are met
 pub mod time;
 
+/// `timer:` - timers that can be read or waited on for their expirations
+pub mod timer;
+
 /// A wrapper around userspace schemes, tightly dependent on `root`
 pub mod user;
 
+/// `zero:` - discards writes, reads as zeroes and maps as anonymous memory
+pub mod zero;
+
 /// Limit on number of schemes
 pub const SCHEME_MAX_SCHEMES: usize = 65_536;
 
@@ -106,6 +129,8 @@ impl<'a> Iterator for SchemeIter<'a> {
 pub struct SchemeList {
     map: BTreeMap<SchemeId, Arc<dyn KernelScheme + Send + Sync>>,
     names: BTreeMap<SchemeNamespace, BTreeMap<Box<str>, SchemeId>>,
+    /// Number of contexts using each namespace made by `make_ns`, see `ns_ref`
+    ns_refs: BTreeMap<SchemeNamespace, usize>,
     next_ns: usize,
     next_id: usize
 }
@@ -116,6 +141,7 @@ impl SchemeList {
         let mut list = SchemeList {
             map: BTreeMap::new(),
             names: BTreeMap::new(),
+            ns_refs: BTreeMap::new(),
             // Scheme namespaces always start at 1. 0 is a reserved namespace, the null namespace
             next_ns: 1,
             next_id: 1
@@ -146,8 +172,13 @@ impl SchemeList {
         self.insert(ns, "event", |_| Arc::new(EventScheme)).unwrap();
         self.insert(ns, "itimer", |_| Arc::new(ITimerScheme::new())).unwrap();
         self.insert(ns, "memory", |_| Arc::new(MemoryScheme::new())).unwrap();
-        self.insert(ns, "sys", |_| Arc::new(SysScheme::new())).unwrap();
+        self.insert(ns, "null", |_| Arc::new(NullScheme)).unwrap();
+        self.insert(ns, "rand", |scheme_id| Arc::new(RandScheme::new(scheme_id, true))).unwrap();
+        self.insert(ns, "sys", |scheme_id| Arc::new(SysScheme::new(scheme_id))).unwrap();
         self.insert(ns, "time", |scheme_id| Arc::new(TimeScheme::new(scheme_id))).unwrap();
+        self.insert(ns, "timer", |scheme_id| Arc::new(TimerScheme::new(scheme_id))).unwrap();
+        self.insert(ns, "urandom", |scheme_id| Arc::new(RandScheme::new(scheme_id, false))).unwrap();
+        self.insert(ns, "zero", |_| Arc::new(ZeroScheme)).unwrap();
 
         ns
     }
@@ -166,11 +197,16 @@ impl SchemeList {
         self.insert(ns, "proc", |scheme_id| Arc::new(ProcScheme::new(scheme_id))).unwrap();
         self.insert(ns, "thisproc", |_| Arc::new(ProcScheme::restricted())).unwrap();
         self.insert(ns, "serio", |scheme_id| Arc::new(SerioScheme::new(scheme_id))).unwrap();
+        self.insert(ns, "keyboard", |scheme_id| Arc::new(KeyboardScheme::new(scheme_id))).unwrap();
 
         if let Some(scheme) = self::live::DiskScheme::new().map(Arc::new) {
             self.insert(ns, "disk/live", move |_| scheme.clone()).unwrap();
         }
 
+        if let Some(scheme) = self::initfs::InitFsScheme::new().map(Arc::new) {
+            self.insert(ns, "initfs", move |_| scheme.clone()).unwrap();
+        }
+
         // Pipe is special and needs to be in the root namespace
         self.insert(ns, "pipe", |scheme_id| Arc::new(PipeScheme::new(scheme_id))).unwrap();
     }
@@ -184,6 +220,7 @@ impl SchemeList {
             let id = if let Some((id, _scheme)) = self.get_name(from, name) {
                 id
             } else {
+                self.names.remove(&to);
                 return Err(Error::new(ENODEV));
             };
 
@@ -197,6 +234,34 @@ impl SchemeList {
         Ok(to)
     }
 
+    /// Count a context as using `ns`. A namespace made by `make_ns` is freed once the last context
+    /// using it exits or leaves it, whereas one that was never used stays until it is. The null
+    /// and root namespaces are never freed.
+    pub fn ns_ref(&mut self, ns: SchemeNamespace) {
+        if ns.into() > 1 && self.names.contains_key(&ns) {
+            *self.ns_refs.entry(ns).or_insert(0) += 1;
+        }
+    }
+
+    /// Stop counting a context as using `ns`, see `ns_ref`
+    pub fn ns_unref(&mut self, ns: SchemeNamespace) {
+        if let Some(refs) = self.ns_refs.get_mut(&ns) {
+            *refs -= 1;
+            if *refs == 0 {
+                self.free_ns(ns);
+            }
+        }
+    }
+
+    /// Free a namespace made by `make_ns` that no context uses any longer, or that was never
+    /// handed to one
+    pub fn free_ns(&mut self, ns: SchemeNamespace) {
+        if ns.into() > 1 {
+            self.ns_refs.remove(&ns);
+            self.names.remove(&ns);
+        }
+    }
+
     pub fn iter(&self) -> ::alloc::collections::btree_map::Iter<SchemeId, Arc<dyn KernelScheme + Send + Sync>> {
         self.map.iter()
     }
@@ -295,8 +360,27 @@ pub fn schemes_mut() -> RwLockWriteGuard<'static, SchemeList> {
     SCHEMES.call_once(init_schemes).write()
 }
 
+/// The process backing a scheme
+pub enum SchemeHandler {
+    /// The scheme is implemented by the kernel
+    Kernel,
+    /// The scheme is served by a userspace context
+    Context { id: ContextId, name: Box<str> },
+    /// The context that served the scheme has exited
+    Orphaned,
+}
+
 #[allow(unused_variables)]
 pub trait KernelScheme: Scheme + Send + Sync + 'static {
+    fn handler(&self) -> SchemeHandler {
+        SchemeHandler::Kernel
+    }
+    /// Number of handles opened on a userspace scheme and not closed yet, or None for schemes
+    /// that do not keep count
+    fn open_handles(&self) -> Option<usize> {
+        None
+    }
+
     fn as_filetable(&self, number: usize) -> Result<Arc<RwLock<Vec<Option<FileDescriptor>>>>> {
         Err(Error::new(EBADF))
     }
@@ -310,4 +394,20 @@ pub trait KernelScheme: Scheme + Send + Sync + 'static {
     fn kfmap(&self, number: usize, addr_space: &Arc<RwLock<AddrSpace>>, map: &crate::syscall::data::Map, consume: bool) -> Result<usize> {
         Err(Error::new(EOPNOTSUPP))
     }
+
+    /// Rename with the `RENAME_*` flags of `syscall::frename_flags`. Schemes that do not support
+    /// them return ENOSYS, and the kernel emulates what it can.
+    fn frename_flags(&self, number: usize, path: &str, flags: usize, uid: u32, gid: u32) -> Result<usize> {
+        Err(Error::new(ENOSYS))
+    }
+
+    /// Read from the file at `offset` without moving its file offset, which other users of the
+    /// description may rely on. Schemes that cannot return ESPIPE.
+    fn kreadoff(&self, number: usize, buf: &mut [u8], offset: usize) -> Result<usize> {
+        Err(Error::new(ESPIPE))
+    }
+    /// Write to the file at `offset` without moving its file offset, see `kreadoff`
+    fn kwriteoff(&self, number: usize, buf: &[u8], offset: usize) -> Result<usize> {
+        Err(Error::new(ESPIPE))
+    }
 }
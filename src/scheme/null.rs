@@ -0,0 +1,41 @@
+use crate::syscall::error::*;
+use crate::syscall::scheme::Scheme;
+
+pub struct NullScheme;
+
+impl Scheme for NullScheme {
+    fn open(&self, _path: &str, _flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn read(&self, _id: usize, _buf: &mut [u8]) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn write(&self, _id: usize, buf: &[u8]) -> Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn seek(&self, _id: usize, _pos: isize, _whence: usize) -> Result<isize> {
+        Ok(0)
+    }
+
+    fn fcntl(&self, _id: usize, _cmd: usize, _arg: usize) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn fpath(&self, _id: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut i = 0;
+        let scheme_path = b"null:";
+        while i < buf.len() && i < scheme_path.len() {
+            buf[i] = scheme_path[i];
+            i += 1;
+        }
+        Ok(i)
+    }
+
+    fn close(&self, _id: usize) -> Result<usize> {
+        Ok(0)
+    }
+}
+impl crate::scheme::KernelScheme for NullScheme {}
@@ -1,13 +1,14 @@
 use alloc::sync::{Arc, Weak};
 use alloc::collections::{BTreeMap, VecDeque};
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use spin::{Mutex, Once, RwLock};
 
+use crate::context;
 use crate::event;
 use crate::scheme::SchemeId;
 use crate::sync::WaitCondition;
 use crate::syscall::error::{Error, Result, EAGAIN, EBADF, EINTR, EINVAL, EPIPE, ESPIPE};
-use crate::syscall::flag::{EventFlags, EVENT_READ, EVENT_WRITE, F_GETFL, F_SETFL, O_ACCMODE, O_NONBLOCK, MODE_FIFO};
+use crate::syscall::flag::{EventFlags, EVENT_READ, EVENT_WRITE, F_GETFL, F_SETFL, O_ACCMODE, O_NONBLOCK, MODE_FIFO, SIGPIPE};
 use crate::syscall::scheme::Scheme;
 use crate::syscall::data::Stat;
 
@@ -18,6 +19,13 @@ static PIPE_SCHEME_ID: Once<SchemeId> = Once::new();
 static PIPE_NEXT_ID: AtomicUsize = AtomicUsize::new(0);
 static PIPES: RwLock<(BTreeMap<usize, Arc<PipeRead>>, BTreeMap<usize, Arc<PipeWrite>>)> = RwLock::new((BTreeMap::new(), BTreeMap::new()));
 
+/// Largest write that is guaranteed not to be interleaved with the writes of others
+// TODO: Move to syscall::flag
+pub const PIPE_BUF: usize = 4096;
+
+/// Number of bytes a pipe can hold before writers block
+pub const PIPE_CAPACITY: usize = 16 * PIPE_BUF;
+
 pub fn pipe_scheme_id() -> Option<SchemeId> {
     PIPE_SCHEME_ID.get().copied()
 }
@@ -87,20 +95,20 @@ impl Scheme for PipeScheme {
         let pipes = PIPES.read();
 
         if let Some(pipe) = pipes.0.get(&id) {
-            if flags == EVENT_READ {
-                // TODO: Return correct flags
-                if pipe.vec.lock().is_empty() {
-                    return Ok(EventFlags::empty());
-                } else {
-                    return Ok(EVENT_READ);
-                }
+            // Readable when there is data, or when the end of file can be read
+            let vec = pipe.vec.lock();
+            if flags.contains(EVENT_READ) && (!vec.is_empty() || Arc::weak_count(&pipe.vec) == 0) {
+                return Ok(EVENT_READ);
             }
+            return Ok(EventFlags::empty());
         }
 
-        if let Some(_pipe) = pipes.1.get(&id) {
-            if flags == EVENT_WRITE {
+        if let Some(pipe) = pipes.1.get(&id) {
+            // Writable when there is room for an atomic write, or when writing fails with EPIPE
+            if flags.contains(EVENT_WRITE) && pipe.writable() {
                 return Ok(EVENT_WRITE);
             }
+            return Ok(EventFlags::empty());
         }
 
         Err(Error::new(EBADF))
@@ -146,6 +154,10 @@ pub struct PipeRead {
     write_id: usize,
     flags: AtomicUsize,
     condition: Arc<WaitCondition>,
+    /// Woken when data is read, or the read side is closed
+    write_condition: Arc<WaitCondition>,
+    /// Set, with `vec` locked, once the read side is closed
+    read_closed: Arc<AtomicBool>,
     vec: Arc<Mutex<VecDeque<u8>>>
 }
 
@@ -156,6 +168,8 @@ impl PipeRead {
             write_id,
             flags: AtomicUsize::new(flags),
             condition: Arc::new(WaitCondition::new()),
+            write_condition: Arc::new(WaitCondition::new()),
+            read_closed: Arc::new(AtomicBool::new(false)),
             vec: Arc::new(Mutex::new(VecDeque::new())),
         }
     }
@@ -186,7 +200,9 @@ impl PipeRead {
             }
 
             if i > 0 {
+                drop(vec);
                 event::trigger(self.scheme_id, self.write_id, EVENT_WRITE);
+                self.write_condition.notify();
 
                 return Ok(i);
             }
@@ -202,12 +218,25 @@ impl PipeRead {
     }
 }
 
-/// Read side of a pipe
+impl Drop for PipeRead {
+    fn drop(&mut self) {
+        {
+            let _vec = self.vec.lock();
+            self.read_closed.store(true, Ordering::SeqCst);
+        }
+        event::trigger(self.scheme_id, self.write_id, EVENT_WRITE);
+        self.write_condition.notify();
+    }
+}
+
+/// Write side of a pipe
 pub struct PipeWrite {
     scheme_id: SchemeId,
     read_id: usize,
     flags: AtomicUsize,
     condition: Arc<WaitCondition>,
+    write_condition: Arc<WaitCondition>,
+    read_closed: Arc<AtomicBool>,
     vec: Option<Weak<Mutex<VecDeque<u8>>>>
 }
 
@@ -218,6 +247,8 @@ impl PipeWrite {
             read_id,
             flags: AtomicUsize::new(flags),
             condition: read.condition.clone(),
+            write_condition: read.write_condition.clone(),
+            read_closed: read.read_closed.clone(),
             vec: Some(Arc::downgrade(&read.vec)),
         }
     }
@@ -233,33 +264,87 @@ impl PipeWrite {
         }
     }
 
+    /// Whether a write of up to `PIPE_BUF` bytes would not block
+    fn writable(&self) -> bool {
+        match self.vec.as_ref().and_then(Weak::upgrade) {
+            Some(vec_lock) => {
+                let vec = vec_lock.lock();
+                self.read_closed.load(Ordering::SeqCst) || PIPE_CAPACITY - vec.len() >= PIPE_BUF
+            }
+            None => true,
+        }
+    }
+
+    /// Fail a write to a pipe without readers, raising SIGPIPE in the writer
+    fn broken() -> Result<usize> {
+        let _ = crate::syscall::kill(context::context_id(), SIGPIPE);
+        Err(Error::new(EPIPE))
+    }
+
     fn write(&self, buf: &[u8]) -> Result<usize> {
-        if let Some(ref vec_weak) = self.vec {
-            if let Some(vec_lock) = vec_weak.upgrade() {
-                {
-                    let mut vec = vec_lock.lock();
-
-                    for &b in buf.iter() {
-                        vec.push_back(b);
-                    }
-                }
+        let vec_weak = self.vec.as_ref().expect("PipeWrite dropped before write");
+
+        // Writes of up to PIPE_BUF bytes are appended all at once, under the lock of the buffer, so
+        // that they are never interleaved with those of other writers. Larger writes are appended
+        // as room becomes available, and may be interleaved.
+        let atomic = buf.len() <= PIPE_BUF;
+        let mut written = 0;
+
+        loop {
+            let vec_lock = match vec_weak.upgrade() {
+                Some(vec_lock) => vec_lock,
+                None if written > 0 => return Ok(written),
+                None => return Self::broken(),
+            };
+            let mut vec = vec_lock.lock();
+
+            if self.read_closed.load(Ordering::SeqCst) {
+                drop(vec);
+                return if written > 0 { Ok(written) } else { Self::broken() };
+            }
+
+            let remaining = &buf[written..];
+            let room = PIPE_CAPACITY - vec.len();
+            let count = if atomic {
+                if room >= remaining.len() { remaining.len() } else { 0 }
+            } else {
+                core::cmp::min(room, remaining.len())
+            };
+
+            if count > 0 || remaining.is_empty() {
+                vec.extend(remaining[..count].iter().copied());
+                written += count;
+                drop(vec);
 
                 event::trigger(self.scheme_id, self.read_id, EVENT_READ);
                 self.condition.notify();
 
-                Ok(buf.len())
-            } else {
-                Err(Error::new(EPIPE))
+                if written == buf.len() {
+                    return Ok(written);
+                }
+                continue;
+            }
+
+            if self.flags.load(Ordering::SeqCst) & O_NONBLOCK == O_NONBLOCK {
+                return if written > 0 { Ok(written) } else { Err(Error::new(EAGAIN)) };
+            }
+            if !self.write_condition.wait(vec, "PipeWrite::write") {
+                return if written > 0 { Ok(written) } else { Err(Error::new(EINTR)) };
             }
-        } else {
-            panic!("PipeWrite dropped before write");
         }
     }
 }
 
 impl Drop for PipeWrite {
     fn drop(&mut self) {
-        drop(self.vec.take());
+        // Let go of the buffer while it is locked, so that a reader checking for writers before
+        // waiting cannot miss the notification
+        let vec_weak = self.vec.take();
+        let vec_lock = vec_weak.as_ref().and_then(Weak::upgrade);
+        let vec = vec_lock.as_ref().map(|vec_lock| vec_lock.lock());
+        drop(vec_weak);
+        drop(vec);
+
         event::trigger(self.scheme_id, self.read_id, EVENT_READ);
         self.condition.notify();
     }
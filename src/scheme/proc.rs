@@ -1,7 +1,7 @@
 use crate::{
     arch::paging::{mapper::InactiveFlusher, Page, RmmA, RmmArch, VirtualAddress},
-    context::{self, Context, ContextId, Status, file::{FileDescription, FileDescriptor}, memory::{AddrSpace, Grant, new_addrspace, map_flags, Region}},
-    memory::PAGE_SIZE,
+    context::{self, Context, ContextId, ContextSnapshot, Status, WaitpidKey, file::{FileDescription, FileDescriptor}, memory::{AddrSpace, Grant, new_addrspace, map_flags, Region, split_huge_pages}},
+    memory::{FrameHint, PAGE_SIZE},
     ptrace,
     scheme::{self, FileHandle, KernelScheme, SchemeId},
     syscall::{
@@ -11,6 +11,7 @@ use crate::{
         data::{Map, PtraceEvent, SigAction, Stat},
         error::*,
         flag::*,
+        limit::{SyscallLimit, SYSCALL_LIMIT_SLEEP},
         scheme::{calc_seek_offset_usize, Scheme},
         self,
     },
@@ -27,6 +28,7 @@ use core::{
     cmp,
     convert::TryFrom,
     mem,
+    ptr,
     slice,
     str,
     sync::atomic::{AtomicUsize, Ordering},
@@ -100,6 +102,19 @@ where
     })
 }
 
+/// Allocate frames for every page of an anonymous range up front, failing with ENOMEM rather than
+/// later when the memory is touched
+// TODO: Move to syscall::flag
+pub const ADDRSPACE_OP_RESERVE: usize = 4;
+/// Free the frames backing an anonymous range, which reads as zero when touched again
+// TODO: Move to syscall::flag
+pub const ADDRSPACE_OP_DONTNEED: usize = 5;
+
+/// File actions that can be written to a `filetable` handle, each as `[action, fd, src_fd]`
+// TODO: Move to syscall::flag
+pub const FILE_ACTION_DUP2: usize = 0;
+pub const FILE_ACTION_CLOSE: usize = 1;
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 enum RegsKind {
     Float,
@@ -127,6 +142,9 @@ enum Operation {
         new_sp: usize,
         new_ip: usize,
     },
+    /// Writing an address space file descriptor, stack pointer and instruction pointer replaces
+    /// the image of the context at once, see `exec_in_place`
+    Exec,
 
     CurrentFiletable,
 
@@ -146,19 +164,55 @@ enum Operation {
     AwaitingSigactionsChange(Arc<RwLock<Vec<(SigAction, usize)>>>),
 
     MmapMinAddr(Arc<RwLock<AddrSpace>>),
+    /// Reads the limit on the size of an address space followed by the size charged against it,
+    /// and writes a new limit, which only root can raise
+    RlimitAs(Arc<RwLock<AddrSpace>>),
+    /// Reads and writes whether the limit on the size of an address space only charges resident
+    /// memory, which only root can turn on
+    Overcommit(Arc<RwLock<AddrSpace>>),
+    Mincore(Arc<RwLock<AddrSpace>>),
+    /// Reads the NUMA node of the frame of each page, as native endian `i32`s, like `mincore`.
+    /// See `AddrSpace::numa_nodes` for pages without a frame.
+    NumaNodes(Arc<RwLock<AddrSpace>>),
+    /// Reads the number of events counted by the performance counter while the context ran, and
+    /// writes the architectural event to count from now on, or `!0` to stop counting
+    Pmc,
+    /// Reads the status the parent's waitpid would report for the context next, such as its exit
+    /// status, without consuming it, so that the parent can still reap the context
+    WaitStatus,
+    /// Reads the whole register state of a stopped context as a `RegsCheckpoint`, and restores it
+    /// when written
+    Checkpoint,
+    /// Writing starts the stopped child, which must share the address space of the caller, and
+    /// suspends the caller until the child execs or exits
+    Vfork,
+    /// Writing newline separated scheme names moves the stopped child into a new namespace with
+    /// only those of the caller's schemes, which neither it nor its children can leave
+    Namespace,
+    /// Reads the syscall rate limit as `usize`s of the rate per second, burst, flags, syscall count
+    /// and throttled count. Root writes the rate, burst and flags, with a rate of 0 removing it.
+    SyscallLimit,
+    /// Reads or writes the CPUs the context may run on as a `u64`, see `Context::affinity`
+    SchedAffinity,
+    /// Writing is a last resort for root to kill a context stuck in the kernel, see
+    /// `Context::force_kill`. This is best effort, a context spinning in the kernel cannot be
+    /// stopped.
+    ForceKill,
 }
 #[derive(Clone, Copy, PartialEq, Eq)]
 enum Attr {
     Uid,
     Gid,
+    /// Whether the context is scheduled as soon as it becomes runnable, see `Context::low_latency`
+    LowLatency,
     // TODO: namespace, tid, etc.
 }
 impl Operation {
     fn needs_child_process(&self) -> bool {
-        matches!(self, Self::Memory { .. } | Self::Regs(_) | Self::Trace | Self::Filetable { .. } | Self::AddrSpace { .. } | Self::CurrentAddrSpace | Self::CurrentFiletable | Self::Sigactions(_) | Self::CurrentSigactions | Self::AwaitingSigactionsChange(_))
+        matches!(self, Self::Memory { .. } | Self::Regs(_) | Self::Trace | Self::Filetable { .. } | Self::AddrSpace { .. } | Self::Mincore(_) | Self::NumaNodes(_) | Self::Pmc | Self::WaitStatus | Self::Vfork | Self::Namespace | Self::Checkpoint | Self::CurrentAddrSpace | Self::Exec | Self::CurrentFiletable | Self::Sigactions(_) | Self::CurrentSigactions | Self::AwaitingSigactionsChange(_) | Self::SyscallLimit)
     }
     fn needs_root(&self) -> bool {
-        matches!(self, Self::Attr(_))
+        matches!(self, Self::Attr(_) | Self::ForceKill)
     }
 }
 struct MemData {
@@ -238,7 +292,7 @@ impl Handle {
             }
             if let Some(context) = contexts.get(pid) {
                 let mut context = context.write();
-                context.ptrace_stop = false;
+                context.start();
             }
         }
         Some(())
@@ -282,10 +336,101 @@ impl ProcScheme {
     }
 }
 
+/// List the open files of a context, one line per descriptor with its number, scheme ID, the
+/// number the scheme uses for the file and the flags of the description. Empty slots are skipped.
+fn fds_listing(context: &Context) -> Box<[u8]> {
+    use core::fmt::Write;
+
+    let snapshot = ContextSnapshot::new(context);
+
+    let mut data = String::new();
+    for (fd, description) in snapshot.files.iter().enumerate() {
+        if let Some(description) = description {
+            let _ = writeln!(data, "{}\t{}\t{}\t{:#x}", fd, description.scheme.into(), description.number, description.flags);
+        }
+    }
+    data.into_bytes().into_boxed_slice()
+}
+
 fn get_context(id: ContextId) -> Result<Arc<RwLock<Context>>> {
     context::contexts().get(id).ok_or(Error::new(ENOENT)).map(Arc::clone)
 }
 
+const CHECKPOINT_MAGIC: u32 = u32::from_le_bytes(*b"RGCK");
+/// Increased whenever the layout of `RegsCheckpoint` changes
+const CHECKPOINT_VERSION: u32 = 1;
+/// ELF machine number of the architecture the registers belong to
+#[cfg(target_arch = "aarch64")]
+const CHECKPOINT_MACHINE: u32 = 183;
+#[cfg(target_arch = "x86")]
+const CHECKPOINT_MACHINE: u32 = 3;
+#[cfg(target_arch = "x86_64")]
+const CHECKPOINT_MACHINE: u32 = 62;
+
+/// Register state of a context, for checkpointing and restoring it
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct RegsCheckpoint {
+    magic: u32,
+    version: u32,
+    machine: u32,
+    /// Size of the whole checkpoint in bytes
+    size: u32,
+    int: IntRegisters,
+    float: FloatRegisters,
+    env: EnvRegisters,
+}
+
+#[cfg(target_arch = "aarch64")]
+fn checkpoint_env(context: &Context) -> EnvRegisters {
+    EnvRegisters {
+        tpidr_el0: context.arch.tpidr_el0,
+        tpidrro_el0: context.arch.tpidrro_el0,
+    }
+}
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn checkpoint_env(context: &Context) -> EnvRegisters {
+    EnvRegisters { fsbase: context.arch.fsbase as _, gsbase: context.arch.gsbase as _ }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn restore_env(context: &mut Context, regs: &EnvRegisters) {
+    context.arch.tpidr_el0 = regs.tpidr_el0;
+    context.arch.tpidrro_el0 = regs.tpidrro_el0;
+}
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn restore_env(context: &mut Context, regs: &EnvRegisters) {
+    context.arch.fsbase = regs.fsbase as usize;
+    context.arch.gsbase = regs.gsbase as usize;
+}
+
+#[cfg(target_arch = "aarch64")]
+fn env_is_valid(_regs: &EnvRegisters) -> bool {
+    true
+}
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn env_is_valid(regs: &EnvRegisters) -> bool {
+    RmmA::virt_is_valid(VirtualAddress::new(regs.fsbase as usize)) && RmmA::virt_is_valid(VirtualAddress::new(regs.gsbase as usize))
+}
+
+/// Run `callback` on the context `pid`, which must have been stopped already, by a signal or a
+/// tracer, and must have been switched away from. Unlike `try_stop_context`, this does not stop
+/// it, so that the state seen is the one it was stopped with.
+fn with_stopped_context<F, T>(pid: ContextId, callback: F) -> Result<T>
+where
+    F: FnOnce(&mut Context) -> Result<T>,
+{
+    if pid == context::context_id() {
+        return Err(Error::new(EBADF));
+    }
+    with_context_mut(pid, |context| {
+        if ! (context.ptrace_stop || matches!(context.status, Status::Stopped(_))) || context.running {
+            return Err(Error::new(EBUSY));
+        }
+        callback(context)
+    })
+}
+
 impl ProcScheme {
     fn open_inner(&self, pid: ContextId, operation_str: Option<&str>, flags: usize, uid: u32, gid: u32) -> Result<usize> {
         let operation = match operation_str {
@@ -293,20 +438,35 @@ impl ProcScheme {
             Some("addrspace") => Operation::AddrSpace { addrspace: Arc::clone(get_context(pid)?.read().addr_space().map_err(|_| Error::new(ENOENT))?) },
             Some("filetable") => Operation::Filetable { filetable: Arc::clone(&get_context(pid)?.read().files) },
             Some("current-addrspace") => Operation::CurrentAddrSpace,
+            Some("exec") => Operation::Exec,
             Some("current-filetable") => Operation::CurrentFiletable,
             Some("regs/float") => Operation::Regs(RegsKind::Float),
             Some("regs/int") => Operation::Regs(RegsKind::Int),
             Some("regs/env") => Operation::Regs(RegsKind::Env),
+            Some("regs/checkpoint") => Operation::Checkpoint,
             Some("trace") => Operation::Trace,
             Some("exe") => Operation::Static("exe"),
+            Some("fds") => Operation::Static("fds"),
             Some("name") => Operation::Name,
             Some("sigstack") => Operation::Sigstack,
             Some("uid") => Operation::Attr(Attr::Uid),
             Some("gid") => Operation::Attr(Attr::Gid),
+            Some("low-latency") => Operation::Attr(Attr::LowLatency),
             Some("open_via_dup") => Operation::OpenViaDup,
             Some("sigactions") => Operation::Sigactions(Arc::clone(&get_context(pid)?.read().actions)),
             Some("current-sigactions") => Operation::CurrentSigactions,
             Some("mmap-min-addr") => Operation::MmapMinAddr(Arc::clone(get_context(pid)?.read().addr_space().map_err(|_| Error::new(ENOENT))?)),
+            Some("rlimit-as") => Operation::RlimitAs(Arc::clone(get_context(pid)?.read().addr_space().map_err(|_| Error::new(ENOENT))?)),
+            Some("overcommit") => Operation::Overcommit(Arc::clone(get_context(pid)?.read().addr_space().map_err(|_| Error::new(ENOENT))?)),
+            Some("mincore") => Operation::Mincore(Arc::clone(get_context(pid)?.read().addr_space().map_err(|_| Error::new(ENOENT))?)),
+            Some("numa-nodes") => Operation::NumaNodes(Arc::clone(get_context(pid)?.read().addr_space().map_err(|_| Error::new(ENOENT))?)),
+            Some("pmc") => Operation::Pmc,
+            Some("wait-status") => Operation::WaitStatus,
+            Some("vfork") => Operation::Vfork,
+            Some("namespace") => Operation::Namespace,
+            Some("syscall-limit") => Operation::SyscallLimit,
+            Some("sched-affinity") => Operation::SchedAffinity,
+            Some("force-kill") => Operation::ForceKill,
             _ => return Err(Error::new(EINVAL))
         };
 
@@ -319,8 +479,9 @@ impl ProcScheme {
             let target = target.read();
 
             data = match operation {
-                Operation::Memory { .. } => OperationData::Memory(MemData::default()),
+                Operation::Memory { .. } | Operation::Mincore(_) | Operation::NumaNodes(_) => OperationData::Memory(MemData::default()),
                 Operation::Trace => OperationData::Trace(TraceData::default()),
+                Operation::Static("fds") => OperationData::Static(StaticData::new(fds_listing(&target))),
                 Operation::Static(_) => OperationData::Static(StaticData::new(
                     target.name.read().clone().into()
                 )),
@@ -334,11 +495,12 @@ impl ProcScheme {
 
             // Unless root, check security
             if operation.needs_child_process() && uid != 0 && gid != 0 {
-                let current = contexts.current().ok_or(Error::new(ESRCH))?;
-                let current = current.read();
+                // Only the ID of the current context is needed, so avoid locking it while the
+                // target is locked.
+                let current_id = context::context_id();
 
                 // Are we the process?
-                if target.id != current.id {
+                if target.id != current_id {
                     // Do we own the process?
                     if uid != target.euid && gid != target.egid {
                         return Err(Error::new(EPERM));
@@ -346,11 +508,11 @@ impl ProcScheme {
 
                     // Is it a subprocess of us? In the future, a capability could
                     // bypass this check.
-                    match contexts.ancestors(target.ppid).find(|&(id, _context)| id == current.id) {
+                    match contexts.ancestors(target.ppid).find(|&(id, _context)| id == current_id) {
                         Some((id, context)) => {
                             // Paranoid sanity check, as ptrace security holes
                             // wouldn't be fun
-                            assert_eq!(id, current.id);
+                            assert_eq!(id, current_id);
                             assert_eq!(id, context.read().id);
                         },
                         None => return Err(Error::new(EPERM)),
@@ -360,6 +522,13 @@ impl ProcScheme {
                 return Err(Error::new(EPERM));
             }
 
+            // Open files may only be listed by the process itself, or by root
+            if let Operation::Static("fds") = operation {
+                if uid != 0 && target.id != context::context_id() {
+                    return Err(Error::new(EPERM));
+                }
+            }
+
             if matches!(operation, Operation::Filetable { .. }) {
                 data = OperationData::Static(StaticData::new({
                     use core::fmt::Write;
@@ -499,8 +668,7 @@ impl ProcScheme {
 
         if info.pid == context::context_id() {
             unsafe {
-                crate::gdt::GDT[crate::gdt::GDT_USER_FS].set_offset(regs.fsbase);
-                crate::gdt::GDT[crate::gdt::GDT_USER_GS].set_offset(regs.gsbase);
+                crate::gdt::set_user_tls(regs.fsbase, regs.gsbase);
 
                 match context::contexts().current().ok_or(Error::new(ESRCH))?.write().arch {
                     ref mut arch => {
@@ -626,6 +794,10 @@ impl Scheme for ProcScheme {
                     b"exclusive" => (Operation::AddrSpace { addrspace: addrspace.write().try_clone()? }, false),
                     b"mem" => (Operation::Memory { addrspace: Arc::clone(addrspace) }, true),
                     b"mmap-min-addr" => (Operation::MmapMinAddr(Arc::clone(addrspace)), false),
+                    b"rlimit-as" => (Operation::RlimitAs(Arc::clone(addrspace)), false),
+                    b"overcommit" => (Operation::Overcommit(Arc::clone(addrspace)), false),
+                    b"mincore" => (Operation::Mincore(Arc::clone(addrspace)), true),
+                    b"numa-nodes" => (Operation::NumaNodes(Arc::clone(addrspace)), true),
 
                     grant_handle if grant_handle.starts_with(b"grant-") => {
                         let start_addr = usize::from_str_radix(core::str::from_utf8(&grant_handle[6..]).map_err(|_| Error::new(EINVAL))?, 16).map_err(|_| Error::new(EINVAL))?;
@@ -742,17 +914,11 @@ impl Scheme for ProcScheme {
 
                         Ok((Output { float: context.get_fx_regs() }, mem::size_of::<FloatRegisters>()))
                     })?,
-                    RegsKind::Int => try_stop_context(info.pid, |context| match unsafe { ptrace::regs_for(context) } {
-                        None => {
-                            assert!(!context.running, "try_stop_context is broken, clearly");
-                            println!("{}:{}: Couldn't read registers from stopped process", file!(), line!());
-                            Err(Error::new(ENOTRECOVERABLE))
-                        },
-                        Some(stack) => {
-                            let mut regs = IntRegisters::default();
-                            stack.save(&mut regs);
-                            Ok((Output { int: regs }, mem::size_of::<IntRegisters>()))
-                        }
+                    RegsKind::Int => try_stop_context(info.pid, |context| {
+                        let stack = ptrace::stopped_regs(context)?;
+                        let mut regs = IntRegisters::default();
+                        stack.save(&mut regs);
+                        Ok((Output { int: regs }, mem::size_of::<IntRegisters>()))
                     })?,
                     RegsKind::Env => {
                         (
@@ -770,6 +936,34 @@ impl Scheme for ProcScheme {
 
                 Ok(len)
             },
+            Operation::Checkpoint => {
+                if buf.len() < mem::size_of::<RegsCheckpoint>() {
+                    return Err(Error::new(EINVAL));
+                }
+                // Built field by field in zeroed memory, so that no padding bytes reach userspace
+                let mut checkpoint = mem::MaybeUninit::<RegsCheckpoint>::zeroed();
+                with_stopped_context(info.pid, |context| {
+                    let mut int = IntRegisters::default();
+                    ptrace::stopped_regs(context)?.save(&mut int);
+                    let out = checkpoint.as_mut_ptr();
+                    unsafe {
+                        ptr::addr_of_mut!((*out).magic).write(CHECKPOINT_MAGIC);
+                        ptr::addr_of_mut!((*out).version).write(CHECKPOINT_VERSION);
+                        ptr::addr_of_mut!((*out).machine).write(CHECKPOINT_MACHINE);
+                        ptr::addr_of_mut!((*out).size).write(mem::size_of::<RegsCheckpoint>() as u32);
+                        ptr::addr_of_mut!((*out).int).write(int);
+                        ptr::addr_of_mut!((*out).float).write(context.get_fx_regs());
+                        ptr::addr_of_mut!((*out).env).write(checkpoint_env(context));
+                    }
+                    Ok(())
+                })?;
+
+                let bytes = unsafe {
+                    slice::from_raw_parts(checkpoint.as_ptr() as *const u8, mem::size_of::<RegsCheckpoint>())
+                };
+                buf[..bytes.len()].copy_from_slice(bytes);
+                Ok(bytes.len())
+            }
             Operation::Trace => {
                 let mut handles = self.handles.write();
                 let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
@@ -792,7 +986,12 @@ impl Scheme for ProcScheme {
                 };
                 let (read, reached) = ptrace::Session::with_session(info.pid, |session| {
                     let mut data = session.data.lock();
-                    Ok((data.recv_events(slice), data.is_reached()))
+                    let read = data.recv_events(slice);
+                    if read > 0 {
+                        // Let a tracee that stopped on a full queue report the rest
+                        session.room.notify();
+                    }
+                    Ok((read, data.is_reached()))
                 })?;
 
                 // Save child processes in a list of processes to restart
@@ -814,10 +1013,43 @@ impl Scheme for ProcScheme {
             }
             Operation::Name => read_from(buf, context::contexts().get(info.pid).ok_or(Error::new(ESRCH))?.read().name.read().as_bytes(), &mut 0),
             Operation::Sigstack => read_from(buf, &context::contexts().get(info.pid).ok_or(Error::new(ESRCH))?.read().sigstack.unwrap_or(!0).to_ne_bytes(), &mut 0),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Operation::Pmc => {
+                let count = with_context(info.pid, |context| Ok(context::pmc_count(context, info.pid == context::context_id())))?;
+                read_from(buf, &count.to_ne_bytes(), &mut 0)
+            }
+            Operation::SyscallLimit => {
+                let words = with_context(info.pid, |context| Ok(match context.syscall_limit {
+                    Some(ref limit) => [limit.rate as usize, limit.burst as usize, limit.flags, context.syscall_count as usize, limit.throttled as usize],
+                    None => [0, 0, 0, context.syscall_count as usize, 0],
+                }))?;
+                let bytes = words.iter().flat_map(|word| word.to_ne_bytes()).collect::<Vec<u8>>();
+                read_from(buf, &bytes, &mut 0)
+            }
+            Operation::SchedAffinity => {
+                let affinity = with_context(info.pid, |context| Ok(context.affinity))?;
+                read_from(buf, &affinity.to_ne_bytes(), &mut 0)
+            }
+            Operation::WaitStatus => {
+                let (pid, ppid) = {
+                    let contexts = context::contexts();
+                    let context = contexts.get(info.pid).ok_or(Error::new(ESRCH))?.read();
+                    (context.id, context.ppid)
+                };
+                let waitpid = Arc::clone(&context::contexts().get(ppid).ok_or(Error::new(ESRCH))?.read().waitpid);
+
+                // Nothing to report yet, or the parent has consumed it already
+                let (_pid, status, _usage) = waitpid.peek(&WaitpidKey {
+                    pid: Some(pid),
+                    pgid: None,
+                }).ok_or(Error::new(EAGAIN))?;
+                read_from(buf, &status.to_ne_bytes(), &mut 0)
+            }
             Operation::Attr(attr) => {
                 let src_buf = match (attr, &*Arc::clone(context::contexts().get(info.pid).ok_or(Error::new(ESRCH))?).read()) {
                     (Attr::Uid, context) => context.euid.to_string(),
                     (Attr::Gid, context) => context.egid.to_string(),
+                    (Attr::LowLatency, context) => (context.low_latency as u8).to_string(),
                 }.into_bytes();
 
                 read_from(buf, &src_buf, &mut 0)
@@ -834,6 +1066,61 @@ impl Scheme for ProcScheme {
                 *buf.array_chunks_mut::<{mem::size_of::<usize>()}>().next().unwrap() = usize::to_ne_bytes(val);
                 Ok(mem::size_of::<usize>())
             }
+            Operation::RlimitAs(ref addrspace) => {
+                let (limit, size) = {
+                    let addrspace = addrspace.read();
+                    (addrspace.rlimit_as, addrspace.charged_size())
+                };
+                let mut chunks = buf.array_chunks_mut::<{mem::size_of::<usize>()}>();
+                *chunks.next().ok_or(Error::new(EINVAL))? = usize::to_ne_bytes(limit);
+                match chunks.next() {
+                    Some(chunk) => {
+                        *chunk = usize::to_ne_bytes(size);
+                        Ok(2 * mem::size_of::<usize>())
+                    }
+                    None => Ok(mem::size_of::<usize>()),
+                }
+            }
+            Operation::Overcommit(ref addrspace) => {
+                let val = addrspace.read().overcommit as usize;
+                *buf.array_chunks_mut::<{mem::size_of::<usize>()}>().next().ok_or(Error::new(EINVAL))? = usize::to_ne_bytes(val);
+                Ok(mem::size_of::<usize>())
+            }
+            Operation::Mincore(addrspace) => {
+                let mut handles = self.handles.write();
+                let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+                let data = handle.data.mem_data().expect("operations can't change");
+
+                // The offset is the address of the first page to report on, one byte per page.
+                if data.offset.data() % PAGE_SIZE != 0 {
+                    return Err(Error::new(EINVAL));
+                }
+
+                let page_count = addrspace.read().mincore(Page::containing_address(data.offset), buf);
+
+                data.offset = data.offset.add(page_count * PAGE_SIZE);
+                Ok(page_count)
+            }
+            Operation::NumaNodes(addrspace) => {
+                let mut handles = self.handles.write();
+                let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+                let data = handle.data.mem_data().expect("operations can't change");
+
+                // The offset is the address of the first page to report on, one i32 per page.
+                if data.offset.data() % PAGE_SIZE != 0 {
+                    return Err(Error::new(EINVAL));
+                }
+
+                let mut nodes = vec![0i32; buf.len() / mem::size_of::<i32>()];
+                let page_count = addrspace.read().numa_nodes(Page::containing_address(data.offset), &mut nodes);
+
+                for (bytes, node) in buf.chunks_exact_mut(mem::size_of::<i32>()).zip(&nodes[..page_count]) {
+                    bytes.copy_from_slice(&node.to_ne_bytes());
+                }
+
+                data.offset = data.offset.add(page_count * PAGE_SIZE);
+                Ok(page_count * mem::size_of::<i32>())
+            }
             // TODO: Replace write() with SYS_DUP_FORWARD.
             // TODO: Find a better way to switch address spaces, since they also require switching
             // the instruction and stack pointer. Maybe remove `<pid>/regs` altogether and replace it
@@ -902,7 +1189,7 @@ impl Scheme for ProcScheme {
                     ADDRSPACE_OP_MUNMAP => {
                         let (page, page_count) = crate::syscall::validate_region(next()?, next()?)?;
 
-                        addrspace.write().munmap(page, page_count);
+                        addrspace.write().munmap(page, page_count)?;
                     }
                     ADDRSPACE_OP_MPROTECT => {
                         let (page, page_count) = crate::syscall::validate_region(next()?, next()?)?;
@@ -910,6 +1197,16 @@ impl Scheme for ProcScheme {
 
                         addrspace.write().mprotect(page, page_count, flags)?;
                     }
+                    ADDRSPACE_OP_RESERVE => {
+                        let (page, page_count) = crate::syscall::validate_region(next()?, next()?)?;
+
+                        addrspace.write().reserve(page, page_count)?;
+                    }
+                    ADDRSPACE_OP_DONTNEED => {
+                        let (page, page_count) = crate::syscall::validate_region(next()?, next()?)?;
+
+                        addrspace.write().release(page, page_count)?;
+                    }
                     _ => return Err(Error::new(EINVAL)),
                 }
                 Ok(words_read * mem::size_of::<usize>())
@@ -928,10 +1225,7 @@ impl Scheme for ProcScheme {
 
                     with_context_mut(info.pid, |context| {
                         // NOTE: The kernel will never touch floats
-
-                        // Ignore the rare case of floating point
-                        // registers being uninitiated
-                        let _ = context.set_fx_regs(regs);
+                        context.set_fx_regs(regs)?;
 
                         Ok(mem::size_of::<FloatRegisters>())
                     })
@@ -947,16 +1241,10 @@ impl Scheme for ProcScheme {
                         *(buf as *const _ as *const IntRegisters)
                     };
 
-                    try_stop_context(info.pid, |context| match unsafe { ptrace::regs_for_mut(context) } {
-                        None => {
-                            println!("{}:{}: Couldn't read registers from stopped process", file!(), line!());
-                            Err(Error::new(ENOTRECOVERABLE))
-                        },
-                        Some(stack) => {
-                            stack.load(&regs);
+                    try_stop_context(info.pid, |context| {
+                        ptrace::stopped_regs_mut(context)?.load(&regs);
 
-                            Ok(mem::size_of::<IntRegisters>())
-                        }
+                        Ok(mem::size_of::<IntRegisters>())
                     })
                 }
                 RegsKind::Env => {
@@ -973,6 +1261,33 @@ impl Scheme for ProcScheme {
                     Ok(mem::size_of::<EnvRegisters>())
                 }
             },
+            Operation::Checkpoint => {
+                if buf.len() < mem::size_of::<RegsCheckpoint>() {
+                    return Err(Error::new(EINVAL));
+                }
+                let checkpoint = unsafe { (buf.as_ptr() as *const RegsCheckpoint).read_unaligned() };
+                if checkpoint.magic != CHECKPOINT_MAGIC
+                    || checkpoint.version != CHECKPOINT_VERSION
+                    || checkpoint.machine != CHECKPOINT_MACHINE
+                    || checkpoint.size as usize != mem::size_of::<RegsCheckpoint>()
+                    || ! env_is_valid(&checkpoint.env)
+                {
+                    return Err(Error::new(EINVAL));
+                }
+
+                with_stopped_context(info.pid, |context| {
+                    // Check everything before changing anything, so that the context is either
+                    // fully restored or left as it was
+                    if ! ptrace::stopped_regs(context)?.can_load(&checkpoint.int) {
+                        return Err(Error::new(EINVAL));
+                    }
+                    context.set_fx_regs(checkpoint.float)?;
+                    ptrace::stopped_regs_mut(context)?.load(&checkpoint.int);
+                    restore_env(context, &checkpoint.env);
+                    Ok(())
+                })?;
+                Ok(mem::size_of::<RegsCheckpoint>())
+            }
             Operation::Trace => {
                 if buf.len() < mem::size_of::<u64>() {
                     return Ok(0);
@@ -982,35 +1297,30 @@ impl Scheme for ProcScheme {
                 let len = bytes.len();
                 bytes.copy_from_slice(&buf[0..len]);
                 let op = u64::from_ne_bytes(bytes);
-                let op = PtraceFlags::from_bits(op).ok_or(Error::new(EINVAL))?;
+                let trace_syscalls = op & ptrace::PTRACE_FLAG_TRACE_SYSCALLS == ptrace::PTRACE_FLAG_TRACE_SYSCALLS;
+                let op = PtraceFlags::from_bits(op & !ptrace::PTRACE_FLAG_TRACE_SYSCALLS).ok_or(Error::new(EINVAL))?;
 
                 // Set next breakpoint
                 ptrace::Session::with_session(info.pid, |session| {
-                    session.data.lock().set_breakpoint(
+                    let mut data = session.data.lock();
+                    data.set_breakpoint(
                         Some(op)
                             .filter(|op| op.intersects(PTRACE_STOP_MASK | PTRACE_EVENT_MASK))
                     );
+                    data.set_trace_syscalls(trace_syscalls);
                     Ok(())
                 })?;
 
                 if op.contains(PTRACE_STOP_SINGLESTEP) {
                     try_stop_context(info.pid, |context| {
-                        match unsafe { ptrace::regs_for_mut(context) } {
-                            None => {
-                                println!("{}:{}: Couldn't read registers from stopped process", file!(), line!());
-                                Err(Error::new(ENOTRECOVERABLE))
-                            },
-                            Some(stack) => {
-                                stack.set_singlestep(true);
-                                Ok(())
-                            }
-                        }
+                        ptrace::stopped_regs_mut(context)?.set_singlestep(true);
+                        Ok(())
                     })?;
                 }
 
                 // disable the ptrace_stop flag, which is used in some cases
                 with_context_mut(info.pid, |context| {
-                    context.ptrace_stop = false;
+                    context.start();
                     Ok(())
                 })?;
 
@@ -1027,6 +1337,204 @@ impl Scheme for ProcScheme {
                 *context::contexts().get(info.pid).ok_or(Error::new(ESRCH))?.read().name.write() = utf8;
                 Ok(buf.len())
             }
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Operation::Pmc => {
+                let bytes = <[u8; mem::size_of::<usize>()]>::try_from(buf).map_err(|_| Error::new(EINVAL))?;
+                let event = usize::from_ne_bytes(bytes);
+                let evtsel = if event == !0 { 0 } else { context::pmc_event_select(event)? };
+
+                // The counter of the current context is reprogrammed right away, while other
+                // contexts must not be running until they are switched to again
+                if info.pid == context::context_id() {
+                    with_context_mut(info.pid, |context| {
+                        unsafe { context::set_pmc(context, evtsel, true) };
+                        Ok(())
+                    })?;
+                } else {
+                    try_stop_context(info.pid, |context| {
+                        unsafe { context::set_pmc(context, evtsel, false) };
+                        Ok(())
+                    })?;
+                }
+                Ok(buf.len())
+            }
+            Operation::Vfork => {
+                let current_id = context::context_id();
+                {
+                    let contexts = context::contexts();
+                    let current_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+                    let child_lock = contexts.get(info.pid).ok_or(Error::new(ESRCH))?;
+                    let (mut current, mut child) = context::lock_two_contexts(current_id, current_lock, info.pid, child_lock)?;
+
+                    if child.ppid != current_id {
+                        return Err(Error::new(EPERM));
+                    }
+                    // A child that already runs could have replaced its address space before
+                    // being marked, and would then never release the parent
+                    if ! matches!(child.status, Status::Stopped(_)) || ! Arc::ptr_eq(child.addr_space()?, current.addr_space()?) {
+                        return Err(Error::new(EINVAL));
+                    }
+
+                    child.vfork = true;
+                    current.vfork_wait = true;
+                    current.block("vfork");
+
+                    // Resume the child like SIGCONT would
+                    child.status = Status::Blocked;
+                    child.unblock();
+                }
+
+                // The child releases us from `exit` or `vfork_release`. Any other wakeup puts us
+                // back to sleep, as signals are only delivered once the child is done with the
+                // address space, except for SIGKILL, since the child may never be.
+                loop {
+                    {
+                        let current = context::current()?;
+                        let mut current = current.write();
+                        if ! current.vfork_wait {
+                            break;
+                        }
+                        if current.force_kill || current.pending.has_kill() {
+                            current.vfork_wait = false;
+                            return Err(Error::new(EINTR));
+                        }
+                        current.block("vfork");
+                    }
+                    unsafe { context::switch(); }
+                }
+                Ok(buf.len())
+            }
+            Operation::Namespace => {
+                let mut names = str::from_utf8(buf).map_err(|_| Error::new(EINVAL))?
+                    .split('\n')
+                    .filter(|name| ! name.is_empty())
+                    .collect::<Vec<&str>>();
+                names.sort_unstable();
+                names.dedup();
+
+                let current_id = context::context_id();
+                let from = context::current()?.read().ens;
+
+                let check = |child: &Context| if child.ppid != current_id {
+                    Err(Error::new(EPERM))
+                } else if ! matches!(child.status, Status::Stopped(_)) {
+                    Err(Error::new(EBUSY))
+                } else {
+                    Ok(())
+                };
+
+                // Check before creating the namespace, to not make one for a child that cannot
+                // enter it
+                with_context(info.pid, check)?;
+
+                let ns = scheme::schemes_mut().make_ns(from, &names)?;
+
+                // The child may have been resumed or replaced since, so check again
+                let (old_rns, old_ens) = match with_context_mut(info.pid, |child| {
+                    check(child)?;
+                    let old = (child.rns, child.ens);
+                    child.rns = ns;
+                    child.ens = ns;
+                    child.sandboxed = true;
+                    Ok(old)
+                }) {
+                    Ok(old) => old,
+                    Err(err) => {
+                        scheme::schemes_mut().free_ns(ns);
+                        return Err(err);
+                    }
+                };
+
+                let mut schemes = scheme::schemes_mut();
+                schemes.ns_ref(ns);
+                schemes.ns_ref(ns);
+                schemes.ns_unref(old_rns);
+                schemes.ns_unref(old_ens);
+
+                Ok(buf.len())
+            }
+            Operation::SyscallLimit => {
+                let (current_id, current_ppid, current_limit) = {
+                    let current = context::current()?;
+                    let current = current.read();
+                    if current.euid != 0 {
+                        return Err(Error::new(EPERM));
+                    }
+                    (current.id, current.ppid, current.syscall_limit.clone())
+                };
+                let words = <[u8; 3 * mem::size_of::<usize>()]>::try_from(buf).map_err(|_| Error::new(EINVAL))?;
+                let mut words = words.array_chunks::<{mem::size_of::<usize>()}>().copied().map(usize::from_ne_bytes);
+                let (rate, burst, flags) = (words.next().unwrap(), words.next().unwrap(), words.next().unwrap());
+                if flags & !SYSCALL_LIMIT_SLEEP != 0 {
+                    return Err(Error::new(EINVAL));
+                }
+
+                let limit = (rate != 0).then(|| SyscallLimit::new(rate as u64, burst as u64, flags));
+
+                // Only the parent or the tracer may limit a context, so that a limited context
+                // can neither lift its own limit nor that of an ancestor handing out work to it
+                {
+                    let contexts = context::contexts();
+                    if info.pid == current_id || contexts.ancestors(current_ppid).any(|(id, _context)| id == info.pid) {
+                        return Err(Error::new(EPERM));
+                    }
+                    let target_ppid = contexts.get(info.pid).ok_or(Error::new(ESRCH))?.read().ppid;
+                    if target_ppid != current_id && ptrace::tracer_of(info.pid) != Some(current_id) {
+                        return Err(Error::new(EPERM));
+                    }
+                }
+                // Nor may a limited context give a child a looser limit than its own
+                if let Some(ref current_limit) = current_limit {
+                    if !limit.as_ref().map_or(false, |limit| limit.is_within(current_limit)) {
+                        return Err(Error::new(EPERM));
+                    }
+                }
+
+                with_context_mut(info.pid, |context| {
+                    context.syscall_limit = limit;
+                    Ok(())
+                })?;
+                Ok(buf.len())
+            }
+            Operation::SchedAffinity => {
+                let affinity = <[u8; mem::size_of::<u64>()]>::try_from(buf).map_err(|_| Error::new(EINVAL))?;
+                context::set_affinity(info.pid, u64::from_ne_bytes(affinity))?;
+                Ok(buf.len())
+            }
+            Operation::ForceKill => {
+                let (name, status, reason, running_on) = with_context_mut(info.pid, |context| {
+                    context.force_kill = true;
+                    let _ = context.pending.push(SIGKILL as u8);
+                    let (status, reason) = (context.status, context.status_reason);
+
+                    // Neither a stopped context nor a vfork parent would run again otherwise
+                    context.vfork_wait = false;
+                    if let Status::Stopped(_) = context.status {
+                        context.status = Status::Blocked;
+                    }
+                    let running_on = if context.running {
+                        context.cpu_id
+                    } else {
+                        context.unblock();
+                        None
+                    };
+                    Ok((context.name.read().clone(), status, reason, running_on))
+                })?;
+                println!("proc: forcibly killing {} ({}), {:?} ({})", info.pid.into(), name, status, reason);
+
+                if let Some(cpu_id) = running_on {
+                    println!("proc: {} is running on CPU {}, it exits at the end of its syscall if it gets there", info.pid.into(), cpu_id);
+                    // Show where the CPU is, in case the context never gets there
+                    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                    {
+                        if cpu_id != crate::cpu_id() && ! crate::ipi::nmi(cpu_id) {
+                            println!("proc: CPU {} cannot be sent an NMI", cpu_id);
+                        }
+                    }
+                    crate::ipi::ipi(crate::ipi::IpiKind::Switch, crate::ipi::IpiTarget::Other);
+                }
+                Ok(buf.len())
+            }
             Operation::Sigstack => {
                 let bytes = <[u8; mem::size_of::<usize>()]>::try_from(buf).map_err(|_| Error::new(EINVAL))?;
                 let sigstack = usize::from_ne_bytes(bytes);
@@ -1040,10 +1548,67 @@ impl Scheme for ProcScheme {
                 match attr {
                     Attr::Uid => context_lock.write().euid = id,
                     Attr::Gid => context_lock.write().egid = id,
+                    Attr::LowLatency => context_lock.write().low_latency = id != 0,
                 }
                 Ok(buf.len())
             }
-            Operation::Filetable { .. } => Err(Error::new(EBADF)),
+            Operation::Filetable { filetable } => {
+                let mut actions = buf.array_chunks::<{mem::size_of::<usize>()}>().copied().map(usize::from_ne_bytes);
+                let action_count = buf.len() / (3 * mem::size_of::<usize>());
+                if buf.len() % (3 * mem::size_of::<usize>()) != 0 {
+                    return Err(Error::new(EINVAL));
+                }
+
+                // Descriptors are bounded by the RLIMIT_NOFILE of the target, as for dup2
+                let max_files = with_context(info.pid, |context| Ok(context.max_files()))?;
+
+                let removed = {
+                    let mut files = filetable.write();
+
+                    // Apply the actions to a copy, so that either all of them take effect or, if
+                    // any is invalid, none do.
+                    let mut new_files = files.clone();
+                    let mut removed = Vec::new();
+
+                    for _ in 0..action_count {
+                        let (action, fd, src_fd) = (actions.next().unwrap(), actions.next().unwrap(), actions.next().unwrap());
+                        if fd >= max_files {
+                            return Err(Error::new(EBADF));
+                        }
+
+                        match action {
+                            FILE_ACTION_DUP2 => {
+                                let src = new_files.get(src_fd).and_then(Option::as_ref).ok_or(Error::new(EBADF))?;
+                                // Like dup2, the new descriptor is never close-on-exec. Duplicating a
+                                // descriptor onto itself only clears the flag, as with posix_spawn.
+                                let new = FileDescriptor { description: Arc::clone(&src.description), cloexec: false };
+
+                                if new_files.len() <= fd {
+                                    new_files.resize(fd + 1, None);
+                                }
+                                if let Some(old) = new_files[fd].replace(new) {
+                                    removed.push(old);
+                                }
+                            }
+                            FILE_ACTION_CLOSE => {
+                                let old = new_files.get_mut(fd).and_then(Option::take).ok_or(Error::new(EBADF))?;
+                                removed.push(old);
+                            }
+                            _ => return Err(Error::new(EINVAL)),
+                        }
+                    }
+
+                    *files = new_files;
+                    removed
+                };
+
+                // The old table has been dropped, so the replaced descriptors can now be closed.
+                for file in removed {
+                    let _ = file.close();
+                }
+
+                Ok(action_count * 3 * mem::size_of::<usize>())
+            }
 
             Operation::CurrentFiletable => {
                 let filetable_fd = usize::from_ne_bytes(<[u8; mem::size_of::<usize>()]>::try_from(buf).map_err(|_| Error::new(EINVAL))?);
@@ -1068,6 +1633,19 @@ impl Scheme for ProcScheme {
 
                 Ok(3 * mem::size_of::<usize>())
             }
+            Operation::Exec => {
+                let mut iter = buf.array_chunks::<{mem::size_of::<usize>()}>().copied().map(usize::from_ne_bytes);
+                let addrspace_fd = iter.next().ok_or(Error::new(EINVAL))?;
+                let sp = iter.next().ok_or(Error::new(EINVAL))?;
+                let ip = iter.next().ok_or(Error::new(EINVAL))?;
+
+                let (hopefully_this_scheme, number) = extract_scheme_number(addrspace_fd)?;
+                let space = hopefully_this_scheme.as_addrspace(number)?;
+
+                exec_in_place(info.pid, space, sp, ip)?;
+
+                Ok(3 * mem::size_of::<usize>())
+            }
             Operation::CurrentSigactions => {
                 let sigactions_fd = usize::from_ne_bytes(<[u8; mem::size_of::<usize>()]>::try_from(buf).map_err(|_| Error::new(EINVAL))?);
                 let (hopefully_this_scheme, number) = extract_scheme_number(sigactions_fd)?;
@@ -1081,6 +1659,33 @@ impl Scheme for ProcScheme {
                 addrspace.write().mmap_min = val;
                 Ok(mem::size_of::<usize>())
             }
+            Operation::RlimitAs(ref addrspace) => {
+                let val = usize::from_ne_bytes(<[u8; mem::size_of::<usize>()]>::try_from(buf).map_err(|_| Error::new(EINVAL))?);
+                let euid = context::current()?.read().euid;
+
+                let mut addrspace = addrspace.write();
+                if val > addrspace.rlimit_as && euid != 0 {
+                    return Err(Error::new(EPERM));
+                }
+                addrspace.rlimit_as = val;
+                Ok(mem::size_of::<usize>())
+            }
+            Operation::Overcommit(ref addrspace) => {
+                let val = usize::from_ne_bytes(<[u8; mem::size_of::<usize>()]>::try_from(buf).map_err(|_| Error::new(EINVAL))?);
+                let overcommit = match val {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(Error::new(EINVAL)),
+                };
+                let euid = context::current()?.read().euid;
+
+                let mut addrspace = addrspace.write();
+                if overcommit && !addrspace.overcommit && euid != 0 {
+                    return Err(Error::new(EPERM));
+                }
+                addrspace.overcommit = overcommit;
+                Ok(mem::size_of::<usize>())
+            }
             _ => Err(Error::new(EBADF)),
         }
     }
@@ -1117,20 +1722,34 @@ impl Scheme for ProcScheme {
             Operation::Regs(RegsKind::Float) => "regs/float",
             Operation::Regs(RegsKind::Int) => "regs/int",
             Operation::Regs(RegsKind::Env) => "regs/env",
+            Operation::Checkpoint => "regs/checkpoint",
             Operation::Trace => "trace",
             Operation::Static(path) => path,
             Operation::Name => "name",
             Operation::Sigstack => "sigstack",
             Operation::Attr(Attr::Uid) => "uid",
             Operation::Attr(Attr::Gid) => "gid",
+            Operation::Attr(Attr::LowLatency) => "low-latency",
             Operation::Filetable { .. } => "filetable",
             Operation::AddrSpace { .. } => "addrspace",
             Operation::Sigactions(_) => "sigactions",
             Operation::CurrentAddrSpace => "current-addrspace",
+            Operation::Exec => "exec",
             Operation::CurrentFiletable => "current-filetable",
             Operation::CurrentSigactions => "current-sigactions",
             Operation::OpenViaDup => "open-via-dup",
             Operation::MmapMinAddr(_) => "mmap-min-addr",
+            Operation::RlimitAs(_) => "rlimit-as",
+            Operation::Overcommit(_) => "overcommit",
+            Operation::Mincore(_) => "mincore",
+            Operation::NumaNodes(_) => "numa-nodes",
+            Operation::Pmc => "pmc",
+            Operation::WaitStatus => "wait-status",
+            Operation::Vfork => "vfork",
+            Operation::Namespace => "namespace",
+            Operation::SyscallLimit => "syscall-limit",
+            Operation::SchedAffinity => "sched-affinity",
+            Operation::ForceKill => "force-kill",
 
             _ => return Err(Error::new(EOPNOTSUPP)),
         });
@@ -1167,28 +1786,8 @@ impl Scheme for ProcScheme {
 
         match handle.info.operation {
             Operation::AwaitingAddrSpaceChange { new, new_sp, new_ip } => {
-                stop_context(handle.info.pid, |context: &mut Context| unsafe {
-                    if let Some(saved_regs) = ptrace::regs_for_mut(context) {
-                        #[cfg(target_arch = "aarch64")]
-                        {
-                            saved_regs.iret.elr_el1 = new_ip;
-                            saved_regs.iret.sp_el0 = new_sp;
-                        }
-
-                        #[cfg(target_arch = "x86")]
-                        {
-                            saved_regs.iret.eip = new_ip;
-                            saved_regs.iret.esp = new_sp;
-                        }
-
-                        #[cfg(target_arch = "x86_64")]
-                        {
-                            saved_regs.iret.rip = new_ip;
-                            saved_regs.iret.rsp = new_sp;
-                        }
-                    } else {
-                        context.clone_entry = Some([new_ip, new_sp]);
-                    }
+                stop_context(handle.info.pid, |context: &mut Context| {
+                    set_entry(context, new_ip, new_sp);
 
                     let prev_addr_space = context.set_addr_space(new);
 
@@ -1199,17 +1798,25 @@ impl Scheme for ProcScheme {
                     Ok(())
                 })?;
                 let _ = ptrace::send_event(crate::syscall::ptrace_event!(PTRACE_EVENT_ADDRSPACE_SWITCH, 0));
+
+                // Exec is done, so a vfork parent may use its address space again
+                syscall::vfork_release(handle.info.pid);
+
+                unshare_forked_actions(handle.info.pid)?;
             }
-            Operation::AddrSpace { addrspace } | Operation::Memory { addrspace } | Operation::MmapMinAddr(addrspace) => maybe_cleanup_addr_space(addrspace),
+            Operation::AddrSpace { addrspace } | Operation::Memory { addrspace } | Operation::MmapMinAddr(addrspace) | Operation::RlimitAs(addrspace) | Operation::Overcommit(addrspace) | Operation::Mincore(addrspace) | Operation::NumaNodes(addrspace) => maybe_cleanup_addr_space(addrspace),
 
             Operation::AwaitingFiletableChange(new) => with_context_mut(handle.info.pid, |context: &mut Context| {
                 context.files = new;
                 Ok(())
             })?,
-            Operation::AwaitingSigactionsChange(new) => with_context_mut(handle.info.pid, |context: &mut Context| {
-                context.actions = new;
-                Ok(())
-            })?,
+            Operation::AwaitingSigactionsChange(new) => {
+                with_context_mut(handle.info.pid, |context: &mut Context| {
+                    context.actions = new;
+                    Ok(())
+                })?;
+                unshare_forked_actions(handle.info.pid)?;
+            }
             Operation::Trace => {
                 ptrace::close_session(handle.info.pid);
 
@@ -1220,7 +1827,7 @@ impl Scheme for ProcScheme {
                 let contexts = context::contexts();
                 if let Some(context) = contexts.get(handle.info.pid) {
                     let mut context = context.write();
-                    context.ptrace_stop = false;
+                    context.start();
                 }
             }
             _ => (),
@@ -1265,11 +1872,11 @@ impl KernelScheme for ProcScheme {
                 let page = current_space
                     .write()
                     .mmap(None, page_count, MapFlags::PROT_READ, |page, flags, mapper, flusher| {
-                        Ok(Grant::zeroed(page, page_count, flags, mapper, flusher)?)
+                        Ok(Grant::zeroed(page, page_count, flags, mapper, flusher, FrameHint::local())?)
                     })?;
 
                 // Write Map using kernel's physmap
-                let (phys, _flags) = current_space.read().table.utable.translate(page.start_address()).expect("could not find mapping that was just made");
+                let (phys, _flags) = current_space.read().table.translate(page.start_address()).expect("could not find mapping that was just made");
                 unsafe { core::ptr::write(RmmA::phys_to_virt(phys).data() as *mut Map, *map); }
 
                 // Scheme fmap with Map in user memory
@@ -1281,8 +1888,8 @@ impl KernelScheme for ProcScheme {
                 let scheme = Arc::clone(scheme::schemes().get(scheme_id).ok_or(Error::new(EBADFD))?);
                 let res = scheme.fmap(number, unsafe { &*(page.start_address().data() as *const Map) });
 
-                // Unmap Map user memory
-                current_space.write().munmap(page, page_count);
+                // Unmap Map user memory, which has no huge pages to split
+                let _ = current_space.write().munmap(page, page_count);
 
                 res
             }
@@ -1319,9 +1926,16 @@ impl KernelScheme for ProcScheme {
 
                 let grant_page_count = src_grant_region.size() / PAGE_SIZE;
 
+                if !consume {
+                    src_addr_space.fault_in_range(Page::containing_address(src_grant_region.start_address()), grant_page_count);
+                }
                 let src_mapper = &mut src_addr_space.table.utable;
 
                 let result_page = if consume {
+                    // Huge pages are moved page by page, and split while the grant can still be
+                    // left in place
+                    split_huge_pages(src_mapper, src_grant_region, InactiveFlusher::new())?;
+
                     let grant = src_addr_space.grants.take(&src_grant_region).expect("grant cannot disappear");
                     let (before, middle, after) = grant.extract(src_grant_region).expect("called intersect(), must succeed");
 
@@ -1350,13 +1964,40 @@ extern "C" fn clone_handler() {
     }
 }
 
+/// Give the context its own copy of the signal actions it shares with its parent, if it does not
+/// also share the address space. As in POSIX, a forked child then has its own dispositions, while
+/// threads, which share the address space, keep sharing them. The address space and the actions
+/// may be set in either order when cloning, so this is checked whenever either changes.
+fn unshare_forked_actions(pid: ContextId) -> Result<()> {
+    let ppid = with_context(pid, |context| Ok(context.ppid))?;
+    let (parent_actions, parent_addr_space) = match with_context(ppid, |parent| Ok((Arc::clone(&parent.actions), parent.addr_space().ok().cloned()))) {
+        Ok(parent) => parent,
+        // Orphans have nobody to share with
+        Err(_) => return Ok(()),
+    };
+
+    with_context_mut(pid, |context| {
+        let addr_space = match context.addr_space() {
+            Ok(addr_space) => addr_space,
+            // Not decided yet, so this is checked again once it is set
+            Err(_) => return Ok(()),
+        };
+        let shares_addr_space = parent_addr_space.map_or(false, |parent| Arc::ptr_eq(addr_space, &parent));
+        if Arc::ptr_eq(&context.actions, &parent_actions) && ! shares_addr_space {
+            context.actions = Arc::new(RwLock::new(parent_actions.read().clone()));
+        }
+        Ok(())
+    })
+}
+
 fn inherit_context() -> Result<ContextId> {
-    let new_id = {
+    let (new_id, rns, ens) = {
         let current_context_lock = Arc::clone(context::contexts().current().ok_or(Error::new(ESRCH))?);
         let new_context_lock = Arc::clone(context::contexts_mut().spawn(clone_handler)?);
 
-        let current_context = current_context_lock.read();
-        let mut new_context = new_context_lock.write();
+        let current_id = context::context_id();
+        let child_id = new_context_lock.read().id;
+        let (current_context, mut new_context) = context::lock_two_contexts(current_id, &current_context_lock, child_id, &new_context_lock)?;
 
         new_context.status = Status::Stopped(SIGSTOP);
         new_context.euid = current_context.euid;
@@ -1365,17 +2006,30 @@ fn inherit_context() -> Result<ContextId> {
         new_context.rgid = current_context.rgid;
         new_context.ens = current_context.ens;
         new_context.rns = current_context.rns;
+        new_context.sandboxed = current_context.sandboxed;
+        new_context.syscall_limit = current_context.syscall_limit.as_ref().map(|limit| SyscallLimit::new(limit.rate, limit.burst, limit.flags));
+        new_context.affinity = current_context.affinity;
+        new_context.rlimits = current_context.rlimits.clone();
         new_context.ppid = current_context.id;
         new_context.pgid = current_context.pgid;
         new_context.umask = current_context.umask;
         new_context.sigmask = current_context.sigmask;
+        new_context.ctty = current_context.ctty;
+        new_context.priority = current_context.priority;
+        new_context.low_latency = current_context.low_latency;
         new_context.cpu_id = current_context.cpu_id;
 
         // TODO: More to copy?
 
-        new_context.id
+        (new_context.id, new_context.rns, new_context.ens)
     };
 
+    {
+        let mut schemes = scheme::schemes_mut();
+        schemes.ns_ref(rns);
+        schemes.ns_ref(ens);
+    }
+
     if ptrace::send_event(crate::syscall::ptrace_event!(PTRACE_EVENT_CLONE, new_id.into())).is_some() {
         // Freeze the clone, allow ptrace to put breakpoints
         // to it before it starts
@@ -1395,6 +2049,104 @@ fn extract_scheme_number(fd: usize) -> Result<(Arc<dyn KernelScheme>, usize)> {
 
     Ok((scheme, number))
 }
+/// Make the context resume at `ip` with the stack at `sp`, when it returns to userspace or, if it
+/// has not run yet, when it first does
+fn set_entry(context: &mut Context, ip: usize, sp: usize) {
+    if let Some(saved_regs) = unsafe { ptrace::regs_for_mut(context) } {
+        #[cfg(target_arch = "aarch64")]
+        {
+            saved_regs.iret.elr_el1 = ip;
+            saved_regs.iret.sp_el0 = sp;
+        }
+
+        #[cfg(target_arch = "x86")]
+        {
+            saved_regs.iret.eip = ip;
+            saved_regs.iret.esp = sp;
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            saved_regs.iret.rip = ip;
+            saved_regs.iret.rsp = sp;
+        }
+    } else {
+        context.clone_entry = Some([ip, sp]);
+    }
+}
+
+/// Replace the image of `pid` with the address space `new`, entered at `new_ip` with the stack at
+/// `new_sp`. Signal handlers are reset to the default unless the signal is ignored, the signal
+/// stack is removed, and close-on-exec file descriptors are closed. The context may be the
+/// current one, which then returns to the new image. If this fails, nothing has been changed and
+/// the old image keeps running.
+fn exec_in_place(pid: ContextId, new: Arc<RwLock<AddrSpace>>, new_sp: usize, new_ip: usize) -> Result<()> {
+    let stop_context = if pid == context::context_id() { with_context_mut } else { try_stop_context };
+
+    let (prev_addr_space, closed) = stop_context(pid, |context: &mut Context| {
+        // Allocate everything first, so that failing leaves the context as it was
+        let actions = {
+            let old_actions = context.actions.read();
+            let mut actions = Vec::new();
+            actions.try_reserve_exact(old_actions.len()).map_err(|_| Error::new(ENOMEM))?;
+            actions.extend(old_actions.iter().map(|(action, restorer)| {
+                if action.sa_handler.map(|ptr| ptr as usize) == Some(SIG_IGN) {
+                    (action.clone(), *restorer)
+                } else {
+                    (SigAction {
+                        sa_handler: unsafe { mem::transmute(SIG_DFL) },
+                        sa_mask: [0; 2],
+                        sa_flags: SigActionFlags::empty(),
+                    }, 0)
+                }
+            }));
+            Arc::try_new(RwLock::new(actions)).map_err(|_| Error::new(ENOMEM))?
+        };
+
+        // The table may be shared with other contexts, which keep their descriptors, so the
+        // remaining ones are copied into a new table
+        let (files, closed) = {
+            let old_files = context.files.read();
+            let mut files = Vec::new();
+            let mut closed = Vec::new();
+            files.try_reserve_exact(old_files.len()).map_err(|_| Error::new(ENOMEM))?;
+            closed.try_reserve_exact(old_files.iter().flatten().filter(|file| file.cloexec).count()).map_err(|_| Error::new(ENOMEM))?;
+            for file in old_files.iter() {
+                match file {
+                    Some(file) if file.cloexec => {
+                        closed.push(file.clone());
+                        files.push(None);
+                    }
+                    file => files.push(file.clone()),
+                }
+            }
+            (Arc::try_new(RwLock::new(files)).map_err(|_| Error::new(ENOMEM))?, closed)
+        };
+
+        set_entry(context, new_ip, new_sp);
+        context.actions = actions;
+        context.files = files;
+        context.sigstack = None;
+
+        Ok((context.set_addr_space(new), closed))
+    })?;
+
+    if let Some(prev_addr_space) = prev_addr_space {
+        maybe_cleanup_addr_space(prev_addr_space);
+    }
+    // The old table has been dropped unless shared, so these are closed if nothing else uses them
+    for file in closed {
+        let _ = file.close();
+    }
+
+    let _ = ptrace::send_event(crate::syscall::ptrace_event!(PTRACE_EVENT_ADDRSPACE_SWITCH, 0));
+
+    // Exec is done, so a vfork parent may use its address space again
+    syscall::vfork_release(pid);
+
+    Ok(())
+}
+
 fn maybe_cleanup_addr_space(addr_space: Arc<RwLock<AddrSpace>>) {
     if let Ok(mut space) = Arc::try_unwrap(addr_space).map(RwLock::into_inner) {
         // We are the last reference to the address space; therefore it must be
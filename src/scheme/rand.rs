@@ -0,0 +1,152 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, RwLock};
+
+use crate::event;
+use crate::rand;
+use crate::scheme::SchemeId;
+use crate::sync::WaitCondition;
+use crate::syscall::error::*;
+use crate::syscall::flag::{EventFlags, EVENT_READ, F_GETFL, F_SETFL, O_ACCMODE, O_NONBLOCK};
+use crate::syscall::scheme::Scheme;
+
+/// Handles of blocking schemes that were not readable when their events were last checked,
+/// because the generator could not be seeded yet. Blocking readers also hold this lock while
+/// checking whether the generator can be seeded, until they wait on `SEED_CONDITION`.
+static UNSEEDED: Mutex<BTreeSet<(SchemeId, usize)>> = Mutex::new(BTreeSet::new());
+/// Blocking readers waiting for enough entropy to seed the generator
+static SEED_CONDITION: WaitCondition = WaitCondition::new();
+
+/// Wake the readers waiting for the generator to be seeded, and trigger EVENT_READ on the handles
+/// that were not readable before enough entropy was gathered. This is called from interrupt
+/// handlers, so if the interrupted code holds any of the locks, false is returned, to be tried
+/// again on a later interrupt.
+pub fn seed_ready() -> bool {
+    let unseeded = match UNSEEDED.try_lock() {
+        Some(mut unseeded) => {
+            if ! SEED_CONDITION.try_notify() {
+                return false;
+            }
+            mem::take(&mut *unseeded)
+        },
+        None => return false,
+    };
+    for (scheme_id, id) in unseeded {
+        event::trigger(scheme_id, id, EVENT_READ);
+    }
+    true
+}
+
+pub struct RandScheme {
+    scheme_id: SchemeId,
+    /// Whether reads wait for the generator to be seeded, rather than returning whatever it has
+    blocking: bool,
+    next_id: AtomicUsize,
+    handles: RwLock<BTreeMap<usize, AtomicUsize>>,
+}
+
+impl RandScheme {
+    pub fn new(scheme_id: SchemeId, blocking: bool) -> RandScheme {
+        RandScheme {
+            scheme_id,
+            blocking,
+            next_id: AtomicUsize::new(0),
+            handles: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    fn flags(&self, id: usize) -> Result<usize> {
+        let handles = self.handles.read();
+        let flags = handles.get(&id).ok_or(Error::new(EBADF))?;
+        Ok(flags.load(Ordering::SeqCst))
+    }
+}
+
+impl Scheme for RandScheme {
+    fn open(&self, _path: &str, flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.handles.write().insert(id, AtomicUsize::new(flags & ! O_ACCMODE));
+        Ok(id)
+    }
+
+    fn read(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let flags = self.flags(id)?;
+
+        if self.blocking {
+            loop {
+                // Checked under the lock, so that `seed_ready` either wakes this reader or runs
+                // before the check and lets it seed the generator
+                let unseeded = UNSEEDED.lock();
+                if rand::is_seeded() {
+                    break;
+                }
+                if flags & O_NONBLOCK == O_NONBLOCK {
+                    return Err(Error::new(EAGAIN));
+                }
+                if ! SEED_CONDITION.wait(unseeded, "RandScheme::read") {
+                    return Err(Error::new(EINTR));
+                }
+            }
+        }
+
+        rand::fill(buf);
+        Ok(buf.len())
+    }
+
+    /// Mix the written data into the entropy pool, without crediting it
+    fn write(&self, id: usize, buf: &[u8]) -> Result<usize> {
+        let _flags = self.flags(id)?;
+        rand::add_entropy(buf);
+        Ok(buf.len())
+    }
+
+    fn fcntl(&self, id: usize, cmd: usize, arg: usize) -> Result<usize> {
+        let handles = self.handles.read();
+        let flags = handles.get(&id).ok_or(Error::new(EBADF))?;
+        match cmd {
+            F_GETFL => Ok(flags.load(Ordering::SeqCst)),
+            F_SETFL => {
+                flags.store(arg & ! O_ACCMODE, Ordering::SeqCst);
+                Ok(0)
+            },
+            _ => Err(Error::new(EINVAL))
+        }
+    }
+
+    fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
+        let _flags = self.flags(id)?;
+        if ! self.blocking {
+            return Ok(EVENT_READ);
+        }
+
+        // Checked under the lock, so that `seed_ready` either sees the handle or runs before the
+        // check and lets it seed the generator
+        let mut unseeded = UNSEEDED.lock();
+        if rand::is_seeded() {
+            unseeded.remove(&(self.scheme_id, id));
+            Ok(EVENT_READ)
+        } else {
+            unseeded.insert((self.scheme_id, id));
+            Ok(EventFlags::empty())
+        }
+    }
+
+    fn fpath(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let _flags = self.flags(id)?;
+
+        let mut i = 0;
+        let scheme_path: &[u8] = if self.blocking { b"rand:" } else { b"urandom:" };
+        while i < buf.len() && i < scheme_path.len() {
+            buf[i] = scheme_path[i];
+            i += 1;
+        }
+        Ok(i)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        UNSEEDED.lock().remove(&(self.scheme_id, id));
+        self.handles.write().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
+impl crate::scheme::KernelScheme for RandScheme {}
@@ -12,7 +12,7 @@ use spin::{Mutex, RwLock};
 use crate::context;
 use crate::syscall::data::Stat;
 use crate::syscall::error::*;
-use crate::syscall::flag::{EventFlags, O_CREAT, MODE_FILE, MODE_DIR};
+use crate::syscall::flag::{EventFlags, F_GETFL, F_SETFL, O_CREAT, MODE_FILE, MODE_DIR};
 use crate::syscall::scheme::{calc_seek_offset_usize, Scheme};
 use crate::scheme::{self, SchemeNamespace, SchemeId};
 use crate::scheme::user::{UserInner, UserScheme};
@@ -44,6 +44,27 @@ impl FolderInner {
     }
 }
 
+/// fcntl command on a scheme handle, making the scheme read-only if the argument is nonzero
+// TODO: Move to syscall::flag
+pub const F_SETRDONLY: usize = 0x100;
+/// fcntl command on `:name`, unmapping all memory of the scheme `name` from its clients. Only root
+/// may do this, and the number of revoked grants is returned.
+// TODO: Move to syscall::flag
+pub const F_REVOKE_GRANTS: usize = 0x101;
+/// fcntl command on a scheme handle, making the kernel enforce O_EXCL on creating opens if the
+/// argument is nonzero, for schemes that don't do so themselves
+// TODO: Move to syscall::flag
+pub const F_SETEXCLCREATE: usize = 0x102;
+/// fcntl command on a scheme handle, failing requests with ETIMEDOUT if the handler has not
+/// answered them within the argument, in milliseconds. Zero waits forever, which is the default.
+// TODO: Move to syscall::flag
+pub const F_SETTIMEOUT: usize = 0x103;
+/// fcntl command on a scheme handle, splitting reads larger than a page into page sized requests
+/// if the argument is nonzero. A read is then only blocked for one page at a time, and stops early
+/// with what was read so far if a signal arrives in between.
+// TODO: Move to syscall::flag
+pub const F_SETCHUNKEDREAD: usize = 0x104;
+
 #[derive(Clone)]
 enum Handle {
     Scheme(Arc<UserInner>),
@@ -67,6 +88,22 @@ impl RootScheme {
             handles: RwLock::new(BTreeMap::new()),
         }
     }
+
+    /// Find the user scheme called `name` that was created through this root scheme
+    fn user_scheme(&self, name: &str) -> Result<Arc<UserInner>> {
+        let handles = self.handles.read();
+        handles.iter().find_map(|(_id, handle)| {
+            match handle {
+                Handle::Scheme(inner) => {
+                    if name == inner.name.as_ref() {
+                        return Some(inner.clone());
+                    }
+                },
+                _ => (),
+            }
+            None
+        }).ok_or(Error::new(ENOENT))
+    }
 }
 
 impl Scheme for RootScheme {
@@ -141,22 +178,7 @@ impl Scheme for RootScheme {
         let path = path.trim_matches('/');
 
         if uid == 0 {
-            let inner = {
-                let handles = self.handles.read();
-                handles.iter().find_map(|(_id, handle)| {
-                    match handle {
-                        Handle::Scheme(inner) => {
-                            if path == inner.name.as_ref() {
-                                return Some(inner.clone());
-                            }
-                        },
-                        _ => (),
-                    }
-                    None
-                }).ok_or(Error::new(ENOENT))?
-            };
-
-            inner.unmount()
+            self.user_scheme(path)?.unmount()
         } else {
             Err(Error::new(EACCES))
         }
@@ -242,6 +264,55 @@ impl Scheme for RootScheme {
         }
     }
 
+    fn fcntl(&self, file: usize, cmd: usize, arg: usize) -> Result<usize> {
+        let handle = {
+            let handles = self.handles.read();
+            let handle = handles.get(&file).ok_or(Error::new(EBADF))?;
+            handle.clone()
+        };
+
+        match handle {
+            Handle::Scheme(inner) => match cmd {
+                F_SETRDONLY => {
+                    inner.set_read_only(arg != 0);
+                    Ok(0)
+                },
+                F_SETEXCLCREATE => {
+                    inner.set_excl_create(arg != 0);
+                    Ok(0)
+                },
+                F_SETTIMEOUT => {
+                    inner.set_timeout(arg);
+                    Ok(0)
+                },
+                F_SETCHUNKEDREAD => {
+                    inner.set_chunked_read(arg != 0);
+                    Ok(0)
+                },
+                F_GETFL => Ok(inner.flags()),
+                F_SETFL => {
+                    inner.set_flags(arg);
+                    Ok(0)
+                },
+                _ => Err(Error::new(EINVAL)),
+            },
+            Handle::File(inner) => match cmd {
+                F_REVOKE_GRANTS => {
+                    let euid = context::current()?.read().euid;
+                    if euid != 0 {
+                        return Err(Error::new(EACCES));
+                    }
+                    let name = str::from_utf8(&inner).or(Err(Error::new(ENOENT)))?;
+                    Ok(self.user_scheme(name)?.revoke_grants())
+                },
+                _ => Err(Error::new(EBADF)),
+            },
+            Handle::Folder(_) => {
+                Err(Error::new(EBADF))
+            }
+        }
+    }
+
     fn fpath(&self, file: usize, buf: &mut [u8]) -> Result<usize> {
         let handle = {
             let handles = self.handles.read();
@@ -335,6 +406,9 @@ impl Scheme for RootScheme {
         let handle = self.handles.write().remove(&file).ok_or(Error::new(EBADF))?;
         match handle {
             Handle::Scheme(inner) => {
+                // Report the hangup to clients before the scheme disappears
+                let _ = inner.unmount();
+
                 let scheme_id = inner.scheme_id.load(Ordering::SeqCst);
                 let mut schemes = scheme::schemes_mut();
                 schemes.remove(scheme_id);
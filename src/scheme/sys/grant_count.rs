@@ -0,0 +1,45 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::context;
+use crate::context::memory::{grant_count, GrantKind};
+use crate::syscall::error::Result;
+
+/// Count the grants of every kind, in total and in every address space, to spot leaks
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = format!("{:<12}{}\n", "KIND", "COUNT");
+    for kind in GrantKind::ALL {
+        string.push_str(&format!("{:<12}{}\n", kind.name(), grant_count(kind)));
+    }
+
+    string.push_str(&format!("\n{:<6}{:<12}{:<12}{}\n",
+                             "PID",
+                             "ANONYMOUS",
+                             "BORROWED",
+                             "FILE"));
+
+    // Threads share their address space, which is only listed for the first of them
+    let mut addr_spaces = Vec::new();
+    {
+        let contexts = context::contexts();
+        for (id, context_lock) in contexts.iter() {
+            let context = context_lock.read();
+            if let Ok(addr_space) = context.addr_space() {
+                if !addr_spaces.iter().any(|(_, known)| Arc::ptr_eq(known, addr_space)) {
+                    addr_spaces.push((*id, Arc::clone(addr_space)));
+                }
+            }
+        }
+    }
+
+    for (id, addr_space) in addr_spaces {
+        let addr_space = addr_space.read();
+        string.push_str(&format!("{:<6}{:<12}{:<12}{}\n",
+                                 id.into(),
+                                 addr_space.grants.count_of(GrantKind::Anonymous),
+                                 addr_space.grants.count_of(GrantKind::Borrowed),
+                                 addr_space.grants.count_of(GrantKind::File)));
+    }
+
+    Ok(string.into_bytes())
+}
@@ -0,0 +1,44 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::context;
+use crate::syscall::error::Result;
+
+/// List the grants of scheme memory in every address space
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = format!("{:<6}{:<8}{:<20}{}\n",
+                             "PID",
+                             "SCHEME",
+                             "ADDRESS",
+                             "SIZE");
+
+    // Threads share their address space, which is only listed for the first of them
+    let mut addr_spaces = Vec::new();
+    {
+        let contexts = context::contexts();
+        for (id, context_lock) in contexts.iter() {
+            let context = context_lock.read();
+            if let Ok(addr_space) = context.addr_space() {
+                if !addr_spaces.iter().any(|(_, known)| Arc::ptr_eq(known, addr_space)) {
+                    addr_spaces.push((*id, Arc::clone(addr_space)));
+                }
+            }
+        }
+    }
+
+    for (id, addr_space) in addr_spaces {
+        for grant in addr_space.read().grants.iter() {
+            if let Some(ref file_ref) = grant.desc_opt {
+                let scheme = file_ref.desc.description.read().scheme;
+                string.push_str(&format!("{:<6}{:<8}{:<#20x}{:#x}\n",
+                                   id.into(),
+                                   scheme.into(),
+                                   grant.start_address().data(),
+                                   grant.size()));
+            }
+        }
+    }
+
+    Ok(string.into_bytes())
+}
@@ -0,0 +1,24 @@
+use alloc::vec::Vec;
+
+use crate::context::memory::AddrSpace;
+use crate::syscall::error::Result;
+
+/// Bytes mapped by the address space of the caller, by what backs them
+pub fn resource() -> Result<Vec<u8>> {
+    let stats = AddrSpace::current()?.read().memory_stats();
+
+    let mut string = format!("{:<20}{}\n", "KIND", "BYTES");
+    for (kind, bytes) in [
+        ("anonymous_resident", stats.anonymous_resident),
+        ("anonymous_reserved", stats.anonymous_reserved),
+        ("file", stats.file),
+        ("borrowed", stats.borrowed),
+        ("total", stats.total),
+    ] {
+        string.push_str(&format!("{:<20}{}\n", kind, bytes));
+    }
+    // Not a category of its own, but counted as anonymous_resident above
+    string.push_str(&format!("{:<20}{}\n", "locked", stats.locked));
+
+    Ok(string.into_bytes())
+}
@@ -5,9 +5,11 @@ use core::str;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::RwLock;
 
+use crate::event;
+use crate::scheme::SchemeId;
 use crate::syscall::data::Stat;
-use crate::syscall::error::{Error, EBADF, ENOENT, Result};
-use crate::syscall::flag::{MODE_DIR, MODE_FILE};
+use crate::syscall::error::{Error, EBADF, ENOENT, EPERM, Result};
+use crate::syscall::flag::{EventFlags, EVENT_READ, MODE_DIR, MODE_FILE};
 use crate::syscall::scheme::{calc_seek_offset_usize, Scheme};
 use crate::arch::interrupt;
 
@@ -15,10 +17,17 @@ mod block;
 mod context;
 mod cpu;
 mod exe;
+mod grant_count;
+mod grants;
 mod iostat;
 mod irq;
 mod log;
+mod memstat;
+mod runqueue;
+mod sched;
 mod scheme;
+mod scheme_handler;
+mod scheme_handles;
 mod scheme_num;
 mod syscall;
 mod uname;
@@ -27,47 +36,92 @@ struct Handle {
     path: &'static str,
     data: Vec<u8>,
     mode: u16,
-    seek: usize
+    seek: usize,
+    uid: u32,
+}
+
+/// Open files of every sys: scheme, by scheme ID and handle ID
+static OPEN_FILES: RwLock<BTreeMap<(SchemeId, usize), &'static str>> = RwLock::new(BTreeMap::new());
+
+/// Notify readers of a sys: file that its contents changed. Handles keep the contents read at open
+/// time, so readers have to open the file again to see the change. Safe to call from interrupt
+/// handlers, but readers are not notified if a handle is being opened or closed concurrently.
+pub fn notify(path: &str) {
+    if let Some(open_files) = OPEN_FILES.try_read() {
+        for (&(scheme_id, id), _) in open_files.iter().filter(|(_, open_path)| **open_path == path) {
+            event::trigger(scheme_id, id, EVENT_READ);
+        }
+    }
 }
 
 type SysFn = dyn Fn() -> Result<Vec<u8>> + Send + Sync;
+type SysWriteFn = dyn Fn(&[u8]) -> Result<usize> + Send + Sync;
 
 /// System information scheme
 pub struct SysScheme {
+    scheme_id: SchemeId,
     next_id: AtomicUsize,
     files: BTreeMap<&'static str, Box<SysFn>>,
+    /// Files that root can also write to, for adjusting the kernel at runtime
+    writable: BTreeMap<&'static str, Box<SysWriteFn>>,
     handles: RwLock<BTreeMap<usize, Handle>>
 }
 
 impl SysScheme {
-    pub fn new() -> SysScheme {
+    pub fn new(scheme_id: SchemeId) -> SysScheme {
         let mut files: BTreeMap<&'static str, Box<SysFn>> = BTreeMap::new();
 
         files.insert("block", Box::new(block::resource));
+        files.insert("cmdline", Box::new(|| Ok(Vec::from(crate::cmdline::cmdline()))));
         files.insert("context", Box::new(context::resource));
         files.insert("cpu", Box::new(cpu::resource));
         files.insert("exe", Box::new(exe::resource));
+        files.insert("grant_count", Box::new(grant_count::resource));
+        files.insert("grants", Box::new(grants::resource));
         files.insert("iostat", Box::new(iostat::resource));
         files.insert("irq", Box::new(irq::resource));
         files.insert("log", Box::new(log::resource));
+        files.insert("memstat", Box::new(memstat::resource));
+        files.insert("numa", Box::new(|| Ok(crate::memory::numa::resource())));
+        files.insert("runqueue", Box::new(runqueue::resource));
+        files.insert("sched", Box::new(sched::resource));
         files.insert("scheme", Box::new(scheme::resource));
+        files.insert("scheme_handler", Box::new(scheme_handler::resource));
+        files.insert("scheme_handles", Box::new(scheme_handles::resource));
         files.insert("scheme_num", Box::new(scheme_num::resource));
         files.insert("syscall", Box::new(syscall::resource));
         files.insert("uname", Box::new(uname::resource));
         files.insert("env", Box::new(|| Ok(Vec::from(crate::init_env()))));
         #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
         files.insert("spurious_irq", Box::new(interrupt::irq::spurious_irq_resource));
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        files.insert("lapic_error", Box::new(interrupt::irq::lapic_error_resource));
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        files.insert("thermal", Box::new(interrupt::irq::thermal_resource));
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        files.insert("irq_balance", Box::new(crate::device::irq_balance::resource));
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        files.insert("microcode", Box::new(crate::device::microcode::resource));
+
+        let mut writable: BTreeMap<&'static str, Box<SysWriteFn>> = BTreeMap::new();
+        writable.insert("runqueue", Box::new(runqueue::write));
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        writable.insert("irq_balance", Box::new(crate::device::irq_balance::write));
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        writable.insert("microcode", Box::new(crate::device::microcode::write));
 
         SysScheme {
+            scheme_id,
             next_id: AtomicUsize::new(0),
             files,
+            writable,
             handles: RwLock::new(BTreeMap::new())
         }
     }
 }
 
 impl Scheme for SysScheme {
-    fn open(&self, path: &str, _flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
+    fn open(&self, path: &str, _flags: usize, uid: u32, _gid: u32) -> Result<usize> {
         let path = path.trim_matches('/');
 
         if path.is_empty() {
@@ -84,7 +138,8 @@ impl Scheme for SysScheme {
                 path: "",
                 data,
                 mode: MODE_DIR | 0o444,
-                seek: 0
+                seek: 0,
+                uid,
             });
             return Ok(id)
         } else {
@@ -93,12 +148,15 @@ impl Scheme for SysScheme {
                 if entry.0 == &path {
                     let id = self.next_id.fetch_add(1, Ordering::SeqCst);
                     let data = entry.1()?;
+                    let mode = if self.writable.contains_key(entry.0) { 0o644 } else { 0o444 };
                     self.handles.write().insert(id, Handle {
                         path: entry.0,
                         data,
-                        mode: MODE_FILE | 0o444,
-                        seek: 0
+                        mode: MODE_FILE | mode,
+                        seek: 0,
+                        uid,
                     });
+                    OPEN_FILES.write().insert((self.scheme_id, id), entry.0);
                     return Ok(id)
                 }
             }
@@ -121,6 +179,20 @@ impl Scheme for SysScheme {
         Ok(i)
     }
 
+    fn write(&self, id: usize, buffer: &[u8]) -> Result<usize> {
+        let (path, uid) = {
+            let handles = self.handles.read();
+            let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+            (handle.path, handle.uid)
+        };
+
+        let write = self.writable.get(path).ok_or(Error::new(EBADF))?;
+        if uid != 0 {
+            return Err(Error::new(EPERM));
+        }
+        write(buffer)
+    }
+
     fn seek(&self, id: usize, pos: isize, whence: usize) -> Result<isize> {
         let mut handles = self.handles.write();
         let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
@@ -164,11 +236,19 @@ impl Scheme for SysScheme {
         Ok(0)
     }
 
+    fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
+        let handles = self.handles.read();
+        let _handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+
+        Ok(EventFlags::empty())
+    }
+
     fn fsync(&self, _id: usize) -> Result<usize> {
         Ok(0)
     }
 
     fn close(&self, id: usize) -> Result<usize> {
+        OPEN_FILES.write().remove(&(self.scheme_id, id));
         self.handles.write().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
     }
 }
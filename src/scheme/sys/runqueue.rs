@@ -0,0 +1,64 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::context::{self, ContextId, Status};
+use crate::syscall::error::{Error, EINVAL, Result};
+
+/// List the contexts waiting to run on each CPU, in the order the scheduler visits them, after the
+/// one that is running. The contexts are copied out first, so that the contexts lock is only held
+/// briefly, and the snapshot is consistent.
+pub fn resource() -> Result<Vec<u8>> {
+    let mut queued = Vec::new();
+    {
+        let contexts = context::contexts();
+        for (&id, context_lock) in contexts.iter() {
+            let context = context_lock.read();
+            let cpu_id = match context.cpu_id {
+                Some(cpu_id) => cpu_id,
+                None => continue,
+            };
+            if context.running || context.status == Status::Runnable {
+                queued.push((cpu_id, id, context.running, context.ptrace_stop, context.priority));
+            }
+        }
+    }
+
+    let mut string = format!("{:<6}{:<8}{:<6}{}\n", "CPU", "PID", "STAT", "NICE");
+    let cpus: Vec<usize> = context::cpu_stats().keys().copied().collect();
+    for cpu_id in cpus {
+        let on_cpu = || queued.iter().filter(move |entry| entry.0 == cpu_id);
+        let current = on_cpu().find(|entry| entry.2).map_or(ContextId::from(0), |entry| entry.1);
+
+        // The running context comes first, followed by those the scheduler tries next
+        let order = on_cpu().filter(|entry| entry.2)
+            .chain(on_cpu().filter(|entry| !entry.2 && entry.1 > current))
+            .chain(on_cpu().filter(|entry| !entry.2 && entry.1 < current));
+        for &(_, id, running, stopped, priority) in order {
+            let mut stat = String::from("R");
+            if stopped {
+                stat.push('T');
+            }
+            if running {
+                stat.push('+');
+            }
+            let _ = writeln!(string, "{:<6}{:<8}{:<6}{}", cpu_id, id.into(), stat, priority);
+        }
+    }
+
+    Ok(string.into_bytes())
+}
+
+/// Move a context to the run queue of another CPU, written as `PID CPU`
+pub fn write(buf: &[u8]) -> Result<usize> {
+    let line = core::str::from_utf8(buf).map_err(|_| Error::new(EINVAL))?;
+    let mut parts = line.split_whitespace();
+    let pid = parts.next().and_then(|pid| pid.parse::<usize>().ok()).ok_or(Error::new(EINVAL))?;
+    let cpu_id = parts.next().and_then(|cpu_id| cpu_id.parse::<usize>().ok()).ok_or(Error::new(EINVAL))?;
+    if parts.next().is_some() {
+        return Err(Error::new(EINVAL));
+    }
+
+    context::migrate(ContextId::from(pid), cpu_id)?;
+    Ok(buf.len())
+}
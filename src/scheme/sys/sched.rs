@@ -0,0 +1,34 @@
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::Ordering;
+
+use crate::context;
+use crate::syscall::error::Result;
+
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = format!("{:<6}{:<12}{:<16}{:<16}{:<8}{:<10}{}\n",
+                             "CPU",
+                             "SWITCHES",
+                             "IDLE_NS",
+                             "BUSY_NS",
+                             "RUNQ",
+                             "RUNQ_AVG",
+                             "STOLEN");
+
+    for (cpu_id, stats) in context::cpu_stats().iter() {
+        let samples = stats.samples.load(Ordering::Relaxed);
+        let run_queue_sum = stats.run_queue_sum.load(Ordering::Relaxed);
+        let run_queue_avg = if samples == 0 { 0 } else { run_queue_sum * 100 / samples };
+
+        let _ = writeln!(string, "{:<6}{:<12}{:<16}{:<16}{:<8}{:<10}{}",
+                         cpu_id,
+                         stats.switches.load(Ordering::Relaxed),
+                         stats.idle_time.load(Ordering::Relaxed),
+                         stats.busy_time.load(Ordering::Relaxed),
+                         stats.run_queue.load(Ordering::Relaxed),
+                         format!("{}.{:02}", run_queue_avg / 100, run_queue_avg % 100),
+                         stats.stolen.load(Ordering::Relaxed));
+    }
+
+    Ok(string.into_bytes())
+}
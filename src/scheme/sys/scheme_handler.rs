@@ -0,0 +1,37 @@
+use alloc::vec::Vec;
+
+use crate::context;
+use crate::scheme::{self, SchemeHandler};
+use crate::syscall::error::{Error, EPERM, ESRCH, Result};
+
+pub fn resource() -> Result<Vec<u8>> {
+    let (scheme_ns, euid) = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        (context.ens, context.euid)
+    };
+
+    // Which process serves which scheme is only of interest to administrators
+    if euid != 0 {
+        return Err(Error::new(EPERM));
+    }
+
+    let mut data = Vec::new();
+
+    let schemes = scheme::schemes();
+    for (name, &scheme_id) in schemes.iter_name(scheme_ns) {
+        let handler = match schemes.get(scheme_id) {
+            Some(scheme) => scheme.handler(),
+            None => continue,
+        };
+        let line = match handler {
+            SchemeHandler::Kernel => format!("{}\tkernel\n", name),
+            SchemeHandler::Context { id, name: context_name } => format!("{}\t{}\t{}\n", name, id.into(), context_name),
+            SchemeHandler::Orphaned => format!("{}\torphaned\n", name),
+        };
+        data.extend_from_slice(line.as_bytes());
+    }
+
+    Ok(data)
+}
@@ -0,0 +1,28 @@
+use alloc::vec::Vec;
+
+use crate::context;
+use crate::scheme;
+use crate::syscall::error::{Error, ESRCH, Result};
+
+/// List how many handles are open on every userspace scheme, to spot leaking clients or schemes
+pub fn resource() -> Result<Vec<u8>> {
+    let scheme_ns = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        context.ens
+    };
+
+    let mut data = Vec::new();
+
+    let schemes = scheme::schemes();
+    for (name, &scheme_id) in schemes.iter_name(scheme_ns) {
+        let open_handles = match schemes.get(scheme_id).and_then(|scheme| scheme.open_handles()) {
+            Some(open_handles) => open_handles,
+            None => continue,
+        };
+        data.extend_from_slice(format!("{}\t{}\n", name, open_handles).as_bytes());
+    }
+
+    Ok(data)
+}
@@ -0,0 +1,215 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::convert::TryFrom;
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, RwLock};
+
+use crate::context::{self, timeout};
+use crate::scheme::SchemeId;
+use crate::sync::WaitCondition;
+use crate::syscall::data::{ITimerSpec, TimeSpec};
+use crate::syscall::error::*;
+use crate::syscall::flag::{CLOCK_MONOTONIC, EventFlags, EVENT_READ, F_GETFL, F_SETFL, O_ACCMODE, O_NONBLOCK};
+use crate::syscall::scheme::Scheme;
+use crate::time;
+
+#[derive(Default)]
+struct Timer {
+    /// Monotonic time of the next expiration, if the timer is armed
+    deadline: Option<u128>,
+    /// Period in nanoseconds, or zero for a one-shot timer
+    interval: u128,
+}
+
+impl Timer {
+    /// Consume the expirations up to `now`, moving the deadline of a periodic timer past it. The
+    /// expirations are derived from the deadline rather than counted as they happen, so that
+    /// overruns are accounted for even if nobody was around to see them.
+    fn expire(&mut self, now: u128) -> u64 {
+        let deadline = match self.deadline {
+            Some(deadline) if now >= deadline => deadline,
+            _ => return 0,
+        };
+
+        if self.interval == 0 {
+            self.deadline = None;
+            return 1;
+        }
+
+        let periods = (now - deadline) / self.interval + 1;
+        self.deadline = Some(deadline + periods * self.interval);
+        u64::try_from(periods).unwrap_or(u64::max_value())
+    }
+
+    fn is_expired(&self, now: u128) -> bool {
+        self.deadline.map_or(false, |deadline| now >= deadline)
+    }
+}
+
+struct Handle {
+    flags: AtomicUsize,
+    timer: Mutex<Timer>,
+    /// Notified when the timer is set, so that blocked readers pick up the new deadline
+    condition: WaitCondition,
+}
+
+pub struct TimerScheme {
+    scheme_id: SchemeId,
+    next_id: AtomicUsize,
+    handles: RwLock<BTreeMap<usize, Arc<Handle>>>
+}
+
+impl TimerScheme {
+    pub fn new(scheme_id: SchemeId) -> TimerScheme {
+        TimerScheme {
+            scheme_id,
+            next_id: AtomicUsize::new(0),
+            handles: RwLock::new(BTreeMap::new())
+        }
+    }
+
+    fn handle(&self, id: usize) -> Result<Arc<Handle>> {
+        self.handles.read().get(&id).map(Arc::clone).ok_or(Error::new(EBADF))
+    }
+
+    /// Send a read event once the monotonic clock reaches `deadline`, instead of at the deadline
+    /// registered before, so that a timer that was set again does not report an old one
+    fn register(&self, id: usize, deadline: u128) {
+        timeout::cancel(self.scheme_id, id);
+        timeout::register(self.scheme_id, id, CLOCK_MONOTONIC, TimeSpec {
+            tv_sec: (deadline / time::NANOS_PER_SEC) as i64,
+            tv_nsec: (deadline % time::NANOS_PER_SEC) as i32,
+        });
+    }
+}
+
+fn nanos(time: &TimeSpec) -> Result<u128> {
+    if time.tv_sec < 0 || time.tv_nsec < 0 || time.tv_nsec as u128 >= time::NANOS_PER_SEC {
+        return Err(Error::new(EINVAL));
+    }
+    Ok((time.tv_sec as u128 * time::NANOS_PER_SEC) + (time.tv_nsec as u128))
+}
+
+impl Scheme for TimerScheme {
+    fn open(&self, _path: &str, flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.handles.write().insert(id, Arc::new(Handle {
+            flags: AtomicUsize::new(flags & ! O_ACCMODE),
+            timer: Mutex::new(Timer::default()),
+            condition: WaitCondition::new(),
+        }));
+
+        Ok(id)
+    }
+
+    /// Read the number of expirations since the last read, as a `u64`
+    fn read(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        if buf.len() < mem::size_of::<u64>() {
+            return Err(Error::new(EINVAL));
+        }
+        let handle = self.handle(id)?;
+
+        loop {
+            let mut timer = handle.timer.lock();
+
+            let expirations = timer.expire(time::monotonic());
+            if expirations > 0 {
+                if let Some(deadline) = timer.deadline {
+                    self.register(id, deadline);
+                }
+                buf[..mem::size_of::<u64>()].copy_from_slice(&expirations.to_ne_bytes());
+                return Ok(mem::size_of::<u64>());
+            }
+
+            if handle.flags.load(Ordering::SeqCst) & O_NONBLOCK == O_NONBLOCK {
+                return Err(Error::new(EAGAIN));
+            }
+
+            // Let the scheduler wake us at the deadline, in addition to rearming the timer
+            let deadline = timer.deadline;
+            let context_lock = context::current()?;
+            context_lock.write().set_wake(deadline);
+
+            if handle.condition.wait(timer, "TimerScheme::read") {
+                context_lock.write().set_wake(None);
+            } else {
+                let mut context = context_lock.write();
+                // The scheduler clears `wake` when the deadline passes, so anything else
+                // unblocking us must have been a signal
+                if deadline.is_none() || context.wake().is_some() {
+                    context.set_wake(None);
+                    return Err(Error::new(EINTR));
+                }
+            }
+        }
+    }
+
+    /// Set the timer from an `ITimerSpec`. The value is relative to now, and disarms the timer
+    /// if zero. A nonzero interval makes the timer periodic.
+    fn write(&self, id: usize, buf: &[u8]) -> Result<usize> {
+        if buf.len() < mem::size_of::<ITimerSpec>() {
+            return Err(Error::new(EINVAL));
+        }
+        let spec = unsafe { (buf.as_ptr() as *const ITimerSpec).read_unaligned() };
+        let interval = nanos(&spec.it_interval)?;
+        let value = nanos(&spec.it_value)?;
+
+        let handle = self.handle(id)?;
+        {
+            let mut timer = handle.timer.lock();
+            timer.interval = interval;
+            timer.deadline = if value == 0 {
+                timeout::cancel(self.scheme_id, id);
+                None
+            } else {
+                let deadline = time::monotonic() + value;
+                self.register(id, deadline);
+                Some(deadline)
+            };
+        }
+        handle.condition.notify();
+
+        Ok(mem::size_of::<ITimerSpec>())
+    }
+
+    fn fcntl(&self, id: usize, cmd: usize, arg: usize) -> Result<usize> {
+        let handle = self.handle(id)?;
+        match cmd {
+            F_GETFL => Ok(handle.flags.load(Ordering::SeqCst)),
+            F_SETFL => {
+                handle.flags.store(arg & ! O_ACCMODE, Ordering::SeqCst);
+                Ok(0)
+            },
+            _ => Err(Error::new(EINVAL))
+        }
+    }
+
+    fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
+        let handle = self.handle(id)?;
+        if handle.timer.lock().is_expired(time::monotonic()) {
+            Ok(EVENT_READ)
+        } else {
+            Ok(EventFlags::empty())
+        }
+    }
+
+    fn fpath(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let _handle = self.handle(id)?;
+
+        let mut i = 0;
+        let scheme_path = b"timer:";
+        while i < buf.len() && i < scheme_path.len() {
+            buf[i] = scheme_path[i];
+            i += 1;
+        }
+        Ok(i)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        self.handles.write().remove(&id).ok_or(Error::new(EBADF))?;
+        timeout::cancel(self.scheme_id, id);
+        Ok(0)
+    }
+}
+impl crate::scheme::KernelScheme for TimerScheme {}
@@ -1,29 +1,47 @@
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
 use alloc::sync::{Arc, Weak};
 use alloc::boxed::Box;
-use alloc::collections::BTreeMap;
-use core::sync::atomic::{AtomicBool, Ordering};
-use core::{mem, slice, usize};
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::{cmp, mem, slice, usize};
 use core::convert::TryFrom;
 use spin::{Mutex, RwLock};
 
-use crate::context::{self, Context};
+use crate::context::{self, Context, ContextId};
 use crate::context::file::FileDescriptor;
 use crate::context::memory::{AddrSpace, DANGLING, Grant, Region, GrantFileRef};
-use crate::event;
-use crate::paging::{PAGE_SIZE, mapper::InactiveFlusher, Page, round_down_pages, round_up_pages, VirtualAddress};
-use crate::scheme::{AtomicSchemeId, SchemeId};
-use crate::sync::{WaitQueue, WaitMap};
+use crate::event::{self, EVENT_HUP};
+use crate::paging::{PAGE_SIZE, mapper::BatchFlusher, Page, round_down_pages, round_up_pages, VirtualAddress};
+use crate::scheme::{AtomicSchemeId, SchemeHandler, SchemeId};
+use crate::sync::{WaitCondition, WaitQueue, WaitMap};
 use crate::syscall::data::{Map, Packet, Stat, StatVfs, TimeSpec};
 use crate::syscall::error::*;
-use crate::syscall::flag::{EventFlags, EVENT_READ, O_NONBLOCK, MapFlags, PROT_READ, PROT_WRITE};
+use crate::syscall::flag::{EventFlags, EVENT_READ, O_ACCMODE, O_CREAT, O_EXCL, O_NONBLOCK, O_RDONLY, O_STAT, O_TRUNC, MapFlags, PROT_READ, PROT_WRITE};
 use crate::syscall::number::*;
 use crate::syscall::scheme::Scheme;
+use crate::time;
+
+/// Most bytes that a scheme handler can push to a client handle before the client reads them
+pub const PUSH_BUFFER_MAX: usize = 64 * 1024;
+
+/// Request to read from a file at an offset without moving the file offset. The buffer `c` of
+/// length `d` holds the offset as a `usize`, and the data is read into the rest of it.
+// TODO: Move to syscall::number
+pub const SYS_PREAD: usize = 336;
+/// Request to write to a file at an offset without moving the file offset. The buffer `c` of
+/// length `d` holds the offset as a `usize`, followed by the data.
+// TODO: Move to syscall::number
+pub const SYS_PWRITE: usize = 337;
 
 pub struct UserInner {
     root_id: SchemeId,
     handle_id: usize,
     pub name: Box<str>,
-    pub flags: usize,
+    /// Flags of the scheme handle, of which O_NONBLOCK can be changed with F_SETFL
+    flags: AtomicUsize,
     pub scheme_id: AtomicSchemeId,
     next_id: Mutex<u64>,
     context: Weak<RwLock<Context>>,
@@ -31,6 +49,78 @@ pub struct UserInner {
     fmap: Mutex<BTreeMap<u64, (Weak<RwLock<Context>>, FileDescriptor, Map)>>,
     done: WaitMap<u64, usize>,
     unmounting: AtomicBool,
+    /// Reject modifying operations with EROFS before they reach the handler
+    read_only: AtomicBool,
+    /// Client handle numbers returned by the scheme, used to report hangups when unmounting and
+    /// to hold the data pushed to them
+    handles: Mutex<BTreeMap<usize, ClientHandle>>,
+    /// Client handles that were opened or duplicated and not closed yet. Counted separately from
+    /// `handles`, as a scheme may hand out the same number more than once.
+    open_handles: AtomicUsize,
+    /// Check for existing files in the kernel before opening with O_CREAT | O_EXCL, for schemes
+    /// that don't enforce O_EXCL themselves
+    excl_create: AtomicBool,
+    /// Paths with an O_CREAT open in progress, if `excl_create` is set
+    creating: Mutex<BTreeSet<Box<str>>>,
+    creating_condition: WaitCondition,
+    /// How long requests wait for the handler to answer, in milliseconds, or zero to wait forever
+    timeout: AtomicUsize,
+    /// Requests that timed out, whose late answers are to be dropped
+    abandoned: Mutex<BTreeSet<u64>>,
+    /// Split large reads into page sized requests
+    chunked_read: AtomicBool,
+}
+
+/// A client handle number returned by the scheme
+struct ClientHandle {
+    /// How many times the number was handed out and not closed yet
+    refs: usize,
+    /// Data pushed by the scheme handler, returned by the next reads of the handle
+    pushed: VecDeque<u8>,
+}
+
+/// Buffer for data that is passed between the kernel and a scheme handler. Capturing maps whole
+/// pages, so the buffer occupies whole pages of its own, rather than sharing them with other
+/// kernel heap objects the handler must not see.
+pub(crate) struct BounceBuffer {
+    ptr: NonNull<u8>,
+    layout: Layout,
+    len: usize,
+}
+
+impl BounceBuffer {
+    /// Allocate a zeroed buffer of `len` bytes
+    pub(crate) fn new(len: usize) -> Result<Self> {
+        let layout = Layout::from_size_align(round_up_pages(cmp::max(len, 1)), PAGE_SIZE)
+            .map_err(|_| Error::new(EINVAL))?;
+        let ptr = NonNull::new(unsafe { alloc_zeroed(layout) }).ok_or(Error::new(ENOMEM))?;
+        Ok(Self { ptr, layout, len })
+    }
+}
+
+impl Deref for BounceBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for BounceBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for BounceBuffer {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+/// Whether the result of an fmap reply is an address that is not page-aligned
+fn is_misaligned_fmap_reply(result: usize) -> bool {
+    matches!(Error::demux(result), Ok(address) if address % PAGE_SIZE != 0)
 }
 
 impl UserInner {
@@ -39,7 +129,7 @@ impl UserInner {
             root_id,
             handle_id,
             name,
-            flags,
+            flags: AtomicUsize::new(flags),
             scheme_id: AtomicSchemeId::default(),
             next_id: Mutex::new(1),
             context,
@@ -47,6 +137,15 @@ impl UserInner {
             fmap: Mutex::new(BTreeMap::new()),
             done: WaitMap::new(),
             unmounting: AtomicBool::new(false),
+            read_only: AtomicBool::new(false),
+            handles: Mutex::new(BTreeMap::new()),
+            open_handles: AtomicUsize::new(0),
+            excl_create: AtomicBool::new(false),
+            creating: Mutex::new(BTreeSet::new()),
+            creating_condition: WaitCondition::new(),
+            timeout: AtomicUsize::new(0),
+            abandoned: Mutex::new(BTreeSet::new()),
+            chunked_read: AtomicBool::new(false),
         }
     }
 
@@ -60,10 +159,199 @@ impl UserInner {
         // Tell the scheme handler to read
         event::trigger(self.root_id, self.handle_id, EVENT_READ);
 
+        // Tell every client holding a handle that the scheme is gone. Handles that are added
+        // after this point will see `unmounting` in `add_handle`.
+        let scheme_id = self.scheme_id.load(Ordering::SeqCst);
+        for &number in self.handles.lock().keys() {
+            event::trigger(scheme_id, number, EVENT_HUP);
+        }
+
         //TODO: wait for all todo and done to be processed?
         Ok(0)
     }
 
+    /// Record a handle returned by the scheme, reporting a hangup right away if the scheme was
+    /// unmounted while the call creating it was in flight
+    fn add_handle(&self, number: usize) {
+        {
+            let mut handles = self.handles.lock();
+            let handle = handles.entry(number).or_insert_with(|| ClientHandle {
+                refs: 0,
+                pushed: VecDeque::new(),
+            });
+            handle.refs += 1;
+        }
+        self.open_handles.fetch_add(1, Ordering::SeqCst);
+
+        if self.unmounting.load(Ordering::SeqCst) {
+            event::trigger(self.scheme_id.load(Ordering::SeqCst), number, EVENT_HUP);
+        }
+    }
+
+    /// Forget a closed client handle, along with the data pushed to it once the last handle with
+    /// its number is closed
+    fn remove_handle(&self, number: usize) {
+        {
+            let mut handles = self.handles.lock();
+            if let Some(handle) = handles.get_mut(&number) {
+                handle.refs -= 1;
+                if handle.refs == 0 {
+                    handles.remove(&number);
+                }
+            }
+        }
+        let _ = self.open_handles.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| count.checked_sub(1));
+    }
+
+    /// Number of client handles that are open, for spotting leaks
+    pub fn open_handles(&self) -> usize {
+        self.open_handles.load(Ordering::SeqCst)
+    }
+
+    /// Queue data for the client handle `number`, which its next read returns instead of asking
+    /// the scheme handler. Fails with EAGAIN if that would buffer more than `PUSH_BUFFER_MAX`
+    /// bytes, in which case nothing is queued.
+    fn push(&self, number: usize, data: &[u8]) -> Result<()> {
+        {
+            let mut handles = self.handles.lock();
+            let queue = &mut handles.get_mut(&number).ok_or(Error::new(EBADF))?.pushed;
+            if queue.len() + data.len() > PUSH_BUFFER_MAX {
+                return Err(Error::new(EAGAIN));
+            }
+            queue.extend(data.iter().copied());
+        }
+        event::trigger(self.scheme_id.load(Ordering::SeqCst), number, EVENT_READ);
+        Ok(())
+    }
+
+    /// Read data that was pushed to the client handle `number`, if there is any
+    fn read_pushed(&self, number: usize, buf: &mut [u8]) -> Option<usize> {
+        let mut handles = self.handles.lock();
+        let queue = handles.get_mut(&number).map(|handle| &mut handle.pushed).filter(|queue| !queue.is_empty())?;
+        let count = queue.len().min(buf.len());
+        for (dst, src) in buf.iter_mut().zip(queue.drain(..count)) {
+            *dst = src;
+        }
+        if queue.is_empty() {
+            // Free the buffer rather than keeping it until the handle is closed
+            *queue = VecDeque::new();
+        }
+        Some(count)
+    }
+
+    pub fn flags(&self) -> usize {
+        self.flags.load(Ordering::SeqCst)
+    }
+
+    /// Change O_NONBLOCK on the scheme handle, the other flags are fixed at registration
+    pub fn set_flags(&self, flags: usize) {
+        let _ = self.flags.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |old| {
+            Some((old & !O_NONBLOCK) | (flags & O_NONBLOCK))
+        });
+    }
+
+    pub fn is_unmounting(&self) -> bool {
+        self.unmounting.load(Ordering::SeqCst)
+    }
+
+    /// Switch the scheme into or out of read-only mode. Requests that have already been forwarded
+    /// to the handler are not affected, while every later modifying request fails with EROFS.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::SeqCst);
+    }
+
+    pub fn set_excl_create(&self, excl_create: bool) {
+        self.excl_create.store(excl_create, Ordering::SeqCst);
+    }
+
+    /// Set how long requests wait for the handler, in milliseconds. Requests that are already
+    /// waiting keep the timeout they started with.
+    pub fn set_timeout(&self, timeout: usize) {
+        self.timeout.store(timeout, Ordering::SeqCst);
+    }
+
+    pub fn set_chunked_read(&self, chunked_read: bool) {
+        self.chunked_read.store(chunked_read, Ordering::SeqCst);
+    }
+
+    /// Read into `buf` one page at a time, capturing only the page being read. Stops at the first
+    /// short read, and before each further page if a signal is pending, returning the number of
+    /// bytes read so far. Errors are only returned if nothing was read.
+    fn read_chunked(&self, file: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut total = 0;
+        for chunk in buf.chunks_mut(PAGE_SIZE) {
+            if total > 0 && crate::syscall::signal_pending()? {
+                break;
+            }
+
+            let result = self.capture_mut(chunk).and_then(|address| {
+                let result = self.call(SYS_READ, file, address, chunk.len());
+                let _ = self.release(address);
+                result
+            });
+
+            match result {
+                Ok(count) => {
+                    total += count;
+                    if count < chunk.len() {
+                        break;
+                    }
+                },
+                Err(err) if total == 0 => return Err(err),
+                Err(_) => break,
+            }
+        }
+        Ok(total)
+    }
+
+    fn open(&self, path: &str, flags: usize) -> Result<usize> {
+        let address = self.capture(path.as_bytes())?;
+        let result = self.call(SYS_OPEN, address, path.len(), flags);
+        let _ = self.release(address);
+        result
+    }
+
+    /// Open `path` with O_CREAT, failing with EEXIST if O_EXCL is set and the path can already be
+    /// opened. Creating opens of the same path are serialized, so that nothing can be created in
+    /// between the check and the open.
+    fn open_create(&self, path: &str, flags: usize) -> Result<usize> {
+        loop {
+            let mut creating = self.creating.lock();
+            if creating.insert(path.into()) {
+                break;
+            }
+            if !self.creating_condition.wait(creating, "UserInner::open_create") {
+                return Err(Error::new(EINTR));
+            }
+        }
+
+        let result = if flags & O_EXCL == O_EXCL {
+            match self.open(path, O_STAT) {
+                Ok(number) => {
+                    let _ = self.call(SYS_CLOSE, number, 0, 0);
+                    Err(Error::new(EEXIST))
+                },
+                Err(err) if err.errno == ENOENT => self.open(path, flags),
+                Err(err) => Err(err),
+            }
+        } else {
+            self.open(path, flags)
+        };
+
+        self.creating.lock().remove(path);
+        self.creating_condition.notify();
+
+        result
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only.load(Ordering::SeqCst) {
+            Err(Error::new(EROFS))
+        } else {
+            Ok(())
+        }
+    }
+
     fn next_id(&self) -> u64 {
         let mut guard = self.next_id.lock();
         let id = *guard;
@@ -71,6 +359,9 @@ impl UserInner {
         id
     }
 
+    /// Send a request to the scheme handler and wait for its answer. Requests on a client handle
+    /// opened or set O_NONBLOCK are sent all the same, as the handler was given the flags with
+    /// the open or the F_SETFL and answers with EAGAIN itself when it would block.
     pub fn call(&self, a: usize, b: usize, c: usize, d: usize) -> Result<usize> {
         let (pid, uid, gid) = {
             let contexts = context::contexts();
@@ -99,17 +390,71 @@ impl UserInner {
         }
 
         let id = packet.id;
+        let timeout = self.timeout.load(Ordering::SeqCst);
+        let deadline = time::monotonic() + timeout as u128 * time::NANOS_PER_SEC / 1000;
 
         self.todo.send(packet);
         event::trigger(self.root_id, self.handle_id, EVENT_READ);
 
-        Error::demux(self.done.receive(&id, "UserInner::call_inner"))
+        // Without a timeout, this only gives up if the context is being forcibly killed, in
+        // which case the request is abandoned like one that timed out
+        let value = if timeout == 0 {
+            self.done.receive(&id, "UserInner::call_inner")
+        } else {
+            self.done.receive_timeout(&id, deadline, "UserInner::call_inner")
+        };
+        if let Some(value) = value {
+            return Error::demux(value);
+        }
+
+        // A request the handler has not picked up yet is taken back, so that it never sees the
+        // buffers captured for it, which the caller releases once this returns
+        if self.withdraw(id) {
+            return Err(Error::new(ETIMEDOUT));
+        }
+
+        // The fmap lock is held by `write` while it handles an answer, so the answer either has
+        // arrived completely by now, or will be dropped
+        let desc_opt = {
+            let mut fmap = self.fmap.lock();
+            if let Some(value) = self.done.receive_nonblock(&id) {
+                return Error::demux(value);
+            }
+            self.abandoned.lock().insert(id);
+            fmap.remove(&id).map(|(_, desc, _)| desc)
+        };
+        if let Some(desc) = desc_opt {
+            let _ = desc.close();
+        }
+
+        Err(Error::new(ETIMEDOUT))
+    }
+
+    /// Take a request back out of the queue, returning whether the handler had not picked it up yet
+    fn withdraw(&self, id: u64) -> bool {
+        let mut todo = self.todo.inner.lock();
+        let len = todo.len();
+        todo.retain(|packet| packet.id != id);
+        todo.len() != len
+    }
+
+    /// Look up the context serving this scheme. The context is only upgraded for as long as it
+    /// takes to read its ID and name, so that this does not keep it alive.
+    pub fn handler(&self) -> SchemeHandler {
+        match self.context.upgrade() {
+            Some(context_lock) => {
+                let context = context_lock.read();
+                SchemeHandler::Context { id: context.id, name: context.name.read().clone() }
+            }
+            None => SchemeHandler::Orphaned,
+        }
     }
 
     /// Map a readable structure to the scheme's userspace and return the
     /// pointer
     pub fn capture(&self, buf: &[u8]) -> Result<usize> {
         UserInner::capture_inner(
+            &self.name,
             &self.context,
             0,
             buf.as_ptr() as usize,
@@ -123,6 +468,7 @@ impl UserInner {
     /// pointer
     pub fn capture_mut(&self, buf: &mut [u8]) -> Result<usize> {
         UserInner::capture_inner(
+            &self.name,
             &self.context,
             0,
             buf.as_mut_ptr() as usize,
@@ -132,10 +478,33 @@ impl UserInner {
         ).map(|addr| addr.data())
     }
 
+    /// Map a readable input buffer and a writeable output buffer to the scheme's userspace, for
+    /// operations that both pass and receive data in a single packet. Either buffer may be empty,
+    /// in which case its pointer is dangling. Both must be released with `release_pair`.
+    pub fn capture_pair(&self, input: &[u8], output: &mut [u8]) -> Result<(usize, usize)> {
+        let input_address = self.capture(input)?;
+        let output_address = match self.capture_mut(output) {
+            Ok(address) => address,
+            Err(err) => {
+                let _ = self.release(input_address);
+                return Err(err);
+            }
+        };
+        Ok((input_address, output_address))
+    }
+
+    /// Release a pair of buffers captured with `capture_pair`. Both are released even if
+    /// releasing the first one fails.
+    pub fn release_pair(&self, input_address: usize, output_address: usize) -> Result<()> {
+        let input_res = self.release(input_address);
+        let output_res = self.release(output_address);
+        input_res.and(output_res)
+    }
+
     // TODO: Use an address space Arc over a context Arc. While contexts which share address spaces
     // still can access borrowed scheme pages, it would both be cleaner and would handle the case
     // where the initial context is closed.
-    fn capture_inner(context_weak: &Weak<RwLock<Context>>, dst_address: usize, address: usize, size: usize, flags: MapFlags, desc_opt: Option<GrantFileRef>)
+    fn capture_inner(scheme_name: &str, context_weak: &Weak<RwLock<Context>>, dst_address: usize, address: usize, size: usize, flags: MapFlags, desc_opt: Option<GrantFileRef>)
                      -> Result<VirtualAddress> {
         if size == 0 {
             // NOTE: Rather than returning NULL, we return a dummy dangling address, that is also
@@ -155,27 +524,54 @@ impl UserInner {
         let page_count = round_up_pages(offset + size) / PAGE_SIZE;
         let requested_dst_page = (dst_address != 0).then_some(Page::containing_address(VirtualAddress::new(round_down_pages(dst_address))));
 
+        // Writes to private file mappings must not reach the scheme, so they are copied on write
+        let private = desc_opt.as_ref().map_or(false, |file_ref| file_ref.flags.contains(MapFlags::MAP_PRIVATE));
+
         let dst_space_lock = Arc::clone(context_weak.upgrade().ok_or(Error::new(ESRCH))?.read().addr_space()?);
         let cur_space_lock = AddrSpace::current()?;
 
+        let same_space = Arc::ptr_eq(&dst_space_lock, &cur_space_lock);
+        let mut dst_space = dst_space_lock.write();
+        if same_space {
+            dst_space.fault_in_range(src_page, page_count);
+        }
+
         //TODO: Use syscall_head and syscall_tail to avoid leaking data
-        let dst_page = if Arc::ptr_eq(
-            &dst_space_lock,
-            &cur_space_lock,
-        ) {
-            let mut dst_space = dst_space_lock.write();
+        let result = if same_space {
             dst_space.mmap(requested_dst_page, page_count, flags, |dst_page, page_flags, mapper, flusher| {
                 //TODO: remove hack to use same mapper for borrow
                 let src_mapper = unsafe { &mut *(mapper as *mut _) };
                 let dst_mapper = unsafe { &mut *(mapper as *mut _) };
-                Ok(Grant::borrow(src_page, dst_page, page_count, page_flags, desc_opt, src_mapper, dst_mapper, flusher)?)
-            })?
+                if private {
+                    Ok(Grant::borrow_private(src_page, dst_page, page_count, page_flags, desc_opt, src_mapper, dst_mapper, flusher)?)
+                } else {
+                    Ok(Grant::borrow(src_page, dst_page, page_count, page_flags, desc_opt, src_mapper, dst_mapper, flusher)?)
+                }
+            })
         } else {
-            let mut dst_space = dst_space_lock.write();
             dst_space.mmap(requested_dst_page, page_count, flags, move |dst_page, page_flags, mapper, flusher| {
                 let mut cur_space = cur_space_lock.write();
-                Ok(Grant::borrow(src_page, dst_page, page_count, page_flags, desc_opt, &mut cur_space.table.utable, mapper, flusher)?)
-            })?
+                cur_space.fault_in_range(src_page, page_count);
+                if private {
+                    Ok(Grant::borrow_private(src_page, dst_page, page_count, page_flags, desc_opt, &mut cur_space.table.utable, mapper, flusher)?)
+                } else {
+                    Ok(Grant::borrow(src_page, dst_page, page_count, page_flags, desc_opt, &mut cur_space.table.utable, mapper, flusher)?)
+                }
+            })
+        };
+
+        let dst_page = match result {
+            Ok(dst_page) => dst_page,
+            Err(err) => {
+                // Borrowed grants are unmapped as soon as they are released, so there is nothing
+                // left to reclaim and retry with. Report how many are still outstanding, which
+                // points at a handler that does not release its requests.
+                if err.errno == ENOMEM {
+                    let borrowed = dst_space.grants.iter().filter(|grant| !grant.is_owned()).count();
+                    log::warn!("scheme {}: out of address space capturing {} pages, {} borrowed grants outstanding", scheme_name, page_count, borrowed);
+                }
+                return Err(err);
+            }
         };
 
         Ok(dst_page.start_address().add(offset))
@@ -194,10 +590,64 @@ impl UserInner {
             Some(region) => region,
             None => return Err(Error::new(EFAULT)),
         };
-        addr_space.grants.take(&region).unwrap().unmap(&mut addr_space.table.utable, InactiveFlusher::new());
+        let flusher = BatchFlusher::new(addr_space.is_current());
+        addr_space.grants.take(&region).unwrap().unmap(&mut addr_space.table.utable, flusher);
         Ok(())
     }
 
+    /// Forcibly unmap the memory of this scheme from the address spaces of its clients, for
+    /// recovering from a handler that leaves grants behind. The scheme is sent SYS_FUNMAP for each
+    /// grant, as if the client had unmapped it, and clients still accessing the memory fault as
+    /// they would on any unmapped memory. Return the number of grants that were revoked, which is
+    /// zero when revoking again.
+    pub fn revoke_grants(&self) -> usize {
+        let scheme_id = self.scheme_id.load(Ordering::SeqCst);
+
+        // TODO: Remove allocation
+        let mut addr_spaces: Vec<Arc<RwLock<AddrSpace>>> = Vec::new();
+        for (_id, context_lock) in context::contexts().iter() {
+            if let Ok(addr_space) = context_lock.read().addr_space() {
+                if !addr_spaces.iter().any(|known| Arc::ptr_eq(known, addr_space)) {
+                    addr_spaces.push(Arc::clone(addr_space));
+                }
+            }
+        }
+
+        let mut revoked = 0;
+        for addr_space_lock in addr_spaces {
+            let mut notify = Vec::new();
+            {
+                let mut addr_space = addr_space_lock.write();
+                let regions = addr_space.grants.iter()
+                    .filter(|grant| grant.desc_opt.as_ref().map_or(false, |file_ref| file_ref.desc.description.read().scheme == scheme_id))
+                    .map(Region::from)
+                    .collect::<Vec<_>>();
+
+                // Threads of the client may be using the memory on other CPUs
+                let mut flusher = BatchFlusher::new(addr_space.is_current());
+
+                for region in regions {
+                    let user_base = addr_space.grants.funmap.remove(&region);
+                    let grant = addr_space.grants.take(&region).expect("grant cannot magically disappear while we hold the lock!");
+                    let mut result = grant.unmap(&mut addr_space.table.utable, &mut flusher);
+                    notify.push((user_base, region.size(), result.file_desc.take()));
+                    revoked += 1;
+                }
+            }
+
+            for (user_base, size, file_ref) in notify {
+                if let Some((user_base, flags)) = user_base {
+                    let _ = self.call(SYS_FUNMAP, user_base.data(), size, flags.bits());
+                }
+                if let Some(file_ref) = file_ref {
+                    let _ = file_ref.desc.close();
+                }
+            }
+        }
+
+        revoked
+    }
+
     pub fn read(&self, buf: &mut [u8]) -> Result<usize> {
         let packet_buf = unsafe { slice::from_raw_parts_mut(
             buf.as_mut_ptr() as *mut Packet,
@@ -205,7 +655,7 @@ impl UserInner {
         };
 
         // If O_NONBLOCK is used, do not block
-        let nonblock = self.flags & O_NONBLOCK == O_NONBLOCK;
+        let nonblock = self.flags() & O_NONBLOCK == O_NONBLOCK;
         // If unmounting, do not block so that EOF can be returned immediately
         let unmounting = self.unmounting.load(Ordering::SeqCst);
         let block = !(nonblock || unmounting);
@@ -240,28 +690,71 @@ impl UserInner {
             if packet.id == 0 {
                 match packet.a {
                     SYS_FEVENT => event::trigger(self.scheme_id.load(Ordering::SeqCst), packet.b, EventFlags::from_bits_truncate(packet.c)),
+                    // Signal the process group c of the terminal handle b with signal d
+                    SYS_KILL => {
+                        let _ = crate::syscall::kill_ctty(self.scheme_id.load(Ordering::SeqCst), packet.b, ContextId::from(packet.c), packet.d);
+                    },
+                    // Push the buffer c of length d to the client handle b. When the buffer of the
+                    // handle is full, stop here so that the handler can retry the rest later.
+                    SYS_WRITE => {
+                        let res = crate::syscall::validate::validate_slice(packet.c as *const u8, packet.d)
+                            .and_then(|data| self.push(packet.b, data));
+                        match res {
+                            Ok(()) => (),
+                            Err(err) if err.errno == EBADF => log::warn!("scheme {} pushed data to unknown handle {}", self.name, packet.b),
+                            Err(err) => return if i == 0 { Err(err) } else { Ok(i * packet_size) },
+                        }
+                    },
                     _ => println!("Unknown scheme -> kernel message {}", packet.a)
                 }
             } else {
+                let mut fmap = self.fmap.lock();
+
+                // The caller gave up waiting, so nothing is to be mapped for it
+                if self.abandoned.lock().remove(&packet.id) {
+                    let desc_opt = fmap.remove(&packet.id).map(|(_, desc, _)| desc);
+                    drop(fmap);
+                    if let Some(desc) = desc_opt {
+                        let _ = desc.close();
+                    }
+                    i += 1;
+                    continue;
+                }
+
+                // The funmap bookkeeping below relies on the user base being page-aligned, so an
+                // unaligned reply fails both the fmap call and the handler's write, leaving the
+                // region with the handler. Earlier packets are acknowledged first, so that the
+                // handler can tell which one failed.
+                if fmap.contains_key(&packet.id) && is_misaligned_fmap_reply(packet.a) {
+                    if i > 0 {
+                        return Ok(i * packet_size);
+                    }
+                    log::warn!("scheme {} returned unaligned fmap address {:#x}", self.name, packet.a);
+                    let desc_opt = fmap.remove(&packet.id).map(|(_, desc, _)| desc);
+                    self.done.send(packet.id, Error::mux(Err(Error::new(EINVAL))));
+                    drop(fmap);
+                    if let Some(desc) = desc_opt {
+                        let _ = desc.close();
+                    }
+                    return Err(Error::new(EINVAL));
+                }
+
                 // The motivation of doing this here instead of within the fmap handler, is that we
                 // can operate on an inactive table. This reduces the number of page table reloads
                 // from two (context switch + active TLB flush) to one (context switch).
-                if let Some((context_weak, desc, map)) = self.fmap.lock().remove(&packet.id) {
+                let mut close_desc = None;
+                if let Some((context_weak, desc, map)) = fmap.remove(&packet.id) {
                     if let Ok(address) = Error::demux(packet.a) {
-                        if address % PAGE_SIZE > 0 {
-                            log::warn!("scheme returned unaligned address, causing extra frame to be allocated");
-                        }
                         let file_ref = GrantFileRef { desc, offset: map.offset, flags: map.flags };
-                        let res = UserInner::capture_inner(&context_weak, map.address, address, map.size, map.flags, Some(file_ref));
+                        let res = UserInner::capture_inner(&self.name, &context_weak, map.address, address, map.size, map.flags, Some(file_ref));
                         if let Ok(grant_address) = res {
                             if let Some(context_lock) = context_weak.upgrade() {
                                 let context = context_lock.read();
                                 let mut addr_space = context.addr_space()?.write();
-                                //TODO: ensure all mappings are aligned!
                                 let map_pages = (map.size + PAGE_SIZE - 1) / PAGE_SIZE;
                                 addr_space.grants.funmap.insert(
                                     Region::new(grant_address, map_pages * PAGE_SIZE),
-                                    VirtualAddress::new(address)
+                                    (VirtualAddress::new(address), map.flags)
                                 );
                             } else {
                                 //TODO: packet.pid is an assumption
@@ -270,11 +763,16 @@ impl UserInner {
                         }
                         packet.a = Error::mux(res.map(|addr| addr.data()));
                     } else {
-                        let _ = desc.close();
+                        close_desc = Some(desc);
                     }
                 }
 
                 self.done.send(packet.id, packet.a);
+                drop(fmap);
+
+                if let Some(desc) = close_desc {
+                    let _ = desc.close();
+                }
             }
             i += 1;
         }
@@ -351,14 +849,23 @@ impl UserScheme {
 impl Scheme for UserScheme {
     fn open(&self, path: &str, flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
         let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
-        let address = inner.capture(path.as_bytes())?;
-        let result = inner.call(SYS_OPEN, address, path.len(), flags);
-        let _ = inner.release(address);
+        if flags & (O_CREAT | O_TRUNC) != 0 || (flags & O_ACCMODE != O_RDONLY && flags & O_STAT != O_STAT) {
+            inner.check_writable()?;
+        }
+        let result = if flags & O_CREAT == O_CREAT && inner.excl_create.load(Ordering::SeqCst) {
+            inner.open_create(path, flags)
+        } else {
+            inner.open(path, flags)
+        };
+        if let Ok(number) = result {
+            inner.add_handle(number);
+        }
         result
     }
 
     fn rmdir(&self, path: &str, _uid: u32, _gid: u32) -> Result<usize> {
         let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        inner.check_writable()?;
         let address = inner.capture(path.as_bytes())?;
         let result = inner.call(SYS_RMDIR, address, path.len(), 0);
         let _ = inner.release(address);
@@ -367,6 +874,7 @@ impl Scheme for UserScheme {
 
     fn unlink(&self, path: &str, _uid: u32, _gid: u32) -> Result<usize> {
         let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        inner.check_writable()?;
         let address = inner.capture(path.as_bytes())?;
         let result = inner.call(SYS_UNLINK, address, path.len(), 0);
         let _ = inner.release(address);
@@ -378,11 +886,20 @@ impl Scheme for UserScheme {
         let address = inner.capture(buf)?;
         let result = inner.call(SYS_DUP, file, address, buf.len());
         let _ = inner.release(address);
+        if let Ok(number) = result {
+            inner.add_handle(number);
+        }
         result
     }
 
     fn read(&self, file: usize, buf: &mut [u8]) -> Result<usize> {
         let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        if let Some(count) = inner.read_pushed(file, buf) {
+            return Ok(count);
+        }
+        if buf.len() > PAGE_SIZE && inner.chunked_read.load(Ordering::SeqCst) {
+            return inner.read_chunked(file, buf);
+        }
         let address = inner.capture_mut(buf)?;
         let result = inner.call(SYS_READ, file, address, buf.len());
         let _ = inner.release(address);
@@ -391,6 +908,7 @@ impl Scheme for UserScheme {
 
     fn write(&self, file: usize, buf: &[u8]) -> Result<usize> {
         let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        inner.check_writable()?;
         let address = inner.capture(buf)?;
         let result = inner.call(SYS_WRITE, file, address, buf.len());
         let _ = inner.release(address);
@@ -405,6 +923,7 @@ impl Scheme for UserScheme {
 
     fn fchmod(&self, file: usize, mode: u16) -> Result<usize> {
         let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        inner.check_writable()?;
         inner.call(SYS_FCHMOD, file, mode as usize, 0)
     }
 
@@ -421,6 +940,7 @@ impl Scheme for UserScheme {
         }
 
         let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        inner.check_writable()?;
         inner.call(SYS_FCHOWN, file, uid as usize, gid as usize)
     }
 
@@ -431,11 +951,18 @@ impl Scheme for UserScheme {
 
     fn fevent(&self, file: usize, flags: EventFlags) -> Result<EventFlags> {
         let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        if inner.is_unmounting() {
+            return Ok(EVENT_HUP);
+        }
         inner.call(SYS_FEVENT, file, flags.bits(), 0).map(EventFlags::from_bits_truncate)
     }
 
     fn fmap(&self, file: usize, map: &Map) -> Result<usize> {
         let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        // Writes to private mappings never reach the file
+        if map.flags.contains(PROT_WRITE) && !map.flags.contains(MapFlags::MAP_PRIVATE) {
+            inner.check_writable()?;
+        }
 
         inner.fmap_inner(file, map)
     }
@@ -452,7 +979,7 @@ impl Scheme for UserScheme {
 
             let grant_address = VirtualAddress::new(grant_address);
 
-            if let Some((&grant, &user_base)) = entry {
+            if let Some((&grant, &(user_base, flags))) = entry {
                 let grant_requested = Region::new(grant_address, size);
                 if grant_requested.end_address() > grant.end_address() {
                     return Err(Error::new(EINVAL));
@@ -463,21 +990,23 @@ impl Scheme for UserScheme {
                 let user = Region::new(user_base, grant.size());
 
                 if let Some(before) = grant.before(grant_requested) {
-                    funmap.insert(before, user_base);
+                    funmap.insert(before, (user_base, flags));
                 }
                 if let Some(after) = grant.after(grant_requested) {
                     let start = grant.rebase(user, after.start_address());
-                    funmap.insert(after, start);
+                    funmap.insert(after, (start, flags));
                 }
 
-                Some(grant.rebase(user, grant_address).data())
+                Some((grant.rebase(user, grant_address).data(), flags))
             } else {
                 None
             }
 
         };
-        if let Some(user_address) = address_opt {
-            inner.call(SYS_FUNMAP, user_address, size, 0)
+        // The flags tell the scheme whether the mapping was private, in which case it holds no
+        // changes that need to be written back
+        if let Some((user_address, flags)) = address_opt {
+            inner.call(SYS_FUNMAP, user_address, size, flags.bits())
         } else {
             Err(Error::new(EINVAL))
         }
@@ -493,6 +1022,7 @@ impl Scheme for UserScheme {
 
     fn frename(&self, file: usize, path: &str, _uid: u32, _gid: u32) -> Result<usize> {
         let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        inner.check_writable()?;
         let address = inner.capture(path.as_bytes())?;
         let result = inner.call(SYS_FRENAME, file, address, path.len());
         let _ = inner.release(address);
@@ -522,11 +1052,13 @@ impl Scheme for UserScheme {
 
     fn ftruncate(&self, file: usize, len: usize) -> Result<usize> {
         let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        inner.check_writable()?;
         inner.call(SYS_FTRUNCATE, file, len, 0)
     }
 
     fn futimens(&self, file: usize, times: &[TimeSpec]) -> Result<usize> {
         let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        inner.check_writable()?;
         let buf = unsafe { slice::from_raw_parts(times.as_ptr() as *const u8, mem::size_of::<TimeSpec>() * times.len()) };
         let address = inner.capture(buf)?;
         let result = inner.call(SYS_FUTIMENS, file, address, buf.len());
@@ -536,7 +1068,76 @@ impl Scheme for UserScheme {
 
     fn close(&self, file: usize) -> Result<usize> {
         let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        inner.remove_handle(file);
         inner.call(SYS_CLOSE, file, 0, 0)
     }
 }
-impl crate::scheme::KernelScheme for UserScheme {}
+impl crate::scheme::KernelScheme for UserScheme {
+    fn handler(&self) -> SchemeHandler {
+        match self.inner.upgrade() {
+            Some(inner) => inner.handler(),
+            None => SchemeHandler::Orphaned,
+        }
+    }
+
+    fn open_handles(&self) -> Option<usize> {
+        self.inner.upgrade().map(|inner| inner.open_handles())
+    }
+
+    /// The flags are passed as a native-endian usize in front of the path
+    fn frename_flags(&self, file: usize, path: &str, flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
+        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        inner.check_writable()?;
+        let header = mem::size_of::<usize>();
+        let mut buf = BounceBuffer::new(header + path.len())?;
+        buf[..header].copy_from_slice(&flags.to_ne_bytes());
+        buf[header..].copy_from_slice(path.as_bytes());
+        let address = inner.capture(&buf)?;
+        let result = inner.call(crate::syscall::SYS_FRENAME_FLAGS, file, address, buf.len());
+        let _ = inner.release(address);
+        result
+    }
+
+    fn kreadoff(&self, file: usize, buf: &mut [u8], offset: usize) -> Result<usize> {
+        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let header = mem::size_of::<usize>();
+        let mut data = BounceBuffer::new(header + buf.len())?;
+        data[..header].copy_from_slice(&offset.to_ne_bytes());
+        let address = inner.capture_mut(&mut data)?;
+        let result = inner.call(SYS_PREAD, file, address, data.len());
+        let _ = inner.release(address);
+        let count = result?.min(buf.len());
+        buf[..count].copy_from_slice(&data[header..header + count]);
+        Ok(count)
+    }
+
+    fn kwriteoff(&self, file: usize, buf: &[u8], offset: usize) -> Result<usize> {
+        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        inner.check_writable()?;
+        let header = mem::size_of::<usize>();
+        let mut data = BounceBuffer::new(header + buf.len())?;
+        data[..header].copy_from_slice(&offset.to_ne_bytes());
+        data[header..].copy_from_slice(buf);
+        let address = inner.capture(&data)?;
+        let result = inner.call(SYS_PWRITE, file, address, data.len());
+        let _ = inner.release(address);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_misaligned_fmap_reply;
+    use crate::paging::PAGE_SIZE;
+    use crate::syscall::error::{Error, ENOMEM};
+
+    /// Replies that are not page-aligned are rejected, while aligned addresses and errors from
+    /// the handler are passed on
+    #[test]
+    fn misaligned_fmap_reply() {
+        assert!(is_misaligned_fmap_reply(0x1000_0123));
+        assert!(is_misaligned_fmap_reply(PAGE_SIZE + 1));
+        assert!(!is_misaligned_fmap_reply(0x1000_0000));
+        assert!(!is_misaligned_fmap_reply(Error::mux(Err(Error::new(ENOMEM)))));
+    }
+}
@@ -0,0 +1,58 @@
+use alloc::sync::Arc;
+use spin::RwLock;
+
+use crate::context;
+use crate::context::memory::AddrSpace;
+use crate::scheme::memory::MemoryScheme;
+
+use crate::syscall::data::Map;
+use crate::syscall::error::*;
+use crate::syscall::scheme::Scheme;
+
+pub struct ZeroScheme;
+
+impl Scheme for ZeroScheme {
+    fn open(&self, _path: &str, _flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn read(&self, _id: usize, buf: &mut [u8]) -> Result<usize> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write(&self, _id: usize, buf: &[u8]) -> Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn seek(&self, _id: usize, _pos: isize, _whence: usize) -> Result<isize> {
+        Ok(0)
+    }
+
+    fn fmap(&self, _id: usize, map: &Map) -> Result<usize> {
+        MemoryScheme::fmap_anonymous(&Arc::clone(context::current()?.read().addr_space()?), map)
+    }
+
+    fn fcntl(&self, _id: usize, _cmd: usize, _arg: usize) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn fpath(&self, _id: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut i = 0;
+        let scheme_path = b"zero:";
+        while i < buf.len() && i < scheme_path.len() {
+            buf[i] = scheme_path[i];
+            i += 1;
+        }
+        Ok(i)
+    }
+
+    fn close(&self, _id: usize) -> Result<usize> {
+        Ok(0)
+    }
+}
+impl crate::scheme::KernelScheme for ZeroScheme {
+    fn kfmap(&self, _number: usize, addr_space: &Arc<RwLock<AddrSpace>>, map: &Map, _consume: bool) -> Result<usize> {
+        MemoryScheme::fmap_anonymous(addr_space, map)
+    }
+}
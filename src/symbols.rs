@@ -0,0 +1,108 @@
+//! Kernel symbols
+//!
+//! The symbol table of the kernel image is located once during boot. Looking up an address only
+//! walks that table in place, without allocating or locking, so that stack traces printed from
+//! fault handlers and panics can show function names. If the image was stripped, no table is
+//! found and stack traces only contain raw addresses.
+
+use core::{fmt, mem, slice, str};
+
+use rustc_demangle::demangle;
+use spin::Once;
+
+use crate::elf::{header, section_header::{SectionHeader, SHT_SYMTAB}, sym::{self, Sym}};
+
+struct SymbolTable {
+    symbols: &'static [Sym],
+    strtab: &'static [u8],
+}
+
+static SYMBOL_TABLE: Once<SymbolTable> = Once::new();
+
+/// A function symbol containing some address
+pub struct Symbol {
+    /// Mangled name of the function
+    pub name: &'static str,
+    /// Start address of the function
+    pub address: usize,
+    /// Offset of the looked up address into the function
+    pub offset: usize,
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#}+{:#x}", demangle(self.name), self.offset)
+    }
+}
+
+/// Locate the symbol table of the kernel image, which must stay mapped for the lifetime of the
+/// kernel
+pub unsafe fn init(image: &'static [u8]) {
+    if let Some(table) = parse(image) {
+        SYMBOL_TABLE.call_once(|| table);
+    }
+}
+
+/// Find the function containing `address`
+pub fn lookup(address: usize) -> Option<Symbol> {
+    let table = SYMBOL_TABLE.get()?;
+
+    for symbol in table.symbols {
+        if sym::st_type(symbol.st_info) != sym::STT_FUNC {
+            continue;
+        }
+
+        let start = symbol.st_value as usize;
+        let offset = match address.checked_sub(start) {
+            Some(offset) if offset < symbol.st_size as usize => offset,
+            _ => continue,
+        };
+
+        let name = table.strtab.get(symbol.st_name as usize..)
+            .and_then(|name| name.split(|&b| b == 0).next())
+            .and_then(|name| str::from_utf8(name).ok())
+            .unwrap_or("?");
+
+        return Some(Symbol { name, address: start, offset });
+    }
+
+    None
+}
+
+/// Validate the ELF image by hand, as `Elf::from` allocates its error messages
+unsafe fn parse(image: &'static [u8]) -> Option<SymbolTable> {
+    if image.len() < header::SIZEOF_EHDR
+    || image.get(..header::SELFMAG)? != header::ELFMAG
+    || image.get(header::EI_CLASS) != Some(&header::ELFCLASS) {
+        return None;
+    }
+    let header = &*(image.as_ptr() as *const header::Header);
+
+    let section = |index: usize| -> Option<&'static SectionHeader> {
+        if index >= header.e_shnum as usize {
+            return None;
+        }
+        let offset = (header.e_shoff as usize).checked_add(index.checked_mul(header.e_shentsize as usize)?)?;
+        let bytes = image.get(offset..offset.checked_add(mem::size_of::<SectionHeader>())?)?;
+        Some(&*(bytes.as_ptr() as *const SectionHeader))
+    };
+    let contents = |section: &SectionHeader| -> Option<&'static [u8]> {
+        let offset = section.sh_offset as usize;
+        image.get(offset..offset.checked_add(section.sh_size as usize)?)
+    };
+
+    let symtab = (0..header.e_shnum as usize)
+        .filter_map(section)
+        .find(|section| section.sh_type == SHT_SYMTAB)?;
+    let strtab = contents(section(symtab.sh_link as usize)?)?;
+
+    let symbols = contents(symtab)?;
+    if symbols.as_ptr() as usize % mem::align_of::<Sym>() != 0 {
+        return None;
+    }
+
+    Some(SymbolTable {
+        symbols: slice::from_raw_parts(symbols.as_ptr() as *const Sym, symbols.len() / mem::size_of::<Sym>()),
+        strtab,
+    })
+}
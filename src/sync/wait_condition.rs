@@ -26,6 +26,24 @@ impl WaitCondition {
         len
     }
 
+    /// Notify all waiters like `notify`, but without waiting for any lock, so that this can be
+    /// called from interrupt handlers. Returns false if some waiters could not be woken, in which
+    /// case this has to be tried again later.
+    pub fn try_notify(&self) -> bool {
+        let mut contexts = match self.contexts.try_lock() {
+            Some(contexts) => contexts,
+            None => return false,
+        };
+        contexts.retain(|context_lock| match context_lock.try_write() {
+            Some(mut context) => {
+                context.unblock();
+                false
+            },
+            None => true,
+        });
+        contexts.is_empty()
+    }
+
     // Notify as though a signal woke the waiters
     pub unsafe fn notify_signal(&self) -> usize {
         let contexts = self.contexts.lock();
@@ -36,26 +54,30 @@ impl WaitCondition {
         len
     }
 
-    // Wait until notified. Unlocks guard when blocking is ready. Returns false if resumed by a signal or the notify_signal function
+    /// Wait until notified. Unlocks guard when blocking is ready. While blocked, `reason` is the
+    /// context's `status_reason`, so that tools listing blocked contexts can show why. Returns
+    /// true if woken by `notify`, and false if resumed by a signal or the `notify_signal`
+    /// function, in which case the caller should usually fail with EINTR. A context that is being
+    /// forcibly killed does not block at all, and false is returned right away.
     pub fn wait<T>(&self, guard: MutexGuard<T>, reason: &'static str) -> bool {
-        let id;
-        {
-            let context_lock = {
-                let contexts = context::contexts();
-                let context_lock = contexts.current().expect("WaitCondition::wait: no context");
-                Arc::clone(context_lock)
-            };
-
-            {
-                let mut context = context_lock.write();
-                id = context.id;
-                context.block(reason);
+        let context_lock = {
+            let contexts = context::contexts();
+            let context_lock = contexts.current().expect("WaitCondition::wait: no context");
+            Arc::clone(context_lock)
+        };
+
+        let id = {
+            let mut context = context_lock.write();
+            if context.force_kill {
+                return false;
             }
+            context.block(reason);
+            context.id
+        };
 
-            self.contexts.lock().push(context_lock);
+        self.contexts.lock().push(Arc::clone(&context_lock));
 
-            drop(guard);
-        }
+        drop(guard);
 
         unsafe { context::switch(); }
 
@@ -81,6 +103,15 @@ impl WaitCondition {
             }
         }
 
+        // Not every wakeup goes through `Context::unblock`, so make sure the reason doesn't
+        // outlive the wait
+        {
+            let mut context = context_lock.write();
+            if context.status_reason == reason {
+                context.status_reason = "";
+            }
+        }
+
         waited
     }
 }
@@ -2,7 +2,9 @@ use alloc::collections::BTreeMap;
 use core::mem;
 use spin::Mutex;
 
+use crate::context;
 use crate::sync::WaitCondition;
+use crate::time;
 
 #[derive(Debug)]
 pub struct WaitMap<K, V> {
@@ -22,17 +24,47 @@ impl<K, V> WaitMap<K, V> where K: Clone + Ord {
         self.inner.lock().remove(key)
     }
 
-    pub fn receive(&self, key: &K, reason: &'static str) -> V {
+    /// Wait for the value for `key`. Returns `None` only if the context is being forcibly killed.
+    pub fn receive(&self, key: &K, reason: &'static str) -> Option<V> {
         loop {
             let mut inner = self.inner.lock();
             if let Some(value) = inner.remove(key) {
-                return value;
+                return Some(value);
             }
             //TODO: use false from wait condition to indicate EINTR
-            let _ = self.condition.wait(inner, reason);
+            if ! self.condition.wait(inner, reason) && context::force_killed() {
+                return None;
+            }
         }
     }
 
+    /// Like `receive`, but give up and return `None` once the monotonic clock reaches
+    /// `deadline`, in nanoseconds
+    pub fn receive_timeout(&self, key: &K, deadline: u128, reason: &'static str) -> Option<V> {
+        let context_lock = context::current().ok()?;
+        loop {
+            let mut inner = self.inner.lock();
+            if let Some(value) = inner.remove(key) {
+                return Some(value);
+            }
+            if time::monotonic() >= deadline {
+                return None;
+            }
+            // Let the scheduler wake us at the deadline, if nothing else does before
+            context_lock.write().set_wake(Some(deadline));
+            let waited = self.condition.wait(inner, reason);
+            context_lock.write().set_wake(None);
+            if ! waited && context::force_killed() {
+                return None;
+            }
+        }
+    }
+
+    /// Look at the value for `key` without removing it, so that a later `receive` still gets it
+    pub fn peek(&self, key: &K) -> Option<V> where V: Clone {
+        self.inner.lock().get(key).cloned()
+    }
+
     pub fn receive_any_nonblock(&self) -> Option<(K, V)> {
         let mut inner = self.inner.lock();
         if let Some(key) = inner.keys().next().cloned() {
@@ -42,15 +74,18 @@ impl<K, V> WaitMap<K, V> where K: Clone + Ord {
         }
     }
 
-    pub fn receive_any(&self, reason: &'static str) -> (K, V) {
+    /// Wait for any value. Returns `None` only if the context is being forcibly killed.
+    pub fn receive_any(&self, reason: &'static str) -> Option<(K, V)> {
         loop {
             let mut inner = self.inner.lock();
             if let Some(key) = inner.keys().next().cloned() {
                 if let Some(entry) = inner.remove(&key).map(|value| (key, value)) {
-                    return entry;
+                    return Some(entry);
                 }
             }
-            let _ = self.condition.wait(inner, reason);
+            if ! self.condition.wait(inner, reason) && context::force_killed() {
+                return None;
+            }
         }
     }
 
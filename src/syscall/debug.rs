@@ -3,8 +3,10 @@ use alloc::string::String;
 
 use super::data::{Map, Stat, TimeSpec};
 use super::flag::*;
+use super::fs::{F_FLOCK, F_FLOCKW, F_SETCTTY, F_SWAPFD};
 use super::number::*;
 use super::validate::*;
+use super::{SYS_CLOCK_GETRES, SYS_COPY_FILE_RANGE, SYS_FRENAME_FLAGS, SYS_GETCPU, SYS_GETPRIORITY, SYS_GETRLIMIT, SYS_MPROBE, SYS_MSYNC, SYS_PMC_READ, SYS_SETPRIORITY, SYS_SETRLIMIT, SYS_SIGPENDING, SYS_SIGQUEUE, SYS_WAIT4};
 
 struct ByteStr<'a>(&'a[u8]);
 
@@ -95,6 +97,10 @@ pub fn format_call(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -
                 F_SETFD => "F_SETFD",
                 F_SETFL => "F_SETFL",
                 F_GETFL => "F_GETFL",
+                F_FLOCK => "F_FLOCK",
+                F_FLOCKW => "F_FLOCKW",
+                F_SETCTTY => "F_SETCTTY",
+                F_SWAPFD => "F_SWAPFD",
                 _ => "UNKNOWN"
             },
             c,
@@ -161,6 +167,11 @@ pub fn format_call(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -
             b,
             validate_slice_mut(c as *mut TimeSpec, 1)
         ),
+        SYS_CLOCK_GETRES => format!(
+            "clock_getres({}, {:?})",
+            b,
+            validate_slice_mut(c as *mut TimeSpec, 1)
+        ),
         SYS_EXIT => format!(
             "exit({})",
             b
@@ -180,8 +191,35 @@ pub fn format_call(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -
         SYS_GETGID => format!("getgid()"),
         SYS_GETNS => format!("getns()"),
         SYS_GETPGID => format!("getpgid()"),
+        SYS_GETCPU => format!("getcpu()"),
+        SYS_PMC_READ => format!("pmc_read({:#X})", b),
+        SYS_COPY_FILE_RANGE => format!(
+            "copy_file_range({}, {:#X}, {}, {:#X}, {})",
+            b,
+            c,
+            d,
+            e,
+            f
+        ),
+        SYS_FRENAME_FLAGS => format!(
+            "frename_flags({}, {:?}, {:#X})",
+            b,
+            validate_slice(c as *const u8, d).map(ByteStr),
+            e
+        ),
         SYS_GETPID => format!("getpid()"),
         SYS_GETPPID => format!("getppid()"),
+        SYS_GETPRIORITY => format!(
+            "getpriority({}, {})",
+            b,
+            c
+        ),
+        SYS_SETPRIORITY => format!(
+            "setpriority({}, {}, {})",
+            b,
+            c,
+            d as isize
+        ),
         SYS_GETUID => format!("getuid()"),
         SYS_IOPL => format!(
             "iopl({})",
@@ -192,6 +230,15 @@ pub fn format_call(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -
             b,
             c
         ),
+        SYS_SIGPENDING => format!("sigpending({:#X})", b),
+        SYS_GETRLIMIT => format!("getrlimit({}, {:#X})", b, c),
+        SYS_SETRLIMIT => format!("setrlimit({}, {:#X})", b, c),
+        SYS_SIGQUEUE => format!(
+            "sigqueue({}, {}, {:#X})",
+            b,
+            c,
+            d
+        ),
         SYS_SIGRETURN => format!("sigreturn()"),
         SYS_SIGACTION => format!(
             "sigaction({}, {:#X}, {:#X}, {:#X})",
@@ -216,6 +263,16 @@ pub fn format_call(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -
             c,
             MapFlags::from_bits(d)
         ),
+        SYS_MSYNC => format!(
+            "msync({:#X}, {:#X}, {:#X})",
+            b,
+            c,
+            d
+        ),
+        SYS_MPROBE => format!(
+            "mprobe({:#X})",
+            b
+        ),
         SYS_NANOSLEEP => format!(
             "nanosleep({:?}, ({}, {}))",
             validate_slice(b as *const TimeSpec, 1),
@@ -279,6 +336,14 @@ pub fn format_call(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -
             c,
             WaitFlags::from_bits(d)
         ),
+        SYS_WAIT4 => format!(
+            "wait4({}, {:#X}, {:?}, {:#X}, {})",
+            b,
+            c,
+            WaitFlags::from_bits(d),
+            e,
+            f
+        ),
         SYS_YIELD => format!("yield()"),
         _ => format!(
             "UNKNOWN{} {:#X}({:#X}, {:#X}, {:#X}, {:#X}, {:#X})",
@@ -96,6 +96,11 @@ pub fn inner_physmap(physical_address: usize, size: usize, flags: PhysmapFlags)
     if (physical_address.saturating_add(size) as u64) > end || physical_address % PAGE_SIZE != 0 {
         return Err(Error::new(EINVAL));
     }
+    // Combined, the two would select an uncached PAT entry rather than either of the requested
+    // memory types
+    if flags.contains(PHYSMAP_WRITE_COMBINE | PHYSMAP_NO_CACHE) {
+        return Err(Error::new(EINVAL));
+    }
 
     if size % PAGE_SIZE != 0 {
         log::warn!("physmap size {} is not multiple of PAGE_SIZE {}", size, PAGE_SIZE);
@@ -112,7 +117,7 @@ pub fn inner_physmap(physical_address: usize, size: usize, flags: PhysmapFlags)
         }
         #[cfg(any(target_arch = "x86", target_arch = "x86_64"))] // TODO: AARCH64
         if flags.contains(PHYSMAP_WRITE_COMBINE) {
-            page_flags = page_flags.custom_flag(EntryFlags::HUGE_PAGE.bits(), true);
+            page_flags = page_flags.custom_flag(EntryFlags::PAT.bits(), true);
         }
         #[cfg(any(target_arch = "x86", target_arch = "x86_64"))] // TODO: AARCH64
         if flags.contains(PHYSMAP_NO_CACHE) {
@@ -162,7 +167,7 @@ pub fn virttophys(virtual_address: usize) -> Result<usize> {
     let addr_space = Arc::clone(context::current()?.read().addr_space()?);
     let addr_space = addr_space.read();
 
-    match addr_space.table.utable.translate(VirtualAddress::new(virtual_address)) {
+    match addr_space.table.translate(VirtualAddress::new(virtual_address)) {
         Some((physical_address, _)) => Ok(physical_address.data()),
         None => Err(Error::new(EFAULT))
     }
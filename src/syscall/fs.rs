@@ -1,12 +1,20 @@
 //! Filesystem syscalls
+use alloc::collections::BTreeSet;
+use alloc::string::{String, ToString};
 use alloc::sync::Arc;
-use core::str;
-use spin::RwLock;
+use core::{cmp, str};
+use spin::{Mutex, RwLock};
 
 use crate::context::file::{FileDescriptor, FileDescription};
+use crate::context::flock::{self, LockKind};
+use crate::context::memory::{AddrSpace, GrantFileRef, MS_ASYNC, MS_INVALIDATE, MS_SYNC};
 use crate::context;
 use crate::memory::PAGE_SIZE;
-use crate::scheme::{self, FileHandle};
+use crate::paging::Page;
+use crate::scheme::{self, FileHandle, KernelScheme, SchemeId};
+use crate::scheme::root::{F_SETRDONLY, F_REVOKE_GRANTS, F_SETEXCLCREATE, F_SETTIMEOUT, F_SETCHUNKEDREAD};
+use crate::scheme::user::BounceBuffer;
+use crate::sync::WaitCondition;
 use crate::syscall::data::{Packet, Stat};
 use crate::syscall::error::*;
 use crate::syscall::flag::*;
@@ -51,6 +59,141 @@ pub fn file_op_mut_slice(a: usize, fd: FileHandle, slice: &mut [u8]) -> Result<u
     file_op(a, fd, slice.as_mut_ptr() as usize, slice.len())
 }
 
+/// Number of pages in the buffer that `copy_file_range` copies through
+const COPY_FILE_RANGE_PAGES: usize = 16;
+
+/// Copy up to `len` bytes from `fd_in` to `fd_out` within the kernel, between files of any two
+/// schemes. If an offset is given for a descriptor, it is used and advanced with positional I/O,
+/// leaving the file offset alone. Returns the number of bytes copied, which is short if the
+/// source ends, the destination stops accepting data, or a signal arrives.
+pub fn copy_file_range(fd_in: FileHandle, off_in: Option<&mut usize>, fd_out: FileHandle, off_out: Option<&mut usize>, len: usize) -> Result<usize> {
+    if len == 0 {
+        return Ok(0);
+    }
+
+    let mut input = CopyEnd::new(fd_in, off_in.as_deref().copied())?;
+    let mut output = CopyEnd::new(fd_out, off_out.as_deref().copied())?;
+
+    let result = copy_through_buffer(&mut input, &mut output, len);
+
+    if let (Some(offset), Some(advanced)) = (off_in, input.offset) {
+        *offset = advanced;
+    }
+    if let (Some(offset), Some(advanced)) = (off_out, output.offset) {
+        *offset = advanced;
+    }
+
+    result
+}
+
+/// A file that `copy_file_range` copies from or to, and the offset to use instead of its file
+/// offset, if one was given
+struct CopyEnd {
+    scheme: Arc<dyn KernelScheme>,
+    number: usize,
+    offset: Option<usize>,
+}
+
+impl CopyEnd {
+    fn new(fd: FileHandle, offset: Option<usize>) -> Result<Self> {
+        let file = context::current()?.read().get_file(fd).ok_or(Error::new(EBADF))?;
+        let (scheme_id, number) = {
+            let description = file.description.read();
+            (description.scheme, description.number)
+        };
+        let scheme = Arc::clone(scheme::schemes().get(scheme_id).ok_or(Error::new(EBADF))?);
+        Ok(Self { scheme, number, offset })
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self.offset {
+            Some(ref mut offset) => {
+                let count = self.scheme.kreadoff(self.number, buf, *offset)?;
+                *offset += count;
+                Ok(count)
+            }
+            None => self.scheme.read(self.number, buf),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self.offset {
+            Some(ref mut offset) => {
+                let count = self.scheme.kwriteoff(self.number, buf, *offset)?;
+                *offset += count;
+                Ok(count)
+            }
+            None => self.scheme.write(self.number, buf),
+        }
+    }
+
+    /// Step back over `count` bytes that were read but could not be written, so that they are
+    /// not lost if the source is seekable
+    fn unread(&mut self, count: usize) {
+        match self.offset {
+            Some(ref mut offset) => *offset -= count,
+            None => {
+                let _ = self.scheme.seek(self.number, -(count as isize), SEEK_CUR);
+            }
+        }
+    }
+}
+
+fn copy_through_buffer(input: &mut CopyEnd, output: &mut CopyEnd, len: usize) -> Result<usize> {
+    // The buffer is kernel memory, so that other threads of the caller cannot see it and it does
+    // not count against the limit of its address space. Reads and writes of user schemes map its
+    // pages into the handler, so it takes whole pages of its own.
+    let mut buffer = BounceBuffer::new(COPY_FILE_RANGE_PAGES * PAGE_SIZE)?;
+
+    let mut copied = 0;
+    loop {
+        if copied >= len {
+            return Ok(copied);
+        }
+        if signal_pending()? {
+            return if copied == 0 { Err(Error::new(EINTR)) } else { Ok(copied) };
+        }
+
+        let count = cmp::min(len - copied, buffer.len());
+        let read = match input.read(&mut buffer[..count]) {
+            Ok(0) => return Ok(copied),
+            Ok(read) => read,
+            Err(err) => return if copied == 0 { Err(err) } else { Ok(copied) },
+        };
+
+        let mut written = 0;
+        let mut error = None;
+        while written < read {
+            match output.write(&buffer[written..read]) {
+                Ok(0) => break,
+                Ok(count) => written += count,
+                Err(err) => {
+                    error = Some(err);
+                    break;
+                }
+            }
+        }
+        copied += written;
+
+        if written < read {
+            input.unread(read - written);
+
+            return match error {
+                Some(err) if copied == 0 => Err(err),
+                _ => Ok(copied),
+            };
+        }
+    }
+}
+
+/// Whether the current context has a signal waiting to be delivered, for long operations that
+/// stop early to let it through
+pub fn signal_pending() -> Result<bool> {
+    let context_lock = context::current()?;
+    let context = context_lock.read();
+    Ok(context.pending.has_deliverable(&context.sigmask))
+}
+
 /// Open syscall
 pub fn open(path: &str, flags: usize) -> Result<FileHandle> {
     let (uid, gid, scheme_ns, umask) = {
@@ -83,42 +226,70 @@ pub fn open(path: &str, flags: usize) -> Result<FileHandle> {
             scheme: scheme_id,
             number: file_id,
             flags: flags & !O_CLOEXEC,
+            lock: None,
         })),
         cloexec: flags & O_CLOEXEC == O_CLOEXEC,
     }).ok_or(Error::new(EMFILE))
 }
 
+/// Create a pipe, and install its read and write ends into `fds[0]` and `fds[1]`. Either both
+/// ends are installed or, if the file table is full, neither is and the pipe is destroyed.
 pub fn pipe2(fds: &mut [usize], flags: usize) -> Result<usize> {
     if fds.len() < 2 {
         return Err(Error::new(EFAULT));
     }
+    if flags & !(O_CLOEXEC | O_NONBLOCK) != 0 {
+        return Err(Error::new(EINVAL));
+    }
 
     let scheme_id = crate::scheme::pipe::pipe_scheme_id().ok_or(Error::new(ENODEV))?;
     let (read_id, write_id) = crate::scheme::pipe::pipe(flags);
 
-    let contexts = context::contexts();
-    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
-    let context = context_lock.read();
-
-    let read_fd = context.add_file(FileDescriptor {
+    let end = |namespace, number, accmode| FileDescriptor {
         description: Arc::new(RwLock::new(FileDescription {
-            namespace: context.ens,
+            namespace,
             scheme: scheme_id,
-            number: read_id,
-            flags: O_RDONLY | flags & !O_ACCMODE & !O_CLOEXEC,
+            number,
+            flags: accmode | flags & !O_CLOEXEC,
+            lock: None,
         })),
         cloexec: flags & O_CLOEXEC == O_CLOEXEC,
-    }).ok_or(Error::new(EMFILE))?;
+    };
 
-    let write_fd = context.add_file(FileDescriptor {
-        description: Arc::new(RwLock::new(FileDescription {
-            namespace: context.ens,
-            scheme: scheme_id,
-            number: write_id,
-            flags: O_WRONLY | flags & !O_ACCMODE & !O_CLOEXEC,
-        })),
-        cloexec: flags & O_CLOEXEC == O_CLOEXEC,
-    }).ok_or(Error::new(EMFILE))?;
+    let (read, write, installed) = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+
+        let read = end(context.ens, read_id, O_RDONLY);
+        let write = end(context.ens, write_id, O_WRONLY);
+
+        let installed = context.add_file(read.clone()).and_then(|read_fd| match context.add_file(write.clone()) {
+            Some(write_fd) => Some((read_fd, write_fd)),
+            None => {
+                // Roll back the read end, unless another thread sharing the file table has
+                // already closed it and reused its slot
+                let mut files = context.files.write();
+                if let Some(slot) = files.get_mut(read_fd.into()) {
+                    if slot.as_ref().map_or(false, |file| Arc::ptr_eq(&file.description, &read.description)) {
+                        *slot = None;
+                    }
+                }
+                None
+            }
+        });
+        (read, write, installed)
+    };
+
+    let (read_fd, write_fd) = match installed {
+        Some(fds) => fds,
+        None => {
+            // Without any other reference to the ends, closing them destroys the pipe
+            let _ = read.close();
+            let _ = write.close();
+            return Err(Error::new(EMFILE));
+        }
+    };
 
     fds[0] = read_fd.into();
     fds[1] = write_fd.into();
@@ -211,6 +382,7 @@ fn duplicate_file(fd: FileHandle, buf: &[u8]) -> Result<FileDescriptor> {
                 scheme: description.scheme,
                 number: new_id,
                 flags: description.flags,
+                lock: None,
             })),
             cloexec: false,
         })
@@ -230,20 +402,90 @@ pub fn dup(fd: FileHandle, buf: &[u8]) -> Result<FileHandle> {
 
 /// Duplicate file descriptor, replacing another
 pub fn dup2(fd: FileHandle, new_fd: FileHandle, buf: &[u8]) -> Result<FileHandle> {
+    let context_lock = context::current()?;
+
     if fd == new_fd {
+        // Nothing is closed, but fd still has to be valid
+        context_lock.read().get_file(fd).ok_or(Error::new(EBADF))?;
         Ok(new_fd)
     } else {
-        let _ = close(new_fd);
         let new_file = duplicate_file(fd, buf)?;
 
-        let contexts = context::contexts();
-        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
-        let context = context_lock.read();
+        let old_file = context_lock.read().replace_file(new_fd, new_file)?;
+        if let Some(old_file) = old_file {
+            let _ = old_file.close();
+        }
 
-        context.insert_file(new_fd, new_file).ok_or(Error::new(EMFILE))
+        Ok(new_fd)
     }
 }
 
+/// fcntl commands taking an advisory lock on the whole file, with LOCK_SH, LOCK_EX or LOCK_UN as
+/// the argument. F_FLOCKW blocks until the lock is available. These are not F_SETLK and F_SETLKW,
+/// which take a `struct flock`.
+// TODO: Move to syscall::flag
+pub const F_FLOCK: usize = 0x202;
+pub const F_FLOCKW: usize = 0x203;
+pub const LOCK_SH: usize = 1;
+pub const LOCK_EX: usize = 2;
+pub const LOCK_UN: usize = 8;
+/// fcntl command making the terminal behind the file the controlling terminal of the calling
+/// process. The scheme is asked first and may refuse. The terminal can then signal process groups
+/// of processes that share it, see `UserInner::write`.
+// TODO: Move to syscall::flag
+pub const F_SETCTTY: usize = 0x200;
+/// fcntl command exchanging the file behind the descriptor with the one behind the descriptor
+/// passed as the argument, including their close-on-exec flags. Unlike dup2, nothing is closed.
+// TODO: Move to syscall::flag
+pub const F_SWAPFD: usize = 0x201;
+
+fn flock(file: &FileDescriptor, operation: usize, wait: bool) -> Result<usize> {
+    let owner = Arc::as_ptr(&file.description) as usize;
+
+    let kind = match operation {
+        LOCK_SH => LockKind::Shared,
+        LOCK_EX => LockKind::Exclusive,
+        LOCK_UN => {
+            if let Some(held) = file.description.write().lock.take() {
+                flock::unlock(owner, held);
+            }
+            return Ok(0);
+        },
+        _ => return Err(Error::new(EINVAL)),
+    };
+
+    let (scheme_id, number) = {
+        let description = file.description.read();
+        (description.scheme, description.number)
+    };
+    let scheme = {
+        let schemes = scheme::schemes();
+        let scheme = schemes.get(scheme_id).ok_or(Error::new(EBADF))?;
+        Arc::clone(scheme)
+    };
+
+    let mut path = vec![0; 4096];
+    let len = scheme.fpath(number, &mut path)?;
+    path.truncate(len);
+
+    let held = flock::lock(owner, (scheme_id, path.into_boxed_slice()), kind, wait)?;
+    let previous = file.description.write().lock.replace(held);
+    if let Some(previous) = previous {
+        // The path of the file changed since it was locked
+        if ! previous.same_file(&held) {
+            flock::unlock(owner, previous);
+        }
+    }
+
+    Ok(0)
+}
+
+/// Whether `cmd` is an fcntl command that schemes implement entirely, so that the result of the
+/// scheme is the result of the call
+fn is_scheme_fcntl(cmd: usize) -> bool {
+    matches!(cmd, F_SETRDONLY | F_REVOKE_GRANTS | F_SETEXCLCREATE | F_SETTIMEOUT | F_SETCHUNKEDREAD)
+}
+
 /// File descriptor controls
 pub fn fcntl(fd: FileHandle, cmd: usize, arg: usize) -> Result<usize> {
     let file = {
@@ -253,6 +495,16 @@ pub fn fcntl(fd: FileHandle, cmd: usize, arg: usize) -> Result<usize> {
         context.get_file(fd).ok_or(Error::new(EBADF))?
     };
 
+    // Advisory locks are kept by the kernel, the scheme is not involved
+    if cmd == F_FLOCK || cmd == F_FLOCKW {
+        return flock(&file, arg, cmd == F_FLOCKW);
+    }
+
+    if cmd == F_SWAPFD {
+        drop(file);
+        return context::current()?.read().swap_files(fd, FileHandle::from(arg)).and(Ok(0));
+    }
+
     let description = file.description.read();
 
     // Communicate fcntl with scheme
@@ -262,10 +514,22 @@ pub fn fcntl(fd: FileHandle, cmd: usize, arg: usize) -> Result<usize> {
             let scheme = schemes.get(description.scheme).ok_or(Error::new(EBADF))?;
             Arc::clone(scheme)
         };
-        scheme.fcntl(description.number, cmd, arg)?;
+        let result = scheme.fcntl(description.number, cmd, arg)?;
+
+        // Nothing is left for the kernel to do for these
+        if is_scheme_fcntl(cmd) {
+            return Ok(result);
+        }
     };
 
     // Perform kernel operation if scheme agrees
+    if cmd == F_SETCTTY {
+        let ctty = (description.scheme, description.number);
+        drop(description);
+        context::current()?.write().ctty = Some(ctty);
+        return Ok(0);
+    }
+
     {
         if cmd == F_DUPFD {
             // Not in match because 'files' cannot be locked
@@ -316,7 +580,8 @@ pub fn fcntl(fd: FileHandle, cmd: usize, arg: usize) -> Result<usize> {
     }
 }
 
-pub fn frename(fd: FileHandle, path: &str) -> Result<usize> {
+/// Resolve the target of a rename of `fd` to `path`, which must be on the same scheme
+fn rename_target(fd: FileHandle, path: &str) -> Result<(SchemeId, Arc<dyn KernelScheme + Send + Sync>, usize, String, u32, u32)> {
     let (file, uid, gid, scheme_ns) = match context::current()?.read() {
         ref context => (context.get_file(fd).ok_or(Error::new(EBADF))?, context.euid, context.egid, context.ens),
     };
@@ -334,12 +599,87 @@ pub fn frename(fd: FileHandle, path: &str) -> Result<usize> {
     let description = file.description.read();
 
     if scheme_id == description.scheme {
-        scheme.frename(description.number, reference, uid, gid)
+        Ok((scheme_id, scheme, description.number, reference.to_string(), uid, gid))
     } else {
         Err(Error::new(EXDEV))
     }
 }
 
+pub fn frename(fd: FileHandle, path: &str) -> Result<usize> {
+    let (_, scheme, number, reference, uid, gid) = rename_target(fd, path)?;
+
+    scheme.frename(number, &reference, uid, gid)
+}
+
+/// Flags of frename_flags. With RENAME_NOREPLACE the rename fails with EEXIST if the target
+/// exists, with RENAME_EXCHANGE the two names are swapped atomically.
+// TODO: Move to syscall::flag
+pub const RENAME_NOREPLACE: usize = 1;
+pub const RENAME_EXCHANGE: usize = 2;
+
+/// Schemes on which an emulated RENAME_NOREPLACE is in progress. The kernel emulates it by checking
+/// for the target before renaming, which must not interleave with another emulated rename on the
+/// same scheme.
+static RENAMING: Mutex<BTreeSet<SchemeId>> = Mutex::new(BTreeSet::new());
+static RENAMED: WaitCondition = WaitCondition::new();
+
+struct RenameGuard(SchemeId);
+
+impl RenameGuard {
+    /// Wait until no other emulated rename is in progress on the scheme, failing with EINTR if a
+    /// signal arrives first.
+    fn lock(scheme_id: SchemeId) -> Result<Self> {
+        loop {
+            let mut renaming = RENAMING.lock();
+            if renaming.insert(scheme_id) {
+                return Ok(RenameGuard(scheme_id));
+            }
+            if ! RENAMED.wait(renaming, "frename") {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+}
+
+impl Drop for RenameGuard {
+    fn drop(&mut self) {
+        RENAMING.lock().remove(&self.0);
+        RENAMED.notify();
+    }
+}
+
+/// Rename with RENAME_NOREPLACE or RENAME_EXCHANGE. The flags are passed on to the scheme, and if
+/// it does not support them, RENAME_NOREPLACE is emulated by probing for the target first.
+pub fn frename_flags(fd: FileHandle, path: &str, flags: usize) -> Result<usize> {
+    if flags & !(RENAME_NOREPLACE | RENAME_EXCHANGE) != 0 || flags == RENAME_NOREPLACE | RENAME_EXCHANGE {
+        return Err(Error::new(EINVAL));
+    }
+    if flags == 0 {
+        return frename(fd, path);
+    }
+
+    let (scheme_id, scheme, number, reference, uid, gid) = rename_target(fd, path)?;
+
+    match scheme.frename_flags(number, &reference, flags, uid, gid) {
+        Err(err) if err.errno == ENOSYS && flags == RENAME_NOREPLACE => {
+            let _guard = RenameGuard::lock(scheme_id)?;
+            match scheme.open(&reference, O_STAT | O_NOFOLLOW, uid, gid) {
+                Ok(target) => {
+                    let _ = scheme.close(target);
+                    Err(Error::new(EEXIST))
+                },
+                // A symbolic link is in the way
+                Err(err) if err.errno == ELOOP => Err(Error::new(EEXIST)),
+                Err(err) if err.errno == ENOENT => scheme.frename(number, &reference, uid, gid),
+                Err(err) => Err(err),
+            }
+        },
+        // Swapping cannot be emulated atomically
+        Err(err) if err.errno == ENOSYS => Err(Error::new(EINVAL)),
+        res => res,
+    }
+}
+
 /// File status
 pub fn fstat(fd: FileHandle, stat: &mut Stat) -> Result<usize> {
     let file = {
@@ -370,7 +710,87 @@ pub fn funmap(virtual_address: usize, length: usize) -> Result<usize> {
     let (page, page_count) = crate::syscall::validate::validate_region(virtual_address, length_aligned)?;
 
     let addr_space = Arc::clone(context::current()?.read().addr_space()?);
-    addr_space.write().munmap(page, page_count);
+    addr_space.write().munmap(page, page_count)?;
 
     Ok(0)
 }
+
+/// Write the pages of shared file mappings in the range that were modified since they were last
+/// written back to their files. If writing a page fails, it and the pages after it stay dirty,
+/// and the error is returned.
+pub fn msync(virtual_address: usize, length: usize, flags: usize) -> Result<usize> {
+    if flags & !(MS_ASYNC | MS_INVALIDATE | MS_SYNC) != 0 || flags & (MS_ASYNC | MS_SYNC) == MS_ASYNC | MS_SYNC {
+        return Err(Error::new(EINVAL));
+    }
+    if virtual_address % PAGE_SIZE != 0 {
+        return Err(Error::new(EINVAL));
+    }
+    let length_aligned = length.checked_add(PAGE_SIZE - 1).ok_or(Error::new(ENOMEM))? / PAGE_SIZE * PAGE_SIZE;
+    let (page, page_count) = crate::syscall::validate::validate_region(virtual_address, length_aligned)?;
+
+    let addr_space = AddrSpace::current()?;
+    let mut dirty = addr_space.write().take_dirty(page, page_count, flags & MS_INVALIDATE == MS_INVALIDATE)?.into_iter();
+
+    // All mappings of a file borrow the same frames from the scheme, so there are no other copies
+    // of the pages that MS_INVALIDATE would have to discard
+    let mut result = Ok(0);
+    while let Some((page, file_ref)) = dirty.next() {
+        if let Err(err) = write_back(page, &file_ref) {
+            let mut failed = vec![page];
+            failed.extend(dirty.by_ref().map(|(page, file_ref)| {
+                let _ = file_ref.desc.close();
+                page
+            }));
+            addr_space.write().set_dirty(failed.into_iter());
+            result = Err(err);
+        }
+        let _ = file_ref.desc.close();
+    }
+    result
+}
+
+/// Write `page` to its file at the offset it is mapped from, leaving the file offset unchanged
+fn write_back(page: Page, file_ref: &GrantFileRef) -> Result<()> {
+    let (scheme_id, number) = {
+        let description = file_ref.desc.description.read();
+        (description.scheme, description.number)
+    };
+    let scheme = Arc::clone(scheme::schemes().get(scheme_id).ok_or(Error::new(EBADF))?);
+
+    // Positional writes neither race with other users of the file offset, nor go to the end of
+    // the file if it was opened with O_APPEND
+    let mut buf = crate::syscall::validate::validate_slice(page.start_address().data() as *const u8, PAGE_SIZE)?;
+    let mut offset = file_ref.offset;
+    while !buf.is_empty() {
+        match scheme.kwriteoff(number, buf, offset) {
+            Ok(0) => return Err(Error::new(EIO)),
+            Ok(count) => {
+                buf = &buf[count..];
+                offset += count;
+            }
+            // Schemes that do not support positional writes are written at the file offset
+            Err(err) if offset == file_ref.offset && (err.errno == ESPIPE || err.errno == ENOSYS) => {
+                return write_back_seek(&*scheme, number, buf, offset);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Write `buf` at `offset` by seeking there, restoring the file offset afterwards
+fn write_back_seek(scheme: &dyn KernelScheme, number: usize, mut buf: &[u8], offset: usize) -> Result<()> {
+    let saved = scheme.seek(number, 0, SEEK_CUR)?;
+    let result = scheme.seek(number, offset as isize, SEEK_SET).and_then(|_| {
+        while !buf.is_empty() {
+            match scheme.write(number, buf)? {
+                0 => return Err(Error::new(EIO)),
+                count => buf = &buf[count..],
+            }
+        }
+        Ok(())
+    });
+    let _ = scheme.seek(number, saved, SEEK_SET);
+
+    result
+}
@@ -9,14 +9,22 @@ use spin::{Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 use rmm::Arch;
 
-use crate::context::{self, Context};
+use crate::context::{self, Context, memory::AddrSpace};
 use crate::time;
 use crate::memory::PhysicalAddress;
 use crate::paging::VirtualAddress;
 use crate::syscall::data::TimeSpec;
 use crate::syscall::error::{Error, Result, ESRCH, EAGAIN, EFAULT, EINVAL};
 use crate::syscall::flag::{FUTEX_WAIT, FUTEX_WAIT64, FUTEX_WAKE, FUTEX_REQUEUE};
-use crate::syscall::validate::validate_array;
+use crate::syscall::validate::{validate_array, validate_slice_mut};
+
+/// Futex operation for condition variables: atomically release the lock at `addr2` by storing 0 to
+/// it and waking one of its waiters, then wait on `addr` as with FUTEX_WAIT. The lock is released
+/// even if the value at `addr` differs and EAGAIN is returned, so the caller must always reacquire
+/// it. Both steps happen under the futex list lock, so a FUTEX_WAKE on `addr` issued after the
+/// lock is observed as released cannot be lost.
+// TODO: Move to syscall::flag
+pub const FUTEX_WAIT_UNLOCK: usize = 4;
 
 type FutexList = VecDeque<FutexEntry>;
 
@@ -43,28 +51,42 @@ pub fn futexes_mut() -> RwLockWriteGuard<'static, FutexList> {
     FUTEXES.call_once(init_futexes).write()
 }
 
-pub fn futex(addr: usize, op: usize, val: usize, val2: usize, addr2: usize) -> Result<usize> {
-    let addr_space = Arc::clone(context::current()?.read().addr_space()?);
+fn translate(addr_space: &RwLock<AddrSpace>, addr: usize) -> Result<PhysicalAddress> {
+    let virtual_address = VirtualAddress::new(addr);
+
+    if !crate::CurrentRmmArch::virt_is_valid(virtual_address) {
+        return Err(Error::new(EFAULT));
+    }
+    // TODO: Use this all over the code, making sure that no user pointers that are higher half
+    // can get to the page table walking procedure.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    if virtual_address.data() & (1 << 63) == (1 << 63) {
+        return Err(Error::new(EFAULT));
+    }
 
-    let (target_physaddr, _) = {
-        let virtual_address = VirtualAddress::new(addr);
+    addr_space.read().table.translate(virtual_address).map(|(physaddr, _)| physaddr).ok_or(Error::new(EFAULT))
+}
 
-        if !crate::CurrentRmmArch::virt_is_valid(virtual_address) {
-            return Err(Error::new(EFAULT));
-        }
-        // TODO: Use this all over the code, making sure that no user pointers that are higher half
-        // can get to the page table walking procedure.
-        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-        if virtual_address.data() & (1 << 63) == (1 << 63) {
-            return Err(Error::new(EFAULT));
-        }
+pub fn futex(addr: usize, op: usize, val: usize, val2: usize, addr2: usize) -> Result<usize> {
+    let addr_space = Arc::clone(context::current()?.read().addr_space()?);
 
-        addr_space.read().table.utable.translate(virtual_address).ok_or(Error::new(EFAULT))?
-    };
+    let target_physaddr = translate(&addr_space, addr)?;
 
     match op {
         // TODO: FUTEX_WAIT_MULTIPLE?
-        FUTEX_WAIT | FUTEX_WAIT64 => {
+        FUTEX_WAIT | FUTEX_WAIT64 | FUTEX_WAIT_UNLOCK => {
+            let lock_physaddr = if op == FUTEX_WAIT_UNLOCK {
+                if addr2 % 4 != 0 {
+                    return Err(Error::new(EINVAL));
+                }
+                // The lock is stored to while the futex list is locked, where a fault on a
+                // read-only page would kill the context with the list still locked
+                validate_slice_mut(addr2 as *mut u32, 1)?;
+                Some(translate(&addr_space, addr2)?)
+            } else {
+                None
+            };
+
             let timeout_ptr = val2 as *const TimeSpec;
 
             let timeout_opt = if timeout_ptr.is_null() {
@@ -83,8 +105,18 @@ pub fn futex(addr: usize, op: usize, val: usize, val2: usize, addr2: usize) -> R
                     Arc::clone(context_lock)
                 };
 
+                if let Some(lock_physaddr) = lock_physaddr {
+                    unsafe { intrinsics::atomic_store::<u32>(addr2 as *mut u32, 0); }
+
+                    if let Some(i) = futexes.iter().position(|futex| futex.target_physaddr == lock_physaddr) {
+                        if let Some(futex) = futexes.swap_remove_back(i) {
+                            futex.context_lock.write().unblock();
+                        }
+                    }
+                }
+
                 // TODO: Is the implicit SeqCst ordering too strong here?
-                let (fetched, expected) = if op == FUTEX_WAIT {
+                let (fetched, expected) = if op != FUTEX_WAIT64 {
                     // Must be aligned, otherwise it could cross a page boundary and mess up the
                     // (simpler) validation we did in the first place.
                     if addr % 4 != 0 {
@@ -108,7 +140,7 @@ pub fn futex(addr: usize, op: usize, val: usize, val2: usize, addr2: usize) -> R
                     if let Some(timeout) = timeout_opt {
                         let start = time::monotonic();
                         let end = start + (timeout.tv_sec as u128 * time::NANOS_PER_SEC) + (timeout.tv_nsec as u128);
-                        context.wake = Some(end);
+                        context.set_wake(Some(end));
                     }
 
                     context.block("futex");
@@ -131,7 +163,7 @@ pub fn futex(addr: usize, op: usize, val: usize, val2: usize, addr2: usize) -> R
 
                 {
                     let mut context = context_lock.write();
-                    context.wake = None;
+                    context.set_wake(None);
                 }
             }
 
@@ -162,21 +194,7 @@ pub fn futex(addr: usize, op: usize, val: usize, val2: usize, addr2: usize) -> R
             Ok(woken)
         },
         FUTEX_REQUEUE => {
-            let (addr2_physaddr, _) = {
-                let addr2_virt = VirtualAddress::new(addr2);
-
-                if !crate::CurrentRmmArch::virt_is_valid(addr2_virt) {
-                    return Err(Error::new(EFAULT));
-                }
-
-                // TODO
-                #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-                if addr2_virt.data() & (1 << 63) == (1 << 63) {
-                    return Err(Error::new(EFAULT));
-                }
-
-                addr_space.read().table.utable.translate(addr2_virt).ok_or(Error::new(EFAULT))?
-            };
+            let addr2_physaddr = translate(&addr_space, addr2)?;
 
             let mut woken = 0;
             let mut requeued = 0;
@@ -0,0 +1,89 @@
+//! # Syscall rate limit
+//! A token bucket per context, refilled from the monotonic clock, that bounds how many syscalls
+//! the context may issue per second. It is configured through the `syscall-limit` file of the
+//! proc: scheme.
+
+use crate::syscall::error::{Error, Result, EAGAIN};
+use crate::syscall::number::{SYS_EXIT, SYS_SIGRETURN, SYS_YIELD};
+use crate::time;
+
+/// Limit flag: sleep until the syscall is allowed instead of failing it with EAGAIN
+// TODO: Move to syscall::flag
+pub const SYSCALL_LIMIT_SLEEP: usize = 1;
+
+/// Cost of one syscall, in the units of `SyscallLimit::balance`
+const COST: i128 = time::NANOS_PER_SEC as i128;
+
+/// Whether syscall `a` is never throttled, as the context could not otherwise exit, return from
+/// a signal handler or give up the CPU
+pub fn is_exempt(a: usize) -> bool {
+    matches!(a, SYS_EXIT | SYS_SIGRETURN | SYS_YIELD)
+}
+
+#[derive(Clone, Debug)]
+pub struct SyscallLimit {
+    /// Syscalls allowed per second
+    pub rate: u64,
+    /// Syscalls that may be issued at once after being idle
+    pub burst: u64,
+    /// Flags, see `SYSCALL_LIMIT_SLEEP`
+    pub flags: usize,
+    /// Syscalls that were delayed or failed by the limit
+    pub throttled: u64,
+    /// Tokens, in syscalls times nanoseconds per second, so that `rate` are added per nanosecond.
+    /// Negative while a sleeping syscall waits for its token.
+    balance: i128,
+    /// Monotonic time the balance was last refilled at
+    updated: u128,
+}
+
+impl SyscallLimit {
+    /// Create a limit, starting with a full bucket. `rate` must not be 0.
+    pub fn new(rate: u64, burst: u64, flags: usize) -> Self {
+        let burst = burst.max(1);
+        SyscallLimit {
+            rate,
+            burst,
+            flags,
+            throttled: 0,
+            balance: burst as i128 * COST,
+            updated: time::monotonic(),
+        }
+    }
+
+    /// Take the token of a syscall issued at monotonic time `now`. Returns the time to sleep
+    /// until if the syscall must wait for it, or EAGAIN if it must fail instead.
+    pub fn take(&mut self, now: u128) -> Result<Option<u128>> {
+        let elapsed = now.saturating_sub(self.updated);
+        self.updated = now;
+        let refill = elapsed.saturating_mul(u128::from(self.rate)).min(i128::MAX as u128) as i128;
+        self.balance = self.balance.saturating_add(refill).min(self.burst as i128 * COST);
+
+        if self.balance >= COST {
+            self.balance -= COST;
+            return Ok(None);
+        }
+
+        self.throttled += 1;
+        if self.flags & SYSCALL_LIMIT_SLEEP == 0 {
+            return Err(Error::new(EAGAIN));
+        }
+
+        // Go into debt, which the refill pays back by the deadline
+        self.balance -= COST;
+        let debt = (-self.balance) as u128;
+        let rate = u128::from(self.rate);
+        Ok(Some(now + (debt + rate - 1) / rate))
+    }
+
+    /// Give back the token taken by a syscall that never ran, as its sleep was interrupted
+    pub fn refund(&mut self) {
+        self.balance = self.balance.saturating_add(COST).min(self.burst as i128 * COST);
+    }
+
+    /// Whether this limit is at least as strict as `other`, so that a limited context setting it
+    /// does not lift its own limit through a child
+    pub fn is_within(&self, other: &SyscallLimit) -> bool {
+        self.rate <= other.rate && self.burst <= other.burst && self.flags == other.flags
+    }
+}
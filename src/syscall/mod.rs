@@ -28,12 +28,14 @@ pub use self::validate::*;
 use self::scheme::Scheme as _;
 
 use self::data::{Map, SigAction, Stat, TimeSpec};
-use self::error::{Error, Result, ENOSYS};
-use self::flag::{MapFlags, PhysmapFlags, WaitFlags};
+use self::error::{Error, Result, EINTR, ENOSYS, ESRCH};
+use self::flag::{MapFlags, PhysmapFlags, WaitFlags, PTRACE_STOP_POST_SYSCALL, PTRACE_STOP_PRE_SYSCALL, SIGKILL};
 use self::number::*;
 
-use crate::context::ContextId;
+use crate::context::{self, ContextId, Rusage};
+use crate::context::rlimit::Rlimit;
 use crate::interrupt::InterruptStack;
+use crate::ptrace;
 use crate::scheme::{FileHandle, SchemeNamespace, memory::MemoryScheme};
 
 /// Debug
@@ -48,6 +50,9 @@ pub mod fs;
 /// Fast userspace mutex
 pub mod futex;
 
+/// Syscall rate limit
+pub mod limit;
+
 /// Privilege syscalls
 pub mod privilege;
 
@@ -60,6 +65,55 @@ pub mod time;
 /// Validate input
 pub mod validate;
 
+/// Sleep until an absolute deadline of the given clock
+// TODO: Move to syscall::number
+pub const SYS_CLOCK_NANOSLEEP_ABS: usize = 267;
+/// Get the resolution of a clock
+// TODO: Move to syscall::number
+pub const SYS_CLOCK_GETRES: usize = 266;
+/// Get the resource usage of the current context
+// TODO: Move to syscall::number
+pub const SYS_GETRUSAGE: usize = 77;
+/// Get the CPU the caller is running on
+// TODO: Move to syscall::number
+pub const SYS_GETCPU: usize = 309;
+/// Copy data between two files within the kernel
+// TODO: Move to syscall::number
+pub const SYS_COPY_FILE_RANGE: usize = 326;
+/// Query whether an address is mapped, and with which permissions
+// TODO: Move to syscall::number
+pub const SYS_MPROBE: usize = 327;
+/// Get the number of events counted by the performance counter of the current context
+// TODO: Move to syscall::number
+pub const SYS_PMC_READ: usize = 328;
+/// Rename a file, failing if the target exists or swapping the two names depending on the flags
+// TODO: Move to syscall::number
+pub const SYS_FRENAME_FLAGS: usize = 329;
+/// Get the nice value of a context
+// TODO: Move to syscall::number
+pub const SYS_GETPRIORITY: usize = 96;
+/// Set the nice value of a context
+// TODO: Move to syscall::number
+pub const SYS_SETPRIORITY: usize = 97;
+/// Wait for a child, and get its resource usage
+// TODO: Move to syscall::number
+pub const SYS_WAIT4: usize = 114;
+/// Send a real-time signal with a value to a process
+// TODO: Move to syscall::number
+pub const SYS_SIGQUEUE: usize = 330;
+/// Get the soft and hard limit of a resource of the current context
+// TODO: Move to syscall::number
+pub const SYS_GETRLIMIT: usize = 332;
+/// Set the soft and hard limit of a resource of the current context
+// TODO: Move to syscall::number
+pub const SYS_SETRLIMIT: usize = 333;
+/// Write the modified pages of shared file mappings back to their files
+// TODO: Move to syscall::number
+pub const SYS_MSYNC: usize = 334;
+/// Get the pending signals that are blocked
+// TODO: Move to syscall::number
+pub const SYS_SIGPENDING: usize = 335;
+
 /// This function is the syscall handler of the kernel, it is composed of an inner function that returns a `Result<usize>`. After the inner function runs, the syscall
 /// function calls [`Error::mux`] on it.
 pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize, stack: &mut InterruptStack) -> usize {
@@ -108,14 +162,36 @@ pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize, stack
                     }
                 ),
                 SYS_CLOCK_GETTIME => clock_gettime(b, validate_slice_mut(c as *mut TimeSpec, 1).map(|time| &mut time[0])?),
+                SYS_CLOCK_GETRES => clock_getres(b, validate_slice_mut(c as *mut TimeSpec, 1).map(|res| &mut res[0])?),
+                SYS_CLOCK_NANOSLEEP_ABS => clock_nanosleep_abs(b, validate_slice(c as *const TimeSpec, 1).map(|deadline| &deadline[0])?),
                 SYS_FUTEX => futex(b, c, d, e, f),
                 SYS_GETPID => getpid().map(ContextId::into),
+                SYS_GETCPU => getcpu(),
+                SYS_PMC_READ => pmc_read(validate_slice_mut(b as *mut u64, 1).map(|count| &mut count[0])?),
+                SYS_COPY_FILE_RANGE => copy_file_range(
+                    FileHandle::from(b),
+                    if c == 0 { None } else { Some(validate_slice_mut(c as *mut usize, 1).map(|offset| &mut offset[0])?) },
+                    FileHandle::from(d),
+                    if e == 0 { None } else { Some(validate_slice_mut(e as *mut usize, 1).map(|offset| &mut offset[0])?) },
+                    f,
+                ),
+                SYS_FRENAME_FLAGS => frename_flags(FileHandle::from(b), validate_str(c as *const u8, d)?, e),
+                SYS_GETRUSAGE => getrusage(b, unsafe { validate_ref_mut(c as *mut Rusage, d)? }),
                 SYS_GETPGID => getpgid(ContextId::from(b)).map(ContextId::into),
                 SYS_GETPPID => getppid().map(ContextId::into),
+                SYS_GETPRIORITY => getpriority(b, ContextId::from(c)),
+                SYS_SETPRIORITY => setpriority(b, ContextId::from(c), d as isize),
 
                 SYS_EXIT => exit((b & 0xFF) << 8),
                 SYS_KILL => kill(ContextId::from(b), c),
+                SYS_SIGQUEUE => sigqueue(ContextId::from(b), c, d),
                 SYS_WAITPID => waitpid(ContextId::from(b), c, WaitFlags::from_bits_truncate(d)).map(ContextId::into),
+                SYS_WAIT4 => wait4(
+                    ContextId::from(b),
+                    c,
+                    WaitFlags::from_bits_truncate(d),
+                    if e == 0 { None } else { Some(unsafe { validate_ref_mut(e as *mut Rusage, f)? }) },
+                ).map(ContextId::into),
                 SYS_IOPL => iopl(b, stack),
                 SYS_GETEGID => getegid(),
                 SYS_GETENS => getens(),
@@ -124,6 +200,8 @@ pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize, stack
                 SYS_GETNS => getns(),
                 SYS_GETUID => getuid(),
                 SYS_MPROTECT => mprotect(b, c, MapFlags::from_bits_truncate(d)),
+                SYS_MPROBE => mprobe(b),
+                SYS_MSYNC => msync(b, c, d),
                 SYS_MKNS => mkns(validate_slice(b as *const [usize; 2], c)?),
                 SYS_SETPGID => setpgid(ContextId::from(b), ContextId::from(c)),
                 SYS_SETREUID => setreuid(b as u32, c as u32),
@@ -156,6 +234,9 @@ pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize, stack
                         Some(validate_slice_mut(d as *mut [u64; 2], 1).map(|s| &mut s[0])?)
                     }
                 ),
+                SYS_SIGPENDING => sigpending(validate_slice_mut(b as *mut [u64; 2], 1).map(|s| &mut s[0])?),
+                SYS_GETRLIMIT => getrlimit(b, &mut validate_slice_mut(c as *mut Rlimit, 1)?[0]),
+                SYS_SETRLIMIT => setrlimit(b, &validate_slice(c as *const Rlimit, 1)?[0]),
                 SYS_SIGRETURN => sigreturn(),
                 SYS_PIPE2 => pipe2(validate_slice_mut(b as *mut usize, 2)?, c),
                 SYS_PHYSALLOC => physalloc(b),
@@ -212,22 +293,45 @@ pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize, stack
     //
     // When the code below falls out of scope it will release the lock
     // see the spin crate for details
-    {
+    let throttle = {
         let contexts = crate::context::contexts();
         if let Some(context_lock) = contexts.current() {
             let mut context = context_lock.write();
             context.syscall = Some((a, b, c, d, e, f));
+            context.syscall_count += 1;
+
+            match context.syscall_limit {
+                Some(ref mut limit) if ! limit::is_exempt(a) => limit.take(crate::time::monotonic()),
+                _ => Ok(None),
+            }
+        } else {
+            Ok(None)
         }
-    }
+    };
 
-    let result = inner(a, b, c, d, e, f, stack);
+    ptrace::syscall_event(ptrace_event!(PTRACE_STOP_PRE_SYSCALL, a, b, c, d, e, f));
 
-    {
+    let result = match throttle {
+        Ok(None) => inner(a, b, c, d, e, f, stack),
+        Ok(Some(deadline)) => throttle_until(deadline).and_then(|()| inner(a, b, c, d, e, f, stack)),
+        Err(err) => Err(err),
+    };
+
+    let force_kill = {
         let contexts = crate::context::contexts();
         if let Some(context_lock) = contexts.current() {
             let mut context = context_lock.write();
             context.syscall = None;
+            context.force_kill
+        } else {
+            false
         }
+    };
+
+    // Every wait gave up, so a forcibly killed context ends up here instead of staying stuck
+    if force_kill {
+        println!("{}: forcibly killed at the end of a syscall", context::context_id().into());
+        exit(SIGKILL);
     }
 
     /*
@@ -256,5 +360,43 @@ pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize, stack
     */
 
     // errormux turns Result<usize> into -errno
-    Error::mux(result)
+    let ret = Error::mux(result);
+
+    ptrace::syscall_event(ptrace_event!(PTRACE_STOP_POST_SYSCALL, a, ret));
+
+    ret
+}
+
+/// Sleep until the syscall rate limit of the current context allows the pending syscall
+fn throttle_until(deadline: u128) -> Result<()> {
+    {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let mut context = context_lock.write();
+
+        context.set_wake(Some(deadline));
+        context.block("syscall limit");
+    }
+
+    loop {
+        unsafe { context::switch(); }
+
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let mut context = context_lock.write();
+
+        // `update` clears `wake` once the deadline has passed
+        if context.wake().is_none() {
+            return Ok(());
+        }
+        // Otherwise, if the context was made runnable, it was woken early by a signal
+        if context.status == context::Status::Runnable {
+            context.set_wake(None);
+            // The syscall does not run, so it does not spend its token
+            if let Some(limit) = context.syscall_limit.as_mut() {
+                limit.refund();
+            }
+            return Err(Error::new(EINTR));
+        }
+    }
 }
@@ -132,8 +132,8 @@ pub fn setrens(rns: SchemeNamespace, ens: SchemeNamespace) -> Result<usize> {
         } else if context.rns.into() == 0 {
             // Do not allow leaving capability mode
             return Err(Error::new(EPERM));
-        } else if context.euid == 0 {
-            // Allow setting RNS if root
+        } else if context.euid == 0 && ! context.sandboxed {
+            // Allow setting RNS if root, unless confined to a namespace by the parent
             true
         } else if rns == context.ens {
             // Allow setting RNS if used for ENS
@@ -156,8 +156,8 @@ pub fn setrens(rns: SchemeNamespace, ens: SchemeNamespace) -> Result<usize> {
         } else if context.ens.into() == 0 {
             // Do not allow leaving capability mode
             return Err(Error::new(EPERM));
-        } else if context.euid == 0 {
-            // Allow setting ENS if root
+        } else if context.euid == 0 && ! context.sandboxed {
+            // Allow setting ENS if root, unless confined to a namespace by the parent
             true
         } else if ens == context.ens {
             // Allow setting ENS if used for ENS
@@ -173,6 +173,8 @@ pub fn setrens(rns: SchemeNamespace, ens: SchemeNamespace) -> Result<usize> {
             return Err(Error::new(EPERM));
         };
 
+    let (old_rns, old_ens) = (context.rns, context.ens);
+
     if setrns {
         context.rns = rns;
     }
@@ -181,6 +183,16 @@ pub fn setrens(rns: SchemeNamespace, ens: SchemeNamespace) -> Result<usize> {
         context.ens = ens;
     }
 
+    let (new_rns, new_ens) = (context.rns, context.ens);
+    drop(context);
+    drop(contexts);
+
+    let mut schemes = scheme::schemes_mut();
+    schemes.ns_ref(new_rns);
+    schemes.ns_ref(new_ens);
+    schemes.ns_unref(old_rns);
+    schemes.ns_unref(old_ens);
+
     Ok(0)
 }
 
@@ -6,20 +6,22 @@ use core::mem;
 
 use spin::{RwLock, RwLockWriteGuard};
 
-use crate::context::{Context, ContextId, memory::AddrSpace, WaitpidKey};
+use crate::context::{Context, ContextId, memory::AddrSpace, Rusage, WaitpidKey};
+use crate::context::rlimit::{Rlimit, RLIMIT_AS};
 
 use crate::Bootstrap;
 use crate::context;
 use crate::interrupt;
-use crate::paging::mapper::{InactiveFlusher, PageFlushAll};
+use crate::paging::mapper::{BatchFlusher, PageFlushAll};
 use crate::paging::{Page, PageFlags, VirtualAddress, PAGE_SIZE};
 use crate::ptrace;
+use crate::scheme::{self, SchemeId};
 use crate::start::usermode;
 use crate::syscall::data::SigAction;
 use crate::syscall::error::*;
 use crate::syscall::flag::{wifcontinued, wifstopped, MapFlags,
     PTRACE_STOP_EXIT, SIG_BLOCK, SIG_SETMASK, SIG_UNBLOCK,
-    SIGCONT, SIGTERM, WaitFlags, WCONTINUED, WNOHANG, WUNTRACED};
+    SIGKILL, SIGSTOP, SIGTERM, WaitFlags, WCONTINUED, WNOHANG, WUNTRACED};
 use crate::syscall::ptrace_event;
 use crate::syscall::validate::validate_slice_mut;
 
@@ -38,16 +40,15 @@ fn empty<'lock>(context_lock: &'lock RwLock<Context>, mut context: RwLockWriteGu
     };
 
     if let Ok(mut addr_space) = Arc::try_unwrap(addr_space_arc).map(RwLock::into_inner) {
+        // The TLB is flushed once all grants are gone, rather than after each of them
+        let mut flusher = BatchFlusher::new(addr_space.is_current());
         let mapper = &mut addr_space.table.utable;
 
         for grant in addr_space.grants.into_iter() {
-            let unmap_result = if reaping {
+            if reaping {
                 log::error!("{}: {}: Grant should not exist: {:?}", context.id.into(), *context.name.read(), grant);
-
-                grant.unmap(mapper, &mut InactiveFlusher::new())
-            } else {
-                grant.unmap(mapper, PageFlushAll::new())
-            };
+            }
+            let unmap_result = grant.unmap(mapper, &mut flusher);
 
             if unmap_result.file_desc.is_some() {
                 drop(context);
@@ -67,15 +68,8 @@ pub fn exit(status: usize) -> ! {
     {
         let context_lock = context::current().expect("exit failed to find context");
 
-        let close_files;
-        let pid = {
-            let mut context = context_lock.write();
-            close_files = Arc::try_unwrap(mem::take(&mut context.files)).map_or_else(|_| Vec::new(), RwLock::into_inner);
-            context.id
-        };
-
         // TODO: Find a better way to implement this, perhaps when the init process calls exit.
-        if pid == ContextId::from(1) {
+        if context_lock.read().id == ContextId::from(1) {
             println!("Main kernel thread exited with status {:X}", status);
 
             extern {
@@ -90,77 +84,140 @@ pub fn exit(status: usize) -> ! {
             }
         }
 
-        // Files must be closed while context is valid so that messages can be passed
-        for (_fd, file_opt) in close_files.into_iter().enumerate() {
-            if let Some(file) = file_opt {
-                let _ = file.close();
-            }
+        exit_context(&context_lock, status, false);
+    }
+
+    let _ = unsafe { context::switch() };
+
+    unreachable!();
+}
+
+/// Release the resources of the context, hand its children to its parent, and leave it to be
+/// reaped with `status`. This is the part of `exit` that does not need the context to be running,
+/// so that a context which was killed before it ever ran can exit without a kernel stack. Such a
+/// context is exited by another one, so with `defer_release`, its files and address space are left
+/// for `reap` to release, rather than having the killer run their closes.
+pub(crate) fn exit_context(context_lock: &Arc<RwLock<Context>>, status: usize, defer_release: bool) {
+    let mut close_files = Vec::new();
+    let pid = {
+        let mut context = context_lock.write();
+        if !defer_release {
+            close_files = Arc::try_unwrap(mem::take(&mut context.files)).map_or_else(|_| Vec::new(), RwLock::into_inner);
         }
+        context.id
+    };
 
-        // PGID and PPID must be grabbed after close, as context switches could change PGID or PPID if parent exits
-        let (pgid, ppid) = {
-            let context = context_lock.read();
-            (context.pgid, context.ppid)
-        };
+    // Files must be closed while context is valid so that messages can be passed
+    for (_fd, file_opt) in close_files.into_iter().enumerate() {
+        if let Some(file) = file_opt {
+            let _ = file.close();
+        }
+    }
 
-        // Transfer child processes to parent
-        {
-            let contexts = context::contexts();
-            for (_id, context_lock) in contexts.iter() {
-                let mut context = context_lock.write();
-                if context.ppid == pid {
-                    context.ppid = ppid;
-                    context.vfork = false;
-                }
+    // PGID and PPID must be grabbed after close, as context switches could change PGID or PPID if parent exits
+    let (pgid, ppid, rns, ens) = {
+        let context = context_lock.read();
+        (context.pgid, context.ppid, context.rns, context.ens)
+    };
+
+    // Leave the scheme namespaces, freeing them if this was the last context in them
+    {
+        let mut schemes = scheme::schemes_mut();
+        schemes.ns_unref(rns);
+        schemes.ns_unref(ens);
+    }
+
+    // Transfer child processes to parent
+    {
+        let contexts = context::contexts();
+        for (_id, context_lock) in contexts.iter() {
+            let mut context = context_lock.write();
+            if context.ppid == pid {
+                context.ppid = ppid;
+                context.vfork = false;
             }
         }
+    }
 
-        let (vfork, children) = {
-            let mut context = context_lock.write();
+    let (vfork, children, rusage) = {
+        let mut context = context_lock.write();
 
-            context = empty(&context_lock, context, false);
+        // Captured before the address space is released, as it holds the peak resident set
+        let rusage = context.rusage();
 
-            let vfork = context.vfork;
-            context.vfork = false;
+        if !defer_release {
+            context = empty(&context_lock, context, false);
+        }
 
-            context.status = context::Status::Exited(status);
+        let vfork = context.vfork;
+        context.vfork = false;
 
-            let children = context.waitpid.receive_all();
+        context.status = context::Status::Exited(status);
 
-            (vfork, children)
-        };
+        let children = context.waitpid.receive_all();
 
-        {
-            let contexts = context::contexts();
-            if let Some(parent_lock) = contexts.get(ppid) {
-                let waitpid = {
-                    let mut parent = parent_lock.write();
-                    if vfork && ! parent.unblock() {
-                        println!("{}: {} not blocked for exit vfork unblock", pid.into(), ppid.into());
-                    }
-                    Arc::clone(&parent.waitpid)
-                };
+        (vfork, children, rusage)
+    };
 
-                for (c_pid, c_status) in children {
-                    waitpid.send(c_pid, c_status);
+    {
+        let contexts = context::contexts();
+        if let Some(parent_lock) = contexts.get(ppid) {
+            let waitpid = {
+                let mut parent = parent_lock.write();
+                if vfork {
+                    parent.vfork_wait = false;
+                }
+                if vfork && ! parent.unblock() {
+                    println!("{}: {} not blocked for exit vfork unblock", pid.into(), ppid.into());
                 }
+                Arc::clone(&parent.waitpid)
+            };
 
-                waitpid.send(WaitpidKey {
-                    pid: Some(pid),
-                    pgid: Some(pgid)
-                }, (pid, status));
-            } else {
-                println!("{}: {} not found for exit vfork unblock", pid.into(), ppid.into());
+            for (c_pid, c_status) in children {
+                waitpid.send(c_pid, c_status);
             }
-        }
 
-        // Alert any tracers waiting of this process
-        ptrace::close_tracee(pid);
+            waitpid.send(WaitpidKey {
+                pid: Some(pid),
+                pgid: Some(pgid)
+            }, (pid, status, rusage));
+        } else {
+            println!("{}: {} not found for exit vfork unblock", pid.into(), ppid.into());
+        }
     }
 
-    let _ = unsafe { context::switch() };
+    // Alert any tracers waiting of this process
+    ptrace::close_tracee(pid);
 
-    unreachable!();
+    // Processes this one was tracing are released when the trace handle is closed, which
+    // happens above once the last context sharing the file table exits
+}
+
+/// Let the parent of the vfork child `pid` run again, once the child no longer uses the parent's
+/// address space because it replaced its own
+pub fn vfork_release(pid: ContextId) {
+    let ppid = {
+        let contexts = context::contexts();
+        let mut context = match contexts.get(pid) {
+            Some(context_lock) => context_lock.write(),
+            None => return,
+        };
+        if ! mem::replace(&mut context.vfork, false) {
+            return;
+        }
+        context.ppid
+    };
+
+    let contexts = context::contexts();
+    if let Some(parent_lock) = contexts.get(ppid) {
+        let mut parent = parent_lock.write();
+        parent.vfork_wait = false;
+        if ! parent.unblock() {
+            println!("{}: {} not blocked for exec vfork unblock", pid.into(), ppid.into());
+        }
+    } else {
+        println!("{}: {} not found for exec vfork unblock", pid.into(), ppid.into());
+    }
 }
 
 pub fn getpid() -> Result<ContextId> {
@@ -170,6 +227,51 @@ pub fn getpid() -> Result<ContextId> {
     Ok(context.id)
 }
 
+/// Get the CPU executing the caller. `Context::cpu_id` is not used, as it is only updated when the
+/// context is scheduled, and reading it would require the context lock.
+pub fn getcpu() -> Result<usize> {
+    Ok(crate::cpu_id())
+}
+
+/// Report the resource usage of the calling context
+// TODO: Support RUSAGE_CHILDREN by accumulating the usage of reaped children
+pub fn getrusage(who: usize, rusage: &mut Rusage) -> Result<usize> {
+    const RUSAGE_SELF: usize = 0;
+
+    if who != RUSAGE_SELF {
+        return Err(Error::new(EINVAL));
+    }
+
+    let contexts = context::contexts();
+    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+    let context = context_lock.read();
+
+    *rusage = context.rusage();
+
+    Ok(0)
+}
+
+/// Get the number of events counted by the performance counter of the calling context, which is
+/// selected through its `pmc` file in the proc: scheme
+pub fn pmc_read(count: &mut u64) -> Result<usize> {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+
+        *count = context::pmc_count(&context, true);
+
+        Ok(0)
+    }
+
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    {
+        let _ = count;
+        Err(Error::new(ENOSYS))
+    }
+}
+
 pub fn getpgid(pid: ContextId) -> Result<ContextId> {
     let contexts = context::contexts();
     let context_lock = if pid.into() == 0 {
@@ -181,6 +283,63 @@ pub fn getpgid(pid: ContextId) -> Result<ContextId> {
     Ok(context.pgid)
 }
 
+/// `which` argument of getpriority and setpriority, selecting a single process by `who`
+// TODO: Move to syscall::flag
+pub const PRIO_PROCESS: usize = 0;
+
+/// Get the nice value of the context `who`, or of the caller if zero. As on Linux, the value is
+/// returned as `20 - nice`, so that it is never negative.
+pub fn getpriority(which: usize, who: ContextId) -> Result<usize> {
+    if which != PRIO_PROCESS {
+        return Err(Error::new(EINVAL));
+    }
+
+    let contexts = context::contexts();
+    let context_lock = if who.into() == 0 {
+        contexts.current().ok_or(Error::new(ESRCH))?
+    } else {
+        contexts.get(who).ok_or(Error::new(ESRCH))?
+    };
+    let context = context_lock.read();
+    Ok((20 - context.priority) as usize)
+}
+
+/// Set the nice value of the context `who`, or of the caller if zero, clamped to the valid range.
+/// Lowering the priority of a context requires owning it, while raising it requires root. The
+/// new priority applies from the next time the context is switched to.
+pub fn setpriority(which: usize, who: ContextId, priority: isize) -> Result<usize> {
+    if which != PRIO_PROCESS {
+        return Err(Error::new(EINVAL));
+    }
+    let priority = priority.max(context::PRIO_MIN as isize).min(context::PRIO_MAX as isize) as i32;
+
+    let (ruid, euid) = {
+        let context_lock = context::current()?;
+        let context = context_lock.read();
+        (context.ruid, context.euid)
+    };
+
+    let contexts = context::contexts();
+    let context_lock = if who.into() == 0 {
+        contexts.current().ok_or(Error::new(ESRCH))?
+    } else {
+        contexts.get(who).ok_or(Error::new(ESRCH))?
+    };
+    let mut context = context_lock.write();
+
+    if euid != 0 {
+        if euid != context.ruid && ruid != context.ruid {
+            return Err(Error::new(EPERM));
+        }
+        if priority < context.priority {
+            return Err(Error::new(EACCES));
+        }
+    }
+
+    context.priority = priority;
+    Ok(0)
+}
+
 pub fn getppid() -> Result<ContextId> {
     let contexts = context::contexts();
     let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
@@ -189,6 +348,19 @@ pub fn getppid() -> Result<ContextId> {
 }
 
 pub fn kill(pid: ContextId, sig: usize) -> Result<usize> {
+    send_signal(pid, sig, None)
+}
+
+/// Queue the real-time signal `sig` with `value` for the single process `pid`, which its handler
+/// receives in `SigInfo::si_value`. Standard signals are sent as with `kill`, without the value.
+pub fn sigqueue(pid: ContextId, sig: usize, value: usize) -> Result<usize> {
+    if pid.into() as isize <= 0 {
+        return Err(Error::new(EINVAL));
+    }
+    send_signal(pid, sig, Some(value))
+}
+
+fn send_signal(pid: ContextId, sig: usize, value: Option<usize>) -> Result<usize> {
     let (ruid, euid, current_pgid) = {
         let contexts = context::contexts();
         let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
@@ -196,14 +368,25 @@ pub fn kill(pid: ContextId, sig: usize) -> Result<usize> {
         (context.ruid, context.euid, context.pgid)
     };
 
+    // Root signalling every process is the shutdown case, which is done without holding the
+    // contexts lock while each context is signalled. As on other systems, the caller is left out.
+    if pid.into() as isize == -1 && euid == 0 && sig > 0 && sig < 0x7F {
+        return match context::broadcast_signal(sig as u8, value)? {
+            0 => Err(Error::new(ESRCH)),
+            _ => Ok(0),
+        };
+    }
+
     if sig < 0x7F {
         let mut found = 0;
         let mut sent = 0;
+        let mut queue_full = 0;
+        let mut unstarted = Vec::new();
 
         {
             let contexts = context::contexts();
 
-            let send = |context: &mut context::Context| -> bool {
+            let mut send = |context: &mut context::Context| -> bool {
                 if euid == 0
                 || euid == context.ruid
                 || ruid == context.ruid
@@ -211,12 +394,13 @@ pub fn kill(pid: ContextId, sig: usize) -> Result<usize> {
                     // If sig = 0, test that process exists and can be
                     // signalled, but don't send any signal.
                     if sig != 0 {
-                        //TODO: sigprocmask
-                        context.pending.push_back(sig as u8);
-                        // Convert stopped processes to blocked if sending SIGCONT
-                        if sig == SIGCONT {
-                            if let context::Status::Stopped(_sig) = context.status {
-                                context.status = context::Status::Blocked;
+                        match context.send_signal(sig as u8, value) {
+                            Ok(true) => unstarted.push(context.id),
+                            Ok(false) => (),
+                            Err(_) => {
+                                // The real-time signal queue is full
+                                queue_full += 1;
+                                return false;
                             }
                         }
                     }
@@ -241,7 +425,7 @@ pub fn kill(pid: ContextId, sig: usize) -> Result<usize> {
                 for (_id, context_lock) in contexts.iter() {
                     let mut context = context_lock.write();
 
-                    if context.id.into() > 2 {
+                    if context.id.into() > context::CONTEXT_BROADCAST_EXEMPT {
                         found += 1;
 
                         if send(&mut context) {
@@ -271,8 +455,17 @@ pub fn kill(pid: ContextId, sig: usize) -> Result<usize> {
             }
         }
 
+        for pid in unstarted {
+            let context_lock = context::contexts().get(pid).map(Arc::clone);
+            if let Some(context_lock) = context_lock {
+                exit_context(&context_lock, SIGKILL, true);
+            }
+        }
+
         if found == 0 {
             Err(Error::new(ESRCH))
+        } else if sent == 0 && queue_full > 0 {
+            Err(Error::new(EAGAIN))
         } else if sent == 0 {
             Err(Error::new(EPERM))
         } else {
@@ -286,6 +479,31 @@ pub fn kill(pid: ContextId, sig: usize) -> Result<usize> {
     }
 }
 
+/// Send a signal from a terminal scheme to a process group, on behalf of the terminal handle
+/// `number` of `scheme_id`. Only processes that have this handle as their controlling terminal are
+/// signalled, so a terminal can notify its foreground group of e.g. SIGWINCH, but nothing else.
+/// The signal is only queued, processes that mask it receive it once it is unmasked.
+pub fn kill_ctty(scheme_id: SchemeId, number: usize, pgid: ContextId, sig: usize) -> Result<usize> {
+    if sig == 0 || sig >= 0x7F {
+        return Err(Error::new(EINVAL));
+    }
+
+    let mut sent = 0;
+    let contexts = context::contexts();
+    for (_id, context_lock) in contexts.iter() {
+        let mut context = context_lock.write();
+        if context.pgid == pgid && context.ctty == Some((scheme_id, number)) && context.pending.push(sig as u8).is_ok() {
+            sent += 1;
+        }
+    }
+
+    if sent == 0 {
+        Err(Error::new(ESRCH))
+    } else {
+        Ok(sent)
+    }
+}
+
 pub fn mprotect(address: usize, size: usize, flags: MapFlags) -> Result<usize> {
     // println!("mprotect {:#X}, {}, {:#X}", address, size, flags);
 
@@ -295,6 +513,12 @@ pub fn mprotect(address: usize, size: usize, flags: MapFlags) -> Result<usize> {
     AddrSpace::current()?.write().mprotect(Page::containing_address(VirtualAddress::new(address)), size / PAGE_SIZE, flags).map(|()| 0)
 }
 
+/// Report whether `address` is mapped in the caller's address space, and with which
+/// permissions. An unmapped address is reported as empty flags rather than as an error.
+pub fn mprobe(address: usize) -> Result<usize> {
+    Ok(AddrSpace::current()?.read().probe(VirtualAddress::new(address)).bits())
+}
+
 pub fn setpgid(pid: ContextId, pgid: ContextId) -> Result<usize> {
     let contexts = context::contexts();
 
@@ -376,6 +600,48 @@ pub fn sigprocmask(how: usize, mask_opt: Option<&[u64; 2]>, oldmask_opt: Option<
     Ok(0)
 }
 
+/// Get the signals that are pending but blocked by the signal mask of the current context, which
+/// would be delivered once unblocked
+pub fn sigpending(set: &mut [u64; 2]) -> Result<usize> {
+    let (pending, mask) = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        (context.pending.set(), context.sigmask)
+    };
+
+    *set = [pending[0] & mask[0], pending[1] & mask[1]];
+    // SIGKILL and SIGSTOP can never be blocked, whatever the mask says
+    set[0] &= !(1 << (SIGKILL - 1) | 1 << (SIGSTOP - 1));
+    Ok(0)
+}
+
+/// Get the soft and hard limit of `resource`, one of the `RLIMIT_*` numbers
+pub fn getrlimit(resource: usize, limit: &mut Rlimit) -> Result<usize> {
+    let contexts = context::contexts();
+    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+    *limit = context_lock.read().rlimits.get(resource)?;
+    Ok(0)
+}
+
+/// Set the soft and hard limit of `resource`. Only root may raise the hard limit.
+pub fn setrlimit(resource: usize, limit: &Rlimit) -> Result<usize> {
+    let contexts = context::contexts();
+    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+    let mut context = context_lock.write();
+    let privileged = context.euid == 0;
+    let old = context.rlimits.get(resource)?;
+    context.rlimits.set(resource, *limit, privileged)?;
+
+    // The address space limit is checked by mmap, which only sees the address space
+    if resource == RLIMIT_AS {
+        if let Ok(addr_space) = context.addr_space() {
+            addr_space.write().update_rlimit_as(old.soft, limit.soft);
+        }
+    }
+    Ok(0)
+}
+
 pub fn sigreturn() -> Result<usize> {
     {
         let contexts = context::contexts();
@@ -417,11 +683,18 @@ fn reap(pid: ContextId) -> Result<ContextId> {
         interrupt::pause();
     }
 
-    let mut contexts = context::contexts_mut();
-    let context_lock = contexts.remove(pid).ok_or(Error::new(ESRCH))?;
-    {
-        let context = context_lock.write();
-        empty(&context_lock, context, true);
+    let context_lock = context::contexts_mut().remove(pid).ok_or(Error::new(ESRCH))?;
+    // A context that was killed before it ever ran left its files and address space to be
+    // released here, see `exit_context`
+    let close_files = {
+        let mut context = context_lock.write();
+        let close_files = Arc::try_unwrap(mem::take(&mut context.files)).map_or_else(|_| Vec::new(), RwLock::into_inner);
+        let ran = context.kstack.is_some();
+        empty(&context_lock, context, ran);
+        close_files
+    };
+    for file in close_files.into_iter().flatten() {
+        let _ = file.close();
     }
     drop(context_lock);
 
@@ -429,6 +702,12 @@ fn reap(pid: ContextId) -> Result<ContextId> {
 }
 
 pub fn waitpid(pid: ContextId, status_ptr: usize, flags: WaitFlags) -> Result<ContextId> {
+    wait4(pid, status_ptr, flags, None)
+}
+
+/// Like `waitpid`, but also report the resource usage of the child into `rusage`, if given. For
+/// a child that exited, this is its usage at the time of exit, captured before it was reaped.
+pub fn wait4(pid: ContextId, status_ptr: usize, flags: WaitFlags, mut rusage: Option<&mut Rusage>) -> Result<ContextId> {
     let (ppid, waitpid) = {
         let contexts = context::contexts();
         let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
@@ -443,10 +722,13 @@ pub fn waitpid(pid: ContextId, status_ptr: usize, flags: WaitFlags) -> Result<Co
         &mut tmp
     };
 
-    let mut grim_reaper = |w_pid: ContextId, status: usize| -> Option<Result<ContextId>> {
+    let mut grim_reaper = |w_pid: ContextId, status: usize, usage: Rusage| -> Option<Result<ContextId>> {
         if wifcontinued(status) {
             if flags & WCONTINUED == WCONTINUED {
                 status_slice[0] = status;
+                if let Some(ref mut rusage) = rusage {
+                    **rusage = usage;
+                }
                 Some(Ok(w_pid))
             } else {
                 None
@@ -454,12 +736,18 @@ pub fn waitpid(pid: ContextId, status_ptr: usize, flags: WaitFlags) -> Result<Co
         } else if wifstopped(status) {
             if flags & WUNTRACED == WUNTRACED {
                 status_slice[0] = status;
+                if let Some(ref mut rusage) = rusage {
+                    **rusage = usage;
+                }
                 Some(Ok(w_pid))
             } else {
                 None
             }
         } else {
             status_slice[0] = status;
+            if let Some(ref mut rusage) = rusage {
+                **rusage = usage;
+            }
             Some(reap(w_pid))
         }
     };
@@ -485,14 +773,16 @@ pub fn waitpid(pid: ContextId, status_ptr: usize, flags: WaitFlags) -> Result<Co
             }
 
             if flags & WNOHANG == WNOHANG {
-                if let Some((_wid, (w_pid, status))) = waitpid.receive_any_nonblock() {
-                    grim_reaper(w_pid, status)
+                if let Some((_wid, (w_pid, status, usage))) = waitpid.receive_any_nonblock() {
+                    grim_reaper(w_pid, status, usage)
                 } else {
                     Some(Ok(ContextId::from(0)))
                 }
             } else {
-                let (_wid, (w_pid, status)) = waitpid.receive_any("waitpid any");
-                grim_reaper(w_pid, status)
+                match waitpid.receive_any("waitpid any") {
+                    Some((_wid, (w_pid, status, usage))) => grim_reaper(w_pid, status, usage),
+                    None => Some(Err(Error::new(EINTR))),
+                }
             }
         } else if (pid.into() as isize) < 0 {
             let pgid = ContextId::from(-(pid.into() as isize) as usize);
@@ -516,20 +806,22 @@ pub fn waitpid(pid: ContextId, status_ptr: usize, flags: WaitFlags) -> Result<Co
             }
 
             if flags & WNOHANG == WNOHANG {
-                if let Some((w_pid, status)) = waitpid.receive_nonblock(&WaitpidKey {
+                if let Some((w_pid, status, usage)) = waitpid.receive_nonblock(&WaitpidKey {
                     pid: None,
                     pgid: Some(pgid)
                 }) {
-                    grim_reaper(w_pid, status)
+                    grim_reaper(w_pid, status, usage)
                 } else {
                     Some(Ok(ContextId::from(0)))
                 }
             } else {
-                let (w_pid, status) = waitpid.receive(&WaitpidKey {
+                match waitpid.receive(&WaitpidKey {
                     pid: None,
                     pgid: Some(pgid)
-                }, "waitpid pgid");
-                grim_reaper(w_pid, status)
+                }, "waitpid pgid") {
+                    Some((w_pid, status, usage)) => grim_reaper(w_pid, status, usage),
+                    None => Some(Err(Error::new(EINTR))),
+                }
             }
         } else {
             let hack_status = {
@@ -547,26 +839,28 @@ pub fn waitpid(pid: ContextId, status_ptr: usize, flags: WaitFlags) -> Result<Co
             };
 
             if let Some(context::Status::Exited(status)) = hack_status {
-                let _ = waitpid.receive_nonblock(&WaitpidKey {
+                let usage = waitpid.receive_nonblock(&WaitpidKey {
                     pid: Some(pid),
                     pgid: None
-                });
-                grim_reaper(pid, status)
+                }).map_or_else(Rusage::default, |(_, _, usage)| usage);
+                grim_reaper(pid, status, usage)
             } else if flags & WNOHANG == WNOHANG {
-                if let Some((w_pid, status)) = waitpid.receive_nonblock(&WaitpidKey {
+                if let Some((w_pid, status, usage)) = waitpid.receive_nonblock(&WaitpidKey {
                     pid: Some(pid),
                     pgid: None
                 }) {
-                    grim_reaper(w_pid, status)
+                    grim_reaper(w_pid, status, usage)
                 } else {
                     Some(Ok(ContextId::from(0)))
                 }
             } else {
-                let (w_pid, status) = waitpid.receive(&WaitpidKey {
+                match waitpid.receive(&WaitpidKey {
                     pid: Some(pid),
                     pgid: None
-                }, "waitpid pid");
-                grim_reaper(w_pid, status)
+                }, "waitpid pid") {
+                    Some((w_pid, status, usage)) => grim_reaper(w_pid, status, usage),
+                    None => Some(Err(Error::new(EINTR))),
+                }
             }
         };
 
@@ -16,6 +16,18 @@ pub fn clock_gettime(clock: usize, time: &mut TimeSpec) -> Result<usize> {
     Ok(0)
 }
 
+/// Get the resolution of a clock, which depends on the timer used as the system timer
+pub fn clock_getres(clock: usize, res: &mut TimeSpec) -> Result<usize> {
+    let resolution = match clock {
+        CLOCK_REALTIME | CLOCK_MONOTONIC => time::resolution(),
+        _ => return Err(Error::new(EINVAL))
+    };
+
+    res.tv_sec = (resolution / time::NANOS_PER_SEC) as i64;
+    res.tv_nsec = (resolution % time::NANOS_PER_SEC) as i32;
+    Ok(0)
+}
+
 /// Nanosleep will sleep by switching the current context
 pub fn nanosleep(req: &TimeSpec, rem_opt: Option<&mut TimeSpec>) -> Result<usize> {
     //start is a tuple of (seconds, nanoseconds)
@@ -27,7 +39,7 @@ pub fn nanosleep(req: &TimeSpec, rem_opt: Option<&mut TimeSpec>) -> Result<usize
         let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
         let mut context = context_lock.write();
 
-        context.wake = Some(end);
+        context.set_wake(Some(end));
         context.block("nanosleep");
     }
 
@@ -38,7 +50,10 @@ pub fn nanosleep(req: &TimeSpec, rem_opt: Option<&mut TimeSpec>) -> Result<usize
         let contexts = context::contexts();
         let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
         let mut context = context_lock.write();
-        if context.wake.is_some() {
+        if context.force_kill {
+            context.set_wake(None);
+            break;
+        } else if context.wake().is_some() {
             context.block("nanosleep spurious");
         } else {
             break;
@@ -61,6 +76,47 @@ pub fn nanosleep(req: &TimeSpec, rem_opt: Option<&mut TimeSpec>) -> Result<usize
     Ok(0)
 }
 
+/// Sleep until the monotonic clock reaches an absolute deadline, like `clock_nanosleep` with
+/// `TIMER_ABSTIME`. The deadline is in the units of `time::monotonic`.
+pub fn clock_nanosleep_abs(clock: usize, deadline: &TimeSpec) -> Result<usize> {
+    if clock != CLOCK_MONOTONIC || deadline.tv_sec < 0 || deadline.tv_nsec < 0 || deadline.tv_nsec as u128 >= time::NANOS_PER_SEC {
+        return Err(Error::new(EINVAL));
+    }
+    let end = (deadline.tv_sec as u128 * time::NANOS_PER_SEC) + (deadline.tv_nsec as u128);
+
+    // A deadline in the past does not block at all
+    if time::monotonic() >= end {
+        return Ok(0);
+    }
+
+    {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let mut context = context_lock.write();
+
+        context.set_wake(Some(end));
+        context.block("clock_nanosleep");
+    }
+
+    loop {
+        unsafe { context::switch(); }
+
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let mut context = context_lock.write();
+
+        // `update` clears `wake` once the deadline has passed
+        if context.wake().is_none() {
+            return Ok(0);
+        }
+        // Otherwise, if the context was made runnable, it was woken early by a signal
+        if context.status == context::Status::Runnable {
+            context.set_wake(None);
+            return Err(Error::new(EINTR));
+        }
+    }
+}
+
 pub fn sched_yield() -> Result<usize> {
     unsafe { context::switch(); }
     Ok(0)
@@ -19,13 +19,30 @@ fn validate(address: usize, size: usize, writable: bool) -> Result<()> {
     let end_offset = size.checked_sub(1).ok_or(Error::new(EFAULT))?;
     let end_address = address.checked_add(end_offset).ok_or(Error::new(EFAULT))?;
 
-    let addr_space = Arc::clone(context::current()?.read().addr_space()?);
-    let addr_space = addr_space.read();
+    let addr_space_lock = Arc::clone(context::current()?.read().addr_space()?);
+    let mut addr_space = addr_space_lock.read();
 
     let start_page = Page::containing_address(VirtualAddress::new(address));
     let end_page = Page::containing_address(VirtualAddress::new(end_address));
     for page in Page::range_inclusive(start_page, end_page) {
-        if let Some((_, flags)) = addr_space.table.utable.translate(page.start_address()) {
+        let translated = addr_space.table.translate(page.start_address());
+        if translated.map_or(true, |(_, flags)| writable && !flags.has_write()) {
+            // Released anonymous memory and private file mappings that are yet to be copied are
+            // resolved as a fault from userspace would. The kernel does not fault on them itself,
+            // as it may hold this lock then.
+            drop(addr_space);
+            {
+                let mut addr_space = addr_space_lock.write();
+                if translated.is_none() {
+                    addr_space.fault_in(page.start_address());
+                } else {
+                    addr_space.copy_on_write(page.start_address());
+                }
+            }
+            addr_space = addr_space_lock.read();
+        }
+
+        if let Some((_, flags)) = addr_space.table.translate(page.start_address()) {
             if !flags.has_user() {
                 // println!("{:X}: Not usermode", page.start_address().data());
                 return Err(Error::new(EFAULT));
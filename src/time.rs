@@ -11,6 +11,11 @@ pub fn monotonic() -> u128 {
     *OFFSET.lock() + crate::arch::time::counter()
 }
 
+/// Resolution of the monotonic and realtime clocks, in nanoseconds
+pub fn resolution() -> u128 {
+    crate::arch::time::resolution()
+}
+
 pub fn realtime() -> u128 {
     *START.lock() + monotonic()
 }